@@ -8,9 +8,9 @@ use std::{
 use aleph_runtime::{self, opaque::Block, RuntimeApi};
 use finality_aleph::{
     run_validator_node, AlephBlockImport, AlephConfig, AllBlockMetrics, BlockImporter,
-    ChannelProvider, Justification, JustificationTranslator, MillisecsPerBlock, Protocol,
-    ProtocolNaming, RateLimiterConfig, RedirectingBlockImport, SessionPeriod, SubstrateChainStatus,
-    SubstrateNetwork, SyncOracle, TracingBlockImport, ValidatorAddressCache,
+    ChannelProvider, GossipConfig, Justification, JustificationTranslator, MillisecsPerBlock,
+    Protocol, ProtocolNaming, RateLimiterConfig, RedirectingBlockImport, SessionPeriod,
+    SubstrateChainStatus, SubstrateNetwork, SyncOracle, TracingBlockImport, ValidatorAddressCache,
 };
 use futures::channel::mpsc;
 use log::warn;
@@ -431,6 +431,7 @@ pub fn new_authority(
         external_addresses: aleph_config.external_addresses(),
         validator_port: aleph_config.validator_port(),
         rate_limiter_config,
+        gossip_config: GossipConfig::default(),
         sync_oracle,
         validator_address_cache,
         transaction_pool,
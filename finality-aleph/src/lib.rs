@@ -73,7 +73,20 @@ pub use crate::{
     metrics::{AllBlockMetrics, DefaultClock, FinalityRateMetrics, TimingBlockMetrics},
     network::{
         address_cache::{ValidatorAddressCache, ValidatorAddressingInfo},
-        Protocol, ProtocolNaming, SubstrateNetwork, SubstrateNetworkEventStream,
+        GossipAdaptiveStatusConfig, GossipBackoffConfig, GossipBatchConfig,
+        GossipBroadcastAllHandle, GossipBroadcastDedupConfig, GossipBroadcastResult,
+        GossipCircuitBreakerConfig, GossipCodec, GossipCompressionConfig, GossipConfig,
+        GossipConnectionEvent, GossipDecodeFailure, GossipDelivery, GossipDirection,
+        GossipDropPolicy, GossipEnvelope, GossipFairDrainConfig, GossipFragmentationConfig,
+        GossipInboundDedupConfig, GossipInboundRateLimitConfig, GossipIncoming,
+        GossipLatencyConfig, GossipMessageKind, GossipMessageSigning, GossipPeerLimitConfig,
+        GossipPeerLimitPolicy, GossipPeerSnapshot, GossipPriority, GossipProtocolConfig,
+        GossipProtocolSnapshot, GossipQuietStatusConfig, GossipRebroadcastConfig,
+        GossipRebroadcastHandle, GossipRequestError, GossipRequestResponse, GossipResponse,
+        GossipScaleCodec, GossipSendDedupConfig, GossipService, GossipServiceHandle, GossipSigner,
+        GossipSpawner, GossipStateSnapshot, GossipStopHandle, GossipStuckPeerConfig,
+        GossipTerminationReason, GossipVerifier, GossipVersionRange, Protocol, ProtocolNaming,
+        SubstrateNetwork, SubstrateNetworkEventStream,
     },
     nodes::run_validator_node,
     session::SessionPeriod,
@@ -297,6 +310,7 @@ pub struct AlephConfig<C, SC, T> {
     pub external_addresses: Vec<String>,
     pub validator_port: u16,
     pub rate_limiter_config: RateLimiterConfig,
+    pub gossip_config: GossipConfig,
     pub sync_oracle: SyncOracle,
     pub validator_address_cache: Option<ValidatorAddressCache>,
     pub transaction_pool: Arc<T>,
@@ -0,0 +1,94 @@
+//! Stopping sending to peers that keep failing to receive anything.
+use std::{sync::Arc, time::Duration};
+
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+use crate::network::gossip::config::CircuitBreakerConfig;
+
+struct State {
+    consecutive_failures: usize,
+    open_until: Option<Instant>,
+}
+
+/// A circuit breaker shared between a peer sender and the service. After enough consecutive
+/// failures the circuit opens and nothing is sent for the cooldown. Afterwards a single attempt
+/// is allowed, its failure opens the circuit again right away.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    failure_threshold: usize,
+    cooldown: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+impl CircuitBreaker {
+    /// A closed circuit.
+    pub fn new(config: &CircuitBreakerConfig) -> Self {
+        CircuitBreaker {
+            failure_threshold: config.failure_threshold.max(1),
+            cooldown: config.cooldown,
+            state: Arc::new(Mutex::new(State {
+                consecutive_failures: 0,
+                open_until: None,
+            })),
+        }
+    }
+
+    /// Whether sending should be attempted, i.e. the circuit is not open.
+    pub fn allows_send(&self) -> bool {
+        !matches!(self.state.lock().open_until, Some(until) if Instant::now() < until)
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock();
+        state.consecutive_failures = 0;
+        state.open_until = None;
+    }
+
+    /// Records a failed attempt, returns whether it opened the circuit.
+    pub fn record_failure(&self) -> bool {
+        let mut state = self.state.lock();
+        state.consecutive_failures += 1;
+        if state.consecutive_failures < self.failure_threshold {
+            return false;
+        }
+        state.open_until = Some(Instant::now() + self.cooldown);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time;
+
+    use super::CircuitBreaker;
+    use crate::network::gossip::config::CircuitBreakerConfig;
+
+    #[tokio::test(start_paused = true)]
+    async fn opens_after_threshold_and_half_opens_after_cooldown() {
+        let breaker = CircuitBreaker::new(&CircuitBreakerConfig {
+            failure_threshold: 3,
+            cooldown: Duration::from_secs(5),
+        });
+        assert!(!breaker.record_failure());
+        breaker.record_success();
+        assert!(!breaker.record_failure());
+        assert!(!breaker.record_failure());
+        assert!(breaker.allows_send());
+        assert!(breaker.record_failure());
+        assert!(!breaker.allows_send());
+
+        time::advance(Duration::from_secs(5)).await;
+        assert!(breaker.allows_send());
+        // The single attempt after the cooldown failing opens the circuit again.
+        assert!(breaker.record_failure());
+        assert!(!breaker.allows_send());
+
+        time::advance(Duration::from_secs(5)).await;
+        breaker.record_success();
+        assert!(!breaker.record_failure());
+        assert!(breaker.allows_send());
+    }
+}
@@ -0,0 +1,266 @@
+//! Turning data sent through the gossip network into bytes and back.
+use std::sync::Arc;
+
+use parity_scale_codec::{Compact, Decode, DecodeLimit, Encode, Error};
+
+use crate::network::gossip::Delivery;
+
+/// How deeply nested received data can be, deeper nesting is only possible in malicious messages.
+const MAX_DECODE_DEPTH: u32 = 64;
+
+const GOSSIP: u8 = 0;
+const DIRECT: u8 = 1;
+
+/// Which kind of message some data is, for protocols carrying several kinds of messages.
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone, Hash)]
+pub enum MessageKind {
+    /// The codec does not tell kinds of messages apart, or did not recognize this one.
+    #[default]
+    Unknown,
+    Named(&'static str),
+}
+
+impl MessageKind {
+    /// The name of the kind, as used in metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageKind::Unknown => "unknown",
+            MessageKind::Named(name) => name,
+        }
+    }
+}
+
+/// The wire format of the data of a single protocol.
+pub trait Codec<D>: Send + Sync + 'static {
+    /// Appends the encoding of the data to `dest`.
+    fn encode_to(&self, data: &D, dest: &mut Vec<u8>);
+
+    /// Decodes data from the beginning of the input, advancing it past the decoded bytes.
+    fn decode(&self, input: &mut &[u8]) -> Result<D, Error>;
+
+    /// Decodes data encoded with the previous version of the encoding, for protocols tagging
+    /// messages with encoding versions. Codecs whose encoding did not change decode it as usual.
+    fn decode_previous(&self, input: &mut &[u8]) -> Result<D, Error> {
+        self.decode(input)
+    }
+
+    /// The kind of the data. Codecs that do not tell kinds of messages apart return
+    /// `MessageKind::Unknown`.
+    fn kind(&self, _data: &D) -> MessageKind {
+        MessageKind::Unknown
+    }
+
+    /// The kind of the encoded data judging only by its beginning, used for reporting messages
+    /// that cannot be decoded.
+    fn encoded_kind(&self, _encoded: &[u8]) -> MessageKind {
+        MessageKind::Unknown
+    }
+}
+
+/// The SCALE encoding of the data, refusing to decode too deeply nested structures.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScaleCodec;
+
+impl<D: Encode + Decode> Codec<D> for ScaleCodec {
+    fn encode_to(&self, data: &D, dest: &mut Vec<u8>) {
+        data.encode_to(dest)
+    }
+
+    fn decode(&self, input: &mut &[u8]) -> Result<D, Error> {
+        D::decode_with_depth_limit(MAX_DECODE_DEPTH, input)
+    }
+}
+
+/// The codec decoding data with `Codec::decode_previous` of the wrapped one, for messages
+/// tagged with the previous encoding version.
+pub struct PreviousVersion<D>(pub Arc<dyn Codec<D>>);
+
+impl<D: 'static> Codec<D> for PreviousVersion<D> {
+    fn encode_to(&self, data: &D, dest: &mut Vec<u8>) {
+        self.0.encode_to(data, dest)
+    }
+
+    fn decode(&self, input: &mut &[u8]) -> Result<D, Error> {
+        self.0.decode_previous(input)
+    }
+
+    fn kind(&self, data: &D) -> MessageKind {
+        self.0.kind(data)
+    }
+
+    fn encoded_kind(&self, encoded: &[u8]) -> MessageKind {
+        self.0.encoded_kind(encoded)
+    }
+}
+
+pub fn encode<D, C: Codec<D> + ?Sized>(codec: &C, data: &D) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    codec.encode_to(data, &mut encoded);
+    encoded
+}
+
+/// Encodes the messages as one, for the SCALE codec this is the same as encoding a `Vec`. Sent
+/// batches are joined from already encoded messages with `join_batch` instead.
+#[cfg(test)]
+pub fn encode_batch<D, C: Codec<D> + ?Sized>(codec: &C, batch: &[D]) -> Vec<u8> {
+    let mut encoded = Compact(batch.len() as u32).encode();
+    for data in batch {
+        codec.encode_to(data, &mut encoded);
+    }
+    encoded
+}
+
+/// Joins messages encoded one by one into a batch, the same as encoding them with `encode_batch`.
+pub fn join_batch<B: AsRef<[u8]>>(encoded: &[B]) -> Vec<u8> {
+    let mut batch = Compact(encoded.len() as u32).encode();
+    for data in encoded {
+        batch.extend_from_slice(data.as_ref());
+    }
+    batch
+}
+
+pub fn decode<D, C: Codec<D> + ?Sized>(codec: &C, mut data: &[u8]) -> Result<D, Error> {
+    codec.decode(&mut data)
+}
+
+/// Decodes messages encoded with `encode_batch`.
+pub fn decode_batch<D, C: Codec<D> + ?Sized>(codec: &C, mut data: &[u8]) -> Result<Vec<D>, Error> {
+    let Compact(len) = Compact::<u32>::decode(&mut data)?;
+    // The length comes from the network, so nothing is allocated up front based on it.
+    (0..len).map(|_| codec.decode(&mut data)).collect()
+}
+
+fn prefixed(byte: u8, encoded: &[u8]) -> Vec<u8> {
+    let mut prefixed = Vec::with_capacity(encoded.len() + 1);
+    prefixed.push(byte);
+    prefixed.extend_from_slice(encoded);
+    prefixed
+}
+
+/// Prefixes the encoded message with a byte saying how it was sent.
+pub fn tag(delivery: Delivery, encoded: Vec<u8>) -> Vec<u8> {
+    let tag = match delivery {
+        Delivery::Gossip => GOSSIP,
+        Delivery::Direct => DIRECT,
+    };
+    prefixed(tag, &encoded)
+}
+
+/// Prefixes the message with the version of the encoding it was sent with.
+pub fn with_version(version: u8, encoded: &[u8]) -> Vec<u8> {
+    prefixed(version, encoded)
+}
+
+fn decode_tagged_from<D, C: Codec<D> + ?Sized>(
+    codec: &C,
+    data: &mut &[u8],
+) -> Result<(D, Delivery), Error> {
+    let delivery = match u8::decode(data)? {
+        GOSSIP => Delivery::Gossip,
+        DIRECT => Delivery::Direct,
+        _ => return Err("unknown delivery tag".into()),
+    };
+    Ok((codec.decode(data)?, delivery))
+}
+
+/// Decodes a message prefixed with `tag`.
+pub fn decode_tagged<D, C: Codec<D> + ?Sized>(
+    codec: &C,
+    mut data: &[u8],
+) -> Result<(D, Delivery), Error> {
+    decode_tagged_from(codec, &mut data)
+}
+
+/// Decodes a batch joined from messages prefixed with `tag`.
+pub fn decode_tagged_batch<D, C: Codec<D> + ?Sized>(
+    codec: &C,
+    mut data: &[u8],
+) -> Result<Vec<(D, Delivery)>, Error> {
+    let Compact(len) = Compact::<u32>::decode(&mut data)?;
+    (0..len)
+        .map(|_| decode_tagged_from(codec, &mut data))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use parity_scale_codec::{Decode, Encode, Error};
+
+    use super::{
+        decode, decode_batch, decode_tagged, decode_tagged_batch, encode, encode_batch, join_batch,
+        tag, Codec, ScaleCodec,
+    };
+    use crate::network::gossip::Delivery;
+
+    #[derive(Debug, PartialEq, Eq, Decode, Encode)]
+    struct Nested(Vec<Nested>);
+
+    fn nested(depth: usize) -> Nested {
+        (0..depth).fold(Nested(Vec::new()), |inner, _| Nested(vec![inner]))
+    }
+
+    #[test]
+    fn decoding_limits_nesting_depth() {
+        assert!(decode::<Nested, _>(&ScaleCodec, &nested(8).encode()).is_ok());
+        assert!(decode::<Nested, _>(&ScaleCodec, &nested(128).encode()).is_err());
+    }
+
+    #[test]
+    fn scale_codec_matches_scale_encoding() {
+        let batch = vec![nested(1), nested(3), nested(0)];
+        assert_eq!(encode(&ScaleCodec, &batch[1]), batch[1].encode());
+        assert_eq!(encode_batch(&ScaleCodec, &batch), batch.encode());
+        assert_eq!(decode_batch(&ScaleCodec, &batch.encode()), Ok(batch));
+    }
+
+    #[test]
+    fn delivery_tags_survive_decoding() {
+        let gossip = tag(Delivery::Gossip, encode(&ScaleCodec, &nested(1)));
+        let direct = tag(Delivery::Direct, encode(&ScaleCodec, &nested(2)));
+        assert_eq!(
+            decode_tagged(&ScaleCodec, &gossip),
+            Ok((nested(1), Delivery::Gossip))
+        );
+        assert_eq!(
+            decode_tagged_batch(&ScaleCodec, &join_batch(&[direct, gossip])),
+            Ok(vec![
+                (nested(2), Delivery::Direct),
+                (nested(1), Delivery::Gossip)
+            ])
+        );
+        assert!(decode_tagged::<Nested, _>(&ScaleCodec, &[2, 0]).is_err());
+    }
+
+    #[test]
+    fn joined_batch_matches_encoded_batch() {
+        let batch = vec![nested(2), nested(0)];
+        let encoded: Vec<_> = batch.iter().map(|data| encode(&ScaleCodec, data)).collect();
+        assert_eq!(join_batch(&encoded), encode_batch(&ScaleCodec, &batch));
+    }
+
+    /// Encodes numbers as a single byte.
+    struct ByteCodec;
+
+    impl Codec<u32> for ByteCodec {
+        fn encode_to(&self, data: &u32, dest: &mut Vec<u8>) {
+            dest.push(*data as u8)
+        }
+
+        fn decode(&self, input: &mut &[u8]) -> Result<u32, Error> {
+            let (byte, rest) = input.split_first().ok_or("missing byte")?;
+            *input = rest;
+            Ok((*byte).into())
+        }
+    }
+
+    #[test]
+    fn custom_codec_round_trips() {
+        assert_eq!(encode(&ByteCodec, &7), vec![7]);
+        assert_eq!(decode(&ByteCodec, &[7]), Ok(7));
+        let batch = vec![1, 2, 3];
+        let encoded = encode_batch(&ByteCodec, &batch);
+        assert_eq!(encoded, vec![12, 1, 2, 3]);
+        assert_eq!(decode_batch(&ByteCodec, &encoded), Ok(batch));
+        assert!(decode_batch(&ByteCodec, &encoded[..3]).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! Optional LZ4 compression of encoded messages. Every message gets prefixed with a tag byte
+//! saying whether the rest of it is compressed.
+use std::{
+    borrow::Cow,
+    fmt::{Display, Error as FmtError, Formatter},
+    io,
+};
+
+use crate::network::gossip::config::CompressionConfig;
+
+const UNCOMPRESSED: u8 = 0;
+const LZ4: u8 = 1;
+
+/// Length of the uncompressed size LZ4 prepends to compressed data.
+const SIZE_PREFIX_LENGTH: usize = 4;
+
+/// What can go wrong when decompressing a message.
+#[derive(Debug)]
+pub enum DecompressionError {
+    /// The message is empty, so it has no tag.
+    MissingTag,
+    /// The tag is not one we know.
+    UnknownTag(u8),
+    /// The compressed data is too short to contain its uncompressed size.
+    MissingSize,
+    /// The message would decompress to more bytes than we accept.
+    TooLarge(usize),
+    /// The compressed data is malformed.
+    Lz4(io::Error),
+}
+
+impl Display for DecompressionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        use DecompressionError::*;
+        match self {
+            MissingTag => write!(f, "missing compression tag"),
+            UnknownTag(tag) => write!(f, "unknown compression tag {tag}"),
+            MissingSize => write!(f, "missing uncompressed size"),
+            TooLarge(size) => write!(f, "uncompressed size {size} is too large"),
+            Lz4(e) => write!(f, "malformed LZ4 data: {e}"),
+        }
+    }
+}
+
+/// Tags the encoded message, compressing it when it is longer than the threshold and
+/// compression actually makes it shorter.
+pub fn compress(encoded: &[u8], config: &CompressionConfig) -> Vec<u8> {
+    if encoded.len() > config.threshold {
+        // Compression only fails for data way larger than any message, send it as is then.
+        if let Ok(compressed) = lz4::block::compress(encoded, None, true) {
+            if compressed.len() < encoded.len() {
+                return tagged(LZ4, &compressed);
+            }
+        }
+    }
+    tagged(UNCOMPRESSED, encoded)
+}
+
+fn tagged(tag: u8, data: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(data.len() + 1);
+    message.push(tag);
+    message.extend_from_slice(data);
+    message
+}
+
+/// Returns the encoded message from a tagged one, refusing to decompress it to more than
+/// `max_size` bytes.
+pub fn decompress(message: &[u8], max_size: usize) -> Result<Cow<[u8]>, DecompressionError> {
+    use DecompressionError::*;
+    match message.split_first() {
+        Some((&UNCOMPRESSED, data)) => Ok(Cow::Borrowed(data)),
+        Some((&LZ4, data)) => {
+            let size: [u8; SIZE_PREFIX_LENGTH] = data
+                .get(..SIZE_PREFIX_LENGTH)
+                .and_then(|size| size.try_into().ok())
+                .ok_or(MissingSize)?;
+            let size = u32::from_le_bytes(size) as usize;
+            if size > max_size {
+                return Err(TooLarge(size));
+            }
+            lz4::block::decompress(data, None)
+                .map(Cow::Owned)
+                .map_err(Lz4)
+        }
+        Some((&tag, _)) => Err(UnknownTag(tag)),
+        None => Err(MissingTag),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress, DecompressionError, LZ4, UNCOMPRESSED};
+    use crate::network::gossip::config::CompressionConfig;
+
+    const THRESHOLD: usize = 64;
+    const MAX_SIZE: usize = 1024;
+
+    fn config() -> CompressionConfig {
+        CompressionConfig {
+            threshold: THRESHOLD,
+        }
+    }
+
+    #[test]
+    fn short_messages_are_not_compressed() {
+        let encoded = vec![7; THRESHOLD - 1];
+        let message = compress(&encoded, &config());
+        assert_eq!(message[0], UNCOMPRESSED);
+        assert_eq!(decompress(&message, MAX_SIZE).unwrap(), &encoded[..]);
+    }
+
+    #[test]
+    fn messages_at_threshold_are_not_compressed() {
+        let encoded = vec![7; THRESHOLD];
+        let message = compress(&encoded, &config());
+        assert_eq!(message[0], UNCOMPRESSED);
+        assert_eq!(decompress(&message, MAX_SIZE).unwrap(), &encoded[..]);
+    }
+
+    #[test]
+    fn messages_above_threshold_are_compressed() {
+        let encoded = vec![7; THRESHOLD + 1];
+        let message = compress(&encoded, &config());
+        assert_eq!(message[0], LZ4);
+        assert!(message.len() < encoded.len());
+        assert_eq!(decompress(&message, MAX_SIZE).unwrap(), &encoded[..]);
+    }
+
+    #[test]
+    fn incompressible_messages_are_sent_as_is() {
+        let encoded: Vec<u8> = (0..=255).collect();
+        let message = compress(&encoded, &config());
+        assert_eq!(message[0], UNCOMPRESSED);
+        assert_eq!(decompress(&message, MAX_SIZE).unwrap(), &encoded[..]);
+    }
+
+    #[test]
+    fn refuses_to_decompress_too_much() {
+        let message = compress(&[7; MAX_SIZE + 1], &config());
+        assert!(matches!(
+            decompress(&message, MAX_SIZE),
+            Err(DecompressionError::TooLarge(size)) if size == MAX_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert!(matches!(
+            decompress(&[], MAX_SIZE),
+            Err(DecompressionError::MissingTag)
+        ));
+        assert!(matches!(
+            decompress(&[2, 1, 2, 3], MAX_SIZE),
+            Err(DecompressionError::UnknownTag(2))
+        ));
+        assert!(matches!(
+            decompress(&[LZ4, 1], MAX_SIZE),
+            Err(DecompressionError::MissingSize)
+        ));
+    }
+}
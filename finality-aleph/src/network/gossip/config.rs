@@ -0,0 +1,407 @@
+use std::time::Duration;
+
+use crate::{
+    network::gossip::{queue::DropPolicy, MessageKind, Protocol},
+    STATUS_REPORT_INTERVAL,
+};
+
+const DEFAULT_QUEUE_CAPACITY: usize = 16;
+const DEFAULT_COMMAND_BUFFER: usize = 4096;
+const DEFAULT_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(10);
+const DEFAULT_STALE_PEER_THRESHOLD: Duration = Duration::from_secs(60);
+const DEFAULT_RATE_SMOOTHING: f64 = 0.3;
+const DEFAULT_CONNECTIVITY_DEBOUNCE: Duration = Duration::from_secs(1);
+const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Configuration of waiting before retrying to create a sender for a peer, the delay doubles
+/// with every consecutive failure.
+#[derive(Debug, Clone)]
+pub struct BackoffConfig {
+    /// The delay after the first failure.
+    pub initial: Duration,
+    /// The delay never grows beyond this.
+    pub max: Duration,
+}
+
+impl BackoffConfig {
+    /// Returns the delay after the given number of consecutive failures.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(31);
+        self.initial.saturating_mul(1 << exponent).min(self.max)
+    }
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        BackoffConfig {
+            initial: DEFAULT_INITIAL_BACKOFF,
+            max: DEFAULT_MAX_BACKOFF,
+        }
+    }
+}
+
+/// Configuration of broadcasting data repeatedly, the interval between broadcasts doubles every
+/// time.
+#[derive(Debug, Clone)]
+pub struct RebroadcastConfig {
+    /// The interval between the first and the second broadcast.
+    pub initial_interval: Duration,
+    /// The interval never grows beyond this.
+    pub max_interval: Duration,
+    /// Nothing is broadcast once this passes since the first broadcast.
+    pub duration: Duration,
+}
+
+/// Configuration of skipping identical broadcasts to peers that recently got them.
+#[derive(Debug, Clone)]
+pub struct BroadcastDedupConfig {
+    /// How many distinct broadcast payloads are remembered.
+    pub cache_size: usize,
+    /// How long a payload sent to a peer is not sent to it again.
+    pub ttl: Duration,
+}
+
+/// Configuration of skipping data sent directly to a peer that recently got identical data, be it
+/// in a broadcast, a replay or a direct send. Unlike broadcast deduplication, payloads are
+/// remembered separately for every peer.
+#[derive(Debug, Clone)]
+pub struct SendDedupConfig {
+    /// How many distinct payloads are remembered for every peer.
+    pub cache_size: usize,
+    /// How long after a payload is queued for a peer identical direct sends to it are skipped.
+    pub ttl: Duration,
+}
+
+/// Configuration of dropping received messages identical to ones received recently, usually
+/// copies of the same broadcast arriving from several peers. Messages are compared by their bytes
+/// as received.
+#[derive(Debug, Clone)]
+pub struct InboundDedupConfig {
+    /// How many distinct received payloads are remembered.
+    pub cache_size: usize,
+    /// How long after receiving a payload identical ones are dropped.
+    pub ttl: Duration,
+    /// Kinds of messages that are meant to be repeated, such as heartbeats, and so are never
+    /// dropped. Network messages containing any of them are not remembered either.
+    pub excluded_kinds: Vec<MessageKind>,
+}
+
+/// Configuration of sending messages queued for a peer together, in a single network message.
+/// Received messages are decoded as batches when this is enabled, so all nodes of a network have
+/// to agree on it.
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+    /// How many messages are sent together at most.
+    pub max_messages: usize,
+}
+
+/// Configuration of compressing messages. Every message gets prefixed with a tag byte saying
+/// whether it is compressed when this is enabled, so all nodes of a network have to agree on it.
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    /// Encoded messages longer than this many bytes get compressed with LZ4.
+    pub threshold: usize,
+}
+
+/// Configuration of splitting messages into frames, for networks limiting the size of a single
+/// message. Every message gets prefixed with a frame header when this is enabled, so all nodes of a
+/// network have to agree on it.
+#[derive(Debug, Clone)]
+pub struct FragmentationConfig {
+    /// Frames are at most this many bytes long, headers included.
+    pub frame_size: usize,
+    /// Received frames of a message are dropped if the rest does not arrive within this time.
+    pub reassembly_timeout: Duration,
+    /// How many incomplete messages of a single peer are kept at most, the oldest ones are
+    /// dropped to make room for new ones.
+    pub max_partial_messages: usize,
+    /// How many bytes of incomplete messages of a single peer are kept at most, the oldest
+    /// messages are dropped to make room for new ones.
+    pub max_partial_bytes: usize,
+}
+
+/// What to do with a new peer when the limit of connected peers is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerLimitPolicy {
+    /// Do not send anything to the new peer and lower its reputation.
+    RejectNew,
+    /// Stop sending to the peer we heard from least recently to make room for the new one.
+    EvictLeastRecentlySeen,
+}
+
+/// Which way data flows on a protocol, from the perspective of this node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Data is both sent and received.
+    Bidirectional,
+    /// Data is only sent, anything received is dropped.
+    SendOnly,
+    /// Data is only received, sending is refused.
+    ReceiveOnly,
+}
+
+/// Configuration of limiting the number of connected peers.
+#[derive(Debug, Clone)]
+pub struct PeerLimitConfig {
+    /// How many peers can be connected at once.
+    pub max_peers: usize,
+    /// What happens when another peer connects.
+    pub policy: PeerLimitPolicy,
+}
+
+/// Configuration of stopping sending to peers that keep failing to receive messages, to avoid
+/// recreating their senders over and over. Unlike the sender backoff, it also covers failures of
+/// senders that were created successfully.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// After how many consecutive failed sends to a peer messages for it get dropped.
+    pub failure_threshold: usize,
+    /// How long messages get dropped before sending is attempted again.
+    pub cooldown: Duration,
+}
+
+/// The versions of the wire format a node supports. When configured, the first message sent to
+/// every peer is a handshake, so all nodes of a network have to agree on enabling it.
+#[derive(Debug, Clone)]
+pub struct VersionRange {
+    /// Peers supporting at most older versions are not talked to.
+    pub min: u16,
+    /// The version used with peers supporting it or newer ones.
+    pub max: u16,
+}
+
+/// Configuration of detecting peers whose queue keeps growing, which usually means sending to
+/// them got stuck.
+#[derive(Debug, Clone)]
+pub struct StuckPeerConfig {
+    /// After how many consecutive status reports, each seeing a longer queue than the previous
+    /// one, a warning about the peer is logged.
+    pub growth_intervals: usize,
+    /// Whether peers that are warned about also get disconnected.
+    pub evict: bool,
+}
+
+/// Configuration of tracking how long sending to each peer takes, as an exponentially weighted
+/// moving average of the send durations.
+#[derive(Debug, Clone)]
+pub struct LatencyConfig {
+    /// The weight of the latest send duration in the average, between 0 and 1.
+    pub smoothing: f64,
+    /// Peers with a higher average are considered slow, they get broadcasts after the others.
+    pub slow_threshold: Duration,
+}
+
+/// Configuration of limiting how many messages a single peer can send us. Every peer gets a
+/// bucket of `burst` tokens refilling at `rate` tokens per second, and every received message
+/// takes a token. Messages arriving when the bucket is empty are dropped.
+#[derive(Debug, Clone)]
+pub struct InboundRateLimitConfig {
+    /// How many messages per second a peer can send on average.
+    pub rate: f64,
+    /// How many messages a peer can send at once after being quiet.
+    pub burst: u32,
+}
+
+/// Configuration of draining the queues of peers fairly while the budget of messages waiting to
+/// be sent is nearly used up. Peer senders then take turns sending, in the order they asked for
+/// them, so peers with queued messages are drained round-robin.
+#[derive(Debug, Clone)]
+pub struct FairDrainConfig {
+    /// Below this many free places in the budget peer senders take turns.
+    pub pressure_threshold: usize,
+    /// How many peer senders can have a turn at once.
+    pub concurrent_sends: usize,
+}
+
+/// Configuration of a single protocol handled by the gossip service.
+#[derive(Debug, Clone)]
+pub struct ProtocolConfig {
+    /// How many messages of each priority can wait to be sent to a single peer. Every priority
+    /// has a queue of its own, so twice as many messages can wait for a peer in total.
+    pub queue_capacity: usize,
+    /// What happens to messages sent to a peer whose queue of their priority is full.
+    pub drop_policy: DropPolicy,
+    /// How many messages from the user can wait for the service before sending reports
+    /// congestion.
+    pub command_buffer: usize,
+    /// Deduplication of broadcasts, disabled when `None`.
+    pub broadcast_dedup: Option<BroadcastDedupConfig>,
+    /// Deduplication of received messages, disabled when `None`.
+    pub inbound_dedup: Option<InboundDedupConfig>,
+    /// Deduplication of data sent directly to peers, disabled when `None`.
+    pub send_dedup: Option<SendDedupConfig>,
+    /// Backoff used when creating a sender for a peer fails.
+    pub sender_backoff: BackoffConfig,
+    /// Batching of messages sent to a single peer, disabled when `None`.
+    pub batching: Option<BatchConfig>,
+    /// How long a connection to a peer can stay idle before an empty keepalive message is sent
+    /// over it, disabled when `None`.
+    pub keepalive_interval: Option<Duration>,
+    /// Compression of large messages, disabled when `None`.
+    pub compression: Option<CompressionConfig>,
+    /// Splitting of messages into frames, disabled when `None`.
+    pub fragmentation: Option<FragmentationConfig>,
+    /// Received messages longer than this many bytes are rejected without decoding them. The
+    /// limit of the underlying network protocol is used when `None`.
+    pub max_message_size: Option<usize>,
+    /// Limit of connected peers, unlimited when `None`.
+    pub peer_limit: Option<PeerLimitConfig>,
+    /// Tracking of send latency to peers, disabled when `None`.
+    pub latency_tracking: Option<LatencyConfig>,
+    /// The weight of the latest status report interval in the averages of message rates shown
+    /// in status reports, between 0 and 1.
+    pub rate_smoothing: f64,
+    /// Limit of messages received from a single peer, unlimited when `None`.
+    pub inbound_rate_limit: Option<InboundRateLimitConfig>,
+    /// Detection of peers with growing queues, disabled when `None`.
+    pub stuck_peer_detection: Option<StuckPeerConfig>,
+    /// How many of the most recent broadcasts are sent to newly connected peers, so they do not
+    /// miss what was broadcast just before they connected.
+    pub replay_buffer_size: usize,
+    /// Circuit breaking of sending to failing peers, disabled when `None`.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// The supported versions of the wire format negotiated with peers, no handshakes are
+    /// exchanged when `None`.
+    pub versions: Option<VersionRange>,
+    /// How long the sender of a peer whose stream closed is kept, so that a quickly reopened
+    /// stream reuses it. Streams are closed right away when zero.
+    pub close_grace_period: Duration,
+    /// How long the protocol has to stay without connected peers before losing connectivity is
+    /// published, so that peers quickly reconnecting do not cause a flood of events.
+    pub connectivity_debounce: Duration,
+    /// How long after disconnecting messages from a peer are still handled, messages from
+    /// peers that are not connected are dropped after that. When `None`, messages are handled
+    /// regardless of whether their peer is connected.
+    pub unconnected_tolerance: Option<Duration>,
+    /// How long sending a single message to a peer can take, after that the send counts as
+    /// failed and the sender of the peer gets recreated.
+    pub send_timeout: Duration,
+    /// Whether broadcasts made while no peers are connected are refused with an error instead of
+    /// silently reaching nobody.
+    pub reject_broadcasts_without_peers: bool,
+    /// Whether every message is prefixed with a byte saying whether it was broadcast or sent
+    /// directly, which is reported to receivers. All nodes of a network have to agree on it.
+    pub delivery_tags: bool,
+    /// The version of the encoding of data, sent in front of every message so that nodes can
+    /// be upgraded one by one. Received messages of this and the previous version are decoded,
+    /// the latter with `Codec::decode_previous`, others are dropped. Unlike `versions`, nothing
+    /// is negotiated. No versions are sent when `None`, all nodes of a network have to agree on
+    /// whether they are.
+    pub encoding_version: Option<u8>,
+    /// How many peer senders run as tasks of their own, the senders of further peers share a
+    /// single pooled task. Every peer sender gets a task of its own when `None`.
+    pub max_sender_tasks: Option<usize>,
+    /// Which way data flows on the protocol.
+    pub direction: Direction,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        ProtocolConfig {
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            drop_policy: DropPolicy::DropNewest,
+            command_buffer: DEFAULT_COMMAND_BUFFER,
+            broadcast_dedup: None,
+            inbound_dedup: None,
+            send_dedup: None,
+            sender_backoff: BackoffConfig::default(),
+            batching: None,
+            keepalive_interval: None,
+            compression: None,
+            fragmentation: None,
+            max_message_size: None,
+            peer_limit: None,
+            latency_tracking: None,
+            rate_smoothing: DEFAULT_RATE_SMOOTHING,
+            inbound_rate_limit: None,
+            stuck_peer_detection: None,
+            replay_buffer_size: 0,
+            circuit_breaker: None,
+            versions: None,
+            close_grace_period: Duration::ZERO,
+            connectivity_debounce: DEFAULT_CONNECTIVITY_DEBOUNCE,
+            unconnected_tolerance: None,
+            send_timeout: DEFAULT_SEND_TIMEOUT,
+            reject_broadcasts_without_peers: false,
+            delivery_tags: false,
+            encoding_version: None,
+            max_sender_tasks: None,
+            direction: Direction::Bidirectional,
+        }
+    }
+}
+
+/// Configuration of skipping status reports when nothing they track changed since the previous
+/// report, like on idle networks.
+#[derive(Debug, Clone)]
+pub struct QuietStatusConfig {
+    /// While nothing changes, a short line saying so is reported this often, never when `None`.
+    pub heartbeat_interval: Option<Duration>,
+}
+
+/// Configuration of reporting the status more often while some peers are backlogged, to catch
+/// problems as they develop. Every report made while some peer is backlogged halves the interval
+/// until the next one, down to the minimum, and the normal status report interval is restored
+/// as soon as no peer is.
+#[derive(Debug, Clone)]
+pub struct AdaptiveStatusConfig {
+    /// A peer is backlogged while more than this many messages wait to be sent to it.
+    pub backlog_threshold: usize,
+    /// The shortest interval between status reports.
+    pub min_interval: Duration,
+}
+
+/// Configuration of the gossip service.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub authentication: ProtocolConfig,
+    pub block_sync: ProtocolConfig,
+    /// How long the service keeps handling messages sent by users after being told to exit.
+    pub shutdown_grace_period: Duration,
+    /// How often the service logs a report of its status.
+    pub status_report_interval: Duration,
+    /// How long a peer can stay silent before the status report counts it as stale.
+    pub stale_peer_threshold: Duration,
+    /// The first status report is delayed by a random duration up to this one, so that nodes
+    /// started together do not report in lockstep.
+    pub status_report_jitter: Duration,
+    /// Skipping unchanged status reports, disabled when `None`.
+    pub quiet_status_reports: Option<QuietStatusConfig>,
+    /// Reporting the status more often while some peers are backlogged, disabled when `None`.
+    pub adaptive_status_reports: Option<AdaptiveStatusConfig>,
+    /// How many messages can wait to be sent to all the peers of both protocols together,
+    /// unlimited when `None`. Sending is refused while this many messages wait.
+    pub max_in_flight_messages: Option<usize>,
+    /// Fair draining of the queues of peers, disabled when `None`. Only used when the number of
+    /// messages waiting to be sent is limited.
+    pub fair_drain: Option<FairDrainConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            authentication: ProtocolConfig::default(),
+            block_sync: ProtocolConfig::default(),
+            shutdown_grace_period: DEFAULT_SHUTDOWN_GRACE_PERIOD,
+            status_report_interval: STATUS_REPORT_INTERVAL,
+            stale_peer_threshold: DEFAULT_STALE_PEER_THRESHOLD,
+            status_report_jitter: Duration::ZERO,
+            quiet_status_reports: None,
+            adaptive_status_reports: None,
+            max_in_flight_messages: None,
+            fair_drain: None,
+        }
+    }
+}
+
+impl Config {
+    /// Returns the configuration of the given protocol.
+    pub fn protocol(&self, protocol: Protocol) -> &ProtocolConfig {
+        match protocol {
+            Protocol::Authentication => &self.authentication,
+            Protocol::BlockSync => &self.block_sync,
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! Remembering which peers recently got a broadcast payload, so identical broadcasts are not
+//! resent to them, which payloads were recently queued for every peer, so that direct sends of
+//! them are skipped, and which payloads were recently received, so that copies of them are
+//! dropped.
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+
+use crate::network::gossip::config::{BroadcastDedupConfig, InboundDedupConfig, SendDedupConfig};
+
+/// Hash identifying a broadcast payload, computed over its encoding.
+pub type PayloadHash = u64;
+
+pub fn payload_hash(payload: &[u8]) -> PayloadHash {
+    let mut hasher = DefaultHasher::new();
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
+
+struct Entry<P> {
+    first_sent: Instant,
+    peers: HashSet<P>,
+}
+
+/// A bounded cache of recently broadcast payloads and the peers they were sent to.
+pub struct BroadcastCache<P: Clone + Eq + Hash> {
+    entries: LruCache<PayloadHash, Entry<P>>,
+    ttl: Duration,
+}
+
+impl<P: Clone + Eq + Hash> BroadcastCache<P> {
+    pub fn new(config: &BroadcastDedupConfig) -> Self {
+        let capacity = NonZeroUsize::new(config.cache_size).unwrap_or(NonZeroUsize::MIN);
+        BroadcastCache {
+            entries: LruCache::new(capacity),
+            ttl: config.ttl,
+        }
+    }
+
+    /// Returns the peers among `peers` that did not get the payload within the TTL.
+    pub fn unsent_peers(
+        &mut self,
+        hash: PayloadHash,
+        peers: impl IntoIterator<Item = P>,
+    ) -> Vec<P> {
+        if let Some(entry) = self.entries.get(&hash) {
+            if entry.first_sent.elapsed() < self.ttl {
+                return peers
+                    .into_iter()
+                    .filter(|peer| !entry.peers.contains(peer))
+                    .collect();
+            }
+            self.entries.pop(&hash);
+        }
+        peers.into_iter().collect()
+    }
+
+    /// Remembers that the payload was sent to the peer.
+    pub fn record_sent(&mut self, hash: PayloadHash, peer: P) {
+        self.entries
+            .get_or_insert_mut(hash, || Entry {
+                first_sent: Instant::now(),
+                peers: HashSet::new(),
+            })
+            .peers
+            .insert(peer);
+    }
+}
+
+/// Bounded caches of payloads recently queued for every peer.
+pub struct SentCache<P: Eq + Hash> {
+    peers: HashMap<P, LruCache<PayloadHash, Instant>>,
+    capacity: NonZeroUsize,
+    ttl: Duration,
+}
+
+impl<P: Eq + Hash> SentCache<P> {
+    pub fn new(config: &SendDedupConfig) -> Self {
+        SentCache {
+            peers: HashMap::new(),
+            capacity: NonZeroUsize::new(config.cache_size).unwrap_or(NonZeroUsize::MIN),
+            ttl: config.ttl,
+        }
+    }
+
+    /// Returns whether the payload was queued for the peer within the TTL.
+    pub fn was_sent(&mut self, peer: &P, hash: PayloadHash) -> bool {
+        self.peers
+            .get_mut(peer)
+            .and_then(|sent| sent.get(&hash))
+            .map(|queued| queued.elapsed() < self.ttl)
+            .unwrap_or(false)
+    }
+
+    /// Remembers that the payload was queued for the peer.
+    pub fn record_sent(&mut self, peer: P, hash: PayloadHash) {
+        let capacity = self.capacity;
+        self.peers
+            .entry(peer)
+            .or_insert_with(|| LruCache::new(capacity))
+            .put(hash, Instant::now());
+    }
+
+    /// Forgets everything queued for the peer.
+    pub fn forget_peer(&mut self, peer: &P) {
+        self.peers.remove(peer);
+    }
+}
+
+/// A bounded cache of recently received payloads.
+pub struct InboundCache {
+    entries: LruCache<PayloadHash, Instant>,
+    ttl: Duration,
+}
+
+impl InboundCache {
+    pub fn new(config: &InboundDedupConfig) -> Self {
+        let capacity = NonZeroUsize::new(config.cache_size).unwrap_or(NonZeroUsize::MIN);
+        InboundCache {
+            entries: LruCache::new(capacity),
+            ttl: config.ttl,
+        }
+    }
+
+    /// Returns whether the payload was received within the TTL, remembering it if not.
+    pub fn check_and_record(&mut self, hash: PayloadHash) -> bool {
+        match self.entries.get(&hash) {
+            Some(received) if received.elapsed() < self.ttl => true,
+            _ => {
+                self.entries.put(hash, Instant::now());
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{payload_hash, BroadcastCache, InboundCache, SentCache};
+    use crate::network::gossip::config::{
+        BroadcastDedupConfig, InboundDedupConfig, SendDedupConfig,
+    };
+
+    #[test]
+    fn forgets_payloads_after_ttl() {
+        let mut cache = BroadcastCache::new(&BroadcastDedupConfig {
+            cache_size: 4,
+            ttl: Duration::ZERO,
+        });
+        let hash = payload_hash(&[1, 2, 3]);
+        cache.record_sent(hash, 1);
+        assert_eq!(cache.unsent_peers(hash, [1, 2]), vec![1, 2]);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_payloads() {
+        let mut cache = BroadcastCache::new(&BroadcastDedupConfig {
+            cache_size: 1,
+            ttl: Duration::from_secs(60),
+        });
+        let first = payload_hash(&[1]);
+        let second = payload_hash(&[2]);
+        cache.record_sent(first, 1);
+        assert!(cache.unsent_peers(first, [1]).is_empty());
+        cache.record_sent(second, 1);
+        assert_eq!(cache.unsent_peers(first, [1]), vec![1]);
+    }
+
+    #[test]
+    fn payloads_are_remembered_per_peer() {
+        let mut cache = SentCache::new(&SendDedupConfig {
+            cache_size: 4,
+            ttl: Duration::from_secs(60),
+        });
+        let hash = payload_hash(&[1, 2, 3]);
+        cache.record_sent(1, hash);
+        assert!(cache.was_sent(&1, hash));
+        assert!(!cache.was_sent(&2, hash));
+        cache.forget_peer(&1);
+        assert!(!cache.was_sent(&1, hash));
+    }
+
+    #[test]
+    fn received_payloads_are_seen_until_ttl() {
+        let config = InboundDedupConfig {
+            cache_size: 4,
+            ttl: Duration::from_secs(60),
+            excluded_kinds: Vec::new(),
+        };
+        let hash = payload_hash(&[1, 2, 3]);
+        let mut cache = InboundCache::new(&config);
+        assert!(!cache.check_and_record(hash));
+        assert!(cache.check_and_record(hash));
+
+        let mut cache = InboundCache::new(&InboundDedupConfig {
+            ttl: Duration::ZERO,
+            ..config
+        });
+        assert!(!cache.check_and_record(hash));
+        assert!(!cache.check_and_record(hash));
+    }
+}
@@ -0,0 +1,331 @@
+//! Splitting encoded messages into frames small enough for networks limiting the size of a single
+//! message, and putting them back together. Every frame starts with the id of the message it is
+//! part of, its index and the number of frames of the message.
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::{Display, Error as FmtError, Formatter},
+    hash::Hash,
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::network::gossip::config::FragmentationConfig;
+
+/// The length of the id, index and number of frames in front of every frame.
+pub const HEADER_LEN: usize = 8;
+
+/// What can go wrong with a received frame.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FragmentError {
+    /// The frame is shorter than its header.
+    TooShort(usize),
+    /// The index of the frame is not below the number of frames.
+    InvalidIndex(u16, u16),
+    /// The number of frames differs from the one in earlier frames of the message.
+    InconsistentTotal(u16, u16),
+    /// The received frames of the message exceed the limit of bytes of incomplete messages.
+    TooLarge(usize),
+}
+
+impl Display for FragmentError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        use FragmentError::*;
+        match self {
+            TooShort(len) => write!(f, "frame of {len} bytes is shorter than its header"),
+            InvalidIndex(index, total) => write!(f, "frame {index} out of {total}"),
+            InconsistentTotal(total, expected) => {
+                write!(
+                    f,
+                    "message of {total} frames, earlier frames said {expected}"
+                )
+            }
+            TooLarge(bytes) => write!(f, "incomplete message of over {bytes} bytes"),
+        }
+    }
+}
+
+/// Splits the encoded message into frames of at most `frame_size` bytes, headers included. Very
+/// long messages get longer frames, as the number of frames cannot exceed `u16::MAX`.
+pub fn split(message_id: u32, encoded: &[u8], frame_size: usize) -> Vec<Vec<u8>> {
+    let chunk_size = frame_size
+        .saturating_sub(HEADER_LEN)
+        .max(1)
+        .max((encoded.len() + u16::MAX as usize - 1) / u16::MAX as usize);
+    let chunks: Vec<_> = match encoded.is_empty() {
+        true => vec![encoded],
+        false => encoded.chunks(chunk_size).collect(),
+    };
+    let total = chunks.len() as u16;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut frame = Vec::with_capacity(HEADER_LEN + chunk.len());
+            frame.extend_from_slice(&message_id.to_le_bytes());
+            frame.extend_from_slice(&(index as u16).to_le_bytes());
+            frame.extend_from_slice(&total.to_le_bytes());
+            frame.extend_from_slice(chunk);
+            frame
+        })
+        .collect()
+}
+
+/// What became of a frame added to a message.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reassembly {
+    /// The frame completed the message.
+    Complete(Vec<u8>),
+    /// The message still misses frames. Older incomplete messages of the peer had to be evicted
+    /// to make room for it, if `evicted` is positive.
+    Incomplete { evicted: usize },
+}
+
+struct Partial {
+    message_id: u32,
+    total: u16,
+    /// Only the frames received so far, the number of frames comes from the peer, so nothing is
+    /// allocated up front based on it.
+    frames: BTreeMap<u16, Vec<u8>>,
+    bytes: usize,
+    started: Instant,
+}
+
+/// The incomplete messages of a single peer, the oldest in front.
+#[derive(Default)]
+struct PeerPartials {
+    messages: VecDeque<Partial>,
+    bytes: usize,
+}
+
+impl PeerPartials {
+    fn remove(&mut self, position: usize) -> Option<Partial> {
+        let partial = self.messages.remove(position)?;
+        self.bytes -= partial.bytes;
+        Some(partial)
+    }
+}
+
+/// The frames received so far of messages that are not complete yet, for every peer. The
+/// incomplete messages of a peer are limited in number and size, so that peers starting messages
+/// they never finish cannot use up the memory.
+pub struct Reassembler<P: Clone + Eq + Hash> {
+    peers: HashMap<P, PeerPartials>,
+    timeout: Duration,
+    max_messages: usize,
+    max_bytes: usize,
+}
+
+impl<P: Clone + Eq + Hash> Reassembler<P> {
+    pub fn new(config: &FragmentationConfig) -> Self {
+        Reassembler {
+            peers: HashMap::new(),
+            timeout: config.reassembly_timeout,
+            max_messages: config.max_partial_messages,
+            max_bytes: config.max_partial_bytes,
+        }
+    }
+
+    /// Adds the frame received from the peer, returning the message once all its frames arrive.
+    /// When the incomplete messages of the peer exceed the limits, the oldest ones are evicted.
+    pub fn push(&mut self, peer: P, frame: &[u8]) -> Result<Reassembly, FragmentError> {
+        if frame.len() < HEADER_LEN {
+            return Err(FragmentError::TooShort(frame.len()));
+        }
+        let (header, chunk) = frame.split_at(HEADER_LEN);
+        let message_id = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let index = u16::from_le_bytes([header[4], header[5]]);
+        let total = u16::from_le_bytes([header[6], header[7]]);
+        if index >= total {
+            return Err(FragmentError::InvalidIndex(index, total));
+        }
+        if total == 1 {
+            return Ok(Reassembly::Complete(chunk.to_vec()));
+        }
+        let partials = self.peers.entry(peer.clone()).or_default();
+        let position = match partials
+            .messages
+            .iter()
+            .position(|partial| partial.message_id == message_id)
+        {
+            Some(position) => position,
+            None => {
+                partials.messages.push_back(Partial {
+                    message_id,
+                    total,
+                    frames: BTreeMap::new(),
+                    bytes: 0,
+                    started: Instant::now(),
+                });
+                partials.messages.len() - 1
+            }
+        };
+        let partial = &mut partials.messages[position];
+        if partial.total != total {
+            return Err(FragmentError::InconsistentTotal(total, partial.total));
+        }
+        if let Some(previous) = partial.frames.insert(index, chunk.to_vec()) {
+            partial.bytes -= previous.len();
+            partials.bytes -= previous.len();
+        }
+        partial.bytes += chunk.len();
+        partials.bytes += chunk.len();
+        if partial.frames.len() == total as usize {
+            let frames = partials
+                .remove(position)
+                .expect("the message is at the position")
+                .frames;
+            if partials.messages.is_empty() {
+                self.peers.remove(&peer);
+            }
+            return Ok(Reassembly::Complete(
+                frames.into_values().flatten().collect(),
+            ));
+        }
+        if partial.bytes > self.max_bytes {
+            // The message alone exceeds the limit, so it could never be completed.
+            let bytes = partial.bytes;
+            partials.remove(position);
+            if partials.messages.is_empty() {
+                self.peers.remove(&peer);
+            }
+            return Err(FragmentError::TooLarge(bytes));
+        }
+        let mut evicted = 0;
+        while partials.messages.len() > self.max_messages.max(1) || partials.bytes > self.max_bytes
+        {
+            let oldest = partials
+                .messages
+                .iter()
+                .position(|partial| partial.message_id != message_id)
+                .expect("the message alone is within the limits");
+            partials.remove(oldest);
+            evicted += 1;
+        }
+        Ok(Reassembly::Incomplete { evicted })
+    }
+
+    /// Forgets the messages missing frames for longer than the timeout, returning how many.
+    pub fn remove_expired(&mut self) -> usize {
+        let timeout = self.timeout;
+        let mut expired = 0;
+        self.peers.retain(|_, partials| {
+            while let Some(oldest) = partials.messages.front() {
+                if oldest.started.elapsed() < timeout {
+                    break;
+                }
+                partials.remove(0);
+                expired += 1;
+            }
+            !partials.messages.is_empty()
+        });
+        expired
+    }
+
+    /// Forgets the frames received from the peer.
+    pub fn forget_peer(&mut self, peer: &P) {
+        self.peers.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{split, FragmentError, Reassembler, Reassembly, HEADER_LEN};
+    use crate::network::gossip::config::FragmentationConfig;
+
+    const INCOMPLETE: Result<Reassembly, FragmentError> = Ok(Reassembly::Incomplete { evicted: 0 });
+
+    fn reassembler(max_partial_messages: usize, max_partial_bytes: usize) -> Reassembler<u32> {
+        Reassembler::new(&FragmentationConfig {
+            frame_size: 16,
+            reassembly_timeout: Duration::from_secs(1),
+            max_partial_messages,
+            max_partial_bytes,
+        })
+    }
+
+    #[test]
+    fn split_messages_are_reassembled() {
+        let message: Vec<u8> = (0..100).collect();
+        let frames = split(7, &message, 16);
+        assert_eq!(frames.len(), 13);
+        assert!(frames.iter().all(|frame| frame.len() <= 16));
+
+        let mut reassembler = reassembler(4, 1024);
+        let (last, rest) = frames.split_last().expect("there are frames");
+        // Frames of other messages and peers do not interfere.
+        assert_eq!(reassembler.push(2, &rest[0]), INCOMPLETE);
+        assert_eq!(reassembler.push(1, &split(8, &message, 16)[0]), INCOMPLETE);
+        for frame in rest.iter().rev() {
+            assert_eq!(reassembler.push(1, frame), INCOMPLETE);
+        }
+        assert_eq!(reassembler.push(1, last), Ok(Reassembly::Complete(message)));
+        assert_eq!(
+            reassembler.push(1, &split(9, &[], 16)[0]),
+            Ok(Reassembly::Complete(Vec::new()))
+        );
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        let mut reassembler = reassembler(4, 1024);
+        assert_eq!(
+            reassembler.push(1, &[0; HEADER_LEN - 1]),
+            Err(FragmentError::TooShort(HEADER_LEN - 1))
+        );
+        let mut frame = split(1, &[1, 2, 3], HEADER_LEN + 1).remove(0);
+        assert_eq!(reassembler.push(1, &frame), INCOMPLETE);
+        frame[6] = 2;
+        assert_eq!(
+            reassembler.push(1, &frame),
+            Err(FragmentError::InconsistentTotal(2, 3))
+        );
+        frame[4] = 2;
+        assert_eq!(
+            reassembler.push(1, &frame),
+            Err(FragmentError::InvalidIndex(2, 2))
+        );
+    }
+
+    #[test]
+    fn claimed_number_of_frames_is_not_allocated() {
+        let mut reassembler = reassembler(4, 1024);
+        let mut frame = vec![0; HEADER_LEN];
+        frame[6..8].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(reassembler.push(1, &frame), INCOMPLETE);
+        let partial = &reassembler.peers[&1].messages[0];
+        assert_eq!(partial.total, u16::MAX);
+        assert_eq!(partial.frames.len(), 1);
+        assert!(partial.frames.values().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn oldest_incomplete_messages_are_evicted() {
+        let message: Vec<u8> = (0..100).collect();
+        let mut reassembler = reassembler(2, 40);
+        assert_eq!(reassembler.push(1, &split(1, &message, 16)[0]), INCOMPLETE);
+        assert_eq!(reassembler.push(1, &split(2, &message, 16)[0]), INCOMPLETE);
+        assert_eq!(
+            reassembler.push(1, &split(3, &message, 16)[0]),
+            Ok(Reassembly::Incomplete { evicted: 1 })
+        );
+        // Other peers have limits of their own.
+        assert_eq!(reassembler.push(2, &split(1, &message, 16)[0]), INCOMPLETE);
+
+        // The third message grows until the second one has to make room for it.
+        let frames = split(3, &message, 16);
+        for frame in &frames[1..4] {
+            assert_eq!(reassembler.push(1, frame), INCOMPLETE);
+        }
+        assert_eq!(
+            reassembler.push(1, &frames[4]),
+            Ok(Reassembly::Incomplete { evicted: 1 })
+        );
+        assert_eq!(
+            reassembler.push(1, &frames[5]),
+            Err(FragmentError::TooLarge(48))
+        );
+    }
+}
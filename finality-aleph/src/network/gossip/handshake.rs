@@ -0,0 +1,70 @@
+//! Agreeing on the version of the wire format with peers. When enabled, the first message sent
+//! over every stream is a handshake carrying the highest version the sender supports. Newer
+//! versions are expected to be able to speak the older ones, so both sides use the lower of
+//! their highest versions, as long as neither side considers it too old.
+use std::fmt::{Display, Error as FmtError, Formatter};
+
+use parity_scale_codec::{DecodeAll, Encode, Error as CodecError};
+
+use crate::network::gossip::config::VersionRange;
+
+/// What can go wrong when negotiating the version with a peer.
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// The handshake could not be decoded.
+    Malformed(CodecError),
+    /// The highest version of the peer is below our minimum.
+    Incompatible(u16),
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        use HandshakeError::*;
+        match self {
+            Malformed(e) => write!(f, "malformed handshake: {e}"),
+            Incompatible(version) => write!(f, "incompatible version {version}"),
+        }
+    }
+}
+
+/// The handshake we send to peers.
+pub fn encode(versions: &VersionRange) -> Vec<u8> {
+    versions.max.encode()
+}
+
+/// Returns the version to use with the peer that sent the handshake.
+pub fn negotiate(versions: &VersionRange, handshake: &[u8]) -> Result<u16, HandshakeError> {
+    let theirs = u16::decode_all(&mut &handshake[..]).map_err(HandshakeError::Malformed)?;
+    if theirs < versions.min {
+        return Err(HandshakeError::Incompatible(theirs));
+    }
+    Ok(theirs.min(versions.max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, negotiate, HandshakeError};
+    use crate::network::gossip::config::VersionRange;
+
+    #[test]
+    fn negotiates_the_lower_highest_version() {
+        let ours = VersionRange { min: 2, max: 4 };
+        let negotiate = |theirs| {
+            negotiate(
+                &ours,
+                &encode(&VersionRange {
+                    min: 0,
+                    max: theirs,
+                }),
+            )
+        };
+        assert!(matches!(negotiate(1), Err(HandshakeError::Incompatible(1))));
+        assert_eq!(negotiate(2).ok(), Some(2));
+        assert_eq!(negotiate(4).ok(), Some(4));
+        assert_eq!(negotiate(7).ok(), Some(4));
+        assert!(matches!(
+            super::negotiate(&ours, &[4]),
+            Err(HandshakeError::Malformed(_))
+        ));
+    }
+}
@@ -1,11 +1,12 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use substrate_prometheus_endpoint::{
-    exponential_buckets, prometheus::HistogramTimer, register, CounterVec, Histogram,
+    exponential_buckets, prometheus::HistogramTimer, register, CounterVec, GaugeVec, Histogram,
     HistogramOpts, Opts, PrometheusError, Registry, U64,
 };
+use tokio::time::Instant;
 
-use crate::Protocol;
+use crate::{network::gossip::MessageKind, Protocol};
 
 fn protocol_name(protocol: Protocol) -> &'static str {
     use Protocol::*;
@@ -15,11 +16,109 @@ fn protocol_name(protocol: Protocol) -> &'static str {
     }
 }
 
+/// Why a message never reached its destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// There was no sender for the peer, usually because we are not connected to it.
+    MissingSender,
+    /// The message could not be queued or sent to the peer.
+    SendFailed,
+    /// The queue of the peer was full, so the message was rejected or an older one was evicted.
+    QueueFull,
+    /// A received message could not be decoded.
+    DecodeError,
+    /// A received message was longer than allowed.
+    TooLarge,
+    /// A received message came from a peer that is not on the allowlist.
+    NotAllowed,
+    /// A received message came from a blocked peer.
+    Blocked,
+    /// A message went the wrong way on a protocol, e.g. it was received on a send-only one.
+    WrongDirection,
+    /// A message waited in the queue of a peer for longer than its TTL.
+    Expired,
+    /// A received message came from a peer exceeding the inbound rate limit.
+    RateLimited,
+    /// A received message did not have a valid signature.
+    InvalidSignature,
+    /// A received message arrived while its protocol was paused.
+    Paused,
+    /// The circuit breaker of the peer was open.
+    CircuitOpen,
+    /// A received message came from a peer with an incompatible version of the wire format.
+    IncompatibleVersion,
+    /// The limit of messages waiting to be sent to all the peers was reached.
+    GlobalBackpressure,
+    /// A received message came from a peer that is not connected.
+    NotConnected,
+    /// A received message did not fit in the buffer of a lagging subscriber.
+    SlowSubscriber,
+    /// A received message was identical to one received recently.
+    Duplicate,
+    /// Data sent directly to a peer was identical to data recently queued for it.
+    AlreadySent,
+    /// Some frames of a received message did not arrive in time.
+    Incomplete,
+    /// A received incomplete message was evicted to keep the incomplete messages of the peer
+    /// within limits.
+    Evicted,
+    /// A received message was encoded with a version that is neither the current nor the
+    /// previous one.
+    UnknownEncodingVersion,
+}
+
+fn drop_reason_name(reason: DropReason) -> &'static str {
+    use DropReason::*;
+    match reason {
+        MissingSender => "missing_sender",
+        SendFailed => "send_failed",
+        QueueFull => "queue_full",
+        DecodeError => "decode_error",
+        TooLarge => "too_large",
+        NotAllowed => "not_allowed",
+        Blocked => "blocked",
+        WrongDirection => "wrong_direction",
+        Expired => "expired",
+        RateLimited => "rate_limited",
+        InvalidSignature => "invalid_signature",
+        Paused => "paused",
+        CircuitOpen => "circuit_open",
+        IncompatibleVersion => "incompatible_version",
+        GlobalBackpressure => "global_backpressure",
+        NotConnected => "not_connected",
+        SlowSubscriber => "slow_subscriber",
+        Duplicate => "duplicate",
+        AlreadySent => "already_sent",
+        Incomplete => "incomplete",
+        Evicted => "evicted",
+        UnknownEncodingVersion => "unknown_encoding_version",
+    }
+}
+
+/// From 64 bytes, through the typical consensus messages of a few kilobytes, up to 4 megabytes
+/// for the largest blocks.
+fn message_size_buckets() -> Result<Vec<f64>, PrometheusError> {
+    exponential_buckets(64.0, 2.0, 17)
+}
+
+// Created once per protocol, so the size of the registered variant does not matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone)]
 pub enum Metrics {
     Prometheus {
         send_times: HashMap<Protocol, Histogram>,
+        queue_wait_times: HashMap<Protocol, Histogram>,
+        times_to_first_byte: HashMap<Protocol, Histogram>,
+        sent_message_sizes: HashMap<Protocol, Histogram>,
+        received_message_sizes: HashMap<Protocol, Histogram>,
+        connection_durations: HashMap<Protocol, Histogram>,
         peer_sender_queue_size: CounterVec<U64>,
+        messages_sent: CounterVec<U64>,
+        messages_received: CounterVec<U64>,
+        messages_dropped: CounterVec<U64>,
+        messages_decoded: CounterVec<U64>,
+        active_peer_senders: GaugeVec<U64>,
+        authority_messages: CounterVec<U64>,
     },
     Noop,
 }
@@ -33,6 +132,11 @@ impl Metrics {
         };
 
         let mut send_times = HashMap::new();
+        let mut queue_wait_times = HashMap::new();
+        let mut times_to_first_byte = HashMap::new();
+        let mut sent_message_sizes = HashMap::new();
+        let mut received_message_sizes = HashMap::new();
+        let mut connection_durations = HashMap::new();
         for protocol in [Authentication, BlockSync] {
             send_times.insert(
                 protocol,
@@ -52,6 +156,97 @@ impl Metrics {
                     &registry,
                 )?,
             );
+            queue_wait_times.insert(
+                protocol,
+                register(
+                    Histogram::with_opts(HistogramOpts {
+                        common_opts: Opts {
+                            namespace: "gossip_network".to_string(),
+                            subsystem: protocol_name(protocol).to_string(),
+                            name: "queue_wait_duration".to_string(),
+                            help: "How long did a message wait in the queue of a peer before sending it started."
+                                .to_string(),
+                            const_labels: Default::default(),
+                            variable_labels: Default::default(),
+                        },
+                        buckets: exponential_buckets(0.001, 1.26, 30)?,
+                    })?,
+                    &registry,
+                )?,
+            );
+            times_to_first_byte.insert(
+                protocol,
+                register(
+                    Histogram::with_opts(HistogramOpts {
+                        common_opts: Opts {
+                            namespace: "gossip_network".to_string(),
+                            subsystem: protocol_name(protocol).to_string(),
+                            name: "time_to_first_byte".to_string(),
+                            help: "How long after a stream opened did the first send to the peer succeed."
+                                .to_string(),
+                            const_labels: Default::default(),
+                            variable_labels: Default::default(),
+                        },
+                        buckets: exponential_buckets(0.001, 1.26, 30)?,
+                    })?,
+                    &registry,
+                )?,
+            );
+            sent_message_sizes.insert(
+                protocol,
+                register(
+                    Histogram::with_opts(HistogramOpts {
+                        common_opts: Opts {
+                            namespace: "gossip_network".to_string(),
+                            subsystem: protocol_name(protocol).to_string(),
+                            name: "sent_message_size".to_string(),
+                            help: "How many bytes did the messages sent to peers have on the wire."
+                                .to_string(),
+                            const_labels: Default::default(),
+                            variable_labels: Default::default(),
+                        },
+                        buckets: message_size_buckets()?,
+                    })?,
+                    &registry,
+                )?,
+            );
+            received_message_sizes.insert(
+                protocol,
+                register(
+                    Histogram::with_opts(HistogramOpts {
+                        common_opts: Opts {
+                            namespace: "gossip_network".to_string(),
+                            subsystem: protocol_name(protocol).to_string(),
+                            name: "received_message_size".to_string(),
+                            help: "How many bytes did the messages received from peers have on the wire."
+                                .to_string(),
+                            const_labels: Default::default(),
+                            variable_labels: Default::default(),
+                        },
+                        buckets: message_size_buckets()?,
+                    })?,
+                    &registry,
+                )?,
+            );
+            connection_durations.insert(
+                protocol,
+                register(
+                    Histogram::with_opts(HistogramOpts {
+                        common_opts: Opts {
+                            namespace: "gossip_network".to_string(),
+                            subsystem: protocol_name(protocol).to_string(),
+                            name: "connection_duration".to_string(),
+                            help: "How long did the connections to peers last, in seconds."
+                                .to_string(),
+                            const_labels: Default::default(),
+                            variable_labels: Default::default(),
+                        },
+                        // From a second up to about a week.
+                        buckets: exponential_buckets(1.0, 2.0, 20)?,
+                    })?,
+                    &registry,
+                )?,
+            );
         }
 
         let peer_sender_queue_size = register(CounterVec::new(
@@ -62,9 +257,86 @@ impl Metrics {
             &["protocol", "action"],
         )?, &registry)?;
 
+        let messages_sent = register(
+            CounterVec::new(
+                Opts::new(
+                    "aleph_gossip_messages_sent_total",
+                    "Number of messages queued for sending to peers, for a given protocol",
+                ),
+                &["protocol"],
+            )?,
+            &registry,
+        )?;
+
+        let messages_received = register(
+            CounterVec::new(
+                Opts::new(
+                    "aleph_gossip_messages_received_total",
+                    "Number of messages received from peers, for a given protocol",
+                ),
+                &["protocol"],
+            )?,
+            &registry,
+        )?;
+
+        let messages_dropped = register(
+            CounterVec::new(
+                Opts::new(
+                    "aleph_gossip_messages_dropped_total",
+                    "Number of messages dropped, for a given protocol and reason",
+                ),
+                &["protocol", "reason"],
+            )?,
+            &registry,
+        )?;
+
+        let messages_decoded = register(
+            CounterVec::new(
+                Opts::new(
+                    "aleph_gossip_messages_decoded_total",
+                    "Number of received messages decoded or failing to decode, for a given protocol and kind of message",
+                ),
+                &["protocol", "kind", "result"],
+            )?,
+            &registry,
+        )?;
+
+        let active_peer_senders = register(
+            GaugeVec::new(
+                Opts::new(
+                    "aleph_gossip_active_peer_senders",
+                    "Number of peer sender tasks currently running, for a given protocol",
+                ),
+                &["protocol"],
+            )?,
+            &registry,
+        )?;
+
+        let authority_messages = register(
+            CounterVec::new(
+                Opts::new(
+                    "aleph_gossip_authority_messages_total",
+                    "Number of messages sent to, received from and dropped for peers, for a given protocol and whether the peer is in the current authority set",
+                ),
+                &["protocol", "event", "authority"],
+            )?,
+            &registry,
+        )?;
+
         Ok(Metrics::Prometheus {
             send_times,
+            queue_wait_times,
+            times_to_first_byte,
+            sent_message_sizes,
+            received_message_sizes,
+            connection_durations,
             peer_sender_queue_size,
+            messages_sent,
+            messages_received,
+            messages_dropped,
+            messages_decoded,
+            active_peer_senders,
+            authority_messages,
         })
     }
 
@@ -81,6 +353,75 @@ impl Metrics {
         }
     }
 
+    /// The current time, only taken if metrics are enabled, e.g. for tracking when a message got
+    /// queued.
+    pub fn now(&self) -> Option<Instant> {
+        match self {
+            Metrics::Prometheus { .. } => Some(Instant::now()),
+            Metrics::Noop => None,
+        }
+    }
+
+    pub fn report_queue_wait(&self, protocol: Protocol, queued_at: Instant) {
+        match self {
+            Metrics::Prometheus {
+                queue_wait_times, ..
+            } => {
+                if let Some(histogram) = queue_wait_times.get(&protocol) {
+                    histogram.observe(queued_at.elapsed().as_secs_f64());
+                }
+            }
+            Metrics::Noop => {}
+        }
+    }
+
+    pub fn report_time_to_first_byte(&self, protocol: Protocol, opened_at: Instant) {
+        if let Metrics::Prometheus {
+            times_to_first_byte,
+            ..
+        } = self
+        {
+            if let Some(histogram) = times_to_first_byte.get(&protocol) {
+                histogram.observe(opened_at.elapsed().as_secs_f64());
+            }
+        }
+    }
+
+    pub fn report_sent_message_size(&self, protocol: Protocol, size: usize) {
+        if let Metrics::Prometheus {
+            sent_message_sizes, ..
+        } = self
+        {
+            if let Some(histogram) = sent_message_sizes.get(&protocol) {
+                histogram.observe(size as f64);
+            }
+        }
+    }
+
+    pub fn report_received_message_size(&self, protocol: Protocol, size: usize) {
+        if let Metrics::Prometheus {
+            received_message_sizes,
+            ..
+        } = self
+        {
+            if let Some(histogram) = received_message_sizes.get(&protocol) {
+                histogram.observe(size as f64);
+            }
+        }
+    }
+
+    pub fn report_connection_duration(&self, protocol: Protocol, duration: Duration) {
+        if let Metrics::Prometheus {
+            connection_durations,
+            ..
+        } = self
+        {
+            if let Some(histogram) = connection_durations.get(&protocol) {
+                histogram.observe(duration.as_secs_f64());
+            }
+        }
+    }
+
     pub fn report_message_pushed_to_peer_sender_queue(&self, protocol: Protocol) {
         match self {
             Metrics::Prometheus {
@@ -108,4 +449,123 @@ impl Metrics {
             Metrics::Noop => {}
         }
     }
+
+    pub fn report_message_sent(&self, protocol: Protocol) {
+        match self {
+            Metrics::Prometheus { messages_sent, .. } => {
+                messages_sent
+                    .with_label_values(&[protocol_name(protocol)])
+                    .inc();
+            }
+            Metrics::Noop => {}
+        }
+    }
+
+    fn report_decoding(&self, protocol: Protocol, kind: MessageKind, result: &str) {
+        if let Metrics::Prometheus {
+            messages_decoded, ..
+        } = self
+        {
+            messages_decoded
+                .with_label_values(&[protocol_name(protocol), kind.name(), result])
+                .inc();
+        }
+    }
+
+    pub fn report_message_decoded(&self, protocol: Protocol, kind: MessageKind) {
+        self.report_decoding(protocol, kind, "ok");
+    }
+
+    pub fn report_decode_error(&self, protocol: Protocol, kind: MessageKind) {
+        self.report_decoding(protocol, kind, "error");
+    }
+
+    pub fn report_message_received(&self, protocol: Protocol) {
+        match self {
+            Metrics::Prometheus {
+                messages_received, ..
+            } => {
+                messages_received
+                    .with_label_values(&[protocol_name(protocol)])
+                    .inc();
+            }
+            Metrics::Noop => {}
+        }
+    }
+
+    pub fn report_peer_sender_started(&self, protocol: Protocol) {
+        if let Metrics::Prometheus {
+            active_peer_senders,
+            ..
+        } = self
+        {
+            active_peer_senders
+                .with_label_values(&[protocol_name(protocol)])
+                .inc();
+        }
+    }
+
+    pub fn report_peer_sender_exited(&self, protocol: Protocol) {
+        if let Metrics::Prometheus {
+            active_peer_senders,
+            ..
+        } = self
+        {
+            active_peer_senders
+                .with_label_values(&[protocol_name(protocol)])
+                .dec();
+        }
+    }
+
+    fn report_authority_messages(
+        &self,
+        protocol: Protocol,
+        event: &str,
+        authority: bool,
+        count: u64,
+    ) {
+        if let Metrics::Prometheus {
+            authority_messages, ..
+        } = self
+        {
+            let authority = if authority { "true" } else { "false" };
+            authority_messages
+                .with_label_values(&[protocol_name(protocol), event, authority])
+                .inc_by(count);
+        }
+    }
+
+    pub fn report_authority_message_sent(&self, protocol: Protocol, authority: bool) {
+        self.report_authority_messages(protocol, "sent", authority, 1);
+    }
+
+    pub fn report_authority_message_received(&self, protocol: Protocol, authority: bool) {
+        self.report_authority_messages(protocol, "received", authority, 1);
+    }
+
+    pub fn report_authority_messages_dropped(
+        &self,
+        protocol: Protocol,
+        authority: bool,
+        count: u64,
+    ) {
+        self.report_authority_messages(protocol, "dropped", authority, count);
+    }
+
+    pub fn report_message_dropped(&self, protocol: Protocol, reason: DropReason) {
+        self.report_messages_dropped(protocol, reason, 1)
+    }
+
+    pub fn report_messages_dropped(&self, protocol: Protocol, reason: DropReason, count: u64) {
+        match self {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => {
+                messages_dropped
+                    .with_label_values(&[protocol_name(protocol), drop_reason_name(reason)])
+                    .inc_by(count);
+            }
+            Metrics::Noop => {}
+        }
+    }
 }
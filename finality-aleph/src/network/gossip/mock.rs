@@ -1,20 +1,46 @@
-use std::{collections::VecDeque, fmt, sync::Arc};
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use async_trait::async_trait;
+use bytes::Bytes;
 use futures::{
     channel::{mpsc, oneshot},
+    future::BoxFuture,
+    task::{noop_waker, Context},
     StreamExt,
 };
 use network_clique::mock::MockPublicKey;
 use parking_lot::Mutex;
+use rand::{thread_rng, Rng};
+use tokio::time::{self, Instant};
 
 use crate::network::{
-    gossip::{Event, EventStream, NetworkSender, Protocol, RawNetwork},
+    gossip::{
+        Event, EventStream, NetworkSender, Protocol, RawNetwork, ReputationChange, Signer, Spawner,
+        Verifier,
+    },
     mock::Channel,
 };
 
 pub type MockEvent = Event<MockPublicKey>;
 
+type EventSinks = Arc<Mutex<Vec<mpsc::UnboundedSender<MockEvent>>>>;
+
+/// The other end of a link between two mock networks.
+#[derive(Clone)]
+struct Link {
+    local_id: MockPublicKey,
+    remote_id: MockPublicKey,
+    remote_sinks: EventSinks,
+}
+
 pub struct MockEventStream(mpsc::UnboundedReceiver<MockEvent>);
 
 #[async_trait]
@@ -29,6 +55,17 @@ pub struct MockNetworkSender {
     peer_id: MockPublicKey,
     protocol: Protocol,
     error: Result<(), MockSenderError>,
+    live_senders: Arc<AtomicUsize>,
+    send_delay: Arc<Mutex<Duration>>,
+    scripted_send_delays: Arc<Mutex<VecDeque<Duration>>>,
+    drop_probability: Arc<Mutex<f64>>,
+    link: Option<Link>,
+}
+
+impl Drop for MockNetworkSender {
+    fn drop(&mut self) {
+        self.live_senders.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 #[async_trait]
@@ -39,7 +76,31 @@ impl NetworkSender for MockNetworkSender {
         &'a self,
         data: impl Into<Vec<u8>> + Send + Sync + 'static,
     ) -> Result<(), MockSenderError> {
+        let delay = self
+            .scripted_send_delays
+            .lock()
+            .pop_front()
+            .unwrap_or_else(|| *self.send_delay.lock());
+        if !delay.is_zero() {
+            time::sleep(delay).await;
+        }
         self.error?;
+        let drop_probability = self.drop_probability.lock().clamp(0.0, 1.0);
+        if drop_probability > 0.0 && thread_rng().gen_bool(drop_probability) {
+            // Lost on the way, the sender cannot tell.
+            return Ok(());
+        }
+        if let Some(link) = &self.link {
+            let event = MockEvent::Messages(
+                link.local_id.clone(),
+                vec![(self.protocol, Bytes::from(data.into()))],
+            );
+            // The other end might have stopped listening already.
+            for sink in &*link.remote_sinks.lock() {
+                let _ = sink.unbounded_send(event.clone());
+            }
+            return Ok(());
+        }
         self.sender
             .unbounded_send((data.into(), self.peer_id.clone(), self.protocol))
             .unwrap();
@@ -47,13 +108,35 @@ impl NetworkSender for MockNetworkSender {
     }
 }
 
+/// An attempt to create a sender, as recorded by `MockRawNetwork`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SenderCreation {
+    pub peer_id: MockPublicKey,
+    pub protocol: Protocol,
+    /// Whether the attempt failed because of an injected error.
+    pub failed: bool,
+    pub at: Instant,
+}
+
 #[derive(Clone)]
 pub struct MockRawNetwork {
     pub send_message: Channel<(Vec<u8>, MockPublicKey, Protocol)>,
-    pub event_sinks: Arc<Mutex<Vec<mpsc::UnboundedSender<MockEvent>>>>,
+    pub created_senders: Channel<SenderCreation>,
+    pub event_sinks: EventSinks,
     event_stream_taken_oneshot: Arc<Mutex<Option<oneshot::Sender<()>>>>,
     pub create_sender_errors: Arc<Mutex<VecDeque<MockSenderError>>>,
     pub send_errors: Arc<Mutex<VecDeque<MockSenderError>>>,
+    pub reported_peers: Arc<Mutex<Vec<(MockPublicKey, ReputationChange)>>>,
+    pub disconnected_peers: Arc<Mutex<Vec<MockPublicKey>>>,
+    pub dialed_peers: Arc<Mutex<Vec<(MockPublicKey, String)>>>,
+    /// How long every send takes, unless a scripted delay is waiting.
+    pub send_delay: Arc<Mutex<Duration>>,
+    /// How long the next sends take, one delay per send in order.
+    pub scripted_send_delays: Arc<Mutex<VecDeque<Duration>>>,
+    /// The chance of a successful send never reaching the peer.
+    pub drop_probability: Arc<Mutex<f64>>,
+    live_senders: Arc<AtomicUsize>,
+    link: Arc<Mutex<Option<Link>>>,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -71,37 +154,120 @@ impl RawNetwork for MockRawNetwork {
     type SenderError = MockSenderError;
     type NetworkSender = MockNetworkSender;
     type PeerId = MockPublicKey;
+    type Address = String;
 
     fn sender(
         &self,
         peer_id: Self::PeerId,
         protocol: Protocol,
     ) -> Result<Self::NetworkSender, Self::SenderError> {
-        self.create_sender_errors
-            .lock()
-            .pop_front()
-            .map_or(Ok(()), Err)?;
+        let creation_error = self.create_sender_errors.lock().pop_front();
+        self.created_senders
+            .0
+            .unbounded_send(SenderCreation {
+                peer_id: peer_id.clone(),
+                protocol,
+                failed: creation_error.is_some(),
+                at: Instant::now(),
+            })
+            .unwrap();
+        creation_error.map_or(Ok(()), Err)?;
         let error = self.send_errors.lock().pop_front().map_or(Ok(()), Err);
+        self.live_senders.fetch_add(1, Ordering::SeqCst);
+        let link = self
+            .link
+            .lock()
+            .clone()
+            .filter(|link| link.remote_id == peer_id);
         Ok(MockNetworkSender {
             sender: self.send_message.0.clone(),
             peer_id,
             protocol,
             error,
+            live_senders: self.live_senders.clone(),
+            send_delay: self.send_delay.clone(),
+            scripted_send_delays: self.scripted_send_delays.clone(),
+            drop_probability: self.drop_probability.clone(),
+            link,
         })
     }
+
+    fn report_peer(&self, peer_id: Self::PeerId, change: ReputationChange) {
+        self.reported_peers.lock().push((peer_id, change));
+    }
+
+    fn disconnect_peer(&self, peer_id: Self::PeerId) {
+        self.disconnected_peers.lock().push(peer_id);
+    }
+
+    fn dial(&self, peer_id: Self::PeerId, address: Self::Address) {
+        self.dialed_peers.lock().push((peer_id, address));
+    }
 }
 
 impl MockRawNetwork {
     pub fn new(oneshot_sender: oneshot::Sender<()>) -> Self {
         MockRawNetwork {
             send_message: Channel::new(),
+            created_senders: Channel::new(),
             event_sinks: Arc::new(Mutex::new(vec![])),
             event_stream_taken_oneshot: Arc::new(Mutex::new(Some(oneshot_sender))),
             create_sender_errors: Arc::new(Mutex::new(VecDeque::new())),
             send_errors: Arc::new(Mutex::new(VecDeque::new())),
+            reported_peers: Arc::new(Mutex::new(Vec::new())),
+            disconnected_peers: Arc::new(Mutex::new(Vec::new())),
+            dialed_peers: Arc::new(Mutex::new(Vec::new())),
+            send_delay: Arc::new(Mutex::new(Duration::ZERO)),
+            scripted_send_delays: Arc::new(Mutex::new(VecDeque::new())),
+            drop_probability: Arc::new(Mutex::new(0.0)),
+            live_senders: Arc::new(AtomicUsize::new(0)),
+            link: Arc::new(Mutex::new(None)),
         }
     }
 
+    /// Links the network to the other one, so that whatever it sends to the peer with
+    /// `remote_id` arrives at the other network as messages from the peer with `local_id`,
+    /// instead of landing in `send_message`. Opening the streams is still up to the tests.
+    pub fn link_to(
+        &self,
+        local_id: MockPublicKey,
+        other: &MockRawNetwork,
+        remote_id: MockPublicKey,
+    ) {
+        *self.link.lock() = Some(Link {
+            local_id,
+            remote_id,
+            remote_sinks: other.event_sinks.clone(),
+        });
+    }
+
+    /// The number of created senders that were not dropped yet.
+    pub fn live_senders(&self) -> usize {
+        self.live_senders.load(Ordering::SeqCst)
+    }
+
+    /// Waits for the given time and fails with the sends that happened in the meantime, if any.
+    /// Sends that happened before the call and were not received yet count as well.
+    pub async fn assert_idle(
+        &self,
+        duration: Duration,
+    ) -> Result<(), Vec<(Vec<u8>, MockPublicKey, Protocol)>> {
+        time::sleep(duration).await;
+        let mut sent = Vec::new();
+        while let Some(send) = self.send_message.try_next().await {
+            sent.push(send);
+        }
+        match sent.is_empty() {
+            true => Ok(()),
+            false => Err(sent),
+        }
+    }
+
+    /// Waits for the next attempt to create a sender.
+    pub async fn next_sender_creation(&mut self) -> Option<SenderCreation> {
+        self.created_senders.next().await
+    }
+
     pub fn event_stream(&self) -> MockEventStream {
         let (tx, rx) = mpsc::unbounded();
         self.event_sinks.lock().push(tx);
@@ -127,3 +293,125 @@ impl MockRawNetwork {
         assert!(self.send_message.close().await.is_none());
     }
 }
+
+type NamedTask = (&'static str, BoxFuture<'static, ()>);
+
+/// A spawner that only runs its tasks when asked to, so tests can control when they make progress.
+#[derive(Clone, Default)]
+pub struct ManualSpawner {
+    tasks: Arc<Mutex<Vec<NamedTask>>>,
+}
+
+impl Spawner for ManualSpawner {
+    fn spawn(&self, name: &'static str, task: BoxFuture<'static, ()>) {
+        self.tasks.lock().push((name, task));
+    }
+}
+
+impl ManualSpawner {
+    /// The names of the tasks that did not finish yet.
+    pub fn pending_tasks(&self) -> Vec<&'static str> {
+        self.tasks.lock().iter().map(|(name, _)| *name).collect()
+    }
+
+    /// Polls every unfinished task once, dropping the ones that finish.
+    pub fn step(&self) {
+        let waker = noop_waker();
+        let mut context = Context::from_waker(&waker);
+        self.tasks
+            .lock()
+            .retain_mut(|(_, task)| task.as_mut().poll(&mut context).is_pending());
+    }
+}
+
+/// Signs with a single byte depending on the key and the payload, not secure in any way.
+pub struct KeyedSum(pub u8);
+
+impl KeyedSum {
+    fn signature(&self, payload: &[u8]) -> Vec<u8> {
+        vec![payload
+            .iter()
+            .fold(self.0, |sum, byte| sum.wrapping_add(*byte))]
+    }
+}
+
+impl Signer for KeyedSum {
+    fn sign(&self, payload: &[u8]) -> Vec<u8> {
+        self.signature(payload)
+    }
+}
+
+impl Verifier for KeyedSum {
+    fn verify(&self, payload: &[u8], signature: &[u8]) -> bool {
+        self.signature(payload) == signature
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::channel::oneshot;
+    use network_clique::mock::random_peer_id;
+    use tokio::time::Instant;
+
+    use super::MockRawNetwork;
+    use crate::network::gossip::{NetworkSender, Protocol, RawNetwork};
+
+    const PROTOCOL: Protocol = Protocol::Authentication;
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_take_the_configured_delays() {
+        let (tx, _rx) = oneshot::channel();
+        let mut network = MockRawNetwork::new(tx);
+        *network.send_delay.lock() = Duration::from_secs(5);
+        network
+            .scripted_send_delays
+            .lock()
+            .extend([Duration::from_secs(1), Duration::from_secs(2)]);
+        let sender = network
+            .sender(random_peer_id(), PROTOCOL)
+            .expect("should create sender");
+
+        for expected_delay in [1, 2, 5, 5].map(Duration::from_secs) {
+            let started = Instant::now();
+            sender.send(vec![1]).await.expect("should send");
+            assert_eq!(started.elapsed(), expected_delay);
+        }
+        assert_eq!(network.send_message.take(4).await.len(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn assert_idle_reports_sends_within_the_window() {
+        let (tx, _rx) = oneshot::channel();
+        let network = MockRawNetwork::new(tx);
+        let peer_id = random_peer_id();
+        let sender = network
+            .sender(peer_id.clone(), PROTOCOL)
+            .expect("should create sender");
+
+        assert_eq!(network.assert_idle(Duration::from_secs(1)).await, Ok(()));
+        sender.send(vec![1]).await.expect("should send");
+        assert_eq!(
+            network.assert_idle(Duration::from_secs(1)).await,
+            Err(vec![(vec![1], peer_id, PROTOCOL)])
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_get_dropped_with_the_configured_probability() {
+        let (tx, _rx) = oneshot::channel();
+        let mut network = MockRawNetwork::new(tx);
+        *network.drop_probability.lock() = 1.0;
+        let sender = network
+            .sender(random_peer_id(), PROTOCOL)
+            .expect("should create sender");
+
+        sender.send(vec![1]).await.expect("drops are not reported");
+        assert_eq!(network.send_message.try_next().await, None);
+
+        *network.drop_probability.lock() = 0.0;
+        sender.send(vec![2]).await.expect("should send");
+        assert!(network.send_message.next().await.is_some());
+    }
+}
@@ -1,32 +1,127 @@
 //! A P2P-based gossip network, for now only for sending broadcasts.
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fmt::{Debug, Display},
     hash::Hash,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use bytes::Bytes;
+use futures::{
+    channel::oneshot,
+    future::{self, BoxFuture},
+    FutureExt,
+};
+use network_clique::SpawnHandleT;
+use serde::Serialize;
 
-use crate::network::Data;
+use crate::{network::Data, SpawnHandle};
 
+mod circuit;
+mod codec;
+mod compression;
+mod config;
+mod dedup;
+mod fragment;
+mod handshake;
 mod metrics;
 #[cfg(test)]
 pub mod mock;
+mod peer_names;
+mod queue;
+mod rate_limit;
+mod request;
 mod service;
+mod signing;
+
+pub use codec::{Codec, MessageKind, ScaleCodec};
+pub use config::{
+    AdaptiveStatusConfig, BackoffConfig, BatchConfig, BroadcastDedupConfig, CircuitBreakerConfig,
+    CompressionConfig, Config, Direction, FairDrainConfig, FragmentationConfig, InboundDedupConfig,
+    InboundRateLimitConfig, LatencyConfig, PeerLimitConfig, PeerLimitPolicy, ProtocolConfig,
+    QuietStatusConfig, RebroadcastConfig, SendDedupConfig, StuckPeerConfig, VersionRange,
+};
+pub use queue::DropPolicy;
+pub use request::{Envelope, Incoming, RequestError, RequestResponse, Response};
+pub use service::{
+    BroadcastAllHandle, Error, Service, ServiceHandle, StopHandle, TerminationReason,
+};
+pub use signing::{MessageSigning, Signer, Verifier};
+
+/// Resolves with the outcome of sending data using `Network::send_to_confirmed`.
+pub type Confirmation<E> = oneshot::Receiver<Result<(), E>>;
 
-pub use service::{Error, Service};
+/// Resolves with the outcome of sending data to every peer using `Network::broadcast_barrier`.
+pub type Barrier<P, E> = BoxFuture<'static, HashMap<P, Result<(), E>>>;
+
+/// Cancels broadcasting data repeatedly, as started with `Network::rebroadcast`. Dropping the
+/// handle does not cancel anything.
+#[derive(Clone, Debug, Default)]
+pub struct RebroadcastHandle(Arc<AtomicBool>);
+
+impl RebroadcastHandle {
+    /// Stops any further broadcasts of the data.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
 
 #[async_trait::async_trait]
 /// Interface for the gossip network. This represents a P2P network and a lot of the properties of
 /// this interface result from that. In particular we might know the ID of a given peer, but not be
 /// connected to them directly.
 pub trait Network<D: Data>: Send + 'static {
-    type Error: Display + Send;
+    type Error: Display + Send + 'static;
     type PeerId: Clone + Debug + Eq + Hash + Send + 'static;
 
     /// Attempt to send data to a peer. Might silently fail if we are not connected to them.
     fn send_to(&mut self, data: D, peer_id: Self::PeerId) -> Result<(), Self::Error>;
 
+    /// Like `send_to`, but with the given priority. Implementations without priorities send the
+    /// data as usual.
+    fn send_to_with_priority(
+        &mut self,
+        data: D,
+        peer_id: Self::PeerId,
+        _priority: Priority,
+    ) -> Result<(), Self::Error> {
+        self.send_to(data, peer_id)
+    }
+
+    /// Like `send_to`, but the data is dropped instead of sent if it still waits to be sent after
+    /// the TTL. Implementations without expiry send the data as usual.
+    fn send_to_with_ttl(
+        &mut self,
+        data: D,
+        peer_id: Self::PeerId,
+        _ttl: Duration,
+    ) -> Result<(), Self::Error> {
+        self.send_to(data, peer_id)
+    }
+
+    /// Like `send_to`, but also returns a receiver that resolves once the data is handed over to
+    /// the underlying network, or with an error if that fails. The default implementation cannot
+    /// tell, so it resolves the receiver as soon as `send_to` succeeds.
+    fn send_to_confirmed(
+        &mut self,
+        data: D,
+        peer_id: Self::PeerId,
+    ) -> Result<Confirmation<Self::Error>, Self::Error> {
+        self.send_to(data, peer_id)?;
+        let (tx, rx) = oneshot::channel();
+        // The receiver is still here, so this cannot fail.
+        let _ = tx.send(Ok(()));
+        Ok(rx)
+    }
+
     /// Send data to a random peer, preferably from a list. It should send the data to a randomly
     /// chosen peer from the provided list, but if it cannot (e.g. because it's not connected) it
     /// will send to a random available peer. No guarantees any peer gets it even if no errors are
@@ -37,15 +132,90 @@ pub trait Network<D: Data>: Send + 'static {
         peer_ids: HashSet<Self::PeerId>,
     ) -> Result<(), Self::Error>;
 
+    /// Send data to the connected peer with the best connection quality, e.g. to fetch something
+    /// from a single peer. Implementations that do not track connection quality send it to a
+    /// random peer. No guarantees the peer gets it even if no errors are returned.
+    fn send_to_best(&mut self, data: D) -> Result<(), Self::Error> {
+        self.send_to_random(data, HashSet::new())
+    }
+
+    /// Send data to all the given peers we are directly connected to. Peers we are not connected
+    /// to are skipped. No guarantees any peer gets it even if no errors are returned.
+    fn send_to_peers(
+        &mut self,
+        data: D,
+        peer_ids: HashSet<Self::PeerId>,
+    ) -> Result<(), Self::Error> {
+        for peer_id in peer_ids {
+            self.send_to(data.clone(), peer_id)?;
+        }
+        Ok(())
+    }
+
     /// Broadcast data to all directly connected peers. Network-wide broadcasts have to be
     /// implemented on top of this abstraction. Note that there might be no currently connected
     /// peers, so there are no guarantees any single call sends anything even if no errors are
-    /// returned, retry appropriately.
+    /// returned, retry appropriately. Implementations can be configured to return an error when
+    /// they know no peers are connected. Implementations are allowed to wait for capacity here,
+    /// see `try_broadcast` for paths where that is not acceptable.
     fn broadcast(&mut self, data: D) -> Result<(), Self::Error>;
 
+    /// Like `broadcast`, but guaranteed to return immediately. When the data cannot be accepted
+    /// right away, e.g. because the implementation is congested, an error is returned instead of
+    /// waiting for capacity, so this is safe to use in latency sensitive paths. The default
+    /// implementation relies on `broadcast` never waiting.
+    fn try_broadcast(&mut self, data: D) -> Result<(), Self::Error> {
+        self.broadcast(data)
+    }
+
+    /// Like `broadcast`, but with the given priority. Implementations without priorities
+    /// broadcast the data as usual.
+    fn broadcast_with_priority(&mut self, data: D, _priority: Priority) -> Result<(), Self::Error> {
+        self.broadcast(data)
+    }
+
+    /// Like `broadcast`, but the data is dropped instead of sent to peers it still waits for after
+    /// the TTL. Implementations without expiry broadcast the data as usual.
+    fn broadcast_with_ttl(&mut self, data: D, _ttl: Duration) -> Result<(), Self::Error> {
+        self.broadcast(data)
+    }
+
+    /// Like `broadcast`, but also returns a future that resolves once sending the data to every
+    /// peer connected at the time was attempted, with the outcome for each of them. Peers that
+    /// disconnect before the data is sent count as failures. The default implementation cannot
+    /// tell, so it resolves right away without any peers.
+    fn broadcast_barrier(
+        &mut self,
+        data: D,
+    ) -> Result<Barrier<Self::PeerId, Self::Error>, Self::Error> {
+        self.broadcast(data)?;
+        Ok(future::ready(HashMap::new()).boxed())
+    }
+
+    /// Broadcasts the data now and then again at growing intervals, until the schedule ends or it
+    /// gets cancelled with the returned handle. Implementations without managed rebroadcasts
+    /// broadcast the data once.
+    fn rebroadcast(
+        &mut self,
+        data: D,
+        _schedule: RebroadcastConfig,
+    ) -> Result<RebroadcastHandle, Self::Error> {
+        self.broadcast(data)?;
+        Ok(RebroadcastHandle::default())
+    }
+
     /// Receive some data from the network, including information about who sent it.
     /// This method's implementation must be cancellation safe.
     async fn next(&mut self) -> Result<(D, Self::PeerId), Self::Error>;
+
+    /// Like `next`, but also tells whether the data was broadcast or sent to us specifically,
+    /// when that is known. The default implementation never knows.
+    async fn next_with_delivery(
+        &mut self,
+    ) -> Result<(D, Self::PeerId, Option<Delivery>), Self::Error> {
+        let (data, peer_id) = self.next().await?;
+        Ok((data, peer_id, None))
+    }
 }
 
 /// Protocols used by the network.
@@ -57,6 +227,17 @@ pub enum Protocol {
     BlockSync,
 }
 
+/// Priority of a message sent to peers, high priority messages are sent before any normal
+/// priority ones waiting for the same peer.
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone, Hash)]
+pub enum Priority {
+    /// Latency sensitive messages, e.g. the ones required for consensus.
+    High,
+    /// Everything else, including bulk data.
+    #[default]
+    Normal,
+}
+
 /// Abstraction over a sender to the raw network.
 #[async_trait::async_trait]
 pub trait NetworkSender: Send + Sync + 'static {
@@ -76,6 +257,83 @@ pub enum Event<P> {
     Messages(P, Vec<(Protocol, Bytes)>),
 }
 
+/// How a received message was sent to us.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// The message was broadcast to all the peers of the sender.
+    Gossip,
+    /// The message was sent to us specifically.
+    Direct,
+}
+
+/// The outcome of a broadcast requested by a user, published to the broadcast feedback channel
+/// of the service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BroadcastResult<P> {
+    pub protocol: Protocol,
+    /// Number of peers the data was supposed to be queued for.
+    pub attempted: usize,
+    /// Number of peers the data was queued for.
+    pub succeeded: usize,
+    /// Peers for which queueing the data failed.
+    pub failed_peers: Vec<P>,
+    /// Number of peers skipped, as their circuit breakers were open.
+    pub skipped_circuit_open: usize,
+}
+
+/// A received message that could not be decoded, published to the decode failure channel of the
+/// service. The message itself is left out, so that it does not leak into audit logs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodeFailure<P> {
+    pub peer: P,
+    pub protocol: Protocol,
+    /// The length of the message as received.
+    pub bytes_len: usize,
+    /// The kind of the message, as far as the codec could tell from its beginning.
+    pub kind: MessageKind,
+    pub error: String,
+}
+
+/// A change in the connections to peers, published to the subscribers of the service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent<P> {
+    PeerConnected(P, Protocol),
+    PeerDisconnected(P, Protocol),
+    /// The last peer of the protocol disconnected and no peer connected within the debounce
+    /// period of the protocol.
+    ProtocolConnectivityLost(Protocol),
+    /// A peer connected to the protocol after its connectivity was lost.
+    ProtocolConnectivityRegained(Protocol),
+}
+
+/// The state of the service at some point, for diagnostics.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StateSnapshot {
+    pub authentication: ProtocolSnapshot,
+    pub block_sync: ProtocolSnapshot,
+}
+
+/// The state of a single protocol of the service.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ProtocolSnapshot {
+    pub connected_peers: usize,
+    /// The averages shown in status reports, as of the latest one.
+    pub received_per_second: f64,
+    pub broadcasts_per_second: f64,
+    pub peers: Vec<PeerSnapshot>,
+}
+
+/// The state of a single connected peer.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PeerSnapshot {
+    /// The id of the peer, as shown in logs.
+    pub peer_id: String,
+    /// How many messages wait to be sent to the peer.
+    pub queue_depth: usize,
+    /// How many milliseconds ago the peer last sent us a message, `None` if it never did.
+    pub last_seen_millis_ago: Option<u64>,
+}
+
 #[async_trait::async_trait]
 pub trait EventStream<P> {
     /// Retrieves next event from the stream or returns None if the stream is closed.
@@ -83,11 +341,26 @@ pub trait EventStream<P> {
     async fn next_event(&mut self) -> Option<Event<P>>;
 }
 
+/// A change of the reputation of a peer, together with a human readable reason for it.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct ReputationChange {
+    /// The amount of reputation to add, negative values penalize the peer.
+    pub value: i32,
+    pub reason: &'static str,
+}
+
+impl ReputationChange {
+    pub const fn new(value: i32, reason: &'static str) -> Self {
+        ReputationChange { value, reason }
+    }
+}
+
 /// Abstraction over a raw p2p network.
 pub trait RawNetwork: Clone + Send + Sync + 'static {
     type SenderError: std::error::Error;
     type NetworkSender: NetworkSender;
     type PeerId: Clone + Debug + Eq + Hash + Send + 'static;
+    type Address: Clone + Debug + Send + 'static;
 
     /// Returns a sender to the given peer using a given protocol. Returns Error if not connected to the peer.
     fn sender(
@@ -95,4 +368,27 @@ pub trait RawNetwork: Clone + Send + Sync + 'static {
         peer_id: Self::PeerId,
         protocol: Protocol,
     ) -> Result<Self::NetworkSender, Self::SenderError>;
+
+    /// Reports a change of the reputation of the given peer, peers with low enough reputation
+    /// get disconnected by the network.
+    fn report_peer(&self, peer_id: Self::PeerId, change: ReputationChange);
+
+    /// Closes the connections to the given peer on all the protocols, and stops reconnecting to it
+    /// if it was dialed.
+    fn disconnect_peer(&self, peer_id: Self::PeerId);
+
+    /// Connects to the peer at the given address on all the protocols, and keeps reconnecting to
+    /// it until it is disconnected with `disconnect_peer`.
+    fn dial(&self, peer_id: Self::PeerId, address: Self::Address);
+}
+
+/// Runs the tasks of the service sending to single peers.
+pub trait Spawner: Send + Sync + 'static {
+    fn spawn(&self, name: &'static str, task: BoxFuture<'static, ()>);
+}
+
+impl Spawner for SpawnHandle {
+    fn spawn(&self, name: &'static str, task: BoxFuture<'static, ()>) {
+        SpawnHandleT::spawn(self, name, task)
+    }
 }
@@ -0,0 +1,223 @@
+//! Short names of peers for logs, as the full ids are long and hard to tell apart.
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{Debug, Display, Error as FmtError, Formatter},
+    hash::Hash,
+    sync::Arc,
+};
+
+use log::info;
+use parking_lot::Mutex;
+
+const LOG_TARGET: &str = "aleph-network";
+
+/// How many disconnected peers keep their names, so that peers reconnecting get the same one.
+const REMEMBERED_DISCONNECTED_PEERS: usize = 1024;
+
+/// The name of a peer in logs, `peer#N`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PeerName(usize);
+
+impl Display for PeerName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        write!(f, "peer#{}", self.0)
+    }
+}
+
+impl Debug for PeerName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        Display::fmt(self, f)
+    }
+}
+
+struct Entry {
+    index: usize,
+    /// In how many protocols the peer is connected.
+    connections: usize,
+}
+
+struct Registry<P> {
+    entries: HashMap<P, Entry>,
+    next_index: usize,
+    /// Peers that are not connected in any protocol, the ones that disconnected first in front.
+    disconnected: VecDeque<P>,
+}
+
+/// Gives peers consecutive indices in the order they are first seen, logging the full id of every
+/// peer once, when it gets its index. Shared by both protocols, so a peer has the same name in
+/// all of them.
+#[derive(Clone)]
+pub struct PeerNames<P>(Arc<Mutex<Registry<P>>>);
+
+impl<P: Clone + Debug + Eq + Hash> Default for PeerNames<P> {
+    fn default() -> Self {
+        PeerNames(Arc::new(Mutex::new(Registry {
+            entries: HashMap::new(),
+            next_index: 0,
+            disconnected: VecDeque::new(),
+        })))
+    }
+}
+
+impl<P: Clone + Debug + Eq + Hash> PeerNames<P> {
+    /// The name of the peer, a new one if the peer was not seen yet.
+    pub fn name(&self, peer: &P) -> PeerName {
+        let mut registry = self.0.lock();
+        PeerName(registry.entry(peer).index)
+    }
+
+    /// Marks the peer as connected in one more protocol, its name is kept at least until it
+    /// disconnects from all of them.
+    pub fn connected(&self, peer: &P) -> PeerName {
+        let mut registry = self.0.lock();
+        let entry = registry.entry(peer);
+        entry.connections += 1;
+        let index = entry.index;
+        if entry.connections == 1 {
+            registry
+                .disconnected
+                .retain(|disconnected| disconnected != peer);
+        }
+        PeerName(index)
+    }
+
+    /// Marks the peer as disconnected from one of the protocols. When too many peers are
+    /// disconnected from all of them, the names of the ones that disconnected first are forgotten.
+    pub fn disconnected(&self, peer: &P) {
+        let mut registry = self.0.lock();
+        let entry = match registry.entries.get_mut(peer) {
+            Some(entry) if entry.connections > 0 => entry,
+            _ => return,
+        };
+        entry.connections -= 1;
+        if entry.connections > 0 {
+            return;
+        }
+        registry.disconnected.push_back(peer.clone());
+        registry.forget_excess();
+    }
+}
+
+impl<P: Clone + Debug + Eq + Hash> Registry<P> {
+    fn entry(&mut self, peer: &P) -> &mut Entry {
+        if !self.entries.contains_key(peer) {
+            let index = self.next_index;
+            self.next_index += 1;
+            info!(target: LOG_TARGET, "Peer {:?} is called peer#{} in logs.", peer, index);
+            self.entries.insert(
+                peer.clone(),
+                Entry {
+                    index,
+                    connections: 0,
+                },
+            );
+            // Peers only seen, never connected, can be forgotten like disconnected ones.
+            self.disconnected.push_back(peer.clone());
+            self.forget_excess();
+        }
+        self.entries
+            .get_mut(peer)
+            .expect("the entry was just inserted")
+    }
+
+    fn forget_excess(&mut self) {
+        while self.disconnected.len() > REMEMBERED_DISCONNECTED_PEERS {
+            if let Some(forgotten) = self.disconnected.pop_front() {
+                self.entries.remove(&forgotten);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, Once};
+
+    use log::{LevelFilter, Log, Metadata, Record};
+    use network_clique::mock::random_peer_id;
+
+    use super::{PeerNames, REMEMBERED_DISCONNECTED_PEERS};
+
+    /// Keeps every logged line, the logger is global so the lines of other tests end up here too.
+    struct RecordingLogger(Mutex<Vec<String>>);
+
+    impl Log for RecordingLogger {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record<'_>) {
+            self.0
+                .lock()
+                .expect("logger is not poisoned")
+                .push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: RecordingLogger = RecordingLogger(Mutex::new(Vec::new()));
+    static INIT_LOGGER: Once = Once::new();
+
+    fn logged_lines_containing(needle: &str) -> usize {
+        INIT_LOGGER.call_once(|| {
+            log::set_logger(&LOGGER).expect("no other logger is set");
+            log::set_max_level(LevelFilter::Info);
+        });
+        LOGGER
+            .0
+            .lock()
+            .expect("logger is not poisoned")
+            .iter()
+            .filter(|line| line.contains(needle))
+            .count()
+    }
+
+    #[test]
+    fn reconnecting_peer_keeps_its_name() {
+        // Sets up the logger before anything gets logged.
+        logged_lines_containing("");
+        let names = PeerNames::default();
+        let peer = random_peer_id();
+        let other_peer = random_peer_id();
+
+        let name = names.connected(&peer);
+        assert_eq!(names.connected(&other_peer).to_string(), "peer#1");
+        names.disconnected(&peer);
+        assert_eq!(names.connected(&peer), name);
+        assert_eq!(names.name(&peer), name);
+        assert_eq!(name.to_string(), "peer#0");
+
+        let mapping = format!("Peer {peer:?} is called {name} in logs.");
+        assert_eq!(logged_lines_containing(&mapping), 1);
+    }
+
+    #[test]
+    fn peer_connected_in_another_protocol_keeps_its_name() {
+        let names = PeerNames::default();
+        let peer = random_peer_id();
+
+        let name = names.connected(&peer);
+        names.connected(&peer);
+        names.disconnected(&peer);
+        for _ in 0..REMEMBERED_DISCONNECTED_PEERS + 1 {
+            let other_peer = random_peer_id();
+            names.connected(&other_peer);
+            names.disconnected(&other_peer);
+        }
+        assert_eq!(names.name(&peer), name);
+    }
+
+    #[test]
+    fn names_of_long_disconnected_peers_are_forgotten() {
+        let names = PeerNames::default();
+        let peer = random_peer_id();
+
+        let name = names.connected(&peer);
+        names.disconnected(&peer);
+        for _ in 0..REMEMBERED_DISCONNECTED_PEERS {
+            names.name(&random_peer_id());
+        }
+        assert_ne!(names.name(&peer), name);
+    }
+}
@@ -0,0 +1,399 @@
+//! Bounded queues for messages waiting to be sent to a single peer.
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use futures::{select_biased, FutureExt};
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+use crate::network::gossip::Priority;
+
+/// What to do with a message pushed to a queue that is already full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Discard the oldest queued message to make room for the new one.
+    DropOldest,
+    /// Reject the new message, leaving the queue intact.
+    DropNewest,
+}
+
+/// Why a message could not be pushed to a queue.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PushError {
+    /// The queue is full and the drop policy rejects new messages.
+    Full,
+    /// The receiving end of the queue is gone.
+    Closed,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+    policy: DropPolicy,
+    sender_dropped: bool,
+    receiver_dropped: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    notify: Notify,
+}
+
+/// The sending end of a peer queue. Dropping it closes the queue, the receiver will still get
+/// all the messages that were queued before that.
+pub struct QueueSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The receiving end of a peer queue.
+pub struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Creates a new queue holding at most `capacity` messages, handling overflow according to
+/// `policy`.
+pub fn channel<T>(capacity: usize, policy: DropPolicy) -> (QueueSender<T>, QueueReceiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(capacity),
+            capacity,
+            policy,
+            sender_dropped: false,
+            receiver_dropped: false,
+        }),
+        notify: Notify::new(),
+    });
+    (
+        QueueSender {
+            shared: shared.clone(),
+        },
+        QueueReceiver { shared },
+    )
+}
+
+impl<T> QueueSender<T> {
+    /// Pushes a message to the queue. If the queue was full and the policy is
+    /// `DropPolicy::DropOldest`, the discarded message is returned.
+    pub fn push(&self, item: T) -> Result<Option<T>, PushError> {
+        let mut state = self.shared.state.lock();
+        if state.receiver_dropped {
+            return Err(PushError::Closed);
+        }
+        let mut dropped = None;
+        if state.items.len() >= state.capacity {
+            match state.policy {
+                DropPolicy::DropNewest => return Err(PushError::Full),
+                DropPolicy::DropOldest => dropped = state.items.pop_front(),
+            }
+        }
+        state.items.push_back(item);
+        drop(state);
+        self.shared.notify.notify_one();
+        Ok(dropped)
+    }
+
+    /// The number of messages currently waiting in the queue.
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().items.len()
+    }
+
+    /// Changes how many messages the queue holds. Messages already waiting are kept even if
+    /// there are more of them than the new capacity, until then new ones are handled according
+    /// to the policy as if the queue was full.
+    pub fn set_capacity(&self, capacity: usize) {
+        self.shared.state.lock().capacity = capacity;
+    }
+
+    /// Takes out all the messages waiting in the queue, so that they can be queued elsewhere.
+    pub fn drain(&self) -> Vec<T> {
+        self.shared.state.lock().items.drain(..).collect()
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().sender_dropped = true;
+        self.shared.notify.notify_one();
+    }
+}
+
+impl<T> QueueReceiver<T> {
+    /// Returns the next message, or `None` once the sender is dropped and the queue is empty.
+    /// This method is cancellation safe.
+    pub async fn next(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.shared.state.lock();
+                if let Some(item) = state.items.pop_front() {
+                    return Some(item);
+                }
+                if state.sender_dropped {
+                    return None;
+                }
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+
+    /// Returns the next message if one is already waiting, without blocking.
+    pub fn try_next(&mut self) -> Option<T> {
+        self.shared.state.lock().items.pop_front()
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.receiver_dropped = true;
+        state.items.clear();
+    }
+}
+
+/// The sending end of a pair of queues for messages of different priorities.
+pub struct PrioritySender<T> {
+    high: QueueSender<T>,
+    normal: QueueSender<T>,
+    unfinished: Arc<AtomicUsize>,
+}
+
+/// The receiving end of a pair of queues for messages of different priorities.
+pub struct PriorityReceiver<T> {
+    high: QueueReceiver<T>,
+    normal: QueueReceiver<T>,
+    unfinished: Arc<AtomicUsize>,
+}
+
+/// Creates a pair of queues, one per priority, each holding at most `capacity` messages.
+pub fn priority_channel<T>(
+    capacity: usize,
+    policy: DropPolicy,
+) -> (PrioritySender<T>, PriorityReceiver<T>) {
+    let (high_tx, high_rx) = channel(capacity, policy);
+    let (normal_tx, normal_rx) = channel(capacity, policy);
+    let unfinished = Arc::new(AtomicUsize::new(0));
+    (
+        PrioritySender {
+            high: high_tx,
+            normal: normal_tx,
+            unfinished: unfinished.clone(),
+        },
+        PriorityReceiver {
+            high: high_rx,
+            normal: normal_rx,
+            unfinished,
+        },
+    )
+}
+
+impl<T> PrioritySender<T> {
+    /// Pushes a message to the queue of the given priority, see `QueueSender::push`.
+    pub fn push(&self, item: T, priority: Priority) -> Result<Option<T>, PushError> {
+        let result = match priority {
+            Priority::High => self.high.push(item),
+            Priority::Normal => self.normal.push(item),
+        };
+        if let Ok(None) = result {
+            self.unfinished.fetch_add(1, Ordering::SeqCst);
+        }
+        result
+    }
+
+    /// The number of messages currently waiting in both queues.
+    pub fn len(&self) -> usize {
+        self.high.len() + self.normal.len()
+    }
+
+    /// Changes the capacity of both queues, see `QueueSender::set_capacity`.
+    pub fn set_capacity(&self, capacity: usize) {
+        self.high.set_capacity(capacity);
+        self.normal.set_capacity(capacity);
+    }
+
+    /// Takes out all the messages waiting in both queues together with their priorities, the
+    /// high priority ones first, see `QueueSender::drain`.
+    pub fn drain(&self) -> Vec<(T, Priority)> {
+        let drained: Vec<_> = self
+            .high
+            .drain()
+            .into_iter()
+            .map(|item| (item, Priority::High))
+            .chain(
+                self.normal
+                    .drain()
+                    .into_iter()
+                    .map(|item| (item, Priority::Normal)),
+            )
+            .collect();
+        let _ = self
+            .unfinished
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |unfinished| {
+                Some(unfinished.saturating_sub(drained.len()))
+            });
+        drained
+    }
+
+    /// The number of pushed messages that are either still waiting in the queues, or were taken
+    /// out but not yet reported as done with `PriorityReceiver::done`.
+    pub fn unfinished(&self) -> usize {
+        self.unfinished.load(Ordering::SeqCst)
+    }
+}
+
+impl<T> PriorityReceiver<T> {
+    /// Returns the next message, preferring high priority ones, or `None` once the sender is
+    /// dropped and both queues are empty. This method is cancellation safe.
+    pub async fn next(&mut self) -> Option<T> {
+        select_biased! {
+            item = self.high.next().fuse() => match item {
+                Some(item) => Some(item),
+                None => self.normal.next().await,
+            },
+            item = self.normal.next().fuse() => match item {
+                Some(item) => Some(item),
+                None => self.high.next().await,
+            },
+        }
+    }
+
+    /// Returns the next message if one is already waiting, preferring high priority ones,
+    /// without blocking.
+    pub fn try_next(&mut self) -> Option<T> {
+        self.high.try_next().or_else(|| self.normal.try_next())
+    }
+
+    /// Reports that the given number of taken out messages were handled.
+    pub fn done(&self, count: usize) {
+        // Saturating, so a mistaken report cannot make the count wrap around.
+        let _ = self
+            .unfinished
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |unfinished| {
+                Some(unfinished.saturating_sub(count))
+            });
+    }
+}
+
+impl<T> Drop for PriorityReceiver<T> {
+    fn drop(&mut self) {
+        // The queued messages are dropped together with the receiver.
+        self.unfinished.store(0, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel, priority_channel, DropPolicy, PushError};
+    use crate::network::gossip::Priority;
+
+    #[tokio::test]
+    async fn drop_newest_rejects_when_full() {
+        let (tx, mut rx) = channel(2, DropPolicy::DropNewest);
+        assert_eq!(tx.push(1), Ok(None));
+        assert_eq!(tx.push(2), Ok(None));
+        assert_eq!(tx.push(3), Err(PushError::Full));
+        assert_eq!(rx.next().await, Some(1));
+        assert_eq!(tx.push(4), Ok(None));
+        drop(tx);
+        assert_eq!(rx.next().await, Some(2));
+        assert_eq!(rx.next().await, Some(4));
+        assert_eq!(rx.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_evicts_when_full() {
+        let (tx, mut rx) = channel(2, DropPolicy::DropOldest);
+        assert_eq!(tx.push(1), Ok(None));
+        assert_eq!(tx.push(2), Ok(None));
+        assert_eq!(tx.push(3), Ok(Some(1)));
+        drop(tx);
+        assert_eq!(rx.next().await, Some(2));
+        assert_eq!(rx.next().await, Some(3));
+        assert_eq!(rx.next().await, None);
+    }
+
+    #[test]
+    fn lowered_capacity_keeps_waiting_messages() {
+        let (tx, mut rx) = channel(3, DropPolicy::DropNewest);
+        for i in 1..=3 {
+            assert_eq!(tx.push(i), Ok(None));
+        }
+        tx.set_capacity(1);
+        assert_eq!(tx.push(4), Err(PushError::Full));
+        assert_eq!(rx.try_next(), Some(1));
+        assert_eq!(rx.try_next(), Some(2));
+        assert_eq!(tx.push(5), Err(PushError::Full));
+        assert_eq!(rx.try_next(), Some(3));
+        assert_eq!(tx.push(6), Ok(None));
+        assert_eq!(tx.push(7), Err(PushError::Full));
+    }
+
+    #[tokio::test]
+    async fn high_priority_goes_first() {
+        let (tx, mut rx) = priority_channel(2, DropPolicy::DropNewest);
+        assert_eq!(tx.push(1, Priority::Normal), Ok(None));
+        assert_eq!(tx.push(2, Priority::High), Ok(None));
+        assert_eq!(tx.push(3, Priority::Normal), Ok(None));
+        assert_eq!(tx.len(), 3);
+        drop(tx);
+        assert_eq!(rx.next().await, Some(2));
+        assert_eq!(rx.next().await, Some(1));
+        assert_eq!(rx.next().await, Some(3));
+        assert_eq!(rx.next().await, None);
+    }
+
+    #[test]
+    fn try_next_does_not_wait() {
+        let (tx, mut rx) = priority_channel(2, DropPolicy::DropNewest);
+        assert_eq!(rx.try_next(), None);
+        assert_eq!(tx.push(1, Priority::Normal), Ok(None));
+        assert_eq!(tx.push(2, Priority::High), Ok(None));
+        assert_eq!(rx.try_next(), Some(2));
+        assert_eq!(rx.try_next(), Some(1));
+        assert_eq!(rx.try_next(), None);
+    }
+
+    #[test]
+    fn unfinished_counts_messages_until_done() {
+        let (tx, mut rx) = priority_channel(1, DropPolicy::DropOldest);
+        assert_eq!(tx.push(1, Priority::Normal), Ok(None));
+        assert_eq!(tx.push(2, Priority::High), Ok(None));
+        assert_eq!(tx.push(3, Priority::High), Ok(Some(2)));
+        assert_eq!(tx.unfinished(), 2);
+        assert_eq!(rx.try_next(), Some(3));
+        assert_eq!(tx.unfinished(), 2);
+        rx.done(1);
+        assert_eq!(tx.unfinished(), 1);
+        drop(rx);
+        assert_eq!(tx.unfinished(), 0);
+    }
+
+    #[tokio::test]
+    async fn drained_messages_are_not_received() {
+        let (tx, mut rx) = priority_channel(2, DropPolicy::DropNewest);
+        assert_eq!(tx.push(1, Priority::Normal), Ok(None));
+        assert_eq!(tx.push(2, Priority::High), Ok(None));
+        assert_eq!(tx.push(3, Priority::Normal), Ok(None));
+        assert_eq!(
+            tx.drain(),
+            vec![
+                (2, Priority::High),
+                (1, Priority::Normal),
+                (3, Priority::Normal)
+            ]
+        );
+        assert_eq!(tx.unfinished(), 0);
+        drop(tx);
+        assert_eq!(rx.next().await, None);
+    }
+
+    #[test]
+    fn push_fails_after_receiver_dropped() {
+        let (tx, rx) = channel(2, DropPolicy::DropNewest);
+        drop(rx);
+        assert_eq!(tx.push(1), Err(PushError::Closed));
+    }
+}
@@ -0,0 +1,66 @@
+//! Limiting how many messages a single peer can send us.
+use tokio::time::Instant;
+
+use crate::network::gossip::config::InboundRateLimitConfig;
+
+/// A token bucket, every accepted message takes a token and tokens refill at a constant rate up
+/// to the burst size.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(config: &InboundRateLimitConfig) -> Self {
+        let burst = config.burst as f64;
+        TokenBucket {
+            rate: config.rate.max(0.0),
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token if there is one, returns whether the message should be accepted.
+    pub fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time;
+
+    use super::TokenBucket;
+    use crate::network::gossip::config::InboundRateLimitConfig;
+
+    #[tokio::test(start_paused = true)]
+    async fn accepts_burst_then_refills_at_rate() {
+        let mut bucket = TokenBucket::new(&InboundRateLimitConfig {
+            rate: 2.0,
+            burst: 3,
+        });
+        assert_eq!((0..4).filter(|_| bucket.try_take()).count(), 3);
+
+        time::advance(Duration::from_millis(500)).await;
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+
+        // Tokens do not accumulate beyond the burst size.
+        time::advance(Duration::from_secs(10)).await;
+        assert_eq!((0..4).filter(|_| bucket.try_take()).count(), 3);
+    }
+}
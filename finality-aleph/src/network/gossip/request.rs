@@ -0,0 +1,401 @@
+//! Requests and responses on top of the fire-and-forget sending of the gossip network.
+use std::{
+    collections::HashMap,
+    fmt::{Display, Error as FmtError, Formatter},
+    time::Duration,
+};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    StreamExt,
+};
+use log::{debug, trace};
+use parity_scale_codec::{Decode, Encode};
+use tokio::time::{self, Instant};
+
+use crate::network::{
+    gossip::{ConnectionEvent, Network, Protocol},
+    Data,
+};
+
+const LOG_TARGET: &str = "network-gossip-request";
+
+/// The data of a protocol used for requests, telling them and their responses apart from other
+/// messages. All nodes of a network have to use it for the protocol.
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Decode)]
+pub enum Envelope<D> {
+    Message(D),
+    Request(u64, D),
+    Response(u64, D),
+}
+
+/// Why a request got no response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestError {
+    /// No response arrived within the timeout.
+    TimedOut,
+    /// The peer disconnected before responding.
+    PeerDisconnected,
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        use RequestError::*;
+        match self {
+            TimedOut => write!(f, "no response arrived in time"),
+            PeerDisconnected => write!(f, "peer disconnected before responding"),
+        }
+    }
+}
+
+/// Resolves with the response to a request.
+pub type Response<D> = oneshot::Receiver<Result<D, RequestError>>;
+
+/// Data received by `RequestResponse`, other than responses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Incoming<D, P> {
+    Message(D, P),
+    /// A request of the peer, answered with `RequestResponse::respond` using the id.
+    Request {
+        id: u64,
+        data: D,
+        peer: P,
+    },
+}
+
+struct PendingRequest<D, P> {
+    peer: P,
+    deadline: Instant,
+    response: oneshot::Sender<Result<D, RequestError>>,
+}
+
+/// Sends requests to peers and matches their responses. Pending requests are abandoned when they
+/// time out or the peer disconnects from the protocol, as seen in the connection events of the
+/// service.
+pub struct RequestResponse<D: Data, N: Network<Envelope<D>>> {
+    network: N,
+    protocol: Protocol,
+    connection_events: mpsc::UnboundedReceiver<ConnectionEvent<N::PeerId>>,
+    next_id: u64,
+    pending: HashMap<u64, PendingRequest<D, N::PeerId>>,
+}
+
+impl<D: Data, N: Network<Envelope<D>>> RequestResponse<D, N> {
+    /// Creates requests on top of the network of the protocol, with connection events coming from
+    /// `Service::subscribe_connection_events`.
+    pub fn new(
+        network: N,
+        protocol: Protocol,
+        connection_events: mpsc::UnboundedReceiver<ConnectionEvent<N::PeerId>>,
+    ) -> Self {
+        RequestResponse {
+            network,
+            protocol,
+            connection_events,
+            next_id: 0,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Sends a message that is not a request to the peer.
+    pub fn send_to(&mut self, data: D, peer_id: N::PeerId) -> Result<(), N::Error> {
+        self.network.send_to(Envelope::Message(data), peer_id)
+    }
+
+    /// Broadcasts a message that is not a request.
+    pub fn broadcast(&mut self, data: D) -> Result<(), N::Error> {
+        self.network.broadcast(Envelope::Message(data))
+    }
+
+    /// Sends the request to the peer. The returned receiver resolves once the response arrives,
+    /// which only happens while `next` is being polled.
+    pub fn request(
+        &mut self,
+        data: D,
+        peer_id: N::PeerId,
+        timeout: Duration,
+    ) -> Result<Response<D>, N::Error> {
+        let id = self.next_id;
+        self.network
+            .send_to(Envelope::Request(id, data), peer_id.clone())?;
+        self.next_id = self.next_id.wrapping_add(1);
+        let (response, rx) = oneshot::channel();
+        self.pending.insert(
+            id,
+            PendingRequest {
+                peer: peer_id,
+                deadline: Instant::now() + timeout,
+                response,
+            },
+        );
+        Ok(rx)
+    }
+
+    /// Sends the response to the request with the given id to the peer that made it.
+    pub fn respond(&mut self, id: u64, data: D, peer_id: N::PeerId) -> Result<(), N::Error> {
+        self.network.send_to(Envelope::Response(id, data), peer_id)
+    }
+
+    /// The number of requests still waiting for a response.
+    pub fn pending_requests(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn handle_response(&mut self, id: u64, data: D, peer_id: N::PeerId) {
+        match self.pending.remove(&id) {
+            Some(request) if request.peer == peer_id => {
+                // The requester might not wait for the response anymore, that is fine.
+                let _ = request.response.send(Ok(data));
+            }
+            Some(request) => {
+                debug!(
+                    target: LOG_TARGET,
+                    "Ignoring response to request {} from peer {:?}, it was sent to {:?}.",
+                    id,
+                    peer_id,
+                    request.peer
+                );
+                self.pending.insert(id, request);
+            }
+            None => trace!(
+                target: LOG_TARGET,
+                "Ignoring response to unknown request {} from peer {:?}.", id, peer_id
+            ),
+        }
+    }
+
+    fn fail_requests(
+        &mut self,
+        error: RequestError,
+        failed: impl Fn(&PendingRequest<D, N::PeerId>) -> bool,
+    ) {
+        let ids: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, request)| failed(request))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids {
+            if let Some(request) = self.pending.remove(&id) {
+                debug!(
+                    target: LOG_TARGET,
+                    "Request {} to peer {:?} failed: {}.", id, request.peer, error
+                );
+                let _ = request.response.send(Err(error));
+            }
+        }
+    }
+
+    fn handle_connection_event(&mut self, event: ConnectionEvent<N::PeerId>) {
+        if let ConnectionEvent::PeerDisconnected(peer_id, protocol) = event {
+            if protocol == self.protocol {
+                self.fail_requests(RequestError::PeerDisconnected, |request| {
+                    request.peer == peer_id
+                });
+            }
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|request| request.deadline).min()
+    }
+
+    /// Receives the next message or request, matching responses to pending requests and timing
+    /// them out in the meantime. Disconnections are handled before anything received afterwards.
+    /// This method is cancellation safe.
+    pub async fn next(&mut self) -> Result<Incoming<D, N::PeerId>, N::Error> {
+        loop {
+            let next_deadline = self.next_deadline();
+            tokio::select! {
+                biased;
+                Some(event) = self.connection_events.next() => {
+                    self.handle_connection_event(event);
+                },
+                _ = time::sleep_until(next_deadline.unwrap_or_else(Instant::now)), if next_deadline.is_some() => {
+                    let now = Instant::now();
+                    self.fail_requests(RequestError::TimedOut, |request| request.deadline <= now);
+                },
+                received = self.network.next() => match received? {
+                    (Envelope::Message(data), peer) => return Ok(Incoming::Message(data, peer)),
+                    (Envelope::Request(id, data), peer) => {
+                        return Ok(Incoming::Request { id, data, peer })
+                    }
+                    (Envelope::Response(id, data), peer) => self.handle_response(id, data, peer),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, time::Duration};
+
+    use futures::{channel::mpsc, StreamExt};
+    use tokio::time;
+
+    use super::{Envelope, Incoming, RequestError, RequestResponse};
+    use crate::network::gossip::{ConnectionEvent, Network, Protocol};
+
+    const PROTOCOL: Protocol = Protocol::BlockSync;
+    const TIMEOUT: Duration = Duration::from_secs(1);
+    const PEER: u32 = 7;
+
+    type Received = (Envelope<u32>, u32);
+
+    struct ChannelNetwork {
+        sent: mpsc::UnboundedSender<Received>,
+        received: mpsc::UnboundedReceiver<Received>,
+    }
+
+    #[async_trait::async_trait]
+    impl Network<Envelope<u32>> for ChannelNetwork {
+        type Error = &'static str;
+        type PeerId = u32;
+
+        fn send_to(&mut self, data: Envelope<u32>, peer_id: u32) -> Result<(), Self::Error> {
+            self.sent
+                .unbounded_send((data, peer_id))
+                .map_err(|_| "receiver dropped")
+        }
+
+        fn send_to_random(&mut self, _: Envelope<u32>, _: HashSet<u32>) -> Result<(), Self::Error> {
+            Err("unsupported")
+        }
+
+        fn broadcast(&mut self, _: Envelope<u32>) -> Result<(), Self::Error> {
+            Err("unsupported")
+        }
+
+        async fn next(&mut self) -> Result<Received, Self::Error> {
+            self.received.next().await.ok_or("sender dropped")
+        }
+    }
+
+    struct TestData {
+        requests: RequestResponse<u32, ChannelNetwork>,
+        sent: mpsc::UnboundedReceiver<Received>,
+        received: mpsc::UnboundedSender<Received>,
+        connection_events: mpsc::UnboundedSender<ConnectionEvent<u32>>,
+    }
+
+    fn prepare() -> TestData {
+        let (sent_tx, sent) = mpsc::unbounded();
+        let (received, received_rx) = mpsc::unbounded();
+        let (connection_events, connection_events_rx) = mpsc::unbounded();
+        let network = ChannelNetwork {
+            sent: sent_tx,
+            received: received_rx,
+        };
+        TestData {
+            requests: RequestResponse::new(network, PROTOCOL, connection_events_rx),
+            sent,
+            received,
+            connection_events,
+        }
+    }
+
+    #[tokio::test]
+    async fn response_resolves_request() {
+        let mut test_data = prepare();
+        let response = test_data
+            .requests
+            .request(1, PEER, TIMEOUT)
+            .expect("should send");
+        let id = match test_data.sent.next().await {
+            Some((Envelope::Request(id, 1), PEER)) => id,
+            other => panic!("expected the request, got {other:?}"),
+        };
+
+        // A response from another peer does not count.
+        for received in [
+            (Envelope::Response(id, 3), PEER + 1),
+            (Envelope::Response(id, 2), PEER),
+            (Envelope::Message(4), PEER),
+        ] {
+            test_data
+                .received
+                .unbounded_send(received)
+                .expect("receiver should exist");
+        }
+        assert_eq!(
+            test_data.requests.next().await,
+            Ok(Incoming::Message(4, PEER))
+        );
+        assert_eq!(response.await, Ok(Ok(2)));
+        assert_eq!(test_data.requests.pending_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn requests_are_received_and_responded_to() {
+        let mut test_data = prepare();
+        test_data
+            .received
+            .unbounded_send((Envelope::Request(5, 1), PEER))
+            .expect("receiver should exist");
+        assert_eq!(
+            test_data.requests.next().await,
+            Ok(Incoming::Request {
+                id: 5,
+                data: 1,
+                peer: PEER
+            })
+        );
+
+        test_data.requests.respond(5, 2, PEER).expect("should send");
+        assert_eq!(
+            test_data.sent.next().await,
+            Some((Envelope::Response(5, 2), PEER))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn request_times_out() {
+        let mut test_data = prepare();
+        let response = test_data
+            .requests
+            .request(1, PEER, TIMEOUT)
+            .expect("should send");
+
+        assert!(time::timeout(2 * TIMEOUT, test_data.requests.next())
+            .await
+            .is_err());
+        assert_eq!(response.await, Ok(Err(RequestError::TimedOut)));
+        assert_eq!(test_data.requests.pending_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn peer_disconnecting_fails_request() {
+        let mut test_data = prepare();
+        let response = test_data
+            .requests
+            .request(1, PEER, TIMEOUT)
+            .expect("should send");
+        let other_response = test_data
+            .requests
+            .request(2, PEER + 1, TIMEOUT)
+            .expect("should send");
+
+        for event in [
+            ConnectionEvent::PeerDisconnected(PEER, Protocol::Authentication),
+            ConnectionEvent::PeerDisconnected(PEER, PROTOCOL),
+        ] {
+            test_data
+                .connection_events
+                .unbounded_send(event)
+                .expect("receiver should exist");
+        }
+        test_data
+            .received
+            .unbounded_send((Envelope::Message(3), PEER + 1))
+            .expect("receiver should exist");
+        assert_eq!(
+            test_data.requests.next().await,
+            Ok(Incoming::Message(3, PEER + 1))
+        );
+
+        assert_eq!(response.await, Ok(Err(RequestError::PeerDisconnected)));
+        assert_eq!(test_data.requests.pending_requests(), 1);
+        drop(other_response);
+    }
+}
@@ -1,37 +1,207 @@
 use core::fmt;
 use std::{
-    collections::{HashMap, HashSet},
+    cmp,
+    collections::{HashMap, HashSet, VecDeque},
     fmt::{Debug, Display, Error as FmtError, Formatter},
     future::Future,
     hash::Hash,
-    time::Instant,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use futures::{channel::mpsc, StreamExt};
+use bytes::Bytes;
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, AbortHandle, BoxFuture},
+    select_biased,
+    stream::FuturesUnordered,
+    FutureExt, StreamExt,
+};
 use log::{debug, info, trace, warn};
 use network_clique::SpawnHandleT;
-use rand::{seq::IteratorRandom, thread_rng};
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, seq::IteratorRandom, thread_rng, Rng, SeedableRng};
 use substrate_prometheus_endpoint::Registry;
-use tokio::time;
-
-const MAX_QUEUE_SIZE: usize = 16;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task, time,
+};
+use tracing::{debug_span, info_span, Instrument, Span};
 
 use crate::{
+    max_message_size,
     network::{
         gossip::{
-            metrics::Metrics, Event, EventStream, Network, NetworkSender, Protocol, RawNetwork,
+            circuit::CircuitBreaker,
+            codec::{self, Codec, MessageKind, PreviousVersion, ScaleCodec},
+            compression,
+            config::{
+                AdaptiveStatusConfig, Config, Direction, FairDrainConfig, PeerLimitPolicy,
+                ProtocolConfig, QuietStatusConfig, RebroadcastConfig,
+            },
+            dedup::{self, BroadcastCache, InboundCache, PayloadHash, SentCache},
+            fragment::{self, Reassembler, Reassembly},
+            handshake::{self, HandshakeError},
+            metrics::{DropReason, Metrics},
+            peer_names::PeerNames,
+            queue::{self, PriorityReceiver, PrioritySender, PushError},
+            rate_limit::TokenBucket,
+            signing::MessageSigning,
+            Barrier, BroadcastResult, Confirmation, ConnectionEvent, DecodeFailure, Delivery,
+            Event, EventStream, Network, NetworkSender, PeerSnapshot, Priority, Protocol,
+            ProtocolSnapshot, RawNetwork, RebroadcastHandle, ReputationChange, Spawner,
+            StateSnapshot,
         },
         Data,
     },
-    SpawnHandle, STATUS_REPORT_INTERVAL,
+    SpawnHandle,
 };
 
 const LOG_TARGET: &str = "aleph-network";
 
+const MALFORMED_MESSAGE: ReputationChange =
+    ReputationChange::new(-(1 << 12), "Malformed gossip message");
+const VALID_MESSAGE: ReputationChange = ReputationChange::new(1 << 4, "Valid gossip message");
+const INVALID_SIGNATURE: ReputationChange =
+    ReputationChange::new(-(1 << 12), "Invalid gossip message signature");
+const RATE_LIMIT_EXCEEDED: ReputationChange =
+    ReputationChange::new(-(1 << 8), "Gossip rate limit exceeded");
+const INCOMPATIBLE_VERSION: ReputationChange =
+    ReputationChange::new(-(1 << 8), "Incompatible gossip version");
+const PEER_LIMIT_REACHED: ReputationChange =
+    ReputationChange::new(-(1 << 8), "Gossip peer limit reached");
+const REASSEMBLY_LIMIT_EXCEEDED: ReputationChange =
+    ReputationChange::new(-(1 << 8), "Gossip reassembly limit exceeded");
+/// How often at most a single peer gets rewarded for sending valid messages.
+const VALID_MESSAGE_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+/// How often flushing checks whether all the queued messages were sent.
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Rebroadcasting more often than this would keep the service busy.
+const MIN_REBROADCAST_INTERVAL: Duration = Duration::from_millis(10);
+/// Peer senders not running this long after being spawned are considered dead.
+const PEER_SENDER_START_TIMEOUT: Duration = Duration::from_secs(10);
+/// Keepalives are empty messages, no data sent through the service encodes to nothing.
+const KEEPALIVE: Bytes = Bytes::new();
+
 enum Command<D: Data, P: Clone + Debug + Eq + Hash + Send + 'static> {
-    Send(D, P),
+    Send(D, P, Priority, Option<time::Instant>),
+    SendConfirmed(D, P, PendingConfirmation),
     SendToRandom(D, HashSet<P>),
-    Broadcast(D),
+    SendToBest(D),
+    SendToPeers(D, HashSet<P>),
+    Broadcast(D, Priority, Option<time::Instant>),
+    Rebroadcast(D, RebroadcastConfig, RebroadcastHandle),
+    BroadcastBarrier(D, oneshot::Sender<Vec<(P, Confirmation<Error>)>>),
+}
+
+/// Orders peers by importance, the ones ordered first are the most important.
+type PeerPriority<P> = Arc<dyn Fn(&P, &P) -> cmp::Ordering + Send + Sync>;
+
+/// Sees the exact bytes sent to peers, for debugging the wire format.
+type OutgoingTap<P> = Arc<dyn Fn(&P, Protocol, &[u8]) + Send + Sync>;
+
+/// Everything the service keeps track of for a single protocol:
+/// 1. The channels used for communicating with the user of the protocol.
+/// 2. The currently connected peers together with queues of messages waiting to be sent to them.
+struct ProtocolState<N: RawNetwork, D: Data> {
+    protocol: Protocol,
+    config: ProtocolConfig,
+    codec: Arc<dyn Codec<D>>,
+    /// Decodes messages of the previous encoding version.
+    previous_codec: Arc<dyn Codec<D>>,
+    network: N,
+    spawner: Arc<dyn Spawner>,
+    /// The number of peer senders running as tasks of their own.
+    sender_tasks: Arc<AtomicUsize>,
+    /// Hands peer senders over to the pooled task, once the limit of their own tasks is reached.
+    sender_pool: Option<mpsc::UnboundedSender<BoxFuture<'static, ()>>>,
+    metrics: Metrics,
+    messages_from_user: mpsc::Receiver<Command<D, N::PeerId>>,
+    /// Commands for handles created before the service runs, dropped once it does, so that the
+    /// commands end when all the users are gone.
+    commands_for_handles: Option<CommandSender<D, N::PeerId>>,
+    messages_for_user: mpsc::UnboundedSender<(D, N::PeerId, Option<Delivery>)>,
+    /// Additional receivers of copies of the messages forwarded to the user.
+    subscribers: Vec<mpsc::Sender<(D, N::PeerId)>>,
+    /// Sinks getting the messages of some kinds instead of the user.
+    kind_routes: HashMap<MessageKind, mpsc::UnboundedSender<(D, N::PeerId)>>,
+    connected_peers: HashSet<N::PeerId>,
+    /// Short names of peers for logs, shared with the other protocol.
+    peer_names: PeerNames<N::PeerId>,
+    /// The number of connected peers, shared with the interface of the user.
+    connected_peer_count: Arc<AtomicUsize>,
+    /// The connected peers in the order they connected, for rotating broadcasts among them.
+    broadcast_order: Vec<N::PeerId>,
+    /// How many broadcasts were made, determines which peer gets the next one first.
+    broadcast_rotation: usize,
+    peer_senders: HashMap<N::PeerId, PrioritySender<Queued>>,
+    send_latencies: HashMap<N::PeerId, SendLatency>,
+    circuit_breakers: HashMap<N::PeerId, CircuitBreaker>,
+    peer_sender_aborts: HashMap<N::PeerId, AbortHandle>,
+    /// Whether the current peer sender of every peer is running.
+    peer_sender_liveness: HashMap<N::PeerId, Liveness>,
+    /// How many peer senders are running, including replaced ones still finishing their sends.
+    active_peer_senders: Arc<AtomicUsize>,
+    timestamp_of_last_log_that_queue_is_full: HashMap<N::PeerId, Instant>,
+    timestamp_of_last_valid_message_report: HashMap<N::PeerId, Instant>,
+    last_seen: HashMap<N::PeerId, Instant>,
+    duplicate_stream_openings: usize,
+    broadcast_cache: Option<BroadcastCache<N::PeerId>>,
+    /// Recently received payloads, if inbound deduplication is enabled.
+    inbound_cache: Option<InboundCache>,
+    /// Payloads recently queued for every peer, if send deduplication is enabled.
+    sent_cache: Option<SentCache<N::PeerId>>,
+    /// Frames of incomplete received messages, if fragmentation is enabled.
+    reassembler: Option<Reassembler<N::PeerId>>,
+    broadcast_feedback: Option<mpsc::UnboundedSender<BroadcastResult<N::PeerId>>>,
+    allowlist: Option<HashSet<N::PeerId>>,
+    received_rate: MessageRate,
+    broadcast_rate: MessageRate,
+    /// The buckets limiting how many messages peers can send us, if rate limiting is enabled.
+    inbound_limits: HashMap<N::PeerId, TokenBucket>,
+    /// Signing of sent and verification of received messages, disabled when `None`.
+    signing: Option<MessageSigning>,
+    /// The queue depth of every peer seen by the previous status report, together with for how
+    /// many status reports in a row the queue was growing.
+    queue_growth: HashMap<N::PeerId, (usize, usize)>,
+    /// The most recent broadcasts, sent to peers when they connect.
+    replay_buffer: VecDeque<D>,
+    /// The fraction of broadcasts sent to peers, peers not in here get all of them.
+    peer_weights: HashMap<N::PeerId, f64>,
+    /// Decides which broadcasts peers with lower weights skip.
+    broadcast_rng: StdRng,
+    /// Whether broadcasts and received messages are dropped, connections are still tracked.
+    paused: bool,
+    /// The bytes handed over to the network by all the peer senders, as sent on the wire.
+    bytes_sent: Arc<AtomicU64>,
+    bytes_received: u64,
+    /// The versions negotiated with peers, `None` for peers with incompatible ones. Peers are
+    /// missing until their handshake arrives.
+    peer_versions: HashMap<N::PeerId, Option<u16>>,
+    decode_failures: Option<mpsc::UnboundedSender<DecodeFailure<N::PeerId>>>,
+    /// Peers whose stream closed, together with when their sender gets dropped unless the stream
+    /// is reopened.
+    pending_closes: HashMap<N::PeerId, time::Instant>,
+    /// The budget of messages waiting to be sent, shared with the other protocol, unlimited when
+    /// `None`.
+    in_flight: Option<Arc<Semaphore>>,
+    /// Makes peer senders take turns while the budget is nearly used up, if present.
+    drain_scheduler: Option<DrainScheduler>,
+    connectivity: Connectivity,
+    rebroadcasts: Vec<Rebroadcast<D>>,
+    /// Overrides the default order of peers for broadcasts and eviction, if present.
+    peer_priority: Option<PeerPriority<N::PeerId>>,
+    /// Peers in the current authority set, only used for breaking the metrics down.
+    authorities: HashSet<N::PeerId>,
+    /// Called by peer senders with everything they send, if present.
+    outgoing_tap: Option<OutgoingTap<N::PeerId>>,
+    /// When peers disconnected, only kept for as long as their messages are still handled.
+    recently_disconnected: HashMap<N::PeerId, time::Instant>,
+    /// When the connected peers connected.
+    connected_at: HashMap<N::PeerId, time::Instant>,
 }
 
 /// A service managing all the direct interaction with the underlying network implementation. It
@@ -41,30 +211,233 @@ enum Command<D: Data, P: Clone + Debug + Eq + Hash + Send + 'static> {
 ///   2. Various forms of (dis)connecting, keeping track of all currently connected nodes.
 /// 3. Outgoing messages, sending them out, using 1.2. to broadcast.
 pub struct Service<N: RawNetwork, ES: EventStream<N::PeerId>, AD: Data, BSD: Data> {
-    network: N,
-    messages_from_authentication_user: mpsc::UnboundedReceiver<Command<AD, N::PeerId>>,
-    messages_from_block_sync_user: mpsc::UnboundedReceiver<Command<BSD, N::PeerId>>,
-    messages_for_authentication_user: mpsc::UnboundedSender<(AD, N::PeerId)>,
-    messages_for_block_sync_user: mpsc::UnboundedSender<(BSD, N::PeerId)>,
-    authentication_connected_peers: HashSet<N::PeerId>,
-    authentication_peer_senders: HashMap<N::PeerId, mpsc::Sender<AD>>,
-    block_sync_connected_peers: HashSet<N::PeerId>,
-    block_sync_peer_senders: HashMap<N::PeerId, mpsc::Sender<BSD>>,
-    spawn_handle: SpawnHandle,
-    metrics: Metrics,
-    timestamp_of_last_log_that_channel_is_full: HashMap<(N::PeerId, Protocol), Instant>,
+    authentication: ProtocolState<N, AD>,
+    block_sync: ProtocolState<N, BSD>,
     network_event_stream: ES,
+    shutdown_grace_period: Duration,
+    status_report_interval: Duration,
+    status_report_jitter: Duration,
+    quiet_status_reports: Option<QuietStatusConfig>,
+    adaptive_status_reports: Option<AdaptiveStatusConfig>,
+    /// The tracked part of the previous full status report and when the last report was logged.
+    last_status_report: Option<(String, time::Instant)>,
+    stale_peer_threshold: Duration,
+    queries_from_handles: mpsc::UnboundedReceiver<Query<N::PeerId, N::Address>>,
+    queries_for_service: mpsc::UnboundedSender<Query<N::PeerId, N::Address>>,
+    connection_event_sinks: Vec<mpsc::UnboundedSender<ConnectionEvent<N::PeerId>>>,
+    /// Our own id, events claiming we connected to or messaged ourselves are ignored.
+    local_peer_id: Option<N::PeerId>,
+    /// Short names of peers for logs, shared with both protocols.
+    peer_names: PeerNames<N::PeerId>,
+    /// Told why the service stopped, once it does.
+    termination_listeners: Vec<oneshot::Sender<TerminationReason>>,
+    /// Peers refused on all the protocols, disconnected whenever they connect until unblocked.
+    blocklist: HashSet<N::PeerId>,
+    /// Flushes requested by handles, answered once nothing is queued or their deadline passes.
+    pending_flushes: Vec<(time::Instant, oneshot::Sender<Result<(), Error>>)>,
+    /// When the pending flushes were last checked, peer senders do not tell us when they are done.
+    flushes_checked_at: time::Instant,
+}
+
+enum Query<P, A> {
+    ConnectedPeers(Protocol, oneshot::Sender<Vec<P>>),
+    IsConnected(Protocol, P, oneshot::Sender<bool>),
+    ConnectedPeerCounts(oneshot::Sender<HashMap<Protocol, usize>>),
+    LastSeen(Protocol, oneshot::Sender<HashMap<P, Instant>>),
+    BestPeer(Protocol, oneshot::Sender<Option<P>>),
+    SetAllowlist(Protocol, Option<HashSet<P>>),
+    SetBlocklist(HashSet<P>),
+    SetAuthorities(HashSet<P>),
+    SetPeerWeight(Protocol, P, f64),
+    SetPaused(Protocol, bool),
+    SetQueueCapacity(Protocol, usize),
+    Disconnect(P),
+    Dial(P, A),
+    Snapshot(oneshot::Sender<StateSnapshot>),
+    Flush(Duration, oneshot::Sender<Result<(), Error>>),
+}
+
+/// A handle for querying and adjusting the state of a running service.
+#[derive(Clone)]
+pub struct ServiceHandle<P, A> {
+    queries_for_service: mpsc::UnboundedSender<Query<P, A>>,
+}
+
+impl<P, A> ServiceHandle<P, A> {
+    async fn query<T>(
+        &self,
+        query: impl FnOnce(oneshot::Sender<T>) -> Query<P, A>,
+    ) -> Result<T, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.queries_for_service
+            .unbounded_send(query(tx))
+            .map_err(|_| Error::ServiceStopped)?;
+        rx.await.map_err(|_| Error::ServiceStopped)
+    }
+
+    /// Returns the peers currently connected on the given protocol.
+    pub async fn connected_peers(&self, protocol: Protocol) -> Result<Vec<P>, Error> {
+        self.query(|tx| Query::ConnectedPeers(protocol, tx)).await
+    }
+
+    /// Returns whether the peer is currently connected on the given protocol.
+    pub async fn is_connected(&self, peer: P, protocol: Protocol) -> Result<bool, Error> {
+        self.query(|tx| Query::IsConnected(protocol, peer, tx))
+            .await
+    }
+
+    /// Returns the number of connected peers for every protocol.
+    pub async fn connected_peer_counts(&self) -> Result<HashMap<Protocol, usize>, Error> {
+        self.query(Query::ConnectedPeerCounts).await
+    }
+
+    /// Returns when each peer connected on the given protocol last sent us a message.
+    pub async fn last_seen(&self, protocol: Protocol) -> Result<HashMap<P, Instant>, Error> {
+        self.query(|tx| Query::LastSeen(protocol, tx)).await
+    }
+
+    /// Returns the connected peer with the best connection quality, see `Service::best_peer`.
+    pub async fn best_peer(&self, protocol: Protocol) -> Result<Option<P>, Error> {
+        self.query(|tx| Query::BestPeer(protocol, tx)).await
+    }
+
+    /// Returns the state of the service, see `Service::snapshot`.
+    pub async fn snapshot(&self) -> Result<StateSnapshot, Error> {
+        self.query(Query::Snapshot).await
+    }
+
+    /// Waits until all the messages sent so far are handed over to the network or dropped, for at
+    /// most the given time. Fails with the number of messages still waiting when the timeout
+    /// elapses.
+    pub async fn flush(&self, timeout: Duration) -> Result<(), Error> {
+        self.query(|tx| Query::Flush(timeout, tx)).await?
+    }
+
+    /// Restricts the peers allowed on the given protocol, see `Service::set_allowlist`.
+    pub fn set_allowlist(
+        &self,
+        protocol: Protocol,
+        allowlist: Option<HashSet<P>>,
+    ) -> Result<(), Error> {
+        self.queries_for_service
+            .unbounded_send(Query::SetAllowlist(protocol, allowlist))
+            .map_err(|_| Error::ServiceStopped)
+    }
+
+    /// Replaces the peers refused on all the protocols, see `Service::set_blocklist`.
+    pub fn set_blocklist(&self, blocklist: HashSet<P>) -> Result<(), Error> {
+        self.queries_for_service
+            .unbounded_send(Query::SetBlocklist(blocklist))
+            .map_err(|_| Error::ServiceStopped)
+    }
+
+    /// Replaces the peers in the current authority set, see `Service::set_authorities`.
+    pub fn set_authorities(&self, authorities: HashSet<P>) -> Result<(), Error> {
+        self.queries_for_service
+            .unbounded_send(Query::SetAuthorities(authorities))
+            .map_err(|_| Error::ServiceStopped)
+    }
+
+    /// Sets the fraction of broadcasts the peer gets, see `Service::set_peer_weight`.
+    pub fn set_peer_weight(
+        &self,
+        protocol: Protocol,
+        peer_id: P,
+        weight: f64,
+    ) -> Result<(), Error> {
+        self.queries_for_service
+            .unbounded_send(Query::SetPeerWeight(protocol, peer_id, weight))
+            .map_err(|_| Error::ServiceStopped)
+    }
+
+    /// Cuts the peer off, see `Service::disconnect_peer`.
+    pub fn disconnect_peer(&self, peer_id: P) -> Result<(), Error> {
+        self.queries_for_service
+            .unbounded_send(Query::Disconnect(peer_id))
+            .map_err(|_| Error::ServiceStopped)
+    }
+
+    /// Connects to the peer at the given address, see `Service::dial`.
+    pub fn dial(&self, peer_id: P, address: A) -> Result<(), Error> {
+        self.queries_for_service
+            .unbounded_send(Query::Dial(peer_id, address))
+            .map_err(|_| Error::ServiceStopped)
+    }
+
+    /// Stops the traffic on the given protocol, see `Service::pause_protocol`.
+    pub fn pause_protocol(&self, protocol: Protocol) -> Result<(), Error> {
+        self.queries_for_service
+            .unbounded_send(Query::SetPaused(protocol, true))
+            .map_err(|_| Error::ServiceStopped)
+    }
+
+    /// Restarts the traffic on the given protocol, see `Service::resume_protocol`.
+    pub fn resume_protocol(&self, protocol: Protocol) -> Result<(), Error> {
+        self.queries_for_service
+            .unbounded_send(Query::SetPaused(protocol, false))
+            .map_err(|_| Error::ServiceStopped)
+    }
+
+    /// Changes the capacity of the peer queues, see `Service::set_queue_capacity`.
+    pub fn set_queue_capacity(&self, protocol: Protocol, capacity: usize) -> Result<(), Error> {
+        self.queries_for_service
+            .unbounded_send(Query::SetQueueCapacity(protocol, capacity))
+            .map_err(|_| Error::ServiceStopped)
+    }
+}
+
+/// A handle for broadcasting on every protocol of a running service, see
+/// `Service::broadcast_all_handle`.
+#[derive(Clone)]
+pub struct BroadcastAllHandle<D: Data, P: Clone + Debug + Eq + Hash + Send + 'static> {
+    authentication: CommandSender<D, P>,
+    block_sync: CommandSender<D, P>,
+}
+
+impl<D: Data, P: Clone + Debug + Eq + Hash + Send + 'static> BroadcastAllHandle<D, P> {
+    /// Broadcasts the data on every protocol. The results are published to the broadcast
+    /// feedback channel like any other. Fails if the data could not be handed over to the service
+    /// for any of the protocols, with the same errors as `Network::broadcast`.
+    pub fn broadcast_all(&mut self, data: D) -> Result<(), Error> {
+        let authentication = self
+            .authentication
+            .broadcast(data.clone(), Priority::Normal, None);
+        let block_sync = self.block_sync.broadcast(data, Priority::Normal, None);
+        authentication.and(block_sync)
+    }
+}
+
+/// Hands the commands of a protocol over to the service, refusing the ones the protocol
+/// configuration does not allow.
+#[derive(Clone)]
+struct CommandSender<D: Data, P: Clone + Debug + Eq + Hash + Send + 'static> {
+    commands: mpsc::Sender<Command<D, P>>,
+    /// The number of connected peers, present if broadcasts without peers are refused.
+    connected_peer_count: Option<Arc<AtomicUsize>>,
+    direction: Direction,
 }
 
 struct ServiceInterface<D: Data, P: Clone + Debug + Eq + Hash + Send + 'static> {
-    messages_from_service: mpsc::UnboundedReceiver<(D, P)>,
-    messages_for_service: mpsc::UnboundedSender<Command<D, P>>,
+    messages_from_service: mpsc::UnboundedReceiver<(D, P, Option<Delivery>)>,
+    commands: CommandSender<D, P>,
 }
 
 /// What can go wrong when receiving or sending data.
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Error {
+    /// The service is gone, no more data can be sent or received.
     ServiceStopped,
+    /// The service cannot keep up with the data it is given, sending can be retried later.
+    Congested,
+    /// The data was dropped before it could be handed over to the network.
+    SendFailed,
+    /// Flushing timed out with the given number of messages still waiting to be sent.
+    FlushTimedOut(usize),
+    /// No peers are connected, so a broadcast would not reach anyone. It was not made.
+    NoPeers,
+    /// The protocol only receives data, nothing can be sent on it.
+    ReceiveOnly,
 }
 
 impl Display for Error {
@@ -74,16 +447,67 @@ impl Display for Error {
             ServiceStopped => {
                 write!(f, "gossip network service stopped")
             }
+            Congested => {
+                write!(f, "gossip network service is congested")
+            }
+            SendFailed => {
+                write!(f, "gossip network service failed to send data")
+            }
+            FlushTimedOut(pending) => {
+                write!(
+                    f,
+                    "gossip network service timed out flushing, {pending} messages are pending"
+                )
+            }
+            NoPeers => {
+                write!(f, "gossip network service has no peers to broadcast to")
+            }
+            ReceiveOnly => {
+                write!(f, "gossip network protocol is receive-only")
+            }
         }
     }
 }
 
-#[derive(Debug)]
+impl std::error::Error for Error {}
+
+fn command_send_error<T>(e: mpsc::TrySendError<T>) -> Error {
+    if e.is_full() {
+        Error::Congested
+    } else {
+        Error::ServiceStopped
+    }
+}
+
+/// Stops a service started with `Service::spawn`.
+pub struct StopHandle {
+    exit: oneshot::Sender<()>,
+    stopped: oneshot::Receiver<Result<(), GossipServiceError>>,
+}
+
+impl StopHandle {
+    /// Tells the service to exit, which gives it the shutdown grace period for handling the
+    /// commands already sent by users, and waits until it stops. Returns the error the service
+    /// stopped with, if it stopped on its own before.
+    pub async fn stop(self) -> Result<(), GossipServiceError> {
+        // The service might have stopped already, then its result is waiting.
+        let _ = self.exit.send(());
+        self.stopped
+            .await
+            .unwrap_or(Err(GossipServiceError::ServiceTaskDropped))
+    }
+}
+
+/// Why the service stopped running, other than being told to exit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum GossipServiceError {
     NetworkStreamTerminated,
     AuthorizationStreamTerminated,
     BlockSyncStreamTerminated,
     UnableToForwardMessageToUser,
+    /// The task running the service was dropped before the service stopped.
+    ServiceTaskDropped,
 }
 
 impl fmt::Display for GossipServiceError {
@@ -99,8 +523,51 @@ impl fmt::Display for GossipServiceError {
             GossipServiceError::UnableToForwardMessageToUser => {
                 write!(f, "Cannot forward messages to user.")
             }
+            GossipServiceError::ServiceTaskDropped => {
+                write!(f, "Task running the service was dropped.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for GossipServiceError {}
+
+/// Why the service stopped running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TerminationReason {
+    /// The service was told to exit.
+    Exited,
+    /// The service stopped on its own.
+    Failed(GossipServiceError),
+}
+
+impl<D: Data, P: Clone + Debug + Eq + Hash + Send + 'static> CommandSender<D, P> {
+    /// Fails if broadcasts without peers are refused and no peers are connected.
+    fn check_peers(&self) -> Result<(), Error> {
+        match &self.connected_peer_count {
+            Some(count) if count.load(Ordering::Relaxed) == 0 => Err(Error::NoPeers),
+            _ => Ok(()),
         }
     }
+
+    /// Hands the command over to the service, unless the protocol is receive-only.
+    fn send(&mut self, command: Command<D, P>) -> Result<(), Error> {
+        if self.direction == Direction::ReceiveOnly {
+            return Err(Error::ReceiveOnly);
+        }
+        self.commands.try_send(command).map_err(command_send_error)
+    }
+
+    /// Hands a broadcast over to the service, unless there are no peers to broadcast to.
+    fn broadcast(
+        &mut self,
+        data: D,
+        priority: Priority,
+        deadline: Option<time::Instant>,
+    ) -> Result<(), Error> {
+        self.check_peers()?;
+        self.send(Command::Broadcast(data, priority, deadline))
+    }
 }
 
 #[async_trait::async_trait]
@@ -109,9 +576,45 @@ impl<D: Data, P: Clone + Debug + Eq + Hash + Send + 'static> Network<D> for Serv
     type PeerId = P;
 
     fn send_to(&mut self, data: D, peer_id: Self::PeerId) -> Result<(), Self::Error> {
-        self.messages_for_service
-            .unbounded_send(Command::Send(data, peer_id))
-            .map_err(|_| Error::ServiceStopped)
+        self.send_to_with_priority(data, peer_id, Priority::Normal)
+    }
+
+    fn send_to_with_priority(
+        &mut self,
+        data: D,
+        peer_id: Self::PeerId,
+        priority: Priority,
+    ) -> Result<(), Self::Error> {
+        self.commands
+            .send(Command::Send(data, peer_id, priority, None))
+    }
+
+    fn send_to_with_ttl(
+        &mut self,
+        data: D,
+        peer_id: Self::PeerId,
+        ttl: Duration,
+    ) -> Result<(), Self::Error> {
+        self.commands.send(Command::Send(
+            data,
+            peer_id,
+            Priority::Normal,
+            Some(time::Instant::now() + ttl),
+        ))
+    }
+
+    fn send_to_confirmed(
+        &mut self,
+        data: D,
+        peer_id: Self::PeerId,
+    ) -> Result<Confirmation<Self::Error>, Self::Error> {
+        let (tx, rx) = oneshot::channel();
+        self.commands.send(Command::SendConfirmed(
+            data,
+            peer_id,
+            PendingConfirmation::new(tx),
+        ))?;
+        Ok(rx)
     }
 
     fn send_to_random(
@@ -119,18 +622,81 @@ impl<D: Data, P: Clone + Debug + Eq + Hash + Send + 'static> Network<D> for Serv
         data: D,
         peer_ids: HashSet<Self::PeerId>,
     ) -> Result<(), Self::Error> {
-        self.messages_for_service
-            .unbounded_send(Command::SendToRandom(data, peer_ids))
-            .map_err(|_| Error::ServiceStopped)
+        self.commands.send(Command::SendToRandom(data, peer_ids))
+    }
+
+    fn send_to_best(&mut self, data: D) -> Result<(), Self::Error> {
+        self.commands.send(Command::SendToBest(data))
+    }
+
+    fn send_to_peers(
+        &mut self,
+        data: D,
+        peer_ids: HashSet<Self::PeerId>,
+    ) -> Result<(), Self::Error> {
+        self.commands.send(Command::SendToPeers(data, peer_ids))
     }
 
     fn broadcast(&mut self, data: D) -> Result<(), Self::Error> {
-        self.messages_for_service
-            .unbounded_send(Command::Broadcast(data))
-            .map_err(|_| Error::ServiceStopped)
+        self.broadcast_with_priority(data, Priority::Normal)
+    }
+
+    fn try_broadcast(&mut self, data: D) -> Result<(), Self::Error> {
+        // Commands never wait for space in the channel, a full one is reported as congestion.
+        self.broadcast_with_priority(data, Priority::Normal)
+    }
+
+    fn broadcast_with_priority(&mut self, data: D, priority: Priority) -> Result<(), Self::Error> {
+        self.commands.broadcast(data, priority, None)
+    }
+
+    fn broadcast_with_ttl(&mut self, data: D, ttl: Duration) -> Result<(), Self::Error> {
+        self.commands
+            .broadcast(data, Priority::Normal, Some(time::Instant::now() + ttl))
+    }
+
+    fn rebroadcast(
+        &mut self,
+        data: D,
+        schedule: RebroadcastConfig,
+    ) -> Result<RebroadcastHandle, Self::Error> {
+        let handle = RebroadcastHandle::default();
+        self.commands
+            .send(Command::Rebroadcast(data, schedule, handle.clone()))?;
+        Ok(handle)
+    }
+
+    fn broadcast_barrier(
+        &mut self,
+        data: D,
+    ) -> Result<Barrier<Self::PeerId, Self::Error>, Self::Error> {
+        let (tx, rx) = oneshot::channel();
+        self.commands.send(Command::BroadcastBarrier(data, tx))?;
+        Ok(async move {
+            // A service that stopped before handling the broadcast did not send to anyone.
+            let confirmations = rx.await.unwrap_or_default();
+            future::join_all(
+                confirmations
+                    .into_iter()
+                    .map(|(peer, confirmation)| async move {
+                        (peer, confirmation.await.unwrap_or(Err(Error::SendFailed)))
+                    }),
+            )
+            .await
+            .into_iter()
+            .collect()
+        }
+        .boxed())
     }
 
     async fn next(&mut self) -> Result<(D, Self::PeerId), Self::Error> {
+        let (data, peer_id, _) = self.next_with_delivery().await?;
+        Ok((data, peer_id))
+    }
+
+    async fn next_with_delivery(
+        &mut self,
+    ) -> Result<(D, Self::PeerId, Option<Delivery>), Self::Error> {
         self.messages_from_service
             .next()
             .await
@@ -138,824 +704,9493 @@ impl<D: Data, P: Clone + Debug + Eq + Hash + Send + 'static> Network<D> for Serv
     }
 }
 
-#[derive(Debug)]
-enum SendError {
-    MissingSender,
-    SendingFailed,
+/// The outcome of a stream to a peer being opened.
+#[derive(Debug, PartialEq, Eq)]
+enum Opening<P> {
+    /// The peer connected, possibly evicting another one.
+    Connected { evicted: Option<P> },
+    /// The peer was already connected, its sender got replaced.
+    Reopened,
+    /// The peer limit is reached, so the peer was not connected.
+    Rejected,
+    /// The peer is not on the allowlist, so it was not connected.
+    NotAllowed,
+    /// The stream of the peer closed recently and got reopened within the grace period, so its
+    /// sender was kept.
+    Resumed,
 }
 
-impl<N: RawNetwork, ES: EventStream<N::PeerId>, AD: Data, BSD: Data> Service<N, ES, AD, BSD> {
-    pub fn new(
-        network: N,
-        network_event_stream: ES,
-        spawn_handle: SpawnHandle,
-        metrics_registry: Option<Registry>,
-    ) -> (
-        Self,
-        impl Network<AD, Error = Error, PeerId = N::PeerId>,
-        impl Network<BSD, Error = Error, PeerId = N::PeerId>,
-    ) {
-        let (messages_for_authentication_user, messages_from_authentication_service) =
-            mpsc::unbounded();
-        let (messages_for_block_sync_user, messages_from_block_sync_service) = mpsc::unbounded();
-        let (messages_for_authentication_service, messages_from_authentication_user) =
-            mpsc::unbounded();
-        let (messages_for_block_sync_service, messages_from_block_sync_user) = mpsc::unbounded();
-        let metrics = match Metrics::new(metrics_registry) {
-            Ok(metrics) => metrics,
-            Err(e) => {
-                warn!(target: LOG_TARGET, "Failed to create metrics: {e}.");
-                Metrics::noop()
-            }
-        };
-        (
-            Service {
-                network,
-                messages_from_authentication_user,
-                messages_from_block_sync_user,
-                messages_for_authentication_user,
-                messages_for_block_sync_user,
-                spawn_handle,
-                metrics,
-                authentication_connected_peers: HashSet::new(),
-                authentication_peer_senders: HashMap::new(),
-                block_sync_connected_peers: HashSet::new(),
-                block_sync_peer_senders: HashMap::new(),
-                timestamp_of_last_log_that_channel_is_full: HashMap::new(),
-                network_event_stream,
-            },
-            ServiceInterface {
-                messages_from_service: messages_from_authentication_service,
-                messages_for_service: messages_for_authentication_service,
-            },
-            ServiceInterface {
-                messages_from_service: messages_from_block_sync_service,
-                messages_for_service: messages_for_block_sync_service,
-            },
-        )
-    }
+/// Completes the confirmation of data sent with `send_to_confirmed`. If dropped before
+/// `confirm` is called, e.g. because the data got dropped from a queue, the send is reported as
+/// failed.
+struct PendingConfirmation(Option<oneshot::Sender<Result<(), Error>>>);
 
-    fn get_authentication_sender(&mut self, peer: &N::PeerId) -> Option<&mut mpsc::Sender<AD>> {
-        self.authentication_peer_senders.get_mut(peer)
+impl PendingConfirmation {
+    fn new(sender: oneshot::Sender<Result<(), Error>>) -> Self {
+        PendingConfirmation(Some(sender))
     }
 
-    fn get_block_sync_sender(&mut self, peer: &N::PeerId) -> Option<&mut mpsc::Sender<BSD>> {
-        self.block_sync_peer_senders.get_mut(peer)
+    /// Reports that the data was handed over to the network.
+    fn confirm(mut self) {
+        if let Some(sender) = self.0.take() {
+            // The user might not be interested in the outcome anymore.
+            let _ = sender.send(Ok(()));
+        }
     }
+}
 
-    fn peer_sender<D: Data>(
-        &self,
-        peer_id: N::PeerId,
-        mut receiver: mpsc::Receiver<D>,
-        protocol: Protocol,
-    ) -> impl Future<Output = ()> + Send + 'static {
-        let network = self.network.clone();
-        let metrics = self.metrics.clone();
-        async move {
-            let mut sender = None;
-            loop {
-                if let Some(data) = receiver.next().await {
-                    metrics.report_message_popped_from_peer_sender_queue(protocol);
-                    let s = if let Some(s) = sender.as_mut() {
-                        s
-                    } else {
-                        match network.sender(peer_id.clone(), protocol) {
-                            Ok(s) => sender.insert(s),
-                            Err(e) => {
-                                debug!(
-                                    target: LOG_TARGET,
-                                    "Failed creating sender. Dropping message: {}", e
-                                );
-                                continue;
-                            }
-                        }
-                    };
-                    let maybe_timer = metrics.start_sending_in(protocol);
-                    if let Err(e) = s.send(data.encode()).await {
-                        debug!(
-                            target: LOG_TARGET,
-                            "Failed sending data to peer. Dropping sender and message: {}", e
-                        );
-                        sender = None;
-                    }
-                    if let Some(timer) = maybe_timer {
-                        timer.observe_duration();
-                    }
-                } else {
-                    debug!(
-                        target: LOG_TARGET,
-                        "Sender was dropped for peer {:?}. Peer sender exiting.", peer_id
-                    );
-                    return;
-                }
-            }
+impl Drop for PendingConfirmation {
+    fn drop(&mut self) {
+        if let Some(sender) = self.0.take() {
+            let _ = sender.send(Err(Error::SendFailed));
         }
     }
+}
 
-    fn possibly_log_that_channel_is_full(&mut self, peer: N::PeerId, protocol: Protocol) {
-        let peer_and_protocol = (peer, protocol);
-        if self
-            .timestamp_of_last_log_that_channel_is_full
-            .get(&peer_and_protocol)
-            .map(|t| t.elapsed() >= time::Duration::from_secs(1))
-            .unwrap_or(true)
-        {
-            debug!(
-                target: LOG_TARGET,
-                "Failed sending data in {:?} protocol to peer {:?}, because peer_sender receiver is full",
-                protocol,
-                peer_and_protocol.0
-            );
-            self.timestamp_of_last_log_that_channel_is_full
-                .insert(peer_and_protocol, Instant::now());
+/// Data waiting in the queue of a peer. It is already encoded, so that a broadcast is encoded
+/// once and the encoding is shared by the queues of all the peers.
+struct Queued {
+    encoded: Bytes,
+    /// Present if the sender of the data waits for a confirmation that it was sent.
+    confirmation: Option<PendingConfirmation>,
+    /// After this the data is stale and gets dropped instead of sent.
+    deadline: Option<time::Instant>,
+    /// When the data got queued, `None` when metrics are disabled.
+    queued_at: Option<time::Instant>,
+    /// Taken from the global budget of in-flight messages, released once the data is sent or
+    /// dropped.
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+type QueuedParts = (
+    Bytes,
+    Option<PendingConfirmation>,
+    Option<time::Instant>,
+    Option<OwnedSemaphorePermit>,
+);
+
+impl Queued {
+    fn new(encoded: Bytes, deadline: Option<time::Instant>) -> Self {
+        Queued {
+            encoded,
+            confirmation: None,
+            deadline,
+            queued_at: None,
+            permit: None,
         }
     }
 
-    fn send_to_authentication_peer(&mut self, data: AD, peer: N::PeerId) -> Result<(), SendError> {
-        match self.get_authentication_sender(&peer) {
-            Some(sender) => {
-                match sender.try_send(data) {
-                    Err(e) => {
-                        if e.is_full() {
-                            self.possibly_log_that_channel_is_full(
-                                peer.clone(),
-                                Protocol::Authentication,
-                            );
-                        }
-                        // Receiver can also be dropped when thread cannot send to peer. In case receiver is dropped this entry will be removed by Event::NotificationStreamClosed
-                        // No need to remove the entry here
-                        if e.is_disconnected() {
-                            trace!(target: LOG_TARGET, "Failed sending data to peer because peer_sender receiver is dropped: {:?}", peer);
-                        }
-                        Err(SendError::SendingFailed)
-                    }
-                    Ok(_) => {
-                        self.metrics
-                            .report_message_pushed_to_peer_sender_queue(Protocol::Authentication);
-                        Ok(())
-                    }
-                }
-            }
-            None => Err(SendError::MissingSender),
+    fn confirmed(encoded: Bytes, confirmation: PendingConfirmation) -> Self {
+        Queued {
+            encoded,
+            confirmation: Some(confirmation),
+            deadline: None,
+            queued_at: None,
+            permit: None,
         }
     }
 
-    fn send_to_block_sync_peer(&mut self, data: BSD, peer: N::PeerId) -> Result<(), SendError> {
-        match self.get_block_sync_sender(&peer) {
-            Some(sender) => {
-                match sender.try_send(data) {
-                    Err(e) => {
-                        if e.is_full() {
-                            self.possibly_log_that_channel_is_full(
-                                peer.clone(),
-                                Protocol::BlockSync,
-                            );
-                        }
-                        // Receiver can also be dropped when thread cannot send to peer. In case receiver is dropped this entry will be removed by Event::NotificationStreamClosed
-                        // No need to remove the entry here
-                        if e.is_disconnected() {
-                            trace!(target: LOG_TARGET, "Failed sending data to peer because peer_sender receiver is dropped: {:?}", peer);
-                        }
-                        Err(SendError::SendingFailed)
-                    }
-                    Ok(_) => {
-                        self.metrics
-                            .report_message_pushed_to_peer_sender_queue(Protocol::BlockSync);
-                        Ok(())
-                    }
-                }
-            }
-            None => Err(SendError::MissingSender),
-        }
+    fn is_expired(&self) -> bool {
+        matches!(self.deadline, Some(deadline) if deadline <= time::Instant::now())
     }
 
-    fn send_authentication_data(&mut self, data: AD, peer_id: N::PeerId) {
-        trace!(
-            target: LOG_TARGET,
-            "Sending authentication data to peer {:?}.",
-            peer_id,
-        );
-        if let Err(e) = self.send_to_authentication_peer(data, peer_id.clone()) {
-            debug!(
-                target: LOG_TARGET,
-                "Failed to send to peer{:?}, {:?}", peer_id, e
-            );
-        }
+    fn into_parts(self) -> QueuedParts {
+        (self.encoded, self.confirmation, self.queued_at, self.permit)
     }
+}
 
-    fn send_block_sync_data(&mut self, data: BSD, peer_id: N::PeerId) {
-        trace!(
-            target: LOG_TARGET,
-            "Sending block sync data to peer {:?}.",
-            peer_id,
-        );
-        if let Err(e) = self.send_to_block_sync_peer(data, peer_id.clone()) {
-            debug!(
-                target: LOG_TARGET,
-                "Failed to send to peer{:?}, {:?}", peer_id, e
-            );
-        }
-    }
+/// Data broadcast repeatedly with growing intervals.
+struct Rebroadcast<D> {
+    data: D,
+    next_at: time::Instant,
+    interval: Duration,
+    max_interval: Duration,
+    until: time::Instant,
+    handle: RebroadcastHandle,
+}
 
-    fn protocol_peers(&self, protocol: Protocol) -> &HashSet<N::PeerId> {
-        match protocol {
-            Protocol::Authentication => &self.authentication_connected_peers,
-            Protocol::BlockSync => &self.block_sync_connected_peers,
+/// Whether a protocol has any connected peers, as published to the subscribers of the service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Connectivity {
+    /// There are connected peers, or there were none to begin with.
+    Available,
+    /// The last peer disconnected, losing connectivity gets published at the given time unless a
+    /// peer connects before.
+    LosingAt(time::Instant),
+    Lost,
+}
+
+/// An exponentially weighted moving average of how long sending to a peer takes, updated by its
+/// peer sender.
+#[derive(Clone)]
+struct SendLatency {
+    smoothing: f64,
+    average: Arc<Mutex<Option<Duration>>>,
+}
+
+impl SendLatency {
+    fn new(smoothing: f64) -> Self {
+        SendLatency {
+            smoothing: smoothing.clamp(0.0, 1.0),
+            average: Arc::new(Mutex::new(None)),
         }
     }
 
-    fn random_peer<'a>(
-        &'a self,
-        peer_ids: &'a HashSet<N::PeerId>,
-        protocol: Protocol,
-    ) -> Option<&'a N::PeerId> {
-        peer_ids
-            .intersection(self.protocol_peers(protocol))
-            .choose(&mut thread_rng())
-            .or_else(|| {
-                self.protocol_peers(protocol)
-                    .iter()
-                    .choose(&mut thread_rng())
-            })
+    fn record(&self, sample: Duration) {
+        let mut average = self.average.lock();
+        *average = Some(match *average {
+            Some(average) => average.mul_f64(1.0 - self.smoothing) + sample.mul_f64(self.smoothing),
+            None => sample,
+        });
     }
 
-    fn send_to_random_authentication(&mut self, data: AD, peer_ids: HashSet<N::PeerId>) {
-        trace!(
-            target: LOG_TARGET,
-            "Sending authentication data to random peer among {:?}.",
-            peer_ids,
-        );
-        let peer_id = match self.random_peer(&peer_ids, Protocol::Authentication) {
-            Some(peer_id) => peer_id.clone(),
-            None => {
-                debug!(
-                    target: LOG_TARGET,
-                    "Failed to send authentication message to random peer, no peers are available."
-                );
-                return;
-            }
-        };
-        self.send_authentication_data(data, peer_id);
+    /// The average, `None` until the first send.
+    fn average(&self) -> Option<Duration> {
+        *self.average.lock()
     }
+}
 
-    fn send_to_random_block_sync(&mut self, data: BSD, peer_ids: HashSet<N::PeerId>) {
-        trace!(
-            target: LOG_TARGET,
-            "Sending block sync data to random peer among {:?}.",
-            peer_ids,
-        );
-        let peer_id = match self.random_peer(&peer_ids, Protocol::BlockSync) {
-            Some(peer_id) => peer_id.clone(),
-            None => {
-                debug!(
-                    target: LOG_TARGET,
-                    "Failed to send block sync message to random peer, no peers are available."
-                );
-                return;
-            }
-        };
-        self.send_block_sync_data(data, peer_id);
-    }
+/// Tracks whether the peer sender of a peer is running, so that peers left with a sender but no
+/// task handling its queue get noticed.
+struct Liveness {
+    spawned_at: time::Instant,
+    started: Arc<AtomicBool>,
+    exited: Arc<AtomicBool>,
+}
 
-    fn broadcast_authentication(&mut self, data: AD) {
-        let peers = self.protocol_peers(Protocol::Authentication).clone();
-        for peer in peers {
-            self.send_authentication_data(data.clone(), peer);
-        }
-    }
+/// Owned by a peer sender, marks it as exited when dropped, also when it never got to run.
+struct LivenessGuard {
+    started: Arc<AtomicBool>,
+    exited: Arc<AtomicBool>,
+}
 
-    fn broadcast_block_sync(&mut self, data: BSD) {
-        let peers = self.protocol_peers(Protocol::BlockSync).clone();
-        for peer in peers {
-            self.send_block_sync_data(data.clone(), peer);
-        }
+impl Liveness {
+    fn new() -> (Self, LivenessGuard) {
+        let started = Arc::new(AtomicBool::new(false));
+        let exited = Arc::new(AtomicBool::new(false));
+        (
+            Liveness {
+                spawned_at: time::Instant::now(),
+                started: started.clone(),
+                exited: exited.clone(),
+            },
+            LivenessGuard { started, exited },
+        )
     }
 
-    fn handle_network_event(&mut self, event: Event<N::PeerId>) -> Result<(), ()> {
-        use Event::*;
-        match event {
-            StreamOpened(peer, protocol) => {
-                trace!(
-                    target: LOG_TARGET,
-                    "StreamOpened event for peer {:?} and the protocol {:?}.",
-                    peer,
-                    protocol
-                );
-                match protocol {
-                    Protocol::Authentication => {
-                        let (tx, rx) = mpsc::channel(MAX_QUEUE_SIZE);
-                        self.authentication_connected_peers.insert(peer.clone());
-                        self.authentication_peer_senders.insert(peer.clone(), tx);
-                        self.spawn_handle.spawn(
-                            "aleph/network/authentication_peer_sender",
-                            self.peer_sender(peer, rx, Protocol::Authentication),
-                        );
-                    }
-                    Protocol::BlockSync => {
-                        let (tx, rx) = mpsc::channel(MAX_QUEUE_SIZE);
-                        self.block_sync_connected_peers.insert(peer.clone());
-                        self.block_sync_peer_senders.insert(peer.clone(), tx);
-                        self.spawn_handle.spawn(
-                            "aleph/network/sync_peer_sender",
-                            self.peer_sender(peer, rx, Protocol::BlockSync),
-                        );
-                    }
-                };
-            }
-            StreamClosed(peer, protocol) => {
-                trace!(
-                    target: LOG_TARGET,
-                    "StreamClosed event for peer {:?} and protocol {:?}",
-                    peer,
-                    protocol
-                );
-                match protocol {
-                    Protocol::Authentication => {
-                        self.authentication_connected_peers.remove(&peer);
-                        self.authentication_peer_senders.remove(&peer);
-                    }
-                    Protocol::BlockSync => {
-                        self.block_sync_connected_peers.remove(&peer);
-                        self.block_sync_peer_senders.remove(&peer);
-                    }
-                }
-            }
-            Messages(peer_id, messages) => {
-                for (protocol, data) in messages.into_iter() {
-                    match protocol {
-                        Protocol::Authentication => match AD::decode(&mut &data[..]) {
-                            Ok(data) => self
-                                .messages_for_authentication_user
-                                .unbounded_send((data, peer_id.clone()))
-                                .map_err(|_| ())?,
-                            Err(e) => {
-                                warn!(
-                                    target: LOG_TARGET,
-                                    "Error decoding authentication protocol message: {}", e
-                                )
-                            }
-                        },
-                        Protocol::BlockSync => match BSD::decode(&mut &data[..]) {
-                            Ok(data) => self
-                                .messages_for_block_sync_user
-                                .unbounded_send((data, peer_id.clone()))
-                                .map_err(|_| ())?,
-                            Err(e) => {
-                                warn!(
-                                    target: LOG_TARGET,
-                                    "Error decoding block sync protocol message: {}", e
-                                )
-                            }
-                        },
-                    };
-                }
-            }
-        }
-        Ok(())
+    /// Whether the peer sender exited, or did not start within the timeout.
+    fn is_dead(&self) -> bool {
+        self.exited.load(Ordering::Relaxed)
+            || (!self.started.load(Ordering::Relaxed)
+                && self.spawned_at.elapsed() >= PEER_SENDER_START_TIMEOUT)
     }
+}
 
-    fn status_report(&self) {
-        let mut status = String::from("Network status report: ");
-
-        status.push_str(&format!(
-            "authentication connected peers - {:?}; ",
-            self.authentication_connected_peers.len()
-        ));
-        status.push_str(&format!(
-            "block sync connected peers - {:?}; ",
-            self.block_sync_connected_peers.len()
-        ));
+impl LivenessGuard {
+    fn start(&self) {
+        self.started.store(true, Ordering::Relaxed);
+    }
+}
 
-        info!(target: LOG_TARGET, "{}", status);
+impl Drop for LivenessGuard {
+    fn drop(&mut self) {
+        self.exited.store(true, Ordering::Relaxed);
     }
+}
 
-    pub async fn run(mut self) -> Result<(), GossipServiceError> {
-        use GossipServiceError as Error;
+/// Counts a running peer sender until dropped, so that peer senders exiting in any way, also by
+/// getting aborted, stop being counted.
+struct ActivePeerSender {
+    count: Arc<AtomicUsize>,
+    metrics: Metrics,
+    protocol: Protocol,
+}
 
-        let mut status_ticker = time::interval(STATUS_REPORT_INTERVAL);
-        loop {
-            tokio::select! {
-                maybe_event = self.network_event_stream.next_event() => {
-                    let event = maybe_event.ok_or(Error::NetworkStreamTerminated)?;
-                    self.handle_network_event(event).map_err(|_| Error::UnableToForwardMessageToUser)?;
-                },
-                maybe_message = self.messages_from_authentication_user.next() => {
-                    match maybe_message.ok_or(Error::AuthorizationStreamTerminated)? {
-                        Command::Broadcast(message) => self.broadcast_authentication(message),
-                        Command::SendToRandom(message, peer_ids) => self.send_to_random_authentication(message, peer_ids),
-                        Command::Send(message, peer_id) => self.send_authentication_data(message, peer_id),
-                    }
-                },
-                maybe_message = self.messages_from_block_sync_user.next() => {
-                    match maybe_message.ok_or(Error::BlockSyncStreamTerminated)? {
-                        Command::Broadcast(message) => self.broadcast_block_sync(message),
-                        Command::SendToRandom(message, peer_ids) => self.send_to_random_block_sync(message, peer_ids),
-                        Command::Send(message, peer_id) => self.send_block_sync_data(message, peer_id),
-                    }
-                },
-                _ = status_ticker.tick() => {
-                    self.status_report();
-                },
-            }
+impl ActivePeerSender {
+    fn start(count: Arc<AtomicUsize>, metrics: Metrics, protocol: Protocol) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        metrics.report_peer_sender_started(protocol);
+        ActivePeerSender {
+            count,
+            metrics,
+            protocol,
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{collections::HashSet, iter};
-
-    use futures::channel::oneshot;
-    use network_clique::mock::{random_peer_id, MockPublicKey};
-    use parity_scale_codec::Encode;
-    use sc_service::TaskManager;
-    use tokio::runtime::Handle;
+impl Drop for ActivePeerSender {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+        self.metrics.report_peer_sender_exited(self.protocol);
+    }
+}
 
-    use super::{Error, SendError, Service};
-    use crate::network::{
-        gossip::{
-            mock::{MockEvent, MockEventStream, MockRawNetwork, MockSenderError},
-            Network,
-        },
-        mock::MockData,
-        Protocol,
-    };
+/// Hands out turns to send to peer senders of both protocols, in the order they asked for them,
+/// while the budget of in-flight messages is nearly used up.
+#[derive(Clone)]
+struct DrainScheduler {
+    budget: Arc<Semaphore>,
+    turns: Arc<Semaphore>,
+    pressure_threshold: usize,
+}
 
-    const PROTOCOL: Protocol = Protocol::Authentication;
+impl DrainScheduler {
+    fn new(budget: Arc<Semaphore>, config: &FairDrainConfig) -> Self {
+        DrainScheduler {
+            budget,
+            turns: Arc::new(Semaphore::new(config.concurrent_sends.max(1))),
+            pressure_threshold: config.pressure_threshold,
+        }
+    }
 
-    pub struct TestData {
-        pub network: MockRawNetwork,
-        gossip_network: Box<dyn Network<MockData, Error = Error, PeerId = MockPublicKey>>,
-        pub service: Service<MockRawNetwork, MockEventStream, MockData, MockData>,
-        // `TaskManager` can't be dropped for `SpawnTaskHandle` to work
-        _task_manager: TaskManager,
-        // If we drop the sync network, the underlying network service dies, stopping the whole
-        // network.
-        _other_network: Box<dyn Network<MockData, Error = Error, PeerId = MockPublicKey>>,
+    /// Waits for a turn if the budget is under pressure, the turn lasts until the returned permit
+    /// is dropped.
+    async fn turn(&self) -> Option<OwnedSemaphorePermit> {
+        if self.budget.available_permits() >= self.pressure_threshold {
+            return None;
+        }
+        // The semaphore is never closed.
+        self.turns.clone().acquire_owned().await.ok()
     }
+}
 
-    impl TestData {
-        fn prepare() -> Self {
-            let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+/// An exponentially weighted moving average of how many messages per second pass through a
+/// protocol, updated with the messages counted since the previous update.
+struct MessageRate {
+    smoothing: f64,
+    count: u64,
+    since: time::Instant,
+    average: Option<f64>,
+}
 
-            let (event_stream_oneshot_tx, _event_stream_oneshot_rx) = oneshot::channel();
+impl MessageRate {
+    fn new(smoothing: f64) -> Self {
+        MessageRate {
+            smoothing: smoothing.clamp(0.0, 1.0),
+            count: 0,
+            since: time::Instant::now(),
+            average: None,
+        }
+    }
 
-            // Prepare service
-            let network = MockRawNetwork::new(event_stream_oneshot_tx);
-            let (service, gossip_network, other_network) = Service::new(
-                network.clone(),
-                network.event_stream(),
-                task_manager.spawn_handle().into(),
-                None,
-            );
-            let gossip_network = Box::new(gossip_network);
-            let other_network = Box::new(other_network);
+    fn record(&mut self) {
+        self.count += 1;
+    }
 
-            // `TaskManager` needs to be passed, so sender threads are running in background.
-            Self {
-                network,
-                service,
-                gossip_network,
-                _task_manager: task_manager,
-                _other_network: other_network,
-            }
+    fn update(&mut self) {
+        let now = time::Instant::now();
+        let elapsed = now.duration_since(self.since).as_secs_f64();
+        if elapsed <= 0.0 {
+            return;
         }
+        let rate = self.count as f64 / elapsed;
+        self.average = Some(match self.average {
+            Some(average) => average * (1.0 - self.smoothing) + rate * self.smoothing,
+            None => rate,
+        });
+        self.count = 0;
+        self.since = now;
+    }
 
-        async fn cleanup(self) {
-            self.network.close_channels().await;
-        }
+    /// Messages per second, zero until the first update.
+    fn average(&self) -> f64 {
+        self.average.unwrap_or(0.0)
     }
+}
 
-    #[async_trait::async_trait]
-    impl Network<MockData> for TestData {
-        type Error = Error;
-        type PeerId = MockPublicKey;
+/// What a peer sender sends next.
+enum Outgoing {
+    Data(Queued),
+    /// Nothing was sent for a while, the connection needs to be kept alive.
+    Keepalive,
+    /// The encoded version handshake, sent before anything else.
+    Handshake(Bytes),
+}
 
-        fn send_to(&mut self, data: MockData, peer_id: Self::PeerId) -> Result<(), Self::Error> {
-            self.gossip_network.send_to(data, peer_id)
-        }
+#[derive(Debug)]
+#[non_exhaustive]
+enum SendError {
+    MissingSender,
+    SendingFailed,
+    /// The queue of the peer is full and the message was rejected.
+    QueueFull,
+    /// Too many messages wait to be sent to all the peers together, the message was rejected.
+    GlobalBackpressure,
+    /// The protocol only receives data.
+    ReceiveOnly,
+}
 
-        fn send_to_random(
-            &mut self,
-            data: MockData,
-            peer_ids: HashSet<Self::PeerId>,
-        ) -> Result<(), Self::Error> {
-            self.gossip_network.send_to_random(data, peer_ids)
+impl Display for SendError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        use SendError::*;
+        match self {
+            MissingSender => write!(f, "no sender for the peer, it is probably not connected"),
+            SendingFailed => write!(f, "the peer sender stopped"),
+            QueueFull => write!(f, "the queue of the peer is full"),
+            GlobalBackpressure => write!(f, "too many messages wait to be sent to all peers"),
+            ReceiveOnly => write!(f, "the protocol is receive-only"),
         }
+    }
+}
 
-        fn broadcast(&mut self, data: MockData) -> Result<(), Self::Error> {
-            self.gossip_network.broadcast(data)
-        }
+impl std::error::Error for SendError {}
 
-        async fn next(&mut self) -> Result<(MockData, Self::PeerId), Self::Error> {
-            self.gossip_network.next().await
-        }
+/// The outcome of sending data to a subset of peers.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct SendSummary {
+    /// Number of peers the data was queued for.
+    succeeded: usize,
+    /// Number of peers skipped because we are not connected to them.
+    missing_sender: usize,
+    /// Number of peers for which queueing the data failed for other reasons.
+    failed: usize,
+}
+
+fn status_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Authentication => "authentication",
+        Protocol::BlockSync => "block sync",
     }
+}
 
-    fn message(i: u8) -> MockData {
-        MockData::new(i.into(), 3)
+fn peer_sender_task_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Authentication => "aleph/network/authentication_peer_sender",
+        Protocol::BlockSync => "aleph/network/sync_peer_sender",
     }
+}
 
-    #[tokio::test]
-    async fn test_notification_stream_opened() {
-        let mut test_data = TestData::prepare();
+fn pooled_peer_senders_task_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Authentication => "aleph/network/authentication_pooled_peer_senders",
+        Protocol::BlockSync => "aleph/network/sync_pooled_peer_senders",
+    }
+}
 
-        let peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
+/// Runs the peer senders handed over to it together, until the service stops handing them over
+/// and all of them finish.
+async fn run_pooled_peer_senders(
+    mut peer_senders: mpsc::UnboundedReceiver<BoxFuture<'static, ()>>,
+) {
+    let mut running = FuturesUnordered::new();
+    loop {
+        select_biased! {
+            peer_sender = peer_senders.next() => match peer_sender {
+                Some(peer_sender) => running.push(peer_sender),
+                None => break,
+            },
+            () = running.select_next_some() => {},
+        }
+    }
+    while running.next().await.is_some() {}
+}
 
-        peer_ids.iter().for_each(|peer_id| {
-            test_data
-                .service
-                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
-                .expect("Should handle");
-        });
+/// The span all the logs of the service are emitted in.
+fn service_span() -> Span {
+    info_span!(target: LOG_TARGET, "gossip_service")
+}
 
-        let message = message(1);
-        test_data.service.broadcast_authentication(message.clone());
+/// The span logs of sending to a single peer are emitted in.
+fn peer_sender_span<P: Debug>(peer_id: &P, protocol: Protocol) -> Span {
+    debug_span!(target: LOG_TARGET, "peer_sender", peer = ?peer_id, protocol = ?protocol)
+}
 
-        let broadcasted_messages = HashSet::<_>::from_iter(
-            test_data
-                .network
-                .send_message
-                .take(peer_ids.len())
-                .await
-                .into_iter(),
+fn record_send_failure<P: Debug>(breaker: &CircuitBreaker, peer_id: &P) {
+    if breaker.record_failure() {
+        warn!(
+            target: LOG_TARGET,
+            "Sending to peer {:?} keeps failing, dropping messages for it for a while.", peer_id
         );
+    }
+}
 
-        let expected_messages = HashSet::from_iter(
-            peer_ids
-                .into_iter()
-                .map(|peer_id| (message.clone().encode(), peer_id, PROTOCOL)),
-        );
+fn with_deliveries<D>(
+    messages: impl IntoIterator<Item = (D, Delivery)>,
+) -> Vec<(D, Option<Delivery>)> {
+    messages
+        .into_iter()
+        .map(|(data, delivery)| (data, Some(delivery)))
+        .collect()
+}
 
-        assert_eq!(broadcasted_messages, expected_messages);
+/// Sends the data, failing if that takes longer than the timeout, so that a wedged connection
+/// does not stall the queue of the peer forever.
+async fn send_with_timeout<S: NetworkSender>(
+    sender: &S,
+    data: Bytes,
+    timeout: Duration,
+) -> Result<(), String> {
+    match time::timeout(timeout, sender.send(data)).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(_) => Err(format!("Sending timed out after {timeout:?}.")),
+    }
+}
 
-        test_data.cleanup().await
+/// Sends the frames in order with `send_with_timeout`, stopping at the first failure.
+async fn send_frames<S: NetworkSender>(
+    sender: &S,
+    frames: &[Bytes],
+    timeout: Duration,
+) -> Result<(), String> {
+    for frame in frames {
+        // Cloning only bumps the reference count, the frames are kept in case of a retry.
+        send_with_timeout(sender, frame.clone(), timeout).await?;
     }
+    Ok(())
+}
 
-    #[tokio::test]
-    async fn test_notification_stream_closed() {
-        let mut test_data = TestData::prepare();
+/// The span logs of handling a network event concerning a single peer are emitted in.
+fn network_event_span<P: Debug>(peer_id: &P) -> Span {
+    debug_span!(target: LOG_TARGET, "network_event", peer = ?peer_id)
+}
 
-        let peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
-        let opened_authorities_n = 2;
+impl<N: RawNetwork, D: Data> ProtocolState<N, D> {
+    fn new(
+        protocol: Protocol,
+        config: ProtocolConfig,
+        codec: Arc<dyn Codec<D>>,
+        network: N,
+        spawner: Arc<dyn Spawner>,
+        metrics: Metrics,
+        broadcast_feedback: Option<mpsc::UnboundedSender<BroadcastResult<N::PeerId>>>,
+    ) -> (Self, ServiceInterface<D, N::PeerId>) {
+        let (messages_for_user, messages_from_service) = mpsc::unbounded();
+        let (messages_for_service, messages_from_user) = mpsc::channel(config.command_buffer);
+        let broadcast_cache = config.broadcast_dedup.as_ref().map(BroadcastCache::new);
+        let inbound_cache = config.inbound_dedup.as_ref().map(InboundCache::new);
+        let sent_cache = config.send_dedup.as_ref().map(SentCache::new);
+        let reassembler = config.fragmentation.as_ref().map(Reassembler::new);
+        let rate_smoothing = config.rate_smoothing;
+        let connected_peer_count = Arc::new(AtomicUsize::new(0));
+        let interface_peer_count = config
+            .reject_broadcasts_without_peers
+            .then(|| connected_peer_count.clone());
+        let commands = CommandSender {
+            commands: messages_for_service,
+            connected_peer_count: interface_peer_count,
+            direction: config.direction,
+        };
+        (
+            ProtocolState {
+                protocol,
+                config,
+                previous_codec: Arc::new(PreviousVersion(codec.clone())),
+                codec,
+                network,
+                spawner,
+                sender_tasks: Arc::new(AtomicUsize::new(0)),
+                sender_pool: None,
+                metrics,
+                messages_from_user,
+                commands_for_handles: Some(commands.clone()),
+                messages_for_user,
+                subscribers: Vec::new(),
+                kind_routes: HashMap::new(),
+                connected_peers: HashSet::new(),
+                peer_names: PeerNames::default(),
+                connected_peer_count,
+                broadcast_order: Vec::new(),
+                broadcast_rotation: 0,
+                peer_senders: HashMap::new(),
+                send_latencies: HashMap::new(),
+                circuit_breakers: HashMap::new(),
+                peer_sender_aborts: HashMap::new(),
+                peer_sender_liveness: HashMap::new(),
+                active_peer_senders: Arc::new(AtomicUsize::new(0)),
+                timestamp_of_last_log_that_queue_is_full: HashMap::new(),
+                timestamp_of_last_valid_message_report: HashMap::new(),
+                last_seen: HashMap::new(),
+                duplicate_stream_openings: 0,
+                broadcast_cache,
+                inbound_cache,
+                sent_cache,
+                reassembler,
+                broadcast_feedback,
+                allowlist: None,
+                received_rate: MessageRate::new(rate_smoothing),
+                broadcast_rate: MessageRate::new(rate_smoothing),
+                inbound_limits: HashMap::new(),
+                signing: None,
+                queue_growth: HashMap::new(),
+                replay_buffer: VecDeque::new(),
+                peer_weights: HashMap::new(),
+                broadcast_rng: StdRng::from_entropy(),
+                paused: false,
+                bytes_sent: Arc::new(AtomicU64::new(0)),
+                bytes_received: 0,
+                peer_versions: HashMap::new(),
+                decode_failures: None,
+                pending_closes: HashMap::new(),
+                in_flight: None,
+                drain_scheduler: None,
+                connectivity: Connectivity::Available,
+                rebroadcasts: Vec::new(),
+                peer_priority: None,
+                authorities: HashSet::new(),
+                outgoing_tap: None,
+                recently_disconnected: HashMap::new(),
+                connected_at: HashMap::new(),
+            },
+            ServiceInterface {
+                messages_from_service,
+                commands,
+            },
+        )
+    }
+
+    fn peer_sender(
+        &self,
+        peer_id: N::PeerId,
+        mut receiver: PriorityReceiver<Queued>,
+        latency: Option<SendLatency>,
+        breaker: Option<CircuitBreaker>,
+        mut opened_at: Option<time::Instant>,
+        liveness: LivenessGuard,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        let network = self.network.clone();
+        let metrics = self.metrics.clone();
+        let protocol = self.protocol;
+        let backoff = self.config.sender_backoff.clone();
+        let max_batch = self
+            .config
+            .batching
+            .as_ref()
+            .map(|batching| batching.max_messages);
+        let keepalive_interval = self.config.keepalive_interval;
+        let send_timeout = self.config.send_timeout;
+        let tap = self.outgoing_tap.clone();
+        let compression = self.config.compression.clone();
+        let frame_size = self
+            .config
+            .fragmentation
+            .as_ref()
+            .map(|fragmentation| fragmentation.frame_size);
+        let signing = self.signing.clone();
+        let encoding_version = self.config.encoding_version;
+        let bytes_sent = self.bytes_sent.clone();
+        let drain_scheduler = self.drain_scheduler.clone();
+        let mut handshake = self
+            .config
+            .versions
+            .as_ref()
+            .map(|versions| Bytes::from(handshake::encode(versions)));
+        let span = peer_sender_span(&peer_id, protocol);
+        let name = self.peer_names.name(&peer_id);
+        let active_peer_senders = self.active_peer_senders.clone();
+        async move {
+            liveness.start();
+            let _active = ActivePeerSender::start(active_peer_senders, metrics.clone(), protocol);
+            let mut sender = None;
+            let mut failures = 0;
+            let mut message_id: u32 = 0;
+            loop {
+                let next = match (handshake.take(), keepalive_interval) {
+                    (Some(handshake), _) => Some(Outgoing::Handshake(handshake)),
+                    (None, Some(interval)) => time::timeout(interval, receiver.next())
+                        .await
+                        .map(|maybe_data| maybe_data.map(Outgoing::Data))
+                        .unwrap_or(Some(Outgoing::Keepalive)),
+                    (None, None) => receiver.next().await.map(Outgoing::Data),
+                };
+                let outgoing = match next {
+                    Some(outgoing) => outgoing,
+                    None => {
+                        debug!(
+                            target: LOG_TARGET,
+                            "Sender was dropped for peer {:?}. Peer sender exiting.", name
+                        );
+                        return;
+                    }
+                };
+                if let Outgoing::Data(_) = outgoing {
+                    metrics.report_message_popped_from_peer_sender_queue(protocol);
+                }
+                // The handshake is never skipped, the peer could not decode anything after it.
+                if matches!(&breaker, Some(breaker) if !breaker.allows_send())
+                    && !matches!(outgoing, Outgoing::Handshake(_))
+                {
+                    if let Outgoing::Data(queued) = outgoing {
+                        trace!(target: LOG_TARGET, "Dropping message, the circuit is open.");
+                        metrics.report_message_dropped(protocol, DropReason::CircuitOpen);
+                        receiver.done(1);
+                        drop(queued);
+                    }
+                    continue;
+                }
+                let mut confirmations = Vec::new();
+                let mut queue_waits = Vec::new();
+                let mut permits = Vec::new();
+                let s = if let Some(s) = sender.as_mut() {
+                    s
+                } else {
+                    // The error is not necessarily `Send`, so it cannot be held across the sleep.
+                    let maybe_sender = network
+                        .sender(peer_id.clone(), protocol)
+                        .map_err(|e| e.to_string());
+                    match maybe_sender {
+                        Ok(s) => {
+                            failures = 0;
+                            sender.insert(s)
+                        }
+                        Err(e) => {
+                            failures += 1;
+                            if let Some(breaker) = &breaker {
+                                record_send_failure(breaker, &name);
+                            }
+                            let delay = backoff.delay(failures);
+                            debug!(
+                                target: LOG_TARGET,
+                                "Failed creating sender. Dropping message and retrying in {:?}: {}",
+                                delay,
+                                e
+                            );
+                            match outgoing {
+                                Outgoing::Data(queued) => {
+                                    metrics
+                                        .report_message_dropped(protocol, DropReason::SendFailed);
+                                    receiver.done(1);
+                                    // Dropping the confirmation reports the failure.
+                                    drop(queued);
+                                }
+                                // Nothing can be sent before the handshake.
+                                Outgoing::Handshake(bytes) => handshake = Some(bytes),
+                                Outgoing::Keepalive => {}
+                            }
+                            time::sleep(delay).await;
+                            continue;
+                        }
+                    }
+                };
+                if let Outgoing::Data(queued) = &outgoing {
+                    if queued.is_expired() {
+                        trace!(target: LOG_TARGET, "Dropping expired message.");
+                        metrics.report_message_dropped(protocol, DropReason::Expired);
+                        receiver.done(1);
+                        continue;
+                    }
+                }
+                let (encoded, messages) = match outgoing {
+                    Outgoing::Keepalive => (KEEPALIVE, 0),
+                    Outgoing::Handshake(bytes) => (bytes, 0),
+                    Outgoing::Data(queued) => {
+                        let (data, confirmation, queued_at, permit) = queued.into_parts();
+                        confirmations.extend(confirmation);
+                        queue_waits.extend(queued_at);
+                        permits.extend(permit);
+                        let (encoded, messages) = match max_batch {
+                            Some(max_batch) => {
+                                let mut batch = vec![data];
+                                while batch.len() < max_batch {
+                                    match receiver.try_next() {
+                                        Some(queued) => {
+                                            metrics.report_message_popped_from_peer_sender_queue(
+                                                protocol,
+                                            );
+                                            if queued.is_expired() {
+                                                metrics.report_message_dropped(
+                                                    protocol,
+                                                    DropReason::Expired,
+                                                );
+                                                receiver.done(1);
+                                                continue;
+                                            }
+                                            let (data, confirmation, queued_at, permit) =
+                                                queued.into_parts();
+                                            confirmations.extend(confirmation);
+                                            queue_waits.extend(queued_at);
+                                            permits.extend(permit);
+                                            batch.push(data);
+                                        }
+                                        None => break,
+                                    }
+                                }
+                                (Bytes::from(codec::join_batch(&batch)), batch.len())
+                            }
+                            // Shared with the queues of the other peers, so not copied.
+                            None => (data, 1),
+                        };
+                        let encoded = match &signing {
+                            Some(signing) => Bytes::from(signing.sign(&encoded)),
+                            None => encoded,
+                        };
+                        let encoded = match &compression {
+                            Some(compression) => {
+                                Bytes::from(compression::compress(&encoded, compression))
+                            }
+                            None => encoded,
+                        };
+                        match encoding_version {
+                            Some(version) => (
+                                Bytes::from(codec::with_version(version, &encoded)),
+                                messages,
+                            ),
+                            None => (encoded, messages),
+                        }
+                    }
+                };
+                for queued_at in queue_waits {
+                    metrics.report_queue_wait(protocol, queued_at);
+                }
+                let turn = match &drain_scheduler {
+                    Some(scheduler) => scheduler.turn().await,
+                    None => None,
+                };
+                let maybe_timer = metrics.start_sending_in(protocol);
+                let send_started = latency.as_ref().map(|_| time::Instant::now());
+                if messages > 0 {
+                    metrics.report_sent_message_size(protocol, encoded.len());
+                }
+                let frames = match (frame_size, messages) {
+                    (Some(frame_size), 1..) => {
+                        message_id = message_id.wrapping_add(1);
+                        fragment::split(message_id, &encoded, frame_size)
+                            .into_iter()
+                            .map(Bytes::from)
+                            .collect()
+                    }
+                    _ => vec![encoded],
+                };
+                let size = frames.iter().map(Bytes::len).sum::<usize>() as u64;
+                if let Some(tap) = &tap {
+                    frames
+                        .iter()
+                        .for_each(|frame| tap(&peer_id, protocol, frame));
+                }
+                let result = send_frames(s, &frames, send_timeout).await;
+                let delivered = if let Err(e) = result {
+                    debug!(
+                        target: LOG_TARGET,
+                        "Failed sending data to peer. Recreating sender and retrying once: {}", e
+                    );
+                    sender = None;
+                    let maybe_sender = network
+                        .sender(peer_id.clone(), protocol)
+                        .map_err(|e| e.to_string());
+                    let result = match maybe_sender {
+                        Ok(s) => {
+                            if let Some(tap) = &tap {
+                                frames
+                                    .iter()
+                                    .for_each(|frame| tap(&peer_id, protocol, frame));
+                            }
+                            let result = send_frames(&s, &frames, send_timeout).await;
+                            result.map(|()| s)
+                        }
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(s) => {
+                            sender = Some(s);
+                            true
+                        }
+                        Err(e) => {
+                            debug!(
+                                target: LOG_TARGET,
+                                "Retrying failed. Dropping sender and {} message(s): {}",
+                                messages,
+                                e
+                            );
+                            metrics.report_messages_dropped(
+                                protocol,
+                                DropReason::SendFailed,
+                                messages as u64,
+                            );
+                            false
+                        }
+                    }
+                } else {
+                    true
+                };
+                if let Some(timer) = maybe_timer {
+                    timer.observe_duration();
+                }
+                match (&breaker, delivered) {
+                    (Some(breaker), true) => breaker.record_success(),
+                    (Some(breaker), false) => record_send_failure(breaker, &name),
+                    (None, _) => {}
+                }
+                if let (true, Some(latency), Some(started)) = (delivered, &latency, send_started) {
+                    latency.record(started.elapsed());
+                }
+                receiver.done(messages);
+                drop(permits);
+                if let Some(turn) = turn {
+                    drop(turn);
+                    // Lets other peer senders ask for a turn before this one asks for the next.
+                    task::yield_now().await;
+                }
+                if delivered {
+                    if let Some(opened_at) = opened_at.take() {
+                        metrics.report_time_to_first_byte(protocol, opened_at);
+                    }
+                    bytes_sent.fetch_add(size, Ordering::Relaxed);
+                    confirmations
+                        .into_iter()
+                        .for_each(PendingConfirmation::confirm);
+                }
+            }
+        }
+        .instrument(span)
+    }
+
+    fn possibly_log_that_queue_is_full(&mut self, peer: N::PeerId) {
+        if self
+            .timestamp_of_last_log_that_queue_is_full
+            .get(&peer)
+            .map(|t| t.elapsed() >= time::Duration::from_secs(1))
+            .unwrap_or(true)
+        {
+            debug!(
+                target: LOG_TARGET,
+                "Queue of peer_sender in {:?} protocol for peer {:?} is full, applying {:?} policy",
+                self.protocol,
+                self.peer_names.name(&peer),
+                self.config.drop_policy,
+            );
+            self.timestamp_of_last_log_that_queue_is_full
+                .insert(peer, Instant::now());
+        }
+    }
+
+    /// Queues the data for sending to the peer. When the queue of the peer for the priority of the
+    /// data is full, the outcome depends on the drop policy of the protocol:
+    /// 1. `DropPolicy::DropNewest` rejects the data with `SendError::QueueFull`.
+    /// 2. `DropPolicy::DropOldest` discards the oldest queued message and accepts the data.
+    fn send_to_peer(&mut self, data: D, peer: N::PeerId) -> Result<(), SendError> {
+        self.send_to_peer_with_priority(data, peer, Priority::Normal)
+    }
+
+    /// Like `send_to_peer`, but puts the data in the queue of the given priority. High priority
+    /// data is sent before any normal priority data waiting for the peer.
+    fn send_to_peer_with_priority(
+        &mut self,
+        data: D,
+        peer: N::PeerId,
+        priority: Priority,
+    ) -> Result<(), SendError> {
+        self.send_to_peer_until(data, peer, priority, None)
+    }
+
+    /// Like `send_to_peer_with_priority`, but the data is dropped instead of sent if it is still
+    /// queued at the deadline. With send deduplication enabled, data identical to data recently
+    /// queued for the peer is skipped.
+    fn send_to_peer_until(
+        &mut self,
+        data: D,
+        peer: N::PeerId,
+        priority: Priority,
+        deadline: Option<time::Instant>,
+    ) -> Result<(), SendError> {
+        let encoded = self.encode(&data, Delivery::Direct);
+        if let (Some(hash), Some(cache)) = (self.sent_hash(&encoded), self.sent_cache.as_mut()) {
+            if cache.was_sent(&peer, hash) {
+                trace!(
+                    target: LOG_TARGET,
+                    "Skipping {:?} data for peer {:?}, it was recently sent to the peer.",
+                    self.protocol,
+                    self.peer_names.name(&peer)
+                );
+                self.metrics
+                    .report_message_dropped(self.protocol, DropReason::AlreadySent);
+                return Ok(());
+            }
+        }
+        self.send_encoded_until(encoded, peer, priority, deadline)
+    }
+
+    /// The hash identifying the encoded data for send deduplication, if it is enabled. The
+    /// delivery tag is skipped, so data sent directly matches the same data broadcast before.
+    fn sent_hash(&self, encoded: &[u8]) -> Option<PayloadHash> {
+        self.sent_cache.as_ref()?;
+        let payload = match self.config.delivery_tags {
+            true => encoded.get(1..).unwrap_or_default(),
+            false => encoded,
+        };
+        Some(dedup::payload_hash(payload))
+    }
+
+    /// Like `send_to_peer_until`, but with data that is already encoded.
+    fn send_encoded_until(
+        &mut self,
+        encoded: Bytes,
+        peer: N::PeerId,
+        priority: Priority,
+        deadline: Option<time::Instant>,
+    ) -> Result<(), SendError> {
+        self.enqueue(Queued::new(encoded, deadline), peer, priority)
+    }
+
+    /// Encodes the data, tagged with how it is sent if the protocol uses delivery tags.
+    fn encode(&self, data: &D, delivery: Delivery) -> Bytes {
+        let encoded = codec::encode(&*self.codec, data);
+        match self.config.delivery_tags {
+            true => codec::tag(delivery, encoded).into(),
+            false => encoded.into(),
+        }
+    }
+
+    /// Like `send_to_peer`, but the confirmation is completed once the data is handed over to
+    /// the network. If the data is dropped before that, the confirmation reports a failure.
+    fn send_to_peer_confirmed(
+        &mut self,
+        data: D,
+        peer: N::PeerId,
+        confirmation: PendingConfirmation,
+    ) -> Result<(), SendError> {
+        let encoded = self.encode(&data, Delivery::Direct);
+        self.enqueue(
+            Queued::confirmed(encoded, confirmation),
+            peer,
+            Priority::Normal,
+        )
+    }
+
+    fn enqueue(
+        &mut self,
+        mut queued: Queued,
+        peer: N::PeerId,
+        priority: Priority,
+    ) -> Result<(), SendError> {
+        if self.config.direction == Direction::ReceiveOnly {
+            self.report_message_dropped_for(&peer, DropReason::WrongDirection);
+            return Err(SendError::ReceiveOnly);
+        }
+        queued.queued_at = self.metrics.now();
+        let sent_hash = self.sent_hash(&queued.encoded);
+        let sender = match self.peer_senders.get(&peer) {
+            Some(sender) => sender,
+            None => {
+                self.report_message_dropped_for(&peer, DropReason::MissingSender);
+                return Err(SendError::MissingSender);
+            }
+        };
+        if let Some(in_flight) = &self.in_flight {
+            match in_flight.clone().try_acquire_owned() {
+                Ok(permit) => queued.permit = Some(permit),
+                Err(_) => {
+                    self.report_message_dropped_for(&peer, DropReason::GlobalBackpressure);
+                    return Err(SendError::GlobalBackpressure);
+                }
+            }
+        }
+        match sender.push(queued, priority) {
+            Ok(dropped) => {
+                if let (Some(hash), Some(cache)) = (sent_hash, self.sent_cache.as_mut()) {
+                    cache.record_sent(peer.clone(), hash);
+                }
+                self.metrics
+                    .report_message_pushed_to_peer_sender_queue(self.protocol);
+                self.metrics.report_message_sent(self.protocol);
+                self.metrics
+                    .report_authority_message_sent(self.protocol, self.authorities.contains(&peer));
+                if dropped.is_some() {
+                    self.metrics
+                        .report_message_popped_from_peer_sender_queue(self.protocol);
+                    self.report_message_dropped_for(&peer, DropReason::QueueFull);
+                    self.possibly_log_that_queue_is_full(peer);
+                }
+                Ok(())
+            }
+            Err(PushError::Full) => {
+                self.report_message_dropped_for(&peer, DropReason::QueueFull);
+                self.possibly_log_that_queue_is_full(peer);
+                Err(SendError::QueueFull)
+            }
+            Err(PushError::Closed) => {
+                self.report_message_dropped_for(&peer, DropReason::SendFailed);
+                // Receiver can also be dropped when thread cannot send to peer. In case receiver is dropped this entry will be removed by Event::NotificationStreamClosed
+                // No need to remove the entry here
+                trace!(target: LOG_TARGET, "Failed sending data to peer because peer_sender receiver is dropped: {:?}", self.peer_names.name(&peer));
+                Err(SendError::SendingFailed)
+            }
+        }
+    }
+
+    fn send_data(
+        &mut self,
+        data: D,
+        peer_id: N::PeerId,
+        priority: Priority,
+        deadline: Option<time::Instant>,
+    ) {
+        trace!(
+            target: LOG_TARGET,
+            "Sending {:?} data to peer {:?} with {:?} priority.",
+            self.protocol,
+            self.peer_names.name(&peer_id),
+            priority,
+        );
+        if let Err(e) = self.send_to_peer_until(data, peer_id.clone(), priority, deadline) {
+            debug!(
+                target: LOG_TARGET,
+                "Failed to send to peer {:?}: {}", self.peer_names.name(&peer_id), e
+            );
+        }
+    }
+
+    fn random_peer<'a>(&'a self, peer_ids: &'a HashSet<N::PeerId>) -> Option<&'a N::PeerId> {
+        peer_ids
+            .intersection(&self.connected_peers)
+            .choose(&mut thread_rng())
+            .or_else(|| self.connected_peers.iter().choose(&mut thread_rng()))
+    }
+
+    fn send_to_random(&mut self, data: D, peer_ids: HashSet<N::PeerId>) {
+        trace!(
+            target: LOG_TARGET,
+            "Sending {:?} data to random peer among {:?}.",
+            self.protocol,
+            peer_ids,
+        );
+        let peer_id = match self.random_peer(&peer_ids) {
+            Some(peer_id) => peer_id.clone(),
+            None => {
+                debug!(
+                    target: LOG_TARGET,
+                    "Failed to send {:?} message to random peer, no peers are available.",
+                    self.protocol,
+                );
+                return;
+            }
+        };
+        self.send_data(data, peer_id, Priority::Normal, None);
+    }
+
+    /// Sends the data to the best peer, or to a random one while no sends to connected peers were
+    /// measured.
+    fn send_to_best(&mut self, data: D) {
+        match self.best_peer() {
+            Some(peer_id) => {
+                trace!(
+                    target: LOG_TARGET,
+                    "Sending {:?} data to the best peer {:?}.",
+                    self.protocol,
+                    self.peer_names.name(&peer_id),
+                );
+                self.send_data(data, peer_id, Priority::Normal, None);
+            }
+            None => self.send_to_random(data, HashSet::new()),
+        }
+    }
+
+    /// The connected peer with the lowest average send latency. Ties go to the more important
+    /// peer if peers have priorities, and then to the peer connected first. Peers nothing was
+    /// sent to yet are not considered, so without latency tracking there is no best peer.
+    fn best_peer(&self) -> Option<N::PeerId> {
+        self.broadcast_order
+            .iter()
+            .filter_map(|peer| {
+                self.send_latencies
+                    .get(peer)
+                    .and_then(SendLatency::average)
+                    .map(|average| (average, peer))
+            })
+            // The first of equal peers is returned, so the order of connecting decides last.
+            .min_by(|(a_average, a), (b_average, b)| {
+                a_average
+                    .cmp(b_average)
+                    .then_with(|| match &self.peer_priority {
+                        Some(priority) => priority(a, b),
+                        None => cmp::Ordering::Equal,
+                    })
+            })
+            .map(|(_, peer)| peer.clone())
+    }
+
+    fn send_to_peers(
+        &mut self,
+        data: D,
+        peers: impl IntoIterator<Item = N::PeerId>,
+    ) -> SendSummary {
+        let mut summary = SendSummary::default();
+        for peer in peers {
+            match self.send_to_peer(data.clone(), peer.clone()) {
+                Ok(()) => summary.succeeded += 1,
+                Err(SendError::MissingSender) => {
+                    trace!(
+                        target: LOG_TARGET,
+                        "Skipping peer {:?} when sending {:?} data, no sender available.",
+                        self.peer_names.name(&peer),
+                        self.protocol,
+                    );
+                    summary.missing_sender += 1;
+                }
+                Err(e) => {
+                    debug!(
+                        target: LOG_TARGET,
+                        "Failed to send to peer {:?}: {}", self.peer_names.name(&peer), e
+                    );
+                    summary.failed += 1;
+                }
+            }
+        }
+        summary
+    }
+
+    /// The connected peers in the order the next broadcast is queued for them. Every broadcast
+    /// starts one peer further, so no peer is consistently the last one.
+    fn rotated_peers(&self) -> Vec<N::PeerId> {
+        if self.broadcast_order.is_empty() {
+            return Vec::new();
+        }
+        let (last, first) = self
+            .broadcast_order
+            .split_at(self.broadcast_rotation % self.broadcast_order.len());
+        first.iter().chain(last).cloned().collect()
+    }
+
+    /// Whether sending to the peer takes longer than the threshold on average.
+    fn is_slow(&self, peer: &N::PeerId, threshold: Duration) -> bool {
+        self.send_latencies
+            .get(peer)
+            .and_then(SendLatency::average)
+            .map(|average| average > threshold)
+            .unwrap_or(false)
+    }
+
+    /// The connected peers in the order the next broadcast is queued for them.
+    fn broadcast_peers(&self) -> Vec<N::PeerId> {
+        let mut peers = self.deprioritize_slow_peers(self.rotated_peers());
+        if let Some(priority) = &self.peer_priority {
+            // The sort is stable, so equally important peers still take turns.
+            peers.sort_by(|a, b| priority(a, b));
+        }
+        peers
+    }
+
+    /// Moves the slow peers to the end, keeping the order otherwise.
+    fn deprioritize_slow_peers(&self, peers: Vec<N::PeerId>) -> Vec<N::PeerId> {
+        let threshold = match &self.config.latency_tracking {
+            Some(config) => config.slow_threshold,
+            None => return peers,
+        };
+        let (mut fast, slow): (Vec<_>, Vec<_>) = peers
+            .into_iter()
+            .partition(|peer| !self.is_slow(peer, threshold));
+        fast.extend(slow);
+        fast
+    }
+
+    fn slow_peers(&self) -> usize {
+        match &self.config.latency_tracking {
+            Some(config) => self
+                .connected_peers
+                .iter()
+                .filter(|peer| self.is_slow(peer, config.slow_threshold))
+                .count(),
+            None => 0,
+        }
+    }
+
+    /// Sends the data to all connected peers, in a rotating order with slow peers last. With
+    /// broadcast deduplication enabled, peers that recently got identical data are skipped. Data
+    /// still queued for a peer at the deadline is not sent to it.
+    fn broadcast(
+        &mut self,
+        data: D,
+        priority: Priority,
+        deadline: Option<time::Instant>,
+    ) -> BroadcastResult<N::PeerId> {
+        if self.paused {
+            trace!(
+                target: LOG_TARGET,
+                "Skipping {:?} broadcast, the protocol is paused.",
+                self.protocol
+            );
+            return BroadcastResult {
+                protocol: self.protocol,
+                attempted: 0,
+                succeeded: 0,
+                failed_peers: Vec::new(),
+                skipped_circuit_open: 0,
+            };
+        }
+        self.buffer_for_replay(&data);
+        let mut peers = self.broadcast_peers();
+        self.broadcast_rotation = self.broadcast_rotation.wrapping_add(1);
+        let (weights, rng) = (&self.peer_weights, &mut self.broadcast_rng);
+        peers.retain(|peer| {
+            weights
+                .get(peer)
+                .map_or(true, |weight| rng.gen_bool(*weight))
+        });
+        let peers_before = peers.len();
+        let breakers = &self.circuit_breakers;
+        peers.retain(|peer| !matches!(breakers.get(peer), Some(breaker) if !breaker.allows_send()));
+        let skipped_circuit_open = peers_before - peers.len();
+        if skipped_circuit_open > 0 {
+            trace!(
+                target: LOG_TARGET,
+                "Skipping {} peer(s) with open circuits in {:?} broadcast.",
+                skipped_circuit_open,
+                self.protocol
+            );
+        }
+        let encoded = self.encode(&data, Delivery::Gossip);
+        let (hash, peers) = match self.broadcast_cache.as_mut() {
+            Some(cache) => {
+                let hash = dedup::payload_hash(&encoded);
+                (Some(hash), cache.unsent_peers(hash, peers))
+            }
+            None => (None, peers),
+        };
+        let mut result = BroadcastResult {
+            protocol: self.protocol,
+            attempted: peers.len(),
+            succeeded: 0,
+            failed_peers: Vec::new(),
+            skipped_circuit_open,
+        };
+        for peer in peers {
+            match self.send_encoded_until(encoded.clone(), peer.clone(), priority, deadline) {
+                Ok(()) => {
+                    result.succeeded += 1;
+                    if let (Some(hash), Some(cache)) = (hash, self.broadcast_cache.as_mut()) {
+                        cache.record_sent(hash, peer);
+                    }
+                }
+                Err(e) => {
+                    debug!(
+                        target: LOG_TARGET,
+                        "Failed to send to peer {:?}: {}", self.peer_names.name(&peer), e
+                    );
+                    result.failed_peers.push(peer);
+                }
+            }
+        }
+        result
+    }
+
+    fn set_peer_weight(&mut self, peer_id: N::PeerId, weight: f64) {
+        // Sampling panics for weights outside of [0, 1], so NaN is treated as no weight at all.
+        if weight >= 1.0 || weight.is_nan() {
+            self.peer_weights.remove(&peer_id);
+        } else {
+            self.peer_weights.insert(peer_id, weight.max(0.0));
+        }
+    }
+
+    /// Publishes the result of a broadcast to the feedback channel, if there is one.
+    fn report_broadcast_result(&mut self, result: BroadcastResult<N::PeerId>) {
+        if let Some(feedback) = &self.broadcast_feedback {
+            if feedback.unbounded_send(result).is_err() {
+                debug!(
+                    target: LOG_TARGET,
+                    "Broadcast feedback receiver dropped, no longer reporting {:?} broadcasts.",
+                    self.protocol
+                );
+                self.broadcast_feedback = None;
+            }
+        }
+    }
+
+    fn handle_command(&mut self, command: Command<D, N::PeerId>) {
+        match command {
+            Command::Broadcast(message, priority, deadline) => {
+                self.broadcast_rate.record();
+                let result = self.broadcast(message, priority, deadline);
+                self.report_broadcast_result(result);
+            }
+            Command::SendToRandom(message, peer_ids) => self.send_to_random(message, peer_ids),
+            Command::SendToBest(message) => self.send_to_best(message),
+            Command::SendToPeers(message, peer_ids) => {
+                let summary = self.send_to_peers(message, peer_ids);
+                trace!(
+                    target: LOG_TARGET,
+                    "Sent {:?} data to a subset of peers: {:?}.",
+                    self.protocol,
+                    summary,
+                );
+            }
+            Command::Send(message, peer_id, priority, deadline) => {
+                self.send_data(message, peer_id, priority, deadline)
+            }
+            Command::Rebroadcast(message, schedule, handle) => {
+                self.start_rebroadcast(message, schedule, handle)
+            }
+            Command::BroadcastBarrier(message, response) => {
+                self.broadcast_rate.record();
+                // The user might have given up on the barrier already.
+                let _ = response.send(self.broadcast_confirmed(message));
+            }
+            Command::SendConfirmed(message, peer_id, confirmation) => {
+                if let Err(e) = self.send_to_peer_confirmed(message, peer_id.clone(), confirmation)
+                {
+                    debug!(
+                        target: LOG_TARGET,
+                        "Failed to send to peer {:?}: {}", self.peer_names.name(&peer_id), e
+                    );
+                }
+            }
+        }
+    }
+
+    /// Queues the data for every connected peer, regardless of weights and deduplication, each
+    /// copy with its own confirmation. Peers for which queueing fails get a failed confirmation.
+    fn broadcast_confirmed(&mut self, data: D) -> Vec<(N::PeerId, Confirmation<Error>)> {
+        let peers = self.broadcast_peers();
+        self.broadcast_rotation = self.broadcast_rotation.wrapping_add(1);
+        let encoded = self.encode(&data, Delivery::Gossip);
+        peers
+            .into_iter()
+            .map(|peer| {
+                let (tx, rx) = oneshot::channel();
+                let queued = Queued::confirmed(encoded.clone(), PendingConfirmation::new(tx));
+                if let Err(e) = self.enqueue(queued, peer.clone(), Priority::Normal) {
+                    debug!(
+                        target: LOG_TARGET,
+                        "Failed to send to peer {:?}: {}", self.peer_names.name(&peer), e
+                    );
+                }
+                (peer, rx)
+            })
+            .collect()
+    }
+
+    /// Broadcasts the data and schedules broadcasting it again.
+    fn start_rebroadcast(
+        &mut self,
+        data: D,
+        schedule: RebroadcastConfig,
+        handle: RebroadcastHandle,
+    ) {
+        self.broadcast_rate.record();
+        let result = self.broadcast(data.clone(), Priority::Normal, None);
+        self.report_broadcast_result(result);
+        let now = time::Instant::now();
+        let interval = schedule.initial_interval.max(MIN_REBROADCAST_INTERVAL);
+        if interval > schedule.duration || handle.is_cancelled() {
+            return;
+        }
+        self.rebroadcasts.push(Rebroadcast {
+            data,
+            next_at: now + interval,
+            interval,
+            max_interval: schedule.max_interval,
+            until: now + schedule.duration,
+            handle,
+        });
+    }
+
+    /// When the next scheduled rebroadcast is due.
+    fn next_rebroadcast(&self) -> Option<time::Instant> {
+        self.rebroadcasts
+            .iter()
+            .map(|rebroadcast| rebroadcast.next_at)
+            .min()
+    }
+
+    /// Broadcasts the data whose rebroadcast is due, dropping the finished and cancelled ones.
+    fn rebroadcast_due(&mut self) {
+        let now = time::Instant::now();
+        let mut due = Vec::new();
+        self.rebroadcasts.retain_mut(|rebroadcast| {
+            if rebroadcast.handle.is_cancelled() {
+                return false;
+            }
+            if rebroadcast.next_at > now {
+                return true;
+            }
+            due.push(rebroadcast.data.clone());
+            rebroadcast.interval = rebroadcast
+                .interval
+                .saturating_mul(2)
+                .min(rebroadcast.max_interval)
+                .max(MIN_REBROADCAST_INTERVAL);
+            rebroadcast.next_at += rebroadcast.interval;
+            rebroadcast.next_at <= rebroadcast.until
+        });
+        for data in due {
+            self.broadcast_rate.record();
+            let result = self.broadcast(data, Priority::Normal, None);
+            self.report_broadcast_result(result);
+        }
+    }
+
+    /// Handles the command and, with batching enabled, the commands already waiting behind it,
+    /// so that messages sent in quick succession get queued for peers together.
+    fn handle_ready_commands(&mut self, command: Command<D, N::PeerId>) {
+        self.handle_command(command);
+        let max_batch = match &self.config.batching {
+            Some(batching) => batching.max_messages,
+            None => return,
+        };
+        for _ in 1..max_batch {
+            match self.messages_from_user.try_next() {
+                Ok(Some(command)) => self.handle_command(command),
+                // The run loop notices when the user is gone.
+                Ok(None) | Err(_) => return,
+            }
+        }
+    }
+
+    /// The least important peer, or the least recently seen one without a peer priority. Among
+    /// equally important peers, the ones we never heard from come first.
+    fn least_recently_seen_peer(&self) -> Option<N::PeerId> {
+        let by_last_seen =
+            |a: &&N::PeerId, b: &&N::PeerId| self.last_seen.get(*a).cmp(&self.last_seen.get(*b));
+        match &self.peer_priority {
+            Some(priority) => self
+                .connected_peers
+                .iter()
+                .max_by(|a, b| priority(a, b).then_with(|| by_last_seen(b, a))),
+            None => self.connected_peers.iter().min_by(by_last_seen),
+        }
+        .cloned()
+    }
+
+    /// Makes room for another peer if the limit of connected peers is reached. Returns the
+    /// evicted peer, or an error if the new peer should be rejected.
+    fn make_room_for_peer(&mut self) -> Result<Option<N::PeerId>, ()> {
+        let limit = match &self.config.peer_limit {
+            Some(limit) if self.connected_peers.len() >= limit.max_peers => limit,
+            _ => return Ok(None),
+        };
+        match limit.policy {
+            PeerLimitPolicy::RejectNew => Err(()),
+            PeerLimitPolicy::EvictLeastRecentlySeen => {
+                let evicted = self.least_recently_seen_peer().ok_or(())?;
+                self.close_stream(&evicted);
+                Ok(Some(evicted))
+            }
+        }
+    }
+
+    /// Starts sending to the peer, unless it is rejected because of the peer limit.
+    fn open_stream(&mut self, peer: N::PeerId) -> Opening<N::PeerId> {
+        if !self.is_allowed(&peer) {
+            debug!(
+                target: LOG_TARGET,
+                "Ignoring {:?} stream of peer {:?}, the peer is not on the allowlist.",
+                self.protocol,
+                self.peer_names.name(&peer)
+            );
+            return Opening::NotAllowed;
+        }
+        // A new stream needs a new handshake, so the sender cannot be reused then.
+        if self.pending_closes.remove(&peer).is_some() && self.config.versions.is_none() {
+            debug!(
+                target: LOG_TARGET,
+                "{:?} stream of peer {:?} reopened within the grace period, keeping its sender.",
+                self.protocol,
+                self.peer_names.name(&peer)
+            );
+            return Opening::Resumed;
+        }
+        let evicted = if self.connected_peers.contains(&peer) {
+            None
+        } else {
+            match self.make_room_for_peer() {
+                Ok(evicted) => evicted,
+                Err(()) => {
+                    debug!(
+                        target: LOG_TARGET,
+                        "Rejecting {:?} stream of peer {:?}, the peer limit is reached.",
+                        self.protocol,
+                        self.peer_names.name(&peer)
+                    );
+                    self.network.report_peer(peer, PEER_LIMIT_REACHED);
+                    return Opening::Rejected;
+                }
+            }
+        };
+        if let Some(evicted) = &evicted {
+            debug!(
+                target: LOG_TARGET,
+                "Evicted peer {:?} from {:?} protocol to make room for peer {:?}.",
+                self.peer_names.name(evicted),
+                self.protocol,
+                self.peer_names.name(&peer)
+            );
+        }
+        let (tx, rx) = queue::priority_channel(self.config.queue_capacity, self.config.drop_policy);
+        let newly_connected = self.connected_peers.insert(peer.clone());
+        if newly_connected {
+            self.peer_names.connected(&peer);
+        }
+        self.connected_peer_count
+            .store(self.connected_peers.len(), Ordering::Relaxed);
+        self.recently_disconnected.remove(&peer);
+        if newly_connected {
+            self.broadcast_order.push(peer.clone());
+            self.connected_at.insert(peer.clone(), time::Instant::now());
+        }
+        // The peer starts every stream with a handshake, reopened ones included, so the version
+        // gets negotiated again.
+        self.peer_versions.remove(&peer);
+        // Dropping the previous sender makes its peer sender exit once its queue is empty.
+        if let Some(previous) = self.peer_senders.insert(peer.clone(), tx) {
+            warn!(
+                target: LOG_TARGET,
+                "{:?} stream opened for peer {:?} that already had one, replacing its sender.",
+                self.protocol,
+                self.peer_names.name(&peer)
+            );
+            self.duplicate_stream_openings += 1;
+            self.requeue(&peer, previous);
+        }
+        if newly_connected {
+            self.replay_broadcasts(&peer);
+        }
+        let opened_at = self.metrics.now();
+        let latency = self.config.latency_tracking.as_ref().map(|config| {
+            let latency = SendLatency::new(config.smoothing);
+            self.send_latencies.insert(peer.clone(), latency.clone());
+            latency
+        });
+        let breaker = self.config.circuit_breaker.as_ref().map(|config| {
+            let breaker = CircuitBreaker::new(config);
+            self.circuit_breakers.insert(peer.clone(), breaker.clone());
+            breaker
+        });
+        let (liveness, guard) = Liveness::new();
+        self.peer_sender_liveness.insert(peer.clone(), liveness);
+        let (peer_sender, abort) = future::abortable(self.peer_sender(
+            peer.clone(),
+            rx,
+            latency,
+            breaker,
+            opened_at,
+            guard,
+        ));
+        // A replaced peer sender is not aborted, it still finishes sending what it already took
+        // out of its queue.
+        self.peer_sender_aborts.insert(peer, abort);
+        self.spawn_peer_sender(peer_sender.map(|_| ()).boxed());
+        if newly_connected {
+            Opening::Connected { evicted }
+        } else {
+            Opening::Reopened
+        }
+    }
+
+    /// Spawns the peer sender as a task of its own, or hands it over to the pooled task once the
+    /// limit of such tasks is reached.
+    fn spawn_peer_sender(&mut self, peer_sender: BoxFuture<'static, ()>) {
+        let limit = match self.config.max_sender_tasks {
+            Some(limit) => limit,
+            None => {
+                self.spawner
+                    .spawn(peer_sender_task_name(self.protocol), peer_sender);
+                return;
+            }
+        };
+        if self.sender_tasks.load(Ordering::SeqCst) < limit {
+            self.sender_tasks.fetch_add(1, Ordering::SeqCst);
+            let sender_tasks = self.sender_tasks.clone();
+            self.spawner.spawn(
+                peer_sender_task_name(self.protocol),
+                peer_sender
+                    .map(move |()| {
+                        sender_tasks.fetch_sub(1, Ordering::SeqCst);
+                    })
+                    .boxed(),
+            );
+            return;
+        }
+        let pool = match &self.sender_pool {
+            Some(pool) if !pool.is_closed() => pool,
+            _ => {
+                let (pool, peer_senders) = mpsc::unbounded();
+                self.spawner.spawn(
+                    pooled_peer_senders_task_name(self.protocol),
+                    run_pooled_peer_senders(peer_senders).boxed(),
+                );
+                self.sender_pool.insert(pool)
+            }
+        };
+        if let Err(e) = pool.unbounded_send(peer_sender) {
+            warn!(
+                target: LOG_TARGET,
+                "Pooled {:?} peer senders stopped, running the peer sender on its own.",
+                self.protocol
+            );
+            self.spawner
+                .spawn(peer_sender_task_name(self.protocol), e.into_inner());
+        }
+    }
+
+    /// Moves the messages still waiting in the queue of the replaced sender of the peer to the
+    /// queue of its current one, so that replacing the sender does not lose them.
+    fn requeue(&self, peer: &N::PeerId, previous: PrioritySender<Queued>) {
+        let sender = match self.peer_senders.get(peer) {
+            Some(sender) => sender,
+            None => return,
+        };
+        let mut requeued = 0;
+        let mut lost = 0;
+        for (queued, priority) in previous.drain() {
+            match sender.push(queued, priority) {
+                Ok(None) => requeued += 1,
+                Ok(Some(_)) | Err(_) => {
+                    self.metrics
+                        .report_message_popped_from_peer_sender_queue(self.protocol);
+                    self.metrics
+                        .report_message_dropped(self.protocol, DropReason::SendFailed);
+                    lost += 1;
+                }
+            }
+        }
+        if requeued + lost > 0 {
+            debug!(
+                target: LOG_TARGET,
+                "Moved {} messages to the new {:?} sender of peer {:?}, {} did not fit.",
+                requeued,
+                self.protocol,
+                self.peer_names.name(peer),
+                lost
+            );
+        }
+    }
+
+    /// Queues the most recent broadcasts for a newly connected peer.
+    fn replay_broadcasts(&mut self, peer: &N::PeerId) {
+        let replayed: Vec<_> = self.replay_buffer.iter().cloned().collect();
+        for data in replayed {
+            let encoded = self.encode(&data, Delivery::Gossip);
+            if let Err(e) = self.send_encoded_until(encoded, peer.clone(), Priority::Normal, None) {
+                debug!(
+                    target: LOG_TARGET,
+                    "Failed to replay broadcasts to peer {:?}: {}", self.peer_names.name(peer), e
+                );
+                return;
+            }
+        }
+    }
+
+    /// Remembers the broadcast data for replaying it to peers connecting later.
+    fn buffer_for_replay(&mut self, data: &D) {
+        if self.config.replay_buffer_size == 0 {
+            return;
+        }
+        if self.replay_buffer.len() >= self.config.replay_buffer_size {
+            self.replay_buffer.pop_front();
+        }
+        self.replay_buffer.push_back(data.clone());
+    }
+
+    /// Handles the stream of the peer closing. With a grace period configured, the peer only gets
+    /// closed once it passes without the stream being reopened. Returns whether the peer got
+    /// disconnected right away.
+    fn handle_stream_closed(&mut self, peer: N::PeerId) -> bool {
+        if self.config.close_grace_period.is_zero() || !self.connected_peers.contains(&peer) {
+            return self.close_stream(&peer);
+        }
+        let deadline = time::Instant::now() + self.config.close_grace_period;
+        self.pending_closes.entry(peer).or_insert(deadline);
+        false
+    }
+
+    /// When the earliest grace period of a closed stream passes.
+    fn next_pending_close(&self) -> Option<time::Instant> {
+        self.pending_closes.values().min().copied()
+    }
+
+    /// Closes the streams whose grace period passed, returns the peers that got disconnected.
+    fn close_expired_streams(&mut self) -> Vec<N::PeerId> {
+        let now = time::Instant::now();
+        let expired: Vec<_> = self
+            .pending_closes
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter(|peer| self.close_stream(peer))
+            .collect()
+    }
+
+    /// Updates the connectivity after peers connected or disconnected. Returns the event to
+    /// publish if it got regained, or lost right away because there is no debounce period.
+    fn update_connectivity(&mut self) -> Option<ConnectionEvent<N::PeerId>> {
+        use Connectivity::*;
+        match (self.connectivity, self.connected_peers.is_empty()) {
+            (Available, true) => {
+                self.connectivity =
+                    LosingAt(time::Instant::now() + self.config.connectivity_debounce);
+                self.check_connectivity()
+            }
+            (LosingAt(_), false) => {
+                trace!(
+                    target: LOG_TARGET,
+                    "A peer connected to {:?} protocol within the debounce period.",
+                    self.protocol
+                );
+                self.connectivity = Available;
+                None
+            }
+            (Lost, false) => {
+                info!(
+                    target: LOG_TARGET,
+                    "Regained connectivity in {:?} protocol.", self.protocol
+                );
+                self.connectivity = Available;
+                Some(ConnectionEvent::ProtocolConnectivityRegained(self.protocol))
+            }
+            (Available, false) | (LosingAt(_), true) | (Lost, true) => None,
+        }
+    }
+
+    /// When losing connectivity gets published, unless a peer connects before.
+    fn connectivity_deadline(&self) -> Option<time::Instant> {
+        match self.connectivity {
+            Connectivity::LosingAt(deadline) => Some(deadline),
+            Connectivity::Available | Connectivity::Lost => None,
+        }
+    }
+
+    /// Returns the event to publish if the connectivity got lost, because the debounce period
+    /// passed.
+    fn check_connectivity(&mut self) -> Option<ConnectionEvent<N::PeerId>> {
+        match self.connectivity_deadline() {
+            Some(deadline) if deadline <= time::Instant::now() => {
+                warn!(
+                    target: LOG_TARGET,
+                    "Lost connectivity in {:?} protocol, no peers are connected.", self.protocol
+                );
+                self.connectivity = Connectivity::Lost;
+                Some(ConnectionEvent::ProtocolConnectivityLost(self.protocol))
+            }
+            _ => None,
+        }
+    }
+
+    /// Stops sending to the peer, abandoning any messages that are still being sent or waiting
+    /// for it. Returns whether it was connected before.
+    fn close_stream(&mut self, peer: &N::PeerId) -> bool {
+        self.pending_closes.remove(peer);
+        if let Some(abort) = self.peer_sender_aborts.remove(peer) {
+            abort.abort();
+        }
+        self.peer_sender_liveness.remove(peer);
+        let was_connected = self.connected_peers.remove(peer);
+        self.connected_peer_count
+            .store(self.connected_peers.len(), Ordering::Relaxed);
+        if was_connected {
+            self.peer_names.disconnected(peer);
+            self.broadcast_order.retain(|connected| connected != peer);
+            self.remember_disconnection(peer);
+        }
+        if let Some(connected_at) = self.connected_at.remove(peer) {
+            self.metrics
+                .report_connection_duration(self.protocol, connected_at.elapsed());
+        }
+        self.peer_senders.remove(peer);
+        self.send_latencies.remove(peer);
+        self.circuit_breakers.remove(peer);
+        self.peer_versions.remove(peer);
+        self.timestamp_of_last_log_that_queue_is_full.remove(peer);
+        self.timestamp_of_last_valid_message_report.remove(peer);
+        self.last_seen.remove(peer);
+        self.inbound_limits.remove(peer);
+        if let Some(cache) = self.sent_cache.as_mut() {
+            cache.forget_peer(peer);
+        }
+        if let Some(reassembler) = self.reassembler.as_mut() {
+            reassembler.forget_peer(peer);
+        }
+        self.queue_growth.remove(peer);
+        was_connected
+    }
+
+    fn remember_disconnection(&mut self, peer: &N::PeerId) {
+        if let Some(tolerance) = self.config.unconnected_tolerance {
+            self.recently_disconnected
+                .retain(|_, disconnected_at| disconnected_at.elapsed() < tolerance);
+            self.recently_disconnected
+                .insert(peer.clone(), time::Instant::now());
+        }
+    }
+
+    /// Whether messages from the peer should be handled, which they always are when the
+    /// protocol does not require peers to be connected.
+    fn is_connected_or_tolerated(&self, peer: &N::PeerId) -> bool {
+        match self.config.unconnected_tolerance {
+            Some(tolerance) => {
+                self.connected_peers.contains(peer)
+                    || self
+                        .recently_disconnected
+                        .get(peer)
+                        .map_or(false, |disconnected_at| {
+                            disconnected_at.elapsed() < tolerance
+                        })
+            }
+            None => true,
+        }
+    }
+
+    fn set_queue_capacity(&mut self, capacity: usize) {
+        self.config.queue_capacity = capacity;
+        for sender in self.peer_senders.values() {
+            sender.set_capacity(capacity);
+        }
+    }
+
+    /// The number of messages queued for peers that were not handled by peer senders yet.
+    fn unflushed(&self) -> usize {
+        self.peer_senders
+            .values()
+            .map(|sender| sender.unfinished())
+            .sum()
+    }
+
+    /// Returns the number of peers with a non-empty queue and the length of the longest queue.
+    fn queue_depths(&self) -> (usize, usize) {
+        self.peer_senders
+            .values()
+            .map(|sender| sender.len())
+            .filter(|depth| *depth > 0)
+            .fold((0, 0), |(backlogged, max_depth), depth| {
+                (backlogged + 1, max_depth.max(depth))
+            })
+    }
+
+    /// Handles the commands already waiting in the channel from the user, at most the given number
+    /// of them and as long as the deadline has not passed. Returns whether the channel was
+    /// emptied.
+    fn handle_pending_commands(&mut self, deadline: time::Instant, limit: usize) -> bool {
+        for _ in 0..limit {
+            if time::Instant::now() >= deadline {
+                return false;
+            }
+            match self.messages_from_user.try_next() {
+                Ok(Some(command)) => self.handle_command(command),
+                // Either the user is gone, or there is nothing more to handle.
+                Ok(None) | Err(_) => return true,
+            }
+        }
+        false
+    }
+
+    /// Returns the number of peers that did not send us anything for longer than the threshold.
+    fn stale_peers(&self, threshold: Duration) -> usize {
+        self.last_seen
+            .values()
+            .filter(|t| t.elapsed() > threshold)
+            .count()
+    }
+
+    fn status(&self, stale_peer_threshold: Duration) -> String {
+        format!(
+            "{}longest connection - {}s; ",
+            self.tracked_status(stale_peer_threshold),
+            self.longest_connection().as_secs(),
+        )
+    }
+
+    /// The part of the status that does not change unless something happens.
+    fn tracked_status(&self, stale_peer_threshold: Duration) -> String {
+        let (backlogged, max_depth) = self.queue_depths();
+        format!(
+            "{} connected peers - {:?}; backlogged peers - {:?}; max queue - {:?}; stale peers - {:?}; duplicate stream openings - {:?}; slow peers - {:?}; received per second - {:.2}; broadcasts per second - {:.2}; open circuits - {:?}; bytes sent - {:?}; bytes received - {:?}; dead peer senders - {:?}; ",
+            status_name(self.protocol),
+            self.connected_peers.len(),
+            backlogged,
+            max_depth,
+            self.stale_peers(stale_peer_threshold),
+            self.duplicate_stream_openings,
+            self.slow_peers(),
+            self.received_rate.average(),
+            self.broadcast_rate.average(),
+            self.open_circuits(),
+            self.bytes_sent(),
+            self.bytes_received,
+            self.dead_peer_senders().len(),
+        )
+    }
+
+    /// How long the peer connected for the longest time has been connected.
+    fn longest_connection(&self) -> Duration {
+        self.connected_at
+            .values()
+            .map(|connected_at| connected_at.elapsed())
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Warns if the number of running peer senders differs from the number of peers with a
+    /// sender. Replaced peer senders may run for a short while, but a lasting difference means
+    /// peer senders leak or never start.
+    fn check_active_peer_senders(&self) {
+        let active = self.active_peer_senders.load(Ordering::Relaxed);
+        if active != self.peer_senders.len() {
+            warn!(
+                target: LOG_TARGET,
+                "{} {:?} peer senders are running for {} peers with a sender.",
+                active,
+                self.protocol,
+                self.peer_senders.len()
+            );
+        }
+    }
+
+    /// Peers with a sender whose peer sender is not running, so nothing gets sent to them.
+    fn dead_peer_senders(&self) -> Vec<N::PeerId> {
+        self.peer_sender_liveness
+            .iter()
+            .filter(|(_, liveness)| liveness.is_dead())
+            .map(|(peer, _)| peer.clone())
+            .collect()
+    }
+
+    fn open_circuits(&self) -> usize {
+        self.circuit_breakers
+            .values()
+            .filter(|breaker| !breaker.allows_send())
+            .count()
+    }
+
+    fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    fn snapshot(&self) -> ProtocolSnapshot {
+        let mut peers: Vec<_> = self
+            .connected_peers
+            .iter()
+            .map(|peer| PeerSnapshot {
+                peer_id: format!("{peer:?}"),
+                queue_depth: self.peer_senders.get(peer).map_or(0, |sender| sender.len()),
+                last_seen_millis_ago: self
+                    .last_seen
+                    .get(peer)
+                    .map(|seen| seen.elapsed().as_millis() as u64),
+            })
+            .collect();
+        peers.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+        ProtocolSnapshot {
+            connected_peers: self.connected_peers.len(),
+            received_per_second: self.received_rate.average(),
+            broadcasts_per_second: self.broadcast_rate.average(),
+            peers,
+        }
+    }
+
+    /// Warns about peers whose queue kept growing for the configured number of status reports.
+    /// Returns the ones that should be evicted.
+    fn check_queue_growth(&mut self) -> Vec<N::PeerId> {
+        let config = match &self.config.stuck_peer_detection {
+            Some(config) => config,
+            None => return Vec::new(),
+        };
+        let mut stuck = Vec::new();
+        for (peer, sender) in &self.peer_senders {
+            let depth = sender.len();
+            let growth = match self.queue_growth.get(peer) {
+                Some((previous, growth)) if depth > *previous => growth + 1,
+                _ => 0,
+            };
+            if growth >= config.growth_intervals {
+                warn!(
+                    target: LOG_TARGET,
+                    "Queue of {:?} peer {:?} grew for {} status reports in a row, up to {} messages, sending to it seems stuck.",
+                    self.protocol,
+                    self.peer_names.name(peer),
+                    growth,
+                    depth,
+                );
+                stuck.push(peer.clone());
+                self.queue_growth.insert(peer.clone(), (depth, 0));
+            } else {
+                self.queue_growth.insert(peer.clone(), (depth, growth));
+            }
+        }
+        if config.evict {
+            stuck
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Folds the messages received and broadcast since the previous status report into the
+    /// averages of their rates.
+    fn update_rates(&mut self) {
+        self.received_rate.update();
+        self.broadcast_rate.update();
+    }
+
+    fn possibly_reward_peer(&mut self, peer: N::PeerId) {
+        if self
+            .timestamp_of_last_valid_message_report
+            .get(&peer)
+            .map(|t| t.elapsed() >= VALID_MESSAGE_REPORT_INTERVAL)
+            .unwrap_or(true)
+        {
+            self.network.report_peer(peer.clone(), VALID_MESSAGE);
+            self.timestamp_of_last_valid_message_report
+                .insert(peer, Instant::now());
+        }
+    }
+
+    /// Whether the peer is on the allowlist, all peers are allowed without one.
+    fn is_allowed(&self, peer: &N::PeerId) -> bool {
+        self.allowlist
+            .as_ref()
+            .map(|allowlist| allowlist.contains(peer))
+            .unwrap_or(true)
+    }
+
+    fn report_malformed_message(&self, peer_id: N::PeerId, reason: DropReason) {
+        self.report_message_dropped_for(&peer_id, reason);
+        self.network.report_peer(peer_id, MALFORMED_MESSAGE);
+    }
+
+    /// Counts the message as dropped, also by whether the peer is in the authority set.
+    fn report_message_dropped_for(&self, peer: &N::PeerId, reason: DropReason) {
+        self.report_messages_dropped_for(peer, reason, 1)
+    }
+
+    fn report_messages_dropped_for(&self, peer: &N::PeerId, reason: DropReason, count: u64) {
+        self.metrics
+            .report_messages_dropped(self.protocol, reason, count);
+        self.metrics.report_authority_messages_dropped(
+            self.protocol,
+            self.authorities.contains(peer),
+            count,
+        );
+    }
+
+    fn subscribe(&mut self, buffer: usize) -> mpsc::Receiver<(D, N::PeerId)> {
+        let (tx, rx) = mpsc::channel(buffer);
+        self.subscribers.push(tx);
+        rx
+    }
+
+    fn route_kind(&mut self, kind: MessageKind) -> mpsc::UnboundedReceiver<(D, N::PeerId)> {
+        let (tx, rx) = mpsc::unbounded();
+        self.kind_routes.insert(kind, tx);
+        rx
+    }
+
+    /// Sends the message to the sink of its kind, returning it if there is none or it is gone.
+    fn route_by_kind(&mut self, kind: MessageKind, data: D, peer_id: &N::PeerId) -> Option<D> {
+        let sink = match self.kind_routes.get(&kind) {
+            Some(sink) => sink,
+            None => return Some(data),
+        };
+        match sink.unbounded_send((data, peer_id.clone())) {
+            Ok(()) => None,
+            Err(e) => {
+                self.kind_routes.remove(&kind);
+                Some(e.into_inner().0)
+            }
+        }
+    }
+
+    /// Sends a copy of the message to every subscriber, dropping it for the ones lagging behind
+    /// and forgetting the ones that are gone.
+    fn forward_to_subscribers(&mut self, data: &D, peer_id: &N::PeerId) {
+        let protocol = self.protocol;
+        let metrics = &self.metrics;
+        self.subscribers.retain_mut(|subscriber| {
+            match subscriber.try_send((data.clone(), peer_id.clone())) {
+                Ok(()) => true,
+                Err(e) if e.is_full() => {
+                    metrics.report_message_dropped(protocol, DropReason::SlowSubscriber);
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+    }
+
+    /// Reports a message that could not be decoded, also to the decode failure channel.
+    fn report_decode_failure(
+        &mut self,
+        peer_id: N::PeerId,
+        bytes_len: usize,
+        kind: MessageKind,
+        error: String,
+    ) {
+        self.metrics.report_decode_error(self.protocol, kind);
+        if let Some(decode_failures) = &self.decode_failures {
+            let failure = DecodeFailure {
+                peer: peer_id.clone(),
+                protocol: self.protocol,
+                bytes_len,
+                kind,
+                error,
+            };
+            if decode_failures.unbounded_send(failure).is_err() {
+                debug!(
+                    target: LOG_TARGET,
+                    "Decode failure receiver dropped, no longer reporting {:?} decode failures.",
+                    self.protocol
+                );
+                self.decode_failures = None;
+            }
+        }
+        self.report_malformed_message(peer_id, DropReason::DecodeError);
+    }
+
+    /// The length of the longest received message that is decoded.
+    fn message_size_limit(&self) -> usize {
+        self.config
+            .max_message_size
+            .unwrap_or_else(|| max_message_size(self.protocol) as usize)
+    }
+
+    /// Whether the peer can send us another message without exceeding the rate limit.
+    fn within_rate_limit(&mut self, peer: &N::PeerId) -> bool {
+        let config = match &self.config.inbound_rate_limit {
+            Some(config) => config,
+            None => return true,
+        };
+        self.inbound_limits
+            .entry(peer.clone())
+            .or_insert_with(|| TokenBucket::new(config))
+            .try_take()
+    }
+
+    /// Negotiates the version with the peer, messages from it are dropped if that fails.
+    fn handle_handshake(&mut self, peer_id: N::PeerId, data: &[u8]) {
+        let versions = match &self.config.versions {
+            Some(versions) => versions,
+            None => return,
+        };
+        let version = match handshake::negotiate(versions, data) {
+            Ok(version) => {
+                debug!(
+                    target: LOG_TARGET,
+                    "Using {:?} protocol version {} with peer {:?}.", self.protocol, version, self.peer_names.name(&peer_id)
+                );
+                Some(version)
+            }
+            Err(HandshakeError::Incompatible(version)) => {
+                warn!(
+                    target: LOG_TARGET,
+                    "Peer {:?} supports at most {:?} protocol version {}, below our minimum of {}, dropping its messages.",
+                    self.peer_names.name(&peer_id),
+                    self.protocol,
+                    version,
+                    versions.min
+                );
+                self.network
+                    .report_peer(peer_id.clone(), INCOMPATIBLE_VERSION);
+                None
+            }
+            Err(e) => {
+                warn!(
+                    target: LOG_TARGET,
+                    "Error negotiating {:?} protocol version with peer {:?}, dropping its messages: {}",
+                    self.protocol,
+                    self.peer_names.name(&peer_id),
+                    e
+                );
+                self.report_decode_failure(
+                    peer_id.clone(),
+                    data.len(),
+                    MessageKind::Unknown,
+                    e.to_string(),
+                );
+                None
+            }
+        };
+        self.peer_versions.insert(peer_id, version);
+    }
+
+    /// Whether a network message with the given hash was received recently, remembering it if
+    /// not. Network messages containing excluded kinds are never duplicates.
+    fn is_duplicate<'a>(
+        &mut self,
+        hash: Option<PayloadHash>,
+        mut messages: impl Iterator<Item = &'a D>,
+    ) -> bool {
+        let (hash, cache, dedup_config) = match (
+            hash,
+            self.inbound_cache.as_mut(),
+            self.config.inbound_dedup.as_ref(),
+        ) {
+            (Some(hash), Some(cache), Some(dedup_config)) => (hash, cache, dedup_config),
+            _ => return false,
+        };
+        let codec = &self.codec;
+        if messages.any(|data| dedup_config.excluded_kinds.contains(&codec.kind(data))) {
+            return false;
+        }
+        cache.check_and_record(hash)
+    }
+
+    fn handle_message(&mut self, peer_id: N::PeerId, data: Bytes) -> Result<(), ()> {
+        self.bytes_received += data.len() as u64;
+        if !data.is_empty() {
+            self.metrics
+                .report_received_message_size(self.protocol, data.len());
+        }
+        if self.config.direction == Direction::SendOnly {
+            trace!(
+                target: LOG_TARGET,
+                "Dropping {:?} message from peer {:?}, the protocol is send-only.",
+                self.protocol,
+                self.peer_names.name(&peer_id)
+            );
+            self.report_message_dropped_for(&peer_id, DropReason::WrongDirection);
+            return Ok(());
+        }
+        if !self.is_connected_or_tolerated(&peer_id) {
+            trace!(
+                target: LOG_TARGET,
+                "Dropping {:?} message from peer {:?}, the peer is not connected.",
+                self.protocol,
+                self.peer_names.name(&peer_id)
+            );
+            self.report_message_dropped_for(&peer_id, DropReason::NotConnected);
+            return Ok(());
+        }
+        if !self.is_allowed(&peer_id) {
+            trace!(
+                target: LOG_TARGET,
+                "Dropping {:?} message from peer {:?}, the peer is not on the allowlist.",
+                self.protocol,
+                self.peer_names.name(&peer_id)
+            );
+            self.report_message_dropped_for(&peer_id, DropReason::NotAllowed);
+            return Ok(());
+        }
+        if self.paused {
+            trace!(
+                target: LOG_TARGET,
+                "Dropping {:?} message from peer {:?}, the protocol is paused.",
+                self.protocol,
+                self.peer_names.name(&peer_id)
+            );
+            self.report_message_dropped_for(&peer_id, DropReason::Paused);
+            return Ok(());
+        }
+        // Unconnected peers would never be forgotten, as only closing a stream removes them.
+        if self.connected_peers.contains(&peer_id) {
+            self.last_seen.insert(peer_id.clone(), Instant::now());
+        }
+        if data.is_empty() {
+            trace!(
+                target: LOG_TARGET,
+                "Received {:?} keepalive from peer {:?}.",
+                self.protocol,
+                self.peer_names.name(&peer_id)
+            );
+            return Ok(());
+        }
+        if self.config.versions.is_some() {
+            match self.peer_versions.get(&peer_id) {
+                None => {
+                    self.handle_handshake(peer_id, &data);
+                    return Ok(());
+                }
+                Some(None) => {
+                    trace!(
+                        target: LOG_TARGET,
+                        "Dropping {:?} message from peer {:?}, its version is incompatible.",
+                        self.protocol,
+                        self.peer_names.name(&peer_id)
+                    );
+                    self.report_message_dropped_for(&peer_id, DropReason::IncompatibleVersion);
+                    return Ok(());
+                }
+                Some(Some(_)) => {}
+            }
+        }
+        let data = match self.reassembler.as_mut() {
+            Some(reassembler) => {
+                let expired = reassembler.remove_expired();
+                if expired > 0 {
+                    self.metrics.report_messages_dropped(
+                        self.protocol,
+                        DropReason::Incomplete,
+                        expired as u64,
+                    );
+                }
+                match reassembler.push(peer_id.clone(), &data) {
+                    Ok(Reassembly::Complete(message)) => Bytes::from(message),
+                    Ok(Reassembly::Incomplete { evicted: 0 }) => return Ok(()),
+                    Ok(Reassembly::Incomplete { evicted }) => {
+                        debug!(
+                            target: LOG_TARGET,
+                            "Evicted {} incomplete {:?} message(s) of peer {:?}, it started too many.",
+                            evicted,
+                            self.protocol,
+                            self.peer_names.name(&peer_id)
+                        );
+                        self.report_messages_dropped_for(
+                            &peer_id,
+                            DropReason::Evicted,
+                            evicted as u64,
+                        );
+                        self.network.report_peer(peer_id, REASSEMBLY_LIMIT_EXCEEDED);
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        warn!(
+                            target: LOG_TARGET,
+                            "Dropping malformed {:?} frame from peer {:?}: {}.",
+                            self.protocol,
+                            self.peer_names.name(&peer_id),
+                            e
+                        );
+                        self.report_malformed_message(peer_id, DropReason::DecodeError);
+                        return Ok(());
+                    }
+                }
+            }
+            None => data,
+        };
+        let (data, previous_version) = match self.config.encoding_version {
+            Some(current) => match data.first() {
+                Some(version) if *version == current => (data.slice(1..), false),
+                Some(version) if Some(*version) == current.checked_sub(1) => {
+                    (data.slice(1..), true)
+                }
+                version => {
+                    debug!(
+                        target: LOG_TARGET,
+                        "Dropping {:?} message from peer {:?} of unknown encoding version {:?}.",
+                        self.protocol,
+                        self.peer_names.name(&peer_id),
+                        version
+                    );
+                    self.report_message_dropped_for(&peer_id, DropReason::UnknownEncodingVersion);
+                    return Ok(());
+                }
+            },
+            None => (data, false),
+        };
+        self.metrics.report_message_received(self.protocol);
+        self.metrics
+            .report_authority_message_received(self.protocol, self.authorities.contains(&peer_id));
+        self.received_rate.record();
+        if !self.within_rate_limit(&peer_id) {
+            trace!(
+                target: LOG_TARGET,
+                "Dropping {:?} message from peer {:?}, the peer exceeded the rate limit.",
+                self.protocol,
+                self.peer_names.name(&peer_id)
+            );
+            self.report_message_dropped_for(&peer_id, DropReason::RateLimited);
+            self.network.report_peer(peer_id, RATE_LIMIT_EXCEEDED);
+            return Ok(());
+        }
+        let max_size = self.message_size_limit();
+        if data.len() > max_size {
+            warn!(
+                target: LOG_TARGET,
+                "Rejecting {:?} protocol message of {} bytes, the limit is {}.",
+                self.protocol,
+                data.len(),
+                max_size
+            );
+            self.report_malformed_message(peer_id, DropReason::TooLarge);
+            return Ok(());
+        }
+        let received_hash = self
+            .inbound_cache
+            .is_some()
+            .then(|| dedup::payload_hash(&data));
+        let bytes_len = data.len();
+        let data = match self.config.compression {
+            Some(_) => match compression::decompress(&data, max_size) {
+                Ok(data) => Bytes::from(data.into_owned()),
+                Err(e) => {
+                    warn!(
+                        target: LOG_TARGET,
+                        "Error decompressing {:?} protocol message: {}", self.protocol, e
+                    );
+                    self.report_decode_failure(
+                        peer_id,
+                        bytes_len,
+                        MessageKind::Unknown,
+                        e.to_string(),
+                    );
+                    return Ok(());
+                }
+            },
+            None => data,
+        };
+        let data = match &self.signing {
+            Some(signing) => match signing.verify(&data) {
+                Some(payload) => Bytes::from(payload),
+                None => {
+                    warn!(
+                        target: LOG_TARGET,
+                        "Dropping {:?} protocol message from peer {:?} with an invalid signature.",
+                        self.protocol,
+                        self.peer_names.name(&peer_id)
+                    );
+                    self.report_message_dropped_for(&peer_id, DropReason::InvalidSignature);
+                    self.network.report_peer(peer_id, INVALID_SIGNATURE);
+                    return Ok(());
+                }
+            },
+            None => data,
+        };
+        let codec = match previous_version {
+            true => &*self.previous_codec,
+            false => &*self.codec,
+        };
+        let decoded = match (&self.config.batching, self.config.delivery_tags) {
+            (Some(_), true) => codec::decode_tagged_batch(codec, &data).map(with_deliveries),
+            (Some(_), false) => codec::decode_batch(codec, &data)
+                .map(|messages| messages.into_iter().map(|data| (data, None)).collect()),
+            (None, true) => {
+                codec::decode_tagged(codec, &data).map(|message| with_deliveries([message]))
+            }
+            (None, false) => codec::decode(codec, &data).map(|data| vec![(data, None)]),
+        };
+        match decoded {
+            Ok(messages) => {
+                if self.is_duplicate(received_hash, messages.iter().map(|(data, _)| data)) {
+                    trace!(
+                        target: LOG_TARGET,
+                        "Dropping {:?} message from peer {:?}, it was already received.",
+                        self.protocol,
+                        self.peer_names.name(&peer_id)
+                    );
+                    self.report_messages_dropped_for(
+                        &peer_id,
+                        DropReason::Duplicate,
+                        messages.len() as u64,
+                    );
+                    return Ok(());
+                }
+                self.possibly_reward_peer(peer_id.clone());
+                for (data, delivery) in messages {
+                    let kind = self.codec.kind(&data);
+                    self.metrics.report_message_decoded(self.protocol, kind);
+                    self.forward_to_subscribers(&data, &peer_id);
+                    let data = match self.route_by_kind(kind, data, &peer_id) {
+                        Some(data) => data,
+                        None => continue,
+                    };
+                    self.messages_for_user
+                        .unbounded_send((data, peer_id.clone(), delivery))
+                        .map_err(|_| ())?;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                warn!(
+                    target: LOG_TARGET,
+                    "Error decoding {:?} protocol message: {}", self.protocol, e
+                );
+                // The beginning of a batch says nothing about the kinds of its messages.
+                let kind = match (&self.config.batching, self.config.delivery_tags) {
+                    (Some(_), _) => MessageKind::Unknown,
+                    (None, true) => self.codec.encoded_kind(data.get(1..).unwrap_or_default()),
+                    (None, false) => self.codec.encoded_kind(&data),
+                };
+                self.report_decode_failure(peer_id, bytes_len, kind, e.to_string());
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<N: RawNetwork, ES: EventStream<N::PeerId>, AD: Data, BSD: Data> Service<N, ES, AD, BSD> {
+    /// Creates the service. When a broadcast feedback channel is given, the result of every
+    /// broadcast requested by users is published to it. When the local peer id is given, the
+    /// service never connects to itself. When a decode failure channel is given, every received
+    /// message that cannot be decoded is reported to it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        network: N,
+        network_event_stream: ES,
+        spawn_handle: SpawnHandle,
+        metrics_registry: Option<Registry>,
+        config: Config,
+        broadcast_feedback: Option<mpsc::UnboundedSender<BroadcastResult<N::PeerId>>>,
+        local_peer_id: Option<N::PeerId>,
+        decode_failures: Option<mpsc::UnboundedSender<DecodeFailure<N::PeerId>>>,
+    ) -> (
+        Self,
+        impl Network<AD, Error = Error, PeerId = N::PeerId>,
+        impl Network<BSD, Error = Error, PeerId = N::PeerId>,
+    ) {
+        Self::new_with_codecs(
+            network,
+            network_event_stream,
+            spawn_handle,
+            metrics_registry,
+            config,
+            broadcast_feedback,
+            local_peer_id,
+            decode_failures,
+            ScaleCodec,
+            ScaleCodec,
+            None,
+        )
+    }
+
+    /// Like `new`, but the data of each protocol is sent using the given codec instead of the
+    /// SCALE encoding. With signing given, authentication messages are sent signed and received
+    /// ones without a valid signature are dropped, so all nodes of a network have to agree on it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_with_codecs(
+        network: N,
+        network_event_stream: ES,
+        spawn_handle: SpawnHandle,
+        metrics_registry: Option<Registry>,
+        config: Config,
+        broadcast_feedback: Option<mpsc::UnboundedSender<BroadcastResult<N::PeerId>>>,
+        local_peer_id: Option<N::PeerId>,
+        decode_failures: Option<mpsc::UnboundedSender<DecodeFailure<N::PeerId>>>,
+        authentication_codec: impl Codec<AD>,
+        block_sync_codec: impl Codec<BSD>,
+        authentication_signing: Option<MessageSigning>,
+    ) -> (
+        Self,
+        impl Network<AD, Error = Error, PeerId = N::PeerId>,
+        impl Network<BSD, Error = Error, PeerId = N::PeerId>,
+    ) {
+        let metrics = match Metrics::new(metrics_registry) {
+            Ok(metrics) => metrics,
+            Err(e) => {
+                warn!(target: LOG_TARGET, "Failed to create metrics: {e}.");
+                Metrics::noop()
+            }
+        };
+        let spawner: Arc<dyn Spawner> = Arc::new(spawn_handle);
+        let (mut authentication, authentication_interface) = ProtocolState::new(
+            Protocol::Authentication,
+            config.protocol(Protocol::Authentication).clone(),
+            Arc::new(authentication_codec),
+            network.clone(),
+            spawner.clone(),
+            metrics.clone(),
+            broadcast_feedback.clone(),
+        );
+        authentication.signing = authentication_signing;
+        authentication.decode_failures = decode_failures.clone();
+        let in_flight = config
+            .max_in_flight_messages
+            .map(|limit| Arc::new(Semaphore::new(limit)));
+        let drain_scheduler = in_flight.as_ref().and_then(|budget| {
+            config
+                .fair_drain
+                .as_ref()
+                .map(|fair_drain| DrainScheduler::new(budget.clone(), fair_drain))
+        });
+        authentication.in_flight = in_flight.clone();
+        authentication.drain_scheduler = drain_scheduler.clone();
+        let (mut block_sync, block_sync_interface) = ProtocolState::new(
+            Protocol::BlockSync,
+            config.protocol(Protocol::BlockSync).clone(),
+            Arc::new(block_sync_codec),
+            network,
+            spawner,
+            metrics,
+            broadcast_feedback,
+        );
+        block_sync.decode_failures = decode_failures;
+        let peer_names = authentication.peer_names.clone();
+        block_sync.peer_names = peer_names.clone();
+        block_sync.in_flight = in_flight;
+        block_sync.drain_scheduler = drain_scheduler;
+        let (queries_for_service, queries_from_handles) = mpsc::unbounded();
+        (
+            Service {
+                authentication,
+                block_sync,
+                network_event_stream,
+                shutdown_grace_period: config.shutdown_grace_period,
+                status_report_interval: config.status_report_interval,
+                status_report_jitter: config.status_report_jitter,
+                quiet_status_reports: config.quiet_status_reports.clone(),
+                adaptive_status_reports: config.adaptive_status_reports.clone(),
+                last_status_report: None,
+                stale_peer_threshold: config.stale_peer_threshold,
+                queries_from_handles,
+                queries_for_service,
+                connection_event_sinks: Vec::new(),
+                local_peer_id,
+                peer_names,
+                termination_listeners: Vec::new(),
+                blocklist: HashSet::new(),
+                pending_flushes: Vec::new(),
+                flushes_checked_at: time::Instant::now(),
+            },
+            authentication_interface,
+            block_sync_interface,
+        )
+    }
+
+    /// Creates the service with the default codecs and runs it on the given spawn handle, so
+    /// that it cannot be forgotten. The returned handle stops it, dropping the handle stops it as
+    /// well, without waiting.
+    pub fn spawn(
+        network: N,
+        network_event_stream: ES,
+        spawn_handle: SpawnHandle,
+        metrics_registry: Option<Registry>,
+        config: Config,
+    ) -> (
+        impl Network<AD, Error = Error, PeerId = N::PeerId>,
+        impl Network<BSD, Error = Error, PeerId = N::PeerId>,
+        StopHandle,
+    )
+    where
+        ES: Send + 'static,
+    {
+        let (service, authentication_network, block_sync_network) = Self::new(
+            network,
+            network_event_stream,
+            spawn_handle.clone(),
+            metrics_registry,
+            config,
+            None,
+            None,
+            None,
+        );
+        let (exit_tx, exit_rx) = oneshot::channel();
+        let (stopped_tx, stopped_rx) = oneshot::channel();
+        SpawnHandleT::spawn(&spawn_handle, "aleph/gossip_network", async move {
+            let result = service.run(exit_rx).await;
+            if let Err(e) = &result {
+                warn!(target: LOG_TARGET, "Gossip network service stopped: {}.", e);
+            }
+            let _ = stopped_tx.send(result);
+        });
+        (
+            authentication_network,
+            block_sync_network,
+            StopHandle {
+                exit: exit_tx,
+                stopped: stopped_rx,
+            },
+        )
+    }
+
+    fn handle_network_event(&mut self, event: Event<N::PeerId>) -> Result<(), ()> {
+        use Event::*;
+        let _span = match &event {
+            StreamOpened(peer, _) | StreamClosed(peer, _) | Messages(peer, _) => {
+                network_event_span(peer).entered()
+            }
+        };
+        match &event {
+            StreamOpened(peer, _) | Messages(peer, _)
+                if self.local_peer_id.as_ref() == Some(peer) =>
+            {
+                warn!(
+                    target: LOG_TARGET,
+                    "Ignoring a network event claiming we are our own peer {:?}.", peer
+                );
+                return Ok(());
+            }
+            StreamOpened(peer, protocol) if self.blocklist.contains(peer) => {
+                debug!(
+                    target: LOG_TARGET,
+                    "Refusing {:?} stream of blocked peer {:?}.",
+                    protocol,
+                    self.peer_names.name(peer)
+                );
+                self.authentication.network.disconnect_peer(peer.clone());
+                return Ok(());
+            }
+            Messages(peer, messages) if self.blocklist.contains(peer) => {
+                trace!(
+                    target: LOG_TARGET,
+                    "Dropping {} message(s) from blocked peer {:?}.",
+                    messages.len(),
+                    self.peer_names.name(peer)
+                );
+                for (protocol, _) in messages {
+                    match protocol {
+                        Protocol::Authentication => self
+                            .authentication
+                            .report_message_dropped_for(peer, DropReason::Blocked),
+                        Protocol::BlockSync => self
+                            .block_sync
+                            .report_message_dropped_for(peer, DropReason::Blocked),
+                    }
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+        match event {
+            StreamOpened(peer, protocol) => {
+                trace!(
+                    target: LOG_TARGET,
+                    "StreamOpened event for peer {:?} and the protocol {:?}.",
+                    self.peer_names.name(&peer),
+                    protocol
+                );
+                let opening = match protocol {
+                    Protocol::Authentication => self.authentication.open_stream(peer.clone()),
+                    Protocol::BlockSync => self.block_sync.open_stream(peer.clone()),
+                };
+                if let Opening::Connected { evicted } = opening {
+                    if let Some(evicted) = evicted {
+                        self.publish_connection_event(ConnectionEvent::PeerDisconnected(
+                            evicted, protocol,
+                        ));
+                    }
+                    self.publish_connection_event(ConnectionEvent::PeerConnected(peer, protocol));
+                }
+            }
+            StreamClosed(peer, protocol) => {
+                trace!(
+                    target: LOG_TARGET,
+                    "StreamClosed event for peer {:?} and protocol {:?}",
+                    self.peer_names.name(&peer),
+                    protocol
+                );
+                let was_connected = match protocol {
+                    Protocol::Authentication => {
+                        self.authentication.handle_stream_closed(peer.clone())
+                    }
+                    Protocol::BlockSync => self.block_sync.handle_stream_closed(peer.clone()),
+                };
+                if was_connected {
+                    self.publish_connection_event(ConnectionEvent::PeerDisconnected(
+                        peer, protocol,
+                    ));
+                }
+            }
+            Messages(peer_id, messages) => {
+                for (protocol, data) in messages.into_iter() {
+                    match protocol {
+                        Protocol::Authentication => {
+                            self.authentication.handle_message(peer_id.clone(), data)?
+                        }
+                        Protocol::BlockSync => {
+                            self.block_sync.handle_message(peer_id.clone(), data)?
+                        }
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the service run the tasks sending to peers with the given spawner, instead of the
+    /// spawn handle it was created with. Only affects peers connecting afterwards.
+    pub fn set_spawner(&mut self, spawner: impl Spawner) {
+        let spawner: Arc<dyn Spawner> = Arc::new(spawner);
+        self.authentication.spawner = spawner.clone();
+        self.block_sync.spawner = spawner;
+    }
+
+    /// Makes broadcasts go to more important peers first and the peer limit evict less important
+    /// peers first, with peers ordered first by the comparison being the most important. Peers
+    /// still take turns and get evicted by when they were seen last among equally important ones.
+    pub fn set_peer_priority(
+        &mut self,
+        priority: impl Fn(&N::PeerId, &N::PeerId) -> cmp::Ordering + Send + Sync + 'static,
+    ) {
+        let priority: PeerPriority<N::PeerId> = Arc::new(priority);
+        self.authentication.peer_priority = Some(priority.clone());
+        self.block_sync.peer_priority = Some(priority);
+    }
+
+    /// Makes broadcasts queue the data for peers sorted by their ids, so that tests can rely on
+    /// the order.
+    #[cfg(test)]
+    fn sort_broadcasts(&mut self)
+    where
+        N::PeerId: Ord,
+    {
+        self.set_peer_priority(|a, b| a.cmp(b));
+    }
+
+    /// Makes peer senders call the tap with every message right before sending it, including
+    /// handshakes and keepalives, exactly as it goes on the wire. Only peers connecting afterwards
+    /// are tapped. The tap is called on the send path, so anything expensive should be done in a
+    /// separate task.
+    pub fn set_outgoing_tap(
+        &mut self,
+        tap: impl Fn(&N::PeerId, Protocol, &[u8]) + Send + Sync + 'static,
+    ) {
+        let tap: OutgoingTap<N::PeerId> = Arc::new(tap);
+        self.authentication.outgoing_tap = Some(tap.clone());
+        self.block_sync.outgoing_tap = Some(tap);
+    }
+
+    /// Returns a receiver told why the service stopped once `run` returns, so that supervisors can
+    /// drop their handles to a dead service. If the service is dropped without running to the
+    /// end, the receiver is cancelled instead.
+    pub fn termination_signal(&mut self) -> oneshot::Receiver<TerminationReason> {
+        let (tx, rx) = oneshot::channel();
+        self.termination_listeners.push(tx);
+        rx
+    }
+
+    /// Returns a stream of copies of the authentication messages forwarded to the user, starting
+    /// with the next one. Once more than `buffer` messages wait for the subscriber, further ones
+    /// are dropped for it without affecting the user. Subscribers that are dropped stop getting
+    /// the messages.
+    pub fn subscribe_authentication_messages(
+        &mut self,
+        buffer: usize,
+    ) -> mpsc::Receiver<(AD, N::PeerId)> {
+        self.authentication.subscribe(buffer)
+    }
+
+    /// Returns a stream of copies of the block sync messages forwarded to the user, the same way
+    /// as for authentication messages.
+    pub fn subscribe_block_sync_messages(
+        &mut self,
+        buffer: usize,
+    ) -> mpsc::Receiver<(BSD, N::PeerId)> {
+        self.block_sync.subscribe(buffer)
+    }
+
+    /// Returns a stream of the authentication messages of the given kind, which the user no
+    /// longer gets, starting with the next one. Registering the kind again replaces the previous
+    /// stream. Once the stream is dropped, messages of the kind go to the user again.
+    pub fn route_authentication_messages(
+        &mut self,
+        kind: MessageKind,
+    ) -> mpsc::UnboundedReceiver<(AD, N::PeerId)> {
+        self.authentication.route_kind(kind)
+    }
+
+    /// Returns a stream of the block sync messages of the given kind, the same way as for
+    /// authentication messages.
+    pub fn route_block_sync_messages(
+        &mut self,
+        kind: MessageKind,
+    ) -> mpsc::UnboundedReceiver<(BSD, N::PeerId)> {
+        self.block_sync.route_kind(kind)
+    }
+
+    /// Returns a stream of peers connecting and disconnecting, starting with the next change.
+    /// Subscribers that are dropped stop getting the events.
+    pub fn subscribe_connection_events(
+        &mut self,
+    ) -> mpsc::UnboundedReceiver<ConnectionEvent<N::PeerId>> {
+        let (tx, rx) = mpsc::unbounded();
+        self.connection_event_sinks.push(tx);
+        rx
+    }
+
+    fn next_pending_close(&self) -> Option<time::Instant> {
+        [
+            self.authentication.next_pending_close(),
+            self.block_sync.next_pending_close(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Closes the streams that were not reopened within their grace period.
+    fn close_expired_streams(&mut self) {
+        for peer in self.authentication.close_expired_streams() {
+            self.publish_connection_event(ConnectionEvent::PeerDisconnected(
+                peer,
+                Protocol::Authentication,
+            ));
+        }
+        for peer in self.block_sync.close_expired_streams() {
+            self.publish_connection_event(ConnectionEvent::PeerDisconnected(
+                peer,
+                Protocol::BlockSync,
+            ));
+        }
+    }
+
+    fn publish_connection_event(&mut self, event: ConnectionEvent<N::PeerId>) {
+        use ConnectionEvent::*;
+        let changed_protocol = match &event {
+            PeerConnected(_, protocol) | PeerDisconnected(_, protocol) => Some(*protocol),
+            ProtocolConnectivityLost(_) | ProtocolConnectivityRegained(_) => None,
+        };
+        self.connection_event_sinks
+            .retain(|sink| sink.unbounded_send(event.clone()).is_ok());
+        let change = match changed_protocol {
+            Some(Protocol::Authentication) => self.authentication.update_connectivity(),
+            Some(Protocol::BlockSync) => self.block_sync.update_connectivity(),
+            None => None,
+        };
+        if let Some(change) = change {
+            self.publish_connection_event(change);
+        }
+    }
+
+    fn next_rebroadcast(&self) -> Option<time::Instant> {
+        [
+            self.authentication.next_rebroadcast(),
+            self.block_sync.next_rebroadcast(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    fn rebroadcast_due(&mut self) {
+        self.authentication.rebroadcast_due();
+        self.block_sync.rebroadcast_due();
+    }
+
+    fn next_connectivity_deadline(&self) -> Option<time::Instant> {
+        [
+            self.authentication.connectivity_deadline(),
+            self.block_sync.connectivity_deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Publishes losing connectivity by the protocols that had no peers for their debounce period.
+    fn check_connectivity(&mut self) {
+        let changes = [
+            self.authentication.check_connectivity(),
+            self.block_sync.check_connectivity(),
+        ];
+        for change in changes.into_iter().flatten() {
+            self.publish_connection_event(change);
+        }
+    }
+
+    /// Returns a handle for querying the service once it is running.
+    pub fn handle(&self) -> ServiceHandle<N::PeerId, N::Address> {
+        ServiceHandle {
+            queries_for_service: self.queries_for_service.clone(),
+        }
+    }
+
+    /// Returns the peers currently connected on the given protocol.
+    pub fn connected_peers(&self, protocol: Protocol) -> Vec<N::PeerId> {
+        match protocol {
+            Protocol::Authentication => self
+                .authentication
+                .connected_peers
+                .iter()
+                .cloned()
+                .collect(),
+            Protocol::BlockSync => self.block_sync.connected_peers.iter().cloned().collect(),
+        }
+    }
+
+    /// Returns whether the peer is currently connected on the given protocol, so that data sent
+    /// to it is queued for sending.
+    pub fn is_connected(&self, peer: &N::PeerId, protocol: Protocol) -> bool {
+        match protocol {
+            Protocol::Authentication => self.authentication.connected_peers.contains(peer),
+            Protocol::BlockSync => self.block_sync.connected_peers.contains(peer),
+        }
+    }
+
+    /// Returns the number of connected peers for every protocol.
+    pub fn connected_peer_counts(&self) -> HashMap<Protocol, usize> {
+        HashMap::from([
+            (
+                Protocol::Authentication,
+                self.authentication.connected_peers.len(),
+            ),
+            (Protocol::BlockSync, self.block_sync.connected_peers.len()),
+        ])
+    }
+
+    /// Returns when each peer connected on the given protocol last sent us a message.
+    pub fn last_seen(&self, protocol: Protocol) -> HashMap<N::PeerId, Instant> {
+        match protocol {
+            Protocol::Authentication => self.authentication.last_seen.clone(),
+            Protocol::BlockSync => self.block_sync.last_seen.clone(),
+        }
+    }
+
+    /// Returns the connected peer with the lowest average send latency on the given protocol,
+    /// ties going to the more important peer, see `set_peer_priority`, and then to the peer
+    /// connected first. Only peers something was sent to with latency tracking enabled are
+    /// considered.
+    pub fn best_peer(&self, protocol: Protocol) -> Option<N::PeerId> {
+        match protocol {
+            Protocol::Authentication => self.authentication.best_peer(),
+            Protocol::BlockSync => self.block_sync.best_peer(),
+        }
+    }
+
+    /// Restricts the peers allowed on the given protocol to the ones on the allowlist, `None`
+    /// allows all peers. Messages from other peers are dropped and their streams are not opened,
+    /// peers that are already connected stay connected.
+    pub fn set_allowlist(&mut self, protocol: Protocol, allowlist: Option<HashSet<N::PeerId>>) {
+        match protocol {
+            Protocol::Authentication => self.authentication.allowlist = allowlist,
+            Protocol::BlockSync => self.block_sync.allowlist = allowlist,
+        }
+    }
+
+    /// Replaces the peers refused on all the protocols. Unlike peers missing from an allowlist,
+    /// blocked peers that are connected get disconnected right away, and whenever they connect
+    /// again they get no sender and are disconnected by the network, while their messages are
+    /// dropped. The blocklist stays until it is replaced.
+    pub fn set_blocklist(&mut self, blocklist: HashSet<N::PeerId>) {
+        let connected: Vec<_> = blocklist
+            .iter()
+            .filter(|peer| {
+                self.is_connected(peer, Protocol::Authentication)
+                    || self.is_connected(peer, Protocol::BlockSync)
+            })
+            .cloned()
+            .collect();
+        self.blocklist = blocklist;
+        for peer in connected {
+            self.disconnect_peer(peer);
+        }
+    }
+
+    /// Replaces the peers in the current authority set. Messages sent to, received from and
+    /// dropped for peers are counted by whether the peer is in the set, e.g. for dashboards
+    /// focused on validators. Drops of messages that already waited in the queue of a peer are
+    /// not broken down.
+    pub fn set_authorities(&mut self, authorities: HashSet<N::PeerId>) {
+        self.authentication.authorities = authorities.clone();
+        self.block_sync.authorities = authorities;
+    }
+
+    /// Makes the peer get only the given fraction of broadcasts on the protocol, e.g. because it
+    /// cannot keep up with all of them. Which broadcasts get skipped is random, weights are
+    /// clamped to between 0 and 1, with 1 meaning every broadcast is sent. Weights persist when
+    /// the peer disconnects.
+    pub fn set_peer_weight(&mut self, protocol: Protocol, peer_id: N::PeerId, weight: f64) {
+        match protocol {
+            Protocol::Authentication => self.authentication.set_peer_weight(peer_id, weight),
+            Protocol::BlockSync => self.block_sync.set_peer_weight(peer_id, weight),
+        }
+    }
+
+    /// Returns the total bytes sent and received on the given protocol, as they went over the wire,
+    /// i.e. after compression and including signatures.
+    pub fn bytes_transferred(&self, protocol: Protocol) -> (u64, u64) {
+        match protocol {
+            Protocol::Authentication => (
+                self.authentication.bytes_sent(),
+                self.authentication.bytes_received,
+            ),
+            Protocol::BlockSync => (self.block_sync.bytes_sent(), self.block_sync.bytes_received),
+        }
+    }
+
+    /// Returns the version of the wire format negotiated with the peer on the given protocol,
+    /// `None` if the handshake of the peer did not arrive yet or its version is incompatible.
+    pub fn negotiated_version(&self, protocol: Protocol, peer_id: &N::PeerId) -> Option<u16> {
+        let peer_versions = match protocol {
+            Protocol::Authentication => &self.authentication.peer_versions,
+            Protocol::BlockSync => &self.block_sync.peer_versions,
+        };
+        peer_versions.get(peer_id).copied().flatten()
+    }
+
+    /// Stops sending to the peer on all the protocols, abandoning anything still waiting for it,
+    /// and makes the network close the connections to it, e.g. because it misbehaves. Without
+    /// the latter the peer would just reconnect.
+    pub fn disconnect_peer(&mut self, peer_id: N::PeerId) {
+        info!(target: LOG_TARGET, "Disconnecting peer {:?}.", self.peer_names.name(&peer_id));
+        if self.authentication.close_stream(&peer_id) {
+            self.publish_connection_event(ConnectionEvent::PeerDisconnected(
+                peer_id.clone(),
+                Protocol::Authentication,
+            ));
+        }
+        if self.block_sync.close_stream(&peer_id) {
+            self.publish_connection_event(ConnectionEvent::PeerDisconnected(
+                peer_id.clone(),
+                Protocol::BlockSync,
+            ));
+        }
+        self.authentication.network.disconnect_peer(peer_id);
+    }
+
+    /// Makes the network connect to the peer at the given address and keep the connection, e.g.
+    /// for committee members we should not wait for to connect to us.
+    pub fn dial(&mut self, peer_id: N::PeerId, address: N::Address) {
+        debug!(
+            target: LOG_TARGET,
+            "Dialing peer {:?} at {:?}.", self.peer_names.name(&peer_id), address
+        );
+        self.authentication.network.dial(peer_id, address);
+    }
+
+    /// Stops broadcasting and forwarding received messages on the given protocol, e.g. for the
+    /// duration of a coordinated upgrade. Peers stay connected and received messages are dropped.
+    pub fn pause_protocol(&mut self, protocol: Protocol) {
+        self.set_paused(protocol, true)
+    }
+
+    /// Undoes `pause_protocol`, messages dropped in the meantime are not recovered.
+    pub fn resume_protocol(&mut self, protocol: Protocol) {
+        self.set_paused(protocol, false)
+    }
+
+    fn set_paused(&mut self, protocol: Protocol, paused: bool) {
+        info!(
+            target: LOG_TARGET,
+            "{} {:?} protocol.",
+            if paused { "Pausing" } else { "Resuming" },
+            protocol
+        );
+        match protocol {
+            Protocol::Authentication => self.authentication.paused = paused,
+            Protocol::BlockSync => self.block_sync.paused = paused,
+        }
+    }
+
+    /// Changes how many messages of each priority can wait to be sent to a single peer on the
+    /// given protocol, for peers connecting later and the already connected ones alike. Messages already waiting
+    /// are never dropped because of this, but while a queue holds more than the new capacity,
+    /// messages sent to it are handled according to the drop policy as if it was full.
+    pub fn set_queue_capacity(&mut self, protocol: Protocol, capacity: usize) {
+        info!(
+            target: LOG_TARGET,
+            "Setting capacity of {:?} peer queues to {}.", protocol, capacity
+        );
+        match protocol {
+            Protocol::Authentication => self.authentication.set_queue_capacity(capacity),
+            Protocol::BlockSync => self.block_sync.set_queue_capacity(capacity),
+        }
+    }
+
+    fn handle_query(&mut self, query: Query<N::PeerId, N::Address>) {
+        // The asking side might have given up already, nothing to do then.
+        match query {
+            Query::ConnectedPeers(protocol, response) => {
+                let _ = response.send(self.connected_peers(protocol));
+            }
+            Query::IsConnected(protocol, peer_id, response) => {
+                let _ = response.send(self.is_connected(&peer_id, protocol));
+            }
+            Query::ConnectedPeerCounts(response) => {
+                let _ = response.send(self.connected_peer_counts());
+            }
+            Query::LastSeen(protocol, response) => {
+                let _ = response.send(self.last_seen(protocol));
+            }
+            Query::BestPeer(protocol, response) => {
+                let _ = response.send(self.best_peer(protocol));
+            }
+            Query::SetAllowlist(protocol, allowlist) => self.set_allowlist(protocol, allowlist),
+            Query::SetBlocklist(blocklist) => self.set_blocklist(blocklist),
+            Query::SetAuthorities(authorities) => self.set_authorities(authorities),
+            Query::SetPeerWeight(protocol, peer_id, weight) => {
+                self.set_peer_weight(protocol, peer_id, weight)
+            }
+            Query::SetPaused(protocol, paused) => self.set_paused(protocol, paused),
+            Query::SetQueueCapacity(protocol, capacity) => {
+                self.set_queue_capacity(protocol, capacity)
+            }
+            Query::Disconnect(peer_id) => self.disconnect_peer(peer_id),
+            Query::Dial(peer_id, address) => self.dial(peer_id, address),
+            Query::Snapshot(response) => {
+                let _ = response.send(self.snapshot());
+            }
+            Query::Flush(timeout, response) => self.flush(timeout, response),
+        }
+    }
+
+    /// Returns the state of the service, for diagnostics.
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            authentication: self.authentication.snapshot(),
+            block_sync: self.block_sync.snapshot(),
+        }
+    }
+
+    fn status(&self) -> String {
+        let mut status = String::from("Network status report: ");
+        status.push_str(&self.authentication.status(self.stale_peer_threshold));
+        status.push_str(&self.block_sync.status(self.stale_peer_threshold));
+        status
+    }
+
+    /// The status report to log, `None` when it should be skipped as nothing changed since the
+    /// previous one.
+    fn next_status_report(&mut self) -> Option<String> {
+        let quiet = match &self.quiet_status_reports {
+            Some(quiet) => quiet,
+            None => return Some(self.status()),
+        };
+        let tracked = self
+            .authentication
+            .tracked_status(self.stale_peer_threshold)
+            + &self.block_sync.tracked_status(self.stale_peer_threshold);
+        let now = time::Instant::now();
+        if let Some((previous, reported_at)) = &mut self.last_status_report {
+            if *previous == tracked {
+                return match quiet.heartbeat_interval {
+                    Some(interval) if now.duration_since(*reported_at) >= interval => {
+                        *reported_at = now;
+                        Some(String::from(
+                            "Network status unchanged since the previous report.",
+                        ))
+                    }
+                    _ => None,
+                };
+            }
+        }
+        self.last_status_report = Some((tracked, now));
+        Some(self.status())
+    }
+
+    /// The first tick is delayed by a random duration up to the configured jitter.
+    fn status_ticker<R: Rng>(&self, rng: &mut R) -> time::Interval {
+        let delay = rng.gen_range(Duration::ZERO..=self.status_report_jitter);
+        time::interval_at(time::Instant::now() + delay, self.status_report_interval)
+    }
+
+    /// Halves the interval of the status ticker while some peer is backlogged, down to the
+    /// minimum of adaptive status reports, and restores the normal interval once no peer is.
+    fn adapt_status_ticker(&self, status_ticker: &mut time::Interval) {
+        let config = match &self.adaptive_status_reports {
+            Some(config) => config,
+            None => return,
+        };
+        let (_, authentication_depth) = self.authentication.queue_depths();
+        let (_, block_sync_depth) = self.block_sync.queue_depths();
+        let period = status_ticker.period();
+        let interval = match authentication_depth.max(block_sync_depth) > config.backlog_threshold {
+            true => (period / 2)
+                .max(config.min_interval)
+                .min(self.status_report_interval),
+            false => self.status_report_interval,
+        };
+        if interval != period {
+            debug!(
+                target: LOG_TARGET,
+                "Reporting the status every {:?} instead of every {:?}.", interval, period
+            );
+            *status_ticker = time::interval_at(time::Instant::now() + interval, interval);
+        }
+    }
+
+    fn status_report(&mut self) {
+        self.authentication.update_rates();
+        self.block_sync.update_rates();
+        if let Some(report) = self.next_status_report() {
+            info!(target: LOG_TARGET, "{}", report);
+        }
+        self.authentication.check_active_peer_senders();
+        self.block_sync.check_active_peer_senders();
+        self.evict_stuck_peers();
+        self.reap_dead_peer_senders();
+    }
+
+    /// Disconnects peers whose peer sender is not running, they get a new one if their stream
+    /// opens again.
+    fn reap_dead_peer_senders(&mut self) {
+        for peer in self.authentication.dead_peer_senders() {
+            warn!(
+                target: LOG_TARGET,
+                "Peer sender of {:?} peer {:?} is not running, disconnecting it.",
+                Protocol::Authentication,
+                self.peer_names.name(&peer)
+            );
+            if self.authentication.close_stream(&peer) {
+                self.publish_connection_event(ConnectionEvent::PeerDisconnected(
+                    peer,
+                    Protocol::Authentication,
+                ));
+            }
+        }
+        for peer in self.block_sync.dead_peer_senders() {
+            warn!(
+                target: LOG_TARGET,
+                "Peer sender of {:?} peer {:?} is not running, disconnecting it.",
+                Protocol::BlockSync,
+                self.peer_names.name(&peer)
+            );
+            if self.block_sync.close_stream(&peer) {
+                self.publish_connection_event(ConnectionEvent::PeerDisconnected(
+                    peer,
+                    Protocol::BlockSync,
+                ));
+            }
+        }
+    }
+
+    fn evict_stuck_peers(&mut self) {
+        for peer in self.authentication.check_queue_growth() {
+            if self.authentication.close_stream(&peer) {
+                self.publish_connection_event(ConnectionEvent::PeerDisconnected(
+                    peer,
+                    Protocol::Authentication,
+                ));
+            }
+        }
+        for peer in self.block_sync.check_queue_growth() {
+            if self.block_sync.close_stream(&peer) {
+                self.publish_connection_event(ConnectionEvent::PeerDisconnected(
+                    peer,
+                    Protocol::BlockSync,
+                ));
+            }
+        }
+    }
+
+    /// Handles the commands sent by users before the exit signal, for at most the shutdown grace
+    /// period. Dropping the service afterwards closes the peer queues, and the peer senders exit
+    /// once they send out whatever is left in them.
+    fn shutdown(&mut self) {
+        let deadline = time::Instant::now() + self.shutdown_grace_period;
+        let authentication_drained = self
+            .authentication
+            .handle_pending_commands(deadline, usize::MAX);
+        let block_sync_drained = self
+            .block_sync
+            .handle_pending_commands(deadline, usize::MAX);
+        if !(authentication_drained && block_sync_drained) {
+            warn!(
+                target: LOG_TARGET,
+                "Shutdown grace period elapsed, dropping remaining user messages."
+            );
+        }
+    }
+
+    /// Handles the commands users sent so far, so that their messages are flushed too, and waits
+    /// until everything queued for peers is handed over to the network or dropped, for at most
+    /// the given time. At most a full command buffer is handled right away, so that users that
+    /// keep sending cannot hold up the service, anything beyond it is handled by the loop.
+    fn flush(&mut self, timeout: Duration, response: oneshot::Sender<Result<(), Error>>) {
+        let deadline = time::Instant::now() + timeout;
+        let authentication_limit = self.authentication.config.command_buffer;
+        self.authentication
+            .handle_pending_commands(deadline, authentication_limit);
+        let block_sync_limit = self.block_sync.config.command_buffer;
+        self.block_sync
+            .handle_pending_commands(deadline, block_sync_limit);
+        self.pending_flushes.push((deadline, response));
+        self.answer_flushes();
+    }
+
+    /// When the pending flushes should be checked next, either because one of them times out or
+    /// to notice the peer senders are done.
+    fn next_flush_check(&self) -> Option<time::Instant> {
+        self.pending_flushes
+            .iter()
+            .map(|(deadline, _)| *deadline)
+            .min()
+            .map(|deadline| deadline.min(self.flushes_checked_at + FLUSH_POLL_INTERVAL))
+    }
+
+    /// Answers the pending flushes that are done or timed out.
+    fn answer_flushes(&mut self) {
+        if self.pending_flushes.is_empty() {
+            return;
+        }
+        let pending = self.authentication.unflushed() + self.block_sync.unflushed();
+        let now = time::Instant::now();
+        self.flushes_checked_at = now;
+        for (deadline, response) in std::mem::take(&mut self.pending_flushes) {
+            if pending == 0 {
+                let _ = response.send(Ok(()));
+            } else if now >= deadline {
+                let _ = response.send(Err(Error::FlushTimedOut(pending)));
+            } else {
+                self.pending_flushes.push((deadline, response));
+            }
+        }
+    }
+
+    pub async fn run(mut self, exit: oneshot::Receiver<()>) -> Result<(), GossipServiceError> {
+        self.authentication.commands_for_handles = None;
+        self.block_sync.commands_for_handles = None;
+        let result = self.run_loop(exit).instrument(service_span()).await;
+        let reason = match &result {
+            Ok(()) => TerminationReason::Exited,
+            Err(e) => TerminationReason::Failed(e.clone()),
+        };
+        for listener in self.termination_listeners.drain(..) {
+            let _ = listener.send(reason.clone());
+        }
+        result
+    }
+
+    async fn run_loop(
+        &mut self,
+        mut exit: oneshot::Receiver<()>,
+    ) -> Result<(), GossipServiceError> {
+        use GossipServiceError as Error;
+
+        let mut status_ticker = self.status_ticker(&mut thread_rng());
+        loop {
+            let next_pending_close = self.next_pending_close();
+            let next_connectivity_deadline = self.next_connectivity_deadline();
+            let next_rebroadcast = self.next_rebroadcast();
+            let next_flush_check = self.next_flush_check();
+            tokio::select! {
+                maybe_event = self.network_event_stream.next_event() => {
+                    let event = maybe_event.ok_or(Error::NetworkStreamTerminated)?;
+                    self.handle_network_event(event).map_err(|_| Error::UnableToForwardMessageToUser)?;
+                },
+                maybe_message = self.authentication.messages_from_user.next() => {
+                    let command = maybe_message.ok_or(Error::AuthorizationStreamTerminated)?;
+                    self.authentication.handle_ready_commands(command);
+                },
+                maybe_message = self.block_sync.messages_from_user.next() => {
+                    let command = maybe_message.ok_or(Error::BlockSyncStreamTerminated)?;
+                    self.block_sync.handle_ready_commands(command);
+                },
+                Some(query) = self.queries_from_handles.next() => {
+                    self.handle_query(query);
+                },
+                _ = status_ticker.tick() => {
+                    self.status_report();
+                    self.adapt_status_ticker(&mut status_ticker);
+                },
+                _ = time::sleep_until(next_pending_close.unwrap_or_else(time::Instant::now)), if next_pending_close.is_some() => {
+                    self.close_expired_streams();
+                },
+                _ = time::sleep_until(next_connectivity_deadline.unwrap_or_else(time::Instant::now)), if next_connectivity_deadline.is_some() => {
+                    self.check_connectivity();
+                },
+                _ = time::sleep_until(next_rebroadcast.unwrap_or_else(time::Instant::now)), if next_rebroadcast.is_some() => {
+                    self.rebroadcast_due();
+                },
+                _ = time::sleep_until(next_flush_check.unwrap_or_else(time::Instant::now)), if next_flush_check.is_some() => {},
+                _ = &mut exit => {
+                    debug!(target: LOG_TARGET, "Gossip network service received exit signal.");
+                    self.shutdown();
+                    return Ok(());
+                },
+            }
+            // Whatever got handled could have finished a flush, and the flush check arm only
+            // wakes the loop up to get here.
+            self.answer_flushes();
+        }
+    }
+}
+
+impl<N: RawNetwork, ES: EventStream<N::PeerId>, D: Data> Service<N, ES, D, D> {
+    /// Returns a handle broadcasting on every protocol, for services where all the protocols
+    /// carry the same data. Like the networks returned by `new`, it has to be created before the
+    /// service is run, which `run` taking the service by value ensures. Broadcasts made before
+    /// the service runs wait for it in the command buffer.
+    pub fn broadcast_all_handle(&self) -> BroadcastAllHandle<D, N::PeerId> {
+        let commands_for_service = |state: &ProtocolState<N, D>| {
+            state
+                .commands_for_handles
+                .clone()
+                .expect("only `run` drops the commands for handles and it consumes the service")
+        };
+        BroadcastAllHandle {
+            authentication: commands_for_service(&self.authentication),
+            block_sync: commands_for_service(&self.block_sync),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        fmt, iter,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use bytes::Bytes;
+    use futures::{
+        channel::{mpsc, oneshot},
+        StreamExt,
+    };
+    use network_clique::mock::{random_peer_id, MockPublicKey};
+    use parity_scale_codec::{Compact, Decode, Encode, Error as CodecError};
+    use parking_lot::Mutex;
+    use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
+    use sc_service::TaskManager;
+    use substrate_prometheus_endpoint::{prometheus::core::Metric, Histogram, Registry};
+    use tokio::{runtime::Handle, task::JoinHandle, time};
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+        Metadata, Subscriber,
+    };
+
+    use super::{
+        network_event_span, peer_sender_span, peer_sender_task_name, pooled_peer_senders_task_name,
+        service_span, Command, Error, GossipServiceError, PendingConfirmation, SendError,
+        SendSummary, Service, ServiceHandle, TerminationReason, FLUSH_POLL_INTERVAL,
+        INCOMPATIBLE_VERSION, INVALID_SIGNATURE, MALFORMED_MESSAGE, PEER_LIMIT_REACHED,
+        PEER_SENDER_START_TIMEOUT, RATE_LIMIT_EXCEEDED, REASSEMBLY_LIMIT_EXCEEDED, VALID_MESSAGE,
+    };
+    use crate::network::{
+        gossip::{
+            codec::{self, Codec, MessageKind, ScaleCodec},
+            compression,
+            config::{
+                AdaptiveStatusConfig, BackoffConfig, BatchConfig, BroadcastDedupConfig,
+                CircuitBreakerConfig, CompressionConfig, Direction, FairDrainConfig,
+                FragmentationConfig, InboundDedupConfig, InboundRateLimitConfig, LatencyConfig,
+                PeerLimitConfig, PeerLimitPolicy, ProtocolConfig, QuietStatusConfig,
+                RebroadcastConfig, SendDedupConfig, StuckPeerConfig, VersionRange,
+            },
+            fragment,
+            metrics::Metrics,
+            mock::{
+                KeyedSum, ManualSpawner, MockEvent, MockEventStream, MockRawNetwork,
+                MockSenderError,
+            },
+            queue::DropPolicy,
+            signing::MessageSigning,
+            Barrier, BroadcastResult, Config, Confirmation, ConnectionEvent, DecodeFailure,
+            Delivery, Network, Priority, RebroadcastHandle,
+        },
+        mock::MockData,
+        Protocol,
+    };
+
+    const PROTOCOL: Protocol = Protocol::Authentication;
+
+    fn authentication_config(config: ProtocolConfig) -> Config {
+        Config {
+            authentication: config,
+            ..Default::default()
+        }
+    }
+
+    pub struct TestData {
+        pub network: MockRawNetwork,
+        gossip_network: Box<dyn Network<MockData, Error = Error, PeerId = MockPublicKey>>,
+        pub service: Service<MockRawNetwork, MockEventStream, MockData, MockData>,
+        // `TaskManager` can't be dropped for `SpawnTaskHandle` to work
+        _task_manager: TaskManager,
+        // If we drop the sync network, the underlying network service dies, stopping the whole
+        // network.
+        _other_network: Box<dyn Network<MockData, Error = Error, PeerId = MockPublicKey>>,
+    }
+
+    impl TestData {
+        fn prepare() -> Self {
+            Self::prepare_with_config(Config::default())
+        }
+
+        fn prepare_with_config(config: Config) -> Self {
+            Self::prepare_with_config_and_registry(config, None)
+        }
+
+        fn prepare_with_config_and_registry(config: Config, registry: Option<Registry>) -> Self {
+            Self::prepare_with_codec(config, registry, ScaleCodec)
+        }
+
+        fn prepare_with_codec(
+            config: Config,
+            registry: Option<Registry>,
+            codec: impl Codec<MockData>,
+        ) -> Self {
+            Self::prepare_with(config, registry, None, None, None, codec, None)
+        }
+
+        fn prepare_with(
+            config: Config,
+            registry: Option<Registry>,
+            broadcast_feedback: Option<mpsc::UnboundedSender<BroadcastResult<MockPublicKey>>>,
+            local_peer_id: Option<MockPublicKey>,
+            decode_failures: Option<mpsc::UnboundedSender<DecodeFailure<MockPublicKey>>>,
+            codec: impl Codec<MockData>,
+            signing: Option<MessageSigning>,
+        ) -> Self {
+            let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+
+            let (event_stream_oneshot_tx, _event_stream_oneshot_rx) = oneshot::channel();
+
+            // Prepare service
+            let network = MockRawNetwork::new(event_stream_oneshot_tx);
+            let (service, gossip_network, other_network) = Service::new_with_codecs(
+                network.clone(),
+                network.event_stream(),
+                task_manager.spawn_handle().into(),
+                registry,
+                config,
+                broadcast_feedback,
+                local_peer_id,
+                decode_failures,
+                codec,
+                ScaleCodec,
+                signing,
+            );
+            let gossip_network = Box::new(gossip_network);
+            let other_network = Box::new(other_network);
+
+            // `TaskManager` needs to be passed, so sender threads are running in background.
+            Self {
+                network,
+                service,
+                gossip_network,
+                _task_manager: task_manager,
+                _other_network: other_network,
+            }
+        }
+
+        async fn cleanup(self) {
+            self.network.close_channels().await;
+        }
+
+        /// Waits until the peer senders sent out everything queued, without running the service.
+        async fn flush(&self, timeout: Duration) -> Result<(), Error> {
+            let service = &self.service;
+            let deadline = time::Instant::now() + timeout;
+            loop {
+                let pending = service.authentication.unflushed() + service.block_sync.unflushed();
+                if pending == 0 {
+                    return Ok(());
+                }
+                if time::Instant::now() >= deadline {
+                    return Err(Error::FlushTimedOut(pending));
+                }
+                time::sleep(FLUSH_POLL_INTERVAL).await;
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Network<MockData> for TestData {
+        type Error = Error;
+        type PeerId = MockPublicKey;
+
+        fn send_to(&mut self, data: MockData, peer_id: Self::PeerId) -> Result<(), Self::Error> {
+            self.gossip_network.send_to(data, peer_id)
+        }
+
+        fn send_to_with_ttl(
+            &mut self,
+            data: MockData,
+            peer_id: Self::PeerId,
+            ttl: Duration,
+        ) -> Result<(), Self::Error> {
+            self.gossip_network.send_to_with_ttl(data, peer_id, ttl)
+        }
+
+        fn send_to_confirmed(
+            &mut self,
+            data: MockData,
+            peer_id: Self::PeerId,
+        ) -> Result<Confirmation<Self::Error>, Self::Error> {
+            self.gossip_network.send_to_confirmed(data, peer_id)
+        }
+
+        fn rebroadcast(
+            &mut self,
+            data: MockData,
+            schedule: RebroadcastConfig,
+        ) -> Result<RebroadcastHandle, Self::Error> {
+            self.gossip_network.rebroadcast(data, schedule)
+        }
+
+        fn broadcast_barrier(
+            &mut self,
+            data: MockData,
+        ) -> Result<Barrier<Self::PeerId, Self::Error>, Self::Error> {
+            self.gossip_network.broadcast_barrier(data)
+        }
+
+        fn send_to_random(
+            &mut self,
+            data: MockData,
+            peer_ids: HashSet<Self::PeerId>,
+        ) -> Result<(), Self::Error> {
+            self.gossip_network.send_to_random(data, peer_ids)
+        }
+
+        fn send_to_best(&mut self, data: MockData) -> Result<(), Self::Error> {
+            self.gossip_network.send_to_best(data)
+        }
+
+        fn send_to_peers(
+            &mut self,
+            data: MockData,
+            peer_ids: HashSet<Self::PeerId>,
+        ) -> Result<(), Self::Error> {
+            self.gossip_network.send_to_peers(data, peer_ids)
+        }
+
+        fn broadcast(&mut self, data: MockData) -> Result<(), Self::Error> {
+            self.gossip_network.broadcast(data)
+        }
+
+        fn try_broadcast(&mut self, data: MockData) -> Result<(), Self::Error> {
+            self.gossip_network.try_broadcast(data)
+        }
+
+        async fn next(&mut self) -> Result<(MockData, Self::PeerId), Self::Error> {
+            self.gossip_network.next().await
+        }
+
+        async fn next_with_delivery(
+            &mut self,
+        ) -> Result<(MockData, Self::PeerId, Option<Delivery>), Self::Error> {
+            self.gossip_network.next_with_delivery().await
+        }
+    }
+
+    /// How long the time moves on while waiting for the running service to handle everything.
+    const SETTLE_TIME: Duration = Duration::from_millis(1);
+
+    /// A service running its loop in the background, fed with events emitted to the mock
+    /// network, data sent by the user and the passing of time. Meant for tests with paused time,
+    /// where the time only moves on once the service handled everything it could.
+    struct RunningService {
+        network: MockRawNetwork,
+        gossip_network: Box<dyn Network<MockData, Error = Error, PeerId = MockPublicKey>>,
+        handle: ServiceHandle<MockPublicKey, String>,
+        status_report_interval: Duration,
+        exit: oneshot::Sender<()>,
+        task: JoinHandle<Result<(), GossipServiceError>>,
+        _task_manager: TaskManager,
+        _other_network: Box<dyn Network<MockData, Error = Error, PeerId = MockPublicKey>>,
+    }
+
+    impl TestData {
+        /// Starts the loop of the service.
+        fn run(self) -> RunningService {
+            let TestData {
+                network,
+                gossip_network,
+                service,
+                _task_manager,
+                _other_network,
+            } = self;
+            let handle = service.handle();
+            let status_report_interval = service.status_report_interval;
+            let (exit, exit_rx) = oneshot::channel();
+            RunningService {
+                network,
+                gossip_network,
+                handle,
+                status_report_interval,
+                exit,
+                task: tokio::spawn(service.run(exit_rx)),
+                _task_manager,
+                _other_network,
+            }
+        }
+    }
+
+    impl RunningService {
+        /// Waits until the service handled everything sent to it so far.
+        async fn settle(&self) {
+            time::sleep(SETTLE_TIME).await;
+        }
+
+        /// Emits the events, returning once the service handled them.
+        async fn emit(&mut self, events: impl IntoIterator<Item = MockEvent>) {
+            for event in events {
+                self.network.emit_event(event);
+            }
+            self.settle().await;
+        }
+
+        /// Broadcasts the data as the user, returning once the service handled it.
+        async fn broadcast(&mut self, data: MockData) {
+            self.gossip_network
+                .broadcast(data)
+                .expect("service is running");
+            self.settle().await;
+        }
+
+        /// Lets a status report interval pass, returning once the service handled the tick.
+        async fn tick_status(&self) {
+            time::sleep(self.status_report_interval).await;
+            self.settle().await;
+        }
+
+        /// Stops the service, checking that it exits cleanly and leaves nothing unsent.
+        async fn stop(self) {
+            self.exit.send(()).expect("service is listening");
+            self.task
+                .await
+                .expect("task should not panic")
+                .expect("should exit cleanly");
+            self.network.close_channels().await;
+        }
+    }
+
+    fn message(i: u8) -> MockData {
+        MockData::new(i.into(), 3)
+    }
+
+    #[test]
+    fn test_errors_display_readable_messages() {
+        let displayed = [
+            Error::ServiceStopped.to_string(),
+            Error::FlushTimedOut(3).to_string(),
+            Error::NoPeers.to_string(),
+            SendError::MissingSender.to_string(),
+            SendError::QueueFull.to_string(),
+        ];
+        assert_eq!(
+            displayed,
+            [
+                "gossip network service stopped",
+                "gossip network service timed out flushing, 3 messages are pending",
+                "gossip network service has no peers to broadcast to",
+                "no sender for the peer, it is probably not connected",
+                "the queue of the peer is full",
+            ]
+        );
+        let error: Box<dyn std::error::Error> = Box::new(Error::Congested);
+        assert_eq!(error.to_string(), "gossip network service is congested");
+    }
+
+    #[tokio::test]
+    async fn test_sending_on_receive_only_protocol_fails() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            direction: Direction::ReceiveOnly,
+            ..Default::default()
+        }));
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        assert_eq!(
+            test_data.send_to(message(1), peer_id.clone()),
+            Err(Error::ReceiveOnly)
+        );
+        assert_eq!(test_data.broadcast(message(1)), Err(Error::ReceiveOnly));
+        assert!(test_data
+            .service
+            .authentication
+            .messages_from_user
+            .try_next()
+            .is_err());
+        assert!(matches!(
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(1), peer_id.clone()),
+            Err(SendError::ReceiveOnly)
+        ));
+
+        // Receiving works as usual.
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![(PROTOCOL, message(2).encode().into())],
+            ))
+            .expect("Should handle");
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message(2), peer_id)
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_messages_on_send_only_protocol_are_dropped() {
+        let mut test_data = TestData::prepare_with_config_and_registry(
+            authentication_config(ProtocolConfig {
+                direction: Direction::SendOnly,
+                ..Default::default()
+            }),
+            Some(Registry::new()),
+        );
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![(PROTOCOL, message(1).encode().into())],
+            ))
+            .expect("Should handle");
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "wrong_direction"])
+                .get(),
+            1
+        );
+
+        // Sending works as usual.
+        test_data
+            .service
+            .authentication
+            .send_to_peer(message(2), peer_id.clone())
+            .expect("peer is connected");
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((message(2).encode(), peer_id, PROTOCOL))
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_broadcasts_without_peers_are_refused_when_enabled() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            reject_broadcasts_without_peers: true,
+            ..Default::default()
+        }));
+
+        assert_eq!(test_data.broadcast(message(1)), Err(Error::NoPeers));
+        assert_eq!(test_data.try_broadcast(message(1)), Err(Error::NoPeers));
+        assert!(test_data
+            .service
+            .authentication
+            .messages_from_user
+            .try_next()
+            .is_err());
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        test_data
+            .broadcast(message(2))
+            .expect("a peer is connected");
+        let command = test_data
+            .service
+            .authentication
+            .messages_from_user
+            .next()
+            .await
+            .expect("Should receive command");
+        test_data.service.authentication.handle_command(command);
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((message(2).encode(), peer_id.clone(), PROTOCOL))
+        );
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamClosed(peer_id, PROTOCOL))
+            .expect("Should handle");
+        assert_eq!(test_data.broadcast(message(3)), Err(Error::NoPeers));
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_broadcasts_without_peers_are_accepted_by_default() {
+        let mut test_data = TestData::prepare();
+
+        test_data
+            .broadcast(message(1))
+            .expect("broadcasts are accepted without peers");
+        assert!(test_data
+            .service
+            .authentication
+            .messages_from_user
+            .try_next()
+            .is_ok());
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_delivery_tags_survive_the_round_trip() {
+        for batching in [None, Some(BatchConfig { max_messages: 2 })] {
+            let mut test_data =
+                TestData::prepare_with_config(authentication_config(ProtocolConfig {
+                    delivery_tags: true,
+                    batching,
+                    ..Default::default()
+                }));
+            let spawner = ManualSpawner::default();
+            test_data.service.set_spawner(spawner.clone());
+
+            let peer_id = random_peer_id();
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+            test_data
+                .service
+                .authentication
+                .broadcast(message(1), Priority::Normal, None);
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(2), peer_id.clone())
+                .expect("peer is connected");
+            spawner.step();
+
+            // Whatever was sent to the peer comes back as if the peer sent it to us.
+            while let Some((data, _, protocol)) = test_data.network.send_message.try_next().await {
+                test_data
+                    .service
+                    .handle_network_event(MockEvent::Messages(
+                        peer_id.clone(),
+                        vec![(protocol, data.into())],
+                    ))
+                    .expect("Should handle");
+            }
+            for (data, delivery) in [
+                (message(1), Delivery::Gossip),
+                (message(2), Delivery::Direct),
+            ] {
+                assert_eq!(
+                    test_data
+                        .next_with_delivery()
+                        .await
+                        .expect("Should receive message"),
+                    (data, peer_id.clone(), Some(delivery))
+                );
+            }
+
+            test_data.cleanup().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_have_unknown_delivery_without_tags() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![(PROTOCOL, message(1).encode().into())],
+            ))
+            .expect("Should handle");
+        assert_eq!(
+            test_data
+                .next_with_delivery()
+                .await
+                .expect("Should receive message"),
+            (message(1), peer_id, None)
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_notification_stream_opened() {
+        let mut test_data = TestData::prepare();
+        test_data.service.sort_broadcasts();
+
+        let mut peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
+
+        peer_ids.iter().for_each(|peer_id| {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        });
+
+        // The peer senders run concurrently, so only the order of queueing is deterministic.
+        peer_ids.sort();
+        assert_eq!(test_data.service.authentication.broadcast_peers(), peer_ids);
+        let message = message(1);
+        test_data
+            .service
+            .authentication
+            .broadcast(message.clone(), Priority::Normal, None);
+
+        let broadcasted_messages = HashSet::<_>::from_iter(
+            test_data
+                .network
+                .send_message
+                .take(peer_ids.len())
+                .await
+                .into_iter(),
+        );
+
+        let expected_messages = HashSet::from_iter(
+            peer_ids
+                .into_iter()
+                .map(|peer_id| (message.clone().encode(), peer_id, PROTOCOL)),
+        );
+
+        assert_eq!(broadcasted_messages, expected_messages);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_notification_stream_closed() {
+        let mut test_data = TestData::prepare();
+
+        let peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
+        let opened_authorities_n = 2;
+
+        peer_ids.iter().for_each(|peer_id| {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        });
+
+        peer_ids
+            .iter()
+            .skip(opened_authorities_n)
+            .for_each(|peer_id| {
+                test_data
+                    .service
+                    .handle_network_event(MockEvent::StreamClosed(peer_id.clone(), PROTOCOL))
+                    .expect("Should handle");
+            });
+
+        let message = message(1);
+        test_data
+            .service
+            .authentication
+            .broadcast(message.clone(), Priority::Normal, None);
+
+        let broadcasted_messages = HashSet::<_>::from_iter(
+            test_data
+                .network
+                .send_message
+                .take(opened_authorities_n)
+                .await
+                .into_iter(),
+        );
+
+        let expected_messages = HashSet::from_iter(
+            peer_ids
+                .into_iter()
+                .take(opened_authorities_n)
+                .map(|peer_id| (message.clone().encode(), peer_id, PROTOCOL)),
+        );
+
+        assert_eq!(broadcasted_messages, expected_messages);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_create_sender_error() {
+        let mut test_data = TestData::prepare();
+
+        test_data
+            .network
+            .create_sender_errors
+            .lock()
+            .push_back(MockSenderError);
+
+        let peer_id = random_peer_id();
+
+        let message_1 = message(1);
+        let message_2 = message(4);
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        test_data
+            .service
+            .authentication
+            .broadcast(message_1, Priority::Normal, None);
+
+        test_data
+            .service
+            .authentication
+            .broadcast(message_2.clone(), Priority::Normal, None);
+
+        let expected = (message_2.encode(), peer_id, PROTOCOL);
+
+        assert_eq!(
+            test_data
+                .network
+                .send_message
+                .next()
+                .await
+                .expect("Should receive message"),
+            expected,
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_send_error() {
+        let mut test_data = TestData::prepare();
+
+        // Both the sender and the one recreated for retrying fail.
+        test_data
+            .network
+            .send_errors
+            .lock()
+            .extend([MockSenderError, MockSenderError]);
+
+        let peer_id = random_peer_id();
+
+        let message_1 = message(1);
+        let message_2 = message(4);
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        test_data
+            .service
+            .authentication
+            .broadcast(message_1, Priority::Normal, None);
+
+        test_data
+            .service
+            .authentication
+            .broadcast(message_2.clone(), Priority::Normal, None);
+
+        let expected = (message_2.encode(), peer_id, PROTOCOL);
+
+        assert_eq!(
+            test_data
+                .network
+                .send_message
+                .next()
+                .await
+                .expect("Should receive message"),
+            expected,
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_notification_received() {
+        let mut test_data = TestData::prepare();
+
+        let message = message(1);
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![(PROTOCOL, message.clone().encode().into())],
+            ))
+            .expect("Should handle");
+
+        let (received_message, received_peer_id) =
+            test_data.next().await.expect("Should receive message");
+        assert_eq!(received_message, message);
+        assert_eq!(received_peer_id, peer_id);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_send_to_connected() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+
+        let message = message(1);
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        test_data
+            .service
+            .authentication
+            .send_to_peer(message.clone(), peer_id.clone())
+            .expect("interface works");
+
+        let expected = (message.encode(), peer_id, PROTOCOL);
+
+        assert_eq!(
+            test_data
+                .network
+                .send_message
+                .next()
+                .await
+                .expect("Should receive message"),
+            expected,
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn peer_senders_only_send_when_spawner_runs_them() {
+        let mut test_data = TestData::prepare();
+        let spawner = ManualSpawner::default();
+        test_data.service.set_spawner(spawner.clone());
+
+        let peer_id = random_peer_id();
+        let message = message(1);
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        assert_eq!(
+            spawner.pending_tasks(),
+            vec![peer_sender_task_name(PROTOCOL)]
+        );
+
+        test_data
+            .service
+            .authentication
+            .send_to_peer(message.clone(), peer_id.clone())
+            .expect("interface works");
+        assert!(test_data.network.send_message.try_next().await.is_none());
+
+        spawner.step();
+        assert_eq!(
+            test_data.network.send_message.try_next().await,
+            Some((message.encode(), peer_id.clone(), PROTOCOL)),
+        );
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamClosed(peer_id, PROTOCOL))
+            .expect("Should handle");
+        spawner.step();
+        assert!(spawner.pending_tasks().is_empty());
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_peers_above_the_sender_task_limit_share_the_pooled_task() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            max_sender_tasks: Some(1),
+            ..Default::default()
+        }));
+        let spawner = ManualSpawner::default();
+        test_data.service.set_spawner(spawner.clone());
+
+        let peer_ids: Vec<_> = (0..4).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        // Only the first peer got a task of its own.
+        assert_eq!(
+            spawner.pending_tasks(),
+            vec![
+                peer_sender_task_name(PROTOCOL),
+                pooled_peer_senders_task_name(PROTOCOL)
+            ]
+        );
+
+        for (i, peer_id) in peer_ids.iter().enumerate() {
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(i as u8), peer_id.clone())
+                .expect("interface works");
+        }
+        spawner.step();
+        let mut sent = HashSet::new();
+        while let Some(send) = test_data.network.send_message.try_next().await {
+            sent.insert(send);
+        }
+        assert_eq!(
+            sent,
+            peer_ids
+                .iter()
+                .enumerate()
+                .map(|(i, peer_id)| (message(i as u8).encode(), peer_id.clone(), PROTOCOL))
+                .collect()
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_all_sends_on_every_protocol() {
+        let mut test_data = TestData::prepare();
+
+        let peer_ids: Vec<_> = (0..2).map(|_| random_peer_id()).collect();
+        let protocols = [Protocol::Authentication, Protocol::BlockSync];
+        for (peer_id, protocol) in peer_ids.iter().zip(protocols) {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), protocol))
+                .expect("Should handle");
+        }
+
+        let mut broadcast_all_handle = test_data.service.broadcast_all_handle();
+        let mut service = test_data.run();
+
+        let message = message(1);
+        broadcast_all_handle
+            .broadcast_all(message.clone())
+            .expect("service is running");
+        service.settle().await;
+
+        let expected: HashSet<_> = peer_ids
+            .into_iter()
+            .zip(protocols)
+            .map(|(peer_id, protocol)| (message.encode(), peer_id, protocol))
+            .collect();
+        let sent: HashSet<_> = service
+            .network
+            .send_message
+            .take(2)
+            .await
+            .into_iter()
+            .collect();
+        assert_eq!(sent, expected);
+
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_all_checks_every_protocol() {
+        let mut test_data = TestData::prepare_with_config(Config {
+            authentication: ProtocolConfig {
+                direction: Direction::ReceiveOnly,
+                ..Default::default()
+            },
+            block_sync: ProtocolConfig {
+                reject_broadcasts_without_peers: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut broadcast_all_handle = test_data.service.broadcast_all_handle();
+
+        assert_eq!(
+            broadcast_all_handle.broadcast_all(message(1)),
+            Err(Error::ReceiveOnly)
+        );
+        assert!(test_data
+            .service
+            .authentication
+            .messages_from_user
+            .try_next()
+            .is_err());
+        assert!(test_data
+            .service
+            .block_sync
+            .messages_from_user
+            .try_next()
+            .is_err());
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(
+                random_peer_id(),
+                Protocol::BlockSync,
+            ))
+            .expect("Should handle");
+        assert_eq!(
+            broadcast_all_handle.broadcast_all(message(2)),
+            Err(Error::ReceiveOnly)
+        );
+        assert!(test_data
+            .service
+            .authentication
+            .messages_from_user
+            .try_next()
+            .is_err());
+        assert!(matches!(
+            test_data.service.block_sync.messages_from_user.try_next(),
+            Ok(Some(Command::Broadcast(data, Priority::Normal, None))) if data == message(2)
+        ));
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_events_about_local_peer_are_ignored() {
+        let local_peer_id = random_peer_id();
+        let mut test_data = TestData::prepare_with(
+            Config::default(),
+            None,
+            None,
+            Some(local_peer_id.clone()),
+            None,
+            ScaleCodec,
+            None,
+        );
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(local_peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                local_peer_id.clone(),
+                vec![(PROTOCOL, message(1).encode().into())],
+            ))
+            .expect("Should handle");
+        assert!(test_data.service.connected_peers(PROTOCOL).is_empty());
+        assert!(test_data.service.last_seen(PROTOCOL).is_empty());
+
+        assert!(matches!(
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(2), local_peer_id),
+            Err(SendError::MissingSender)
+        ));
+        assert!(test_data.network.created_senders.try_next().await.is_none());
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_no_send_to_disconnected() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+
+        let message = message(1);
+
+        assert!(matches!(
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message, peer_id),
+            Err(SendError::MissingSender)
+        ));
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_send_to_random_connected() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+
+        let message = message(1);
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        test_data
+            .service
+            .authentication
+            .send_to_random(message.clone(), iter::once(peer_id.clone()).collect());
+
+        let expected = (message.encode(), peer_id, PROTOCOL);
+
+        assert_eq!(
+            test_data
+                .network
+                .send_message
+                .next()
+                .await
+                .expect("Should receive message"),
+            expected,
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_send_to_random_disconnected() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+        let other_peer_id = random_peer_id();
+
+        let message = message(1);
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(other_peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        test_data
+            .service
+            .authentication
+            .send_to_random(message.clone(), iter::once(peer_id.clone()).collect());
+
+        let expected = (message.encode(), other_peer_id, PROTOCOL);
+
+        assert_eq!(
+            test_data
+                .network
+                .send_message
+                .next()
+                .await
+                .expect("Should receive message"),
+            expected,
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_lowered_queue_capacity_applies_to_new_peers() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            queue_capacity: 4,
+            drop_policy: DropPolicy::DropNewest,
+            ..Default::default()
+        }));
+        // Peer senders never run, so nothing leaves the queues.
+        test_data.service.set_spawner(ManualSpawner::default());
+
+        let old_peer = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(old_peer.clone(), PROTOCOL))
+            .expect("Should handle");
+        for i in 0..3 {
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(i), old_peer.clone())
+                .expect("queue has space");
+        }
+        test_data
+            .service
+            .handle()
+            .set_queue_capacity(PROTOCOL, 2)
+            .expect("service is running");
+        while let Ok(Some(query)) = test_data.service.queries_from_handles.try_next() {
+            test_data.service.handle_query(query);
+        }
+
+        let new_peer = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(new_peer.clone(), PROTOCOL))
+            .expect("Should handle");
+        for i in 0..2 {
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(i), new_peer.clone())
+                .expect("queue has space");
+        }
+        assert!(matches!(
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(2), new_peer.clone()),
+            Err(SendError::QueueFull)
+        ));
+        // The old queue keeps what it had, but takes nothing more until it shrinks.
+        assert!(matches!(
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(3), old_peer.clone()),
+            Err(SendError::QueueFull)
+        ));
+        assert_eq!(
+            test_data.service.authentication.peer_senders[&old_peer].len(),
+            3
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_newest() {
+        let mut test_data = TestData::prepare_with_config_and_registry(
+            authentication_config(ProtocolConfig {
+                queue_capacity: 2,
+                drop_policy: DropPolicy::DropNewest,
+                ..Default::default()
+            }),
+            Some(Registry::new()),
+        );
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        // The peer sender does not run until we yield, so the queue fills up.
+        for i in 0..2 {
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(i), peer_id.clone())
+                .expect("queue has space");
+        }
+        assert!(matches!(
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(2), peer_id.clone()),
+            Err(SendError::QueueFull)
+        ));
+
+        let expected: Vec<_> = (0..2)
+            .map(|i| (message(i).encode(), peer_id.clone(), PROTOCOL))
+            .collect();
+        assert_eq!(test_data.network.send_message.take(2).await, expected);
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "queue_full"])
+                .get(),
+            1
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_oldest() {
+        let mut test_data = TestData::prepare_with_config_and_registry(
+            authentication_config(ProtocolConfig {
+                queue_capacity: 2,
+                drop_policy: DropPolicy::DropOldest,
+                ..Default::default()
+            }),
+            Some(Registry::new()),
+        );
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        for i in 0..3 {
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(i), peer_id.clone())
+                .expect("oldest message should be dropped instead");
+        }
+
+        let expected: Vec<_> = (1..3)
+            .map(|i| (message(i).encode(), peer_id.clone(), PROTOCOL))
+            .collect();
+        assert_eq!(test_data.network.send_message.take(2).await, expected);
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "queue_full"])
+                .get(),
+            1
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_global_budget_refuses_messages_for_all_peers() {
+        let mut test_data = TestData::prepare_with_config(Config {
+            max_in_flight_messages: Some(2),
+            ..Default::default()
+        });
+        *test_data.network.send_delay.lock() = Duration::from_secs(1);
+
+        let blocked_peer = random_peer_id();
+        let other_peer = random_peer_id();
+        for peer_id in [&blocked_peer, &other_peer] {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        for i in 0..2 {
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(i), blocked_peer.clone())
+                .expect("budget has space");
+        }
+        assert!(matches!(
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(2), other_peer.clone()),
+            Err(SendError::GlobalBackpressure)
+        ));
+
+        // Sending releases the budget.
+        let expected: Vec<_> = (0..2)
+            .map(|i| (message(i).encode(), blocked_peer.clone(), PROTOCOL))
+            .collect();
+        assert_eq!(test_data.network.send_message.take(2).await, expected);
+        test_data
+            .service
+            .authentication
+            .send_to_peer(message(2), other_peer.clone())
+            .expect("budget has space again");
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((message(2).encode(), other_peer, PROTOCOL))
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_peers_take_turns_draining_under_budget_pressure() {
+        let mut test_data = TestData::prepare_with_config(Config {
+            max_in_flight_messages: Some(6),
+            fair_drain: Some(FairDrainConfig {
+                pressure_threshold: 6,
+                concurrent_sends: 1,
+            }),
+            ..Default::default()
+        });
+        let spawner = ManualSpawner::default();
+        test_data.service.set_spawner(spawner.clone());
+
+        let peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        for peer_id in &peer_ids {
+            for i in 0..2 {
+                test_data
+                    .service
+                    .authentication
+                    .send_to_peer(message(i), peer_id.clone())
+                    .expect("budget has space");
+            }
+        }
+
+        // Every peer gets its first message out before any gets its second one.
+        for i in 0..2 {
+            spawner.step();
+            let sent: HashSet<_> = test_data
+                .network
+                .send_message
+                .take(3)
+                .await
+                .into_iter()
+                .map(|(data, peer_id, _)| {
+                    assert_eq!(data, message(i).encode());
+                    peer_id
+                })
+                .collect();
+            assert_eq!(sent, peer_ids.iter().cloned().collect());
+        }
+        assert_eq!(test_data.network.assert_idle(Duration::ZERO).await, Ok(()));
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_status_reports_queue_depth() {
+        let mut test_data = TestData::prepare();
+
+        let peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+
+        // The peer senders do not run until we yield, so the messages stay queued.
+        for i in 0..3 {
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(i), peer_ids[0].clone())
+                .expect("queue has space");
+        }
+        test_data
+            .service
+            .authentication
+            .send_to_peer(message(3), peer_ids[1].clone())
+            .expect("queue has space");
+
+        assert_eq!(test_data.service.authentication.queue_depths(), (2, 3));
+        assert!(test_data
+            .service
+            .status()
+            .contains("authentication connected peers - 3; backlogged peers - 2; max queue - 3;"));
+
+        test_data.network.send_message.take(4).await;
+        assert_eq!(test_data.service.authentication.queue_depths(), (0, 0));
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_send_to_subset_of_peers() {
+        let mut test_data = TestData::prepare();
+
+        let peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        let disconnected_peer_id = random_peer_id();
+
+        let message = message(1);
+        let targets = vec![
+            peer_ids[0].clone(),
+            peer_ids[2].clone(),
+            disconnected_peer_id,
+        ];
+        let summary = test_data
+            .service
+            .authentication
+            .send_to_peers(message.clone(), targets);
+        assert_eq!(
+            summary,
+            SendSummary {
+                succeeded: 2,
+                missing_sender: 1,
+                failed: 0,
+            }
+        );
+
+        let sent_messages = HashSet::<_>::from_iter(test_data.network.send_message.take(2).await);
+        let expected_messages = HashSet::from_iter(
+            [&peer_ids[0], &peer_ids[2]]
+                .into_iter()
+                .map(|peer_id| (message.encode(), peer_id.clone(), PROTOCOL)),
+        );
+        assert_eq!(sent_messages, expected_messages);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_exit_handles_pending_messages() {
+        let TestData {
+            mut network,
+            mut gossip_network,
+            mut service,
+            _task_manager,
+            _other_network,
+        } = TestData::prepare();
+
+        let peer_id = random_peer_id();
+        service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        for i in 0..3 {
+            gossip_network
+                .send_to(message(i), peer_id.clone())
+                .expect("service is running");
+        }
+
+        let (exit_tx, exit_rx) = oneshot::channel();
+        exit_tx.send(()).expect("service is listening");
+        service.run(exit_rx).await.expect("should exit cleanly");
+
+        let expected: Vec<_> = (0..3)
+            .map(|i| (message(i).encode(), peer_id.clone(), PROTOCOL))
+            .collect();
+        assert_eq!(network.send_message.take(3).await, expected);
+
+        network.close_channels().await;
+    }
+
+    #[tokio::test]
+    async fn test_spawned_service_sends_until_stopped() {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let (event_stream_oneshot_tx, _event_stream_oneshot_rx) = oneshot::channel();
+        let mut network = MockRawNetwork::new(event_stream_oneshot_tx);
+        let (mut gossip_network, _other_network, stop_handle) =
+            Service::<_, _, MockData, MockData>::spawn(
+                network.clone(),
+                network.event_stream(),
+                task_manager.spawn_handle().into(),
+                None,
+                Config::default(),
+            );
+
+        let peer_id = random_peer_id();
+        network.emit_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL));
+        // Give the service a moment to handle the event, so that the peer is connected.
+        time::sleep(Duration::from_millis(100)).await;
+        let message = message(1);
+        gossip_network
+            .send_to(message.clone(), peer_id.clone())
+            .expect("service is running");
+        assert_eq!(
+            network.send_message.next().await,
+            Some((message.encode(), peer_id.clone(), PROTOCOL))
+        );
+
+        assert_eq!(stop_handle.stop().await, Ok(()));
+        assert!(gossip_network.send_to(message, peer_id).is_err());
+
+        network.close_channels().await;
+    }
+
+    async fn run_until_terminated(
+        service: Service<MockRawNetwork, MockEventStream, MockData, MockData>,
+    ) -> Result<(), GossipServiceError> {
+        let (_exit_tx, exit_rx) = oneshot::channel();
+        time::timeout(Duration::from_secs(1), service.run(exit_rx))
+            .await
+            .expect("service should terminate")
+    }
+
+    #[tokio::test]
+    async fn test_run_reports_network_stream_ending() {
+        let TestData {
+            network,
+            gossip_network: _gossip_network,
+            service,
+            _task_manager,
+            _other_network,
+        } = TestData::prepare();
+
+        network.event_sinks.lock().clear();
+        assert_eq!(
+            run_until_terminated(service).await,
+            Err(GossipServiceError::NetworkStreamTerminated)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_termination_signal_fires_when_network_stream_ends() {
+        let TestData {
+            network,
+            gossip_network: _gossip_network,
+            mut service,
+            _task_manager,
+            _other_network,
+        } = TestData::prepare();
+
+        let termination = service.termination_signal();
+        network.event_sinks.lock().clear();
+        assert!(run_until_terminated(service).await.is_err());
+        assert_eq!(
+            termination.await,
+            Ok(TerminationReason::Failed(
+                GossipServiceError::NetworkStreamTerminated
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_reports_user_streams_ending() {
+        let TestData {
+            network: _network,
+            gossip_network,
+            service,
+            _task_manager,
+            _other_network,
+        } = TestData::prepare();
+        drop(gossip_network);
+        assert_eq!(
+            run_until_terminated(service).await,
+            Err(GossipServiceError::AuthorizationStreamTerminated)
+        );
+
+        let TestData {
+            network: _network,
+            gossip_network: _gossip_network,
+            service,
+            _task_manager,
+            _other_network: other_network,
+        } = TestData::prepare();
+        drop(other_network);
+        assert_eq!(
+            run_until_terminated(service).await,
+            Err(GossipServiceError::BlockSyncStreamTerminated)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_reports_failing_to_forward_messages() {
+        let TestData {
+            mut network,
+            gossip_network: _gossip_network,
+            service,
+            _task_manager,
+            _other_network,
+        } = TestData::prepare();
+
+        service.authentication.messages_for_user.close_channel();
+        network.emit_event(MockEvent::Messages(
+            random_peer_id(),
+            vec![(PROTOCOL, message(1).encode().into())],
+        ));
+        assert_eq!(
+            run_until_terminated(service).await,
+            Err(GossipServiceError::UnableToForwardMessageToUser)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_congested_and_stopped_errors() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            command_buffer: 1,
+            ..Default::default()
+        }));
+
+        // The channel fits the buffer and one message per sender.
+        for i in 0..2 {
+            test_data.broadcast(message(i)).expect("channel has space");
+        }
+        assert_eq!(test_data.broadcast(message(2)), Err(Error::Congested));
+
+        let TestData {
+            network,
+            mut gossip_network,
+            service,
+            ..
+        } = test_data;
+        drop(service);
+        assert_eq!(
+            gossip_network.broadcast(message(3)),
+            Err(Error::ServiceStopped)
+        );
+
+        network.close_channels().await;
+    }
+
+    #[tokio::test]
+    async fn test_dropped_messages_are_counted() {
+        let mut test_data =
+            TestData::prepare_with_config_and_registry(Config::default(), Some(Registry::new()));
+
+        let peer_id = random_peer_id();
+        assert!(matches!(
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(1), peer_id),
+            Err(SendError::MissingSender)
+        ));
+
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "missing_sender"])
+                .get(),
+            1
+        );
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "send_failed"])
+                .get(),
+            0
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_counted_by_authority() {
+        let mut test_data =
+            TestData::prepare_with_config_and_registry(Config::default(), Some(Registry::new()));
+
+        let authority = random_peer_id();
+        let other = random_peer_id();
+        test_data
+            .service
+            .handle()
+            .set_authorities(HashSet::from([authority.clone()]))
+            .expect("service is running");
+        while let Ok(Some(query)) = test_data.service.queries_from_handles.try_next() {
+            test_data.service.handle_query(query);
+        }
+        for peer_id in [&authority, &other] {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(1), peer_id.clone())
+                .expect("peer is connected");
+        }
+        test_data.network.send_message.take(2).await;
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                authority.clone(),
+                vec![(PROTOCOL, message(2).encode().into())],
+            ))
+            .expect("Should handle");
+        test_data.next().await.expect("Should receive message");
+        // Not connected, so the message is dropped.
+        assert!(test_data
+            .service
+            .authentication
+            .send_to_peer(message(3), random_peer_id())
+            .is_err());
+
+        let authority_messages = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                authority_messages, ..
+            } => authority_messages,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        let count = |event, authority| {
+            authority_messages
+                .with_label_values(&["authentication", event, authority])
+                .get()
+        };
+        assert_eq!(count("sent", "true"), 1);
+        assert_eq!(count("sent", "false"), 1);
+        assert_eq!(count("received", "true"), 1);
+        assert_eq!(count("received", "false"), 0);
+        assert_eq!(count("dropped", "true"), 0);
+        assert_eq!(count("dropped", "false"), 1);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_malformed_message_reports_peer() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![(PROTOCOL, vec![0u8].into())],
+            ))
+            .expect("Should handle");
+
+        assert_eq!(
+            *test_data.network.reported_peers.lock(),
+            vec![(peer_id.clone(), MALFORMED_MESSAGE)]
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_decode_failures_are_published() {
+        let (failures_tx, mut failures_rx) = mpsc::unbounded();
+        let mut test_data = TestData::prepare_with(
+            Config::default(),
+            None,
+            None,
+            None,
+            Some(failures_tx),
+            ScaleCodec,
+            None,
+        );
+
+        let peer_id = random_peer_id();
+        let malformed = vec![0u8, 1, 2];
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![(PROTOCOL, malformed.clone().into())],
+            ))
+            .expect("Should handle");
+
+        let failure = failures_rx.try_next().expect("failure should be published");
+        assert!(matches!(
+            failure,
+            Some(DecodeFailure {
+                peer,
+                protocol: PROTOCOL,
+                bytes_len,
+                kind: MessageKind::Unknown,
+                error,
+            }) if peer == peer_id && bytes_len == malformed.len() && !error.is_empty()
+        ));
+        assert_eq!(
+            *test_data.network.reported_peers.lock(),
+            vec![(peer_id, MALFORMED_MESSAGE)]
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_valid_messages_reward_peer_once() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                (0..2)
+                    .map(|i| (PROTOCOL, message(i).encode().into()))
+                    .collect(),
+            ))
+            .expect("Should handle");
+
+        assert_eq!(
+            *test_data.network.reported_peers.lock(),
+            vec![(peer_id.clone(), VALID_MESSAGE)]
+        );
+        for i in 0..2 {
+            assert_eq!(
+                test_data.next().await.expect("Should receive message"),
+                (message(i), peer_id.clone())
+            );
+        }
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_identical_broadcasts_are_deduplicated() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            broadcast_dedup: Some(BroadcastDedupConfig {
+                cache_size: 16,
+                ttl: Duration::from_secs(60),
+            }),
+            ..Default::default()
+        }));
+
+        let peer_ids: Vec<_> = (0..2).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+
+        test_data
+            .service
+            .authentication
+            .broadcast(message(1), Priority::Normal, None);
+        test_data
+            .service
+            .authentication
+            .broadcast(message(1), Priority::Normal, None);
+        test_data
+            .service
+            .authentication
+            .broadcast(message(2), Priority::Normal, None);
+
+        let sent_messages = test_data.network.send_message.take(4).await;
+        let expected_messages: HashSet<_> = [1, 2]
+            .into_iter()
+            .flat_map(|i| {
+                peer_ids
+                    .iter()
+                    .map(move |peer_id| (message(i).encode(), peer_id.clone(), PROTOCOL))
+            })
+            .collect();
+        assert_eq!(HashSet::from_iter(sent_messages), expected_messages);
+
+        // Closing the channels asserts the duplicate was never sent.
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_direct_sends_of_broadcast_data_are_deduplicated() {
+        let mut test_data = TestData::prepare_with_config_and_registry(
+            authentication_config(ProtocolConfig {
+                send_dedup: Some(SendDedupConfig {
+                    cache_size: 16,
+                    ttl: Duration::from_secs(60),
+                }),
+                // Broadcasts and direct sends are tagged differently, yet still match.
+                delivery_tags: true,
+                ..Default::default()
+            }),
+            Some(Registry::new()),
+        );
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        test_data
+            .service
+            .authentication
+            .broadcast(message(1), Priority::Normal, None);
+        for i in [1, 2] {
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(i), peer_id.clone())
+                .expect("interface works");
+        }
+
+        let sent_messages = test_data.network.send_message.take(2).await;
+        assert_eq!(
+            sent_messages,
+            vec![
+                (
+                    codec::tag(Delivery::Gossip, message(1).encode()),
+                    peer_id.clone(),
+                    PROTOCOL
+                ),
+                (
+                    codec::tag(Delivery::Direct, message(2).encode()),
+                    peer_id,
+                    PROTOCOL
+                ),
+            ]
+        );
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "already_sent"])
+                .get(),
+            1
+        );
+
+        // Closing the channels asserts the direct send was never sent.
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_sender_creation_backs_off() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            sender_backoff: BackoffConfig {
+                initial: Duration::from_millis(10),
+                max: Duration::from_millis(20),
+            },
+            ..Default::default()
+        }));
+
+        for _ in 0..3 {
+            test_data
+                .network
+                .create_sender_errors
+                .lock()
+                .push_back(MockSenderError);
+        }
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        // Every failure drops one message, the last one gets through.
+        let start = Instant::now();
+        for i in 0..4 {
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(i), peer_id.clone())
+                .expect("queue has space");
+        }
+
+        let expected = (message(3).encode(), peer_id.clone(), PROTOCOL);
+        assert_eq!(
+            test_data
+                .network
+                .send_message
+                .next()
+                .await
+                .expect("Should receive message"),
+            expected,
+        );
+        // Waited 10ms, 20ms and 20ms again because of the cap.
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let mut attempts = Vec::new();
+        for _ in 0..4 {
+            attempts.push(
+                test_data
+                    .network
+                    .next_sender_creation()
+                    .await
+                    .expect("sender creation should be recorded"),
+            );
+        }
+        assert_eq!(
+            attempts
+                .iter()
+                .map(|attempt| attempt.failed)
+                .collect::<Vec<_>>(),
+            vec![true, true, true, false],
+        );
+        for (pair, delay) in attempts.windows(2).zip([10, 20, 20]) {
+            assert_eq!(pair[1].peer_id, peer_id);
+            assert!(pair[1].at - pair[0].at >= Duration::from_millis(delay));
+        }
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wedged_send_times_out_and_sender_is_recreated() {
+        let send_timeout = Duration::from_secs(1);
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            send_timeout,
+            ..Default::default()
+        }));
+        // The first send never completes, at least not before the test is over.
+        test_data
+            .network
+            .scripted_send_delays
+            .lock()
+            .push_back(Duration::from_secs(3600));
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        let start = time::Instant::now();
+        test_data
+            .service
+            .authentication
+            .send_to_peer(message(1), peer_id.clone())
+            .expect("queue has space");
+
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((message(1).encode(), peer_id.clone(), PROTOCOL))
+        );
+        assert_eq!(start.elapsed(), send_timeout);
+        for _ in 0..2 {
+            let creation = test_data
+                .network
+                .next_sender_creation()
+                .await
+                .expect("sender creation should be recorded");
+            assert_eq!(creation.peer_id, peer_id);
+            assert!(!creation.failed);
+        }
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_tap_sees_the_exact_bytes_sent() {
+        let mut test_data = TestData::prepare();
+        let tapped = Arc::new(Mutex::new(Vec::new()));
+        let tap_sink = tapped.clone();
+        test_data
+            .service
+            .set_outgoing_tap(move |peer_id, protocol, bytes| {
+                tap_sink
+                    .lock()
+                    .push((peer_id.clone(), protocol, bytes.to_vec()))
+            });
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        test_data
+            .service
+            .authentication
+            .broadcast(message(1), Priority::Normal, None);
+
+        let (sent, _, _) = test_data
+            .network
+            .send_message
+            .next()
+            .await
+            .expect("Should receive message");
+        assert_eq!(sent, message(1).encode());
+        assert_eq!(*tapped.lock(), vec![(peer_id, PROTOCOL, sent)]);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_high_priority_is_sent_first() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        // The peer sender does not run until we yield, so both messages are queued.
+        test_data
+            .service
+            .authentication
+            .send_to_peer_with_priority(message(1), peer_id.clone(), Priority::Normal)
+            .expect("queue has space");
+        test_data
+            .service
+            .authentication
+            .send_to_peer_with_priority(message(2), peer_id.clone(), Priority::High)
+            .expect("queue has space");
+
+        let expected: Vec<_> = [2, 1]
+            .into_iter()
+            .map(|i| (message(i).encode(), peer_id.clone(), PROTOCOL))
+            .collect();
+        assert_eq!(test_data.network.send_message.take(2).await, expected);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_connected_peers() {
+        let mut test_data = TestData::prepare();
+
+        let peer_ids: HashSet<_> = (0..3).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+
+        assert_eq!(
+            HashSet::from_iter(test_data.service.connected_peers(PROTOCOL)),
+            peer_ids
+        );
+        assert!(test_data
+            .service
+            .connected_peers(Protocol::BlockSync)
+            .is_empty());
+        assert_eq!(
+            test_data.service.connected_peer_counts(),
+            HashMap::from([(PROTOCOL, 3), (Protocol::BlockSync, 0)])
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_connected_peers_query_while_running() {
+        let TestData {
+            network,
+            mut service,
+            _task_manager,
+            _other_network,
+            gossip_network: _gossip_network,
+        } = TestData::prepare();
+
+        let peer_ids: HashSet<_> = (0..3).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+
+        let handle = service.handle();
+        let (exit_tx, exit_rx) = oneshot::channel();
+        let service_task = tokio::spawn(service.run(exit_rx));
+
+        assert_eq!(
+            HashSet::from_iter(
+                handle
+                    .connected_peers(PROTOCOL)
+                    .await
+                    .expect("service is running")
+            ),
+            peer_ids
+        );
+        assert_eq!(
+            handle
+                .connected_peer_counts()
+                .await
+                .expect("service is running"),
+            HashMap::from([(PROTOCOL, 3), (Protocol::BlockSync, 0)])
+        );
+
+        exit_tx.send(()).expect("service is listening");
+        service_task
+            .await
+            .expect("task should not panic")
+            .expect("should exit cleanly");
+        assert_eq!(
+            handle.connected_peers(PROTOCOL).await,
+            Err(Error::ServiceStopped)
+        );
+
+        network.close_channels().await;
+    }
+
+    #[tokio::test]
+    async fn test_peers_are_connected_between_opening_and_closing_streams() {
+        let TestData {
+            network,
+            mut service,
+            _task_manager,
+            _other_network,
+            gossip_network: _gossip_network,
+        } = TestData::prepare();
+
+        let peer_id = random_peer_id();
+        assert!(!service.is_connected(&peer_id, PROTOCOL));
+        service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        assert!(service.is_connected(&peer_id, PROTOCOL));
+        assert!(!service.is_connected(&peer_id, Protocol::BlockSync));
+        service
+            .handle_network_event(MockEvent::StreamClosed(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        assert!(!service.is_connected(&peer_id, PROTOCOL));
+
+        service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        let handle = service.handle();
+        let (exit_tx, exit_rx) = oneshot::channel();
+        let service_task = tokio::spawn(service.run(exit_rx));
+        assert_eq!(
+            handle.is_connected(peer_id.clone(), PROTOCOL).await,
+            Ok(true)
+        );
+        assert_eq!(
+            handle.is_connected(random_peer_id(), PROTOCOL).await,
+            Ok(false)
+        );
+
+        exit_tx.send(()).expect("service is listening");
+        service_task
+            .await
+            .expect("task should not panic")
+            .expect("should exit cleanly");
+        assert_eq!(
+            handle.is_connected(peer_id, PROTOCOL).await,
+            Err(Error::ServiceStopped)
+        );
+
+        network.close_channels().await;
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_query_while_running() {
+        let TestData {
+            network,
+            mut service,
+            _task_manager,
+            _other_network,
+            gossip_network: _gossip_network,
+        } = TestData::prepare();
+
+        let peer_ids: Vec<_> = (0..2).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        service
+            .handle_network_event(MockEvent::Messages(
+                peer_ids[0].clone(),
+                vec![(PROTOCOL, message(1).encode().into())],
+            ))
+            .expect("Should handle");
+
+        let handle = service.handle();
+        let (exit_tx, exit_rx) = oneshot::channel();
+        let service_task = tokio::spawn(service.run(exit_rx));
+
+        let snapshot = handle.snapshot().await.expect("service is running");
+        assert_eq!(snapshot.authentication.connected_peers, 2);
+        assert_eq!(snapshot.block_sync.connected_peers, 0);
+        assert!(snapshot.block_sync.peers.is_empty());
+        let peer = |peer_id: &MockPublicKey| {
+            snapshot
+                .authentication
+                .peers
+                .iter()
+                .find(|peer| peer.peer_id == format!("{peer_id:?}"))
+                .expect("peer is connected")
+        };
+        assert!(peer(&peer_ids[0]).last_seen_millis_ago.is_some());
+        assert_eq!(peer(&peer_ids[1]).last_seen_millis_ago, None);
+        assert!(snapshot
+            .authentication
+            .peers
+            .iter()
+            .all(|peer| peer.queue_depth == 0));
+
+        exit_tx.send(()).expect("service is listening");
+        service_task
+            .await
+            .expect("task should not panic")
+            .expect("should exit cleanly");
+        network.close_channels().await;
+    }
+
+    #[tokio::test]
+    async fn test_broadcasts_reach_the_user_of_a_linked_service() {
+        let first_id = random_peer_id();
+        let second_id = random_peer_id();
+        let TestData {
+            network: first_network,
+            service: mut first_service,
+            gossip_network: mut first_gossip_network,
+            _task_manager: _first_task_manager,
+            _other_network: _first_other_network,
+        } = TestData::prepare();
+        let TestData {
+            network: second_network,
+            service: mut second_service,
+            gossip_network: mut second_gossip_network,
+            _task_manager: _second_task_manager,
+            _other_network: _second_other_network,
+        } = TestData::prepare();
+        first_network.link_to(first_id.clone(), &second_network, second_id.clone());
+        second_network.link_to(second_id.clone(), &first_network, first_id.clone());
+
+        first_service
+            .handle_network_event(MockEvent::StreamOpened(second_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        second_service
+            .handle_network_event(MockEvent::StreamOpened(first_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        let (first_exit_tx, first_exit_rx) = oneshot::channel();
+        let (second_exit_tx, second_exit_rx) = oneshot::channel();
+        let first_task = tokio::spawn(first_service.run(first_exit_rx));
+        let second_task = tokio::spawn(second_service.run(second_exit_rx));
+
+        let broadcast = message(1);
+        first_gossip_network
+            .broadcast(broadcast.clone())
+            .expect("Should broadcast");
+        let received = time::timeout(Duration::from_secs(5), second_gossip_network.next())
+            .await
+            .expect("should arrive well within the timeout")
+            .expect("Should receive message");
+        assert_eq!(received, (broadcast, first_id.clone()));
+
+        let reply = message(2);
+        second_gossip_network
+            .send_to(reply.clone(), first_id)
+            .expect("Should send");
+        let received = time::timeout(Duration::from_secs(5), first_gossip_network.next())
+            .await
+            .expect("should arrive well within the timeout")
+            .expect("Should receive message");
+        assert_eq!(received, (reply, second_id));
+
+        first_exit_tx.send(()).expect("service is listening");
+        second_exit_tx.send(()).expect("service is listening");
+        for task in [first_task, second_task] {
+            task.await
+                .expect("task should not panic")
+                .expect("should exit cleanly");
+        }
+        // Everything went over the link, nothing was left in the channels.
+        first_network.close_channels().await;
+        second_network.close_channels().await;
+    }
+
+    #[tokio::test]
+    async fn test_status_ticker_respects_interval() {
+        let test_data = TestData::prepare_with_config(Config {
+            status_report_interval: Duration::from_millis(10),
+            ..Default::default()
+        });
+
+        let mut status_ticker = test_data.service.status_ticker(&mut thread_rng());
+        let start = Instant::now();
+        // The first tick is immediate, the next ones follow the interval.
+        time::timeout(Duration::from_secs(1), async {
+            for _ in 0..3 {
+                status_ticker.tick().await;
+            }
+        })
+        .await
+        .expect("should tick well within the timeout");
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_status_reports_are_more_frequent_while_peers_are_backlogged() {
+        let interval = Duration::from_secs(1);
+        let mut test_data = TestData::prepare_with_config(Config {
+            status_report_interval: interval,
+            adaptive_status_reports: Some(AdaptiveStatusConfig {
+                backlog_threshold: 2,
+                min_interval: Duration::from_millis(250),
+            }),
+            ..Default::default()
+        });
+        // Peer senders never run, so nothing leaves the queues.
+        test_data.service.set_spawner(ManualSpawner::default());
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        for i in 0..3 {
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(i), peer_id.clone())
+                .expect("queue has space");
+        }
+
+        let mut status_ticker = test_data.service.status_ticker(&mut thread_rng());
+        status_ticker.tick().await;
+        test_data.service.adapt_status_ticker(&mut status_ticker);
+        for expected in [500, 250, 250].map(Duration::from_millis) {
+            let start = time::Instant::now();
+            status_ticker.tick().await;
+            assert_eq!(start.elapsed(), expected);
+            test_data.service.adapt_status_ticker(&mut status_ticker);
+        }
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamClosed(peer_id, PROTOCOL))
+            .expect("Should handle");
+        test_data.service.adapt_status_ticker(&mut status_ticker);
+        let start = time::Instant::now();
+        status_ticker.tick().await;
+        assert_eq!(start.elapsed(), interval);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unchanged_status_reports_are_suppressed() {
+        let interval = Duration::from_millis(10);
+        let heartbeat_interval = Duration::from_millis(100);
+        let mut test_data = TestData::prepare_with_config(Config {
+            status_report_interval: interval,
+            quiet_status_reports: Some(QuietStatusConfig {
+                heartbeat_interval: Some(heartbeat_interval),
+            }),
+            ..Default::default()
+        });
+
+        let first = test_data.service.next_status_report();
+        assert_eq!(first, Some(test_data.service.status()));
+        // Nothing is broadcast, so nothing changes between the reports.
+        for _ in 0..5 {
+            time::advance(interval).await;
+            assert_eq!(test_data.service.next_status_report(), None);
+        }
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(random_peer_id(), PROTOCOL))
+            .expect("Should handle");
+        let report = test_data
+            .service
+            .next_status_report()
+            .expect("the peer count changed");
+        assert!(report.contains("connected peers - 1;"));
+        assert_eq!(test_data.service.next_status_report(), None);
+
+        time::advance(heartbeat_interval).await;
+        assert_eq!(
+            test_data.service.next_status_report(),
+            Some(String::from(
+                "Network status unchanged since the previous report."
+            ))
+        );
+        assert_eq!(test_data.service.next_status_report(), None);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_first_status_report_is_jittered() {
+        let jitter = Duration::from_secs(5);
+        let test_data = TestData::prepare_with_config(Config {
+            status_report_interval: Duration::from_secs(10),
+            status_report_jitter: jitter,
+            ..Default::default()
+        });
+
+        let expected_delay = StdRng::seed_from_u64(42).gen_range(Duration::ZERO..=jitter);
+        let mut status_ticker = test_data
+            .service
+            .status_ticker(&mut StdRng::seed_from_u64(42));
+        let start = time::Instant::now();
+        status_ticker.tick().await;
+        // Timers have millisecond resolution.
+        let delay = start.elapsed();
+        assert!(delay >= expected_delay);
+        assert!(delay < expected_delay + Duration::from_millis(1));
+        assert!(expected_delay <= jitter);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_rapid_messages_are_sent_in_one_batch() {
+        let TestData {
+            mut network,
+            mut gossip_network,
+            mut service,
+            _task_manager,
+            _other_network,
+        } = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            batching: Some(BatchConfig { max_messages: 8 }),
+            ..Default::default()
+        }));
+
+        let peer_id = random_peer_id();
+        service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        for i in 0..3 {
+            gossip_network
+                .broadcast(message(i))
+                .expect("service is running");
+        }
+
+        let (exit_tx, exit_rx) = oneshot::channel();
+        let service_task = tokio::spawn(service.run(exit_rx));
+
+        let batch: Vec<_> = (0..3).map(message).collect();
+        assert_eq!(
+            network.send_message.next().await,
+            Some((batch.encode(), peer_id, PROTOCOL))
+        );
+
+        exit_tx.send(()).expect("service is listening");
+        service_task
+            .await
+            .expect("task should not panic")
+            .expect("should exit cleanly");
+
+        network.close_channels().await;
+    }
+
+    #[tokio::test]
+    async fn test_received_batch_is_delivered_message_by_message() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            batching: Some(BatchConfig { max_messages: 8 }),
+            ..Default::default()
+        }));
+
+        let peer_id = random_peer_id();
+        let batch: Vec<_> = (0..3).map(message).collect();
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![(PROTOCOL, batch.encode().into())],
+            ))
+            .expect("Should handle");
+
+        for expected in batch {
+            assert_eq!(
+                test_data.next().await.expect("Should receive message"),
+                (expected, peer_id.clone())
+            );
+        }
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_peer_gets_keepalive() {
+        let keepalive_interval = Duration::from_secs(30);
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            keepalive_interval: Some(keepalive_interval),
+            ..Default::default()
+        }));
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        // Let the peer sender start waiting for messages.
+        tokio::task::yield_now().await;
+
+        time::advance(keepalive_interval - Duration::from_secs(1)).await;
+        assert_eq!(test_data.network.send_message.try_next().await, None);
+
+        time::advance(Duration::from_secs(1)).await;
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((Vec::new(), peer_id, PROTOCOL))
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_received_keepalive_is_discarded() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![
+                    (PROTOCOL, Vec::new().into()),
+                    (PROTOCOL, message(1).encode().into()),
+                ],
+            ))
+            .expect("Should handle");
+
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message(1), peer_id)
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_last_seen_is_tracked_until_stream_closed() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        assert!(test_data.service.last_seen(PROTOCOL).is_empty());
+
+        let before = Instant::now();
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![(PROTOCOL, message(1).encode().into())],
+            ))
+            .expect("Should handle");
+        test_data.next().await.expect("Should receive message");
+
+        let last_seen = test_data.service.last_seen(PROTOCOL);
+        assert!(last_seen[&peer_id] >= before);
+        assert!(test_data.service.last_seen(Protocol::BlockSync).is_empty());
+        assert!(test_data.service.status().contains("stale peers - 0;"));
+        assert!(test_data
+            .service
+            .authentication
+            .status(Duration::ZERO)
+            .contains("stale peers - 1;"));
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamClosed(peer_id, PROTOCOL))
+            .expect("Should handle");
+        assert!(test_data.service.last_seen(PROTOCOL).is_empty());
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                random_peer_id(),
+                vec![(PROTOCOL, message(2).encode().into())],
+            ))
+            .expect("Should handle");
+        test_data.next().await.expect("Should receive message");
+        assert!(test_data.service.last_seen(PROTOCOL).is_empty());
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_compressed_messages_round_trip() {
+        let compression = CompressionConfig { threshold: 0 };
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            compression: Some(compression.clone()),
+            ..Default::default()
+        }));
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        let message = MockData::new(1, 1024);
+        test_data
+            .service
+            .authentication
+            .broadcast(message.clone(), Priority::Normal, None);
+        let (sent, _, _) = test_data
+            .network
+            .send_message
+            .next()
+            .await
+            .expect("Should send message");
+        assert_eq!(sent, compression::compress(&message.encode(), &compression));
+        assert!(sent.len() < message.encode().len());
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![(PROTOCOL, sent.into())],
+            ))
+            .expect("Should handle");
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message, peer_id)
+        );
+
+        test_data.cleanup().await
+    }
+
+    /// The frames of `MockData::new(data, 200)`, four of them.
+    fn fragmented_message(message_id: u32, data: u32) -> Vec<(Protocol, Bytes)> {
+        fragment::split(message_id, &MockData::new(data, 200).encode(), 64)
+            .into_iter()
+            .map(|frame| (PROTOCOL, frame.into()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_messages_round_trip() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            fragmentation: Some(FragmentationConfig {
+                frame_size: 64,
+                reassembly_timeout: Duration::from_secs(10),
+                max_partial_messages: 4,
+                max_partial_bytes: 4096,
+            }),
+            ..Default::default()
+        }));
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        let message = MockData::new(1, 200);
+        test_data
+            .service
+            .authentication
+            .broadcast(message.clone(), Priority::Normal, None);
+        let frames: Vec<_> = test_data
+            .network
+            .send_message
+            .take(4)
+            .await
+            .into_iter()
+            .map(|(frame, _, _)| frame)
+            .collect();
+        assert!(frames.iter().all(|frame| frame.len() <= 64));
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                frames
+                    .into_iter()
+                    .map(|frame| (PROTOCOL, frame.into()))
+                    .collect(),
+            ))
+            .expect("Should handle");
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message, peer_id)
+        );
+
+        // Closing the channels asserts the message was split into exactly four frames.
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_incomplete_fragmented_messages_time_out() {
+        let reassembly_timeout = Duration::from_secs(10);
+        let mut test_data = TestData::prepare_with_config_and_registry(
+            authentication_config(ProtocolConfig {
+                fragmentation: Some(FragmentationConfig {
+                    frame_size: 64,
+                    reassembly_timeout,
+                    max_partial_messages: 4,
+                    max_partial_bytes: 4096,
+                }),
+                ..Default::default()
+            }),
+            Some(Registry::new()),
+        );
+
+        let peer_id = random_peer_id();
+        let mut rest = fragmented_message(1, 1);
+        let first = rest.remove(0);
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(peer_id.clone(), vec![first]))
+            .expect("Should handle");
+        time::advance(reassembly_timeout).await;
+        // The rest of the first message comes too late, so only the second one is complete.
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(peer_id.clone(), rest))
+            .expect("Should handle");
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                fragmented_message(2, 2),
+            ))
+            .expect("Should handle");
+
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (MockData::new(2, 200), peer_id)
+        );
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "incomplete"])
+                .get(),
+            1
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_oldest_incomplete_messages_are_evicted() {
+        let mut test_data = TestData::prepare_with_config_and_registry(
+            authentication_config(ProtocolConfig {
+                fragmentation: Some(FragmentationConfig {
+                    frame_size: 64,
+                    reassembly_timeout: Duration::from_secs(10),
+                    max_partial_messages: 2,
+                    max_partial_bytes: 4096,
+                }),
+                ..Default::default()
+            }),
+            Some(Registry::new()),
+        );
+
+        let peer_id = random_peer_id();
+        let mut messages: Vec<_> = (1..=3).map(|i| fragmented_message(i, i)).collect();
+        let firsts: Vec<_> = messages.iter_mut().map(|frames| frames.remove(0)).collect();
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(peer_id.clone(), firsts))
+            .expect("Should handle");
+        // The first message was evicted, so the rest of its frames is not enough.
+        let rest_of_first = messages.remove(0);
+        for rest in messages
+            .into_iter()
+            .chain([rest_of_first, fragmented_message(4, 4)])
+        {
+            test_data
+                .service
+                .handle_network_event(MockEvent::Messages(peer_id.clone(), rest))
+                .expect("Should handle");
+        }
+
+        for i in [2, 3, 4] {
+            assert_eq!(
+                test_data.next().await.expect("Should receive message"),
+                (MockData::new(i, 200), peer_id.clone())
+            );
+        }
+        assert!(test_data
+            .network
+            .reported_peers
+            .lock()
+            .contains(&(peer_id, REASSEMBLY_LIMIT_EXCEEDED)));
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "evicted"])
+                .get(),
+            1
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_send_is_retried_with_new_sender() {
+        let mut test_data = TestData::prepare();
+
+        test_data
+            .network
+            .send_errors
+            .lock()
+            .push_back(MockSenderError);
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        test_data
+            .service
+            .authentication
+            .broadcast(message(1), Priority::Normal, None);
+
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((message(1).encode(), peer_id, PROTOCOL))
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_breaker_drops_messages_during_cooldown() {
+        let cooldown = Duration::from_secs(10);
+        let mut test_data = TestData::prepare_with_config_and_registry(
+            authentication_config(ProtocolConfig {
+                circuit_breaker: Some(CircuitBreakerConfig {
+                    failure_threshold: 2,
+                    cooldown,
+                }),
+                ..Default::default()
+            }),
+            Some(Registry::new()),
+        );
+        // Every failed message uses up two senders, as sending is retried once.
+        test_data
+            .network
+            .send_errors
+            .lock()
+            .extend([MockSenderError; 4]);
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        for i in 1..=3 {
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(i), peer_id.clone())
+                .expect("peer is connected");
+        }
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped.clone(),
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        let circuit_open = messages_dropped.with_label_values(&["authentication", "circuit_open"]);
+        time::timeout(Duration::from_secs(1), async {
+            while circuit_open.get() == 0 {
+                time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("third message should be dropped");
+        assert_eq!(circuit_open.get(), 1);
+        assert_eq!(test_data.service.authentication.open_circuits(), 1);
+        assert!(test_data.network.send_message.try_next().await.is_none());
+
+        time::advance(cooldown).await;
+        test_data
+            .service
+            .authentication
+            .send_to_peer(message(4), peer_id.clone())
+            .expect("peer is connected");
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((message(4).encode(), peer_id, PROTOCOL))
+        );
+        assert_eq!(test_data.service.authentication.open_circuits(), 0);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_broadcasts_skip_peers_with_open_circuits() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            circuit_breaker: Some(CircuitBreakerConfig {
+                failure_threshold: 1,
+                cooldown: Duration::from_secs(10),
+            }),
+            ..Default::default()
+        }));
+
+        let peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        assert!(test_data.service.authentication.circuit_breakers[&peer_ids[0]].record_failure());
+
+        let result = test_data
+            .service
+            .authentication
+            .broadcast(message(1), Priority::Normal, None);
+        assert_eq!(
+            result,
+            BroadcastResult {
+                protocol: PROTOCOL,
+                attempted: 2,
+                succeeded: 2,
+                failed_peers: Vec::new(),
+                skipped_circuit_open: 1,
+            }
+        );
+        let receivers: HashSet<_> = test_data
+            .network
+            .send_message
+            .take(2)
+            .await
+            .into_iter()
+            .map(|(_, peer_id, _)| peer_id)
+            .collect();
+        assert_eq!(receivers, HashSet::from_iter(peer_ids[1..].iter().cloned()));
+
+        // Closing the channels asserts nothing was sent to the peer with the open circuit.
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_confirmation_fires_after_send() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        let mut confirmation = test_data
+            .send_to_confirmed(message(1), peer_id.clone())
+            .expect("interface works");
+        let command = test_data
+            .service
+            .authentication
+            .messages_from_user
+            .next()
+            .await
+            .expect("command was sent");
+        test_data.service.authentication.handle_command(command);
+        assert_eq!(confirmation.try_recv(), Ok(None));
+
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((message(1).encode(), peer_id, PROTOCOL))
+        );
+        assert_eq!(confirmation.await, Ok(Ok(())));
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_confirmation_reports_failed_send() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+        let (tx, rx) = oneshot::channel();
+        assert!(test_data
+            .service
+            .authentication
+            .send_to_peer_confirmed(message(1), peer_id.clone(), PendingConfirmation::new(tx))
+            .is_err());
+        assert_eq!(rx.await, Ok(Err(Error::SendFailed)));
+
+        test_data
+            .network
+            .send_errors
+            .lock()
+            .extend([MockSenderError, MockSenderError]);
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        let (tx, rx) = oneshot::channel();
+        test_data
+            .service
+            .authentication
+            .send_to_peer_confirmed(message(1), peer_id, PendingConfirmation::new(tx))
+            .expect("peer is connected");
+        assert_eq!(rx.await, Ok(Err(Error::SendFailed)));
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_barrier_reports_every_peer() {
+        let mut test_data = TestData::prepare();
+        let spawner = ManualSpawner::default();
+        test_data.service.set_spawner(spawner.clone());
+
+        let peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        let barrier = test_data
+            .broadcast_barrier(message(1))
+            .expect("service is running");
+        let command = test_data
+            .service
+            .authentication
+            .messages_from_user
+            .next()
+            .await
+            .expect("command should be sent");
+        test_data.service.authentication.handle_command(command);
+        // Peer senders run in the order they were spawned, so both the send and the retry of the
+        // first peer fail.
+        test_data
+            .network
+            .send_errors
+            .lock()
+            .extend([MockSenderError, MockSenderError]);
+        spawner.step();
+
+        assert_eq!(
+            barrier.await,
+            HashMap::from([
+                (peer_ids[0].clone(), Err(Error::SendFailed)),
+                (peer_ids[1].clone(), Ok(())),
+                (peer_ids[2].clone(), Ok(())),
+            ])
+        );
+        assert_eq!(test_data.network.send_message.take(2).await.len(), 2);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_try_broadcast_reports_congestion() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            command_buffer: 1,
+            ..Default::default()
+        }));
+
+        // The channel fits the buffer and one message per sender.
+        for i in 0..2 {
+            test_data
+                .try_broadcast(message(i))
+                .expect("channel has space");
+        }
+        assert_eq!(test_data.try_broadcast(message(2)), Err(Error::Congested));
+
+        test_data.cleanup().await
+    }
+
+    type SpanFields = Vec<(&'static str, String)>;
+
+    /// Records the names and fields of all the created spans.
+    #[derive(Clone, Default)]
+    struct SpanRecorder(Arc<Mutex<Vec<(&'static str, SpanFields)>>>);
+
+    struct FieldRecorder<'a>(&'a mut SpanFields);
+
+    impl Visit for FieldRecorder<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push((field.name(), format!("{value:?}")));
+        }
+    }
+
+    impl Subscriber for SpanRecorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut fields = Vec::new();
+            span.record(&mut FieldRecorder(&mut fields));
+            let mut spans = self.0.lock();
+            spans.push((span.metadata().name(), fields));
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, _: &tracing::Event<'_>) {}
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn test_spans_carry_peer_and_protocol() {
+        let recorder = SpanRecorder::default();
+        let peer_id = random_peer_id();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            service_span();
+            peer_sender_span(&peer_id, PROTOCOL);
+            network_event_span(&peer_id);
+        });
+
+        let peer = format!("{peer_id:?}");
+        assert_eq!(
+            *recorder.0.lock(),
+            vec![
+                ("gossip_service", vec![]),
+                (
+                    "peer_sender",
+                    vec![
+                        ("peer", peer.clone()),
+                        ("protocol", format!("{PROTOCOL:?}"))
+                    ]
+                ),
+                ("network_event", vec![("peer", peer)]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_peers_keep_their_log_names_across_reconnects() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        let name = test_data.service.peer_names.name(&peer_id);
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamClosed(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(
+                peer_id.clone(),
+                Protocol::BlockSync,
+            ))
+            .expect("Should handle");
+
+        assert_eq!(test_data.service.peer_names.name(&peer_id), name);
+        assert_eq!(test_data.service.block_sync.peer_names.name(&peer_id), name);
+        assert_ne!(test_data.service.peer_names.name(&random_peer_id()), name);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_stream_opened_replaces_sender() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+        for i in 0..2 {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(i), peer_id.clone())
+                .expect("queue has space");
+            assert_eq!(
+                test_data.network.send_message.next().await,
+                Some((message(i).encode(), peer_id.clone(), PROTOCOL))
+            );
+        }
+
+        assert_eq!(test_data.service.authentication.peer_senders.len(), 1);
+        // The replaced peer sender exited, dropping its network sender.
+        tokio::task::yield_now().await;
+        assert_eq!(test_data.network.live_senders(), 1);
+        assert!(test_data
+            .service
+            .status()
+            .contains("duplicate stream openings - 1;"));
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_messages_queued_for_a_replaced_sender_are_requeued() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        test_data
+            .service
+            .authentication
+            .send_to_peer(message(0), peer_id.clone())
+            .expect("queue has space");
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((message(0).encode(), peer_id.clone(), PROTOCOL))
+        );
+        test_data
+            .network
+            .next_sender_creation()
+            .await
+            .expect("a sender was created");
+
+        // The peer sender does not run before the next await, so the messages stay queued.
+        for i in 1..4 {
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(i), peer_id.clone())
+                .expect("queue has space");
+        }
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        assert_eq!(
+            test_data.network.send_message.take(3).await,
+            (1..4)
+                .map(|i| (message(i).encode(), peer_id.clone(), PROTOCOL))
+                .collect::<Vec<_>>()
+        );
+        // They were sent by the new peer sender, the replaced one exited without sending them.
+        let creation = test_data
+            .network
+            .next_sender_creation()
+            .await
+            .expect("a sender was created");
+        assert_eq!(creation.peer_id, peer_id);
+        tokio::task::yield_now().await;
+        assert_eq!(test_data.network.live_senders(), 1);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_active_peer_senders_return_to_zero() {
+        let mut test_data =
+            TestData::prepare_with_config_and_registry(Config::default(), Some(Registry::new()));
+        let active_peer_senders = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                active_peer_senders,
+                ..
+            } => active_peer_senders.with_label_values(&["authentication"]),
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+
+        let peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        // Reopening replaces the peer sender, the replaced one exits.
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_ids[0].clone(), PROTOCOL))
+            .expect("Should handle");
+        tokio::task::yield_now().await;
+        assert_eq!(active_peer_senders.get(), 3);
+
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamClosed(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        tokio::task::yield_now().await;
+        assert_eq!(active_peer_senders.get(), 0);
+        assert_eq!(
+            test_data
+                .service
+                .authentication
+                .active_peer_senders
+                .load(Ordering::Relaxed),
+            0
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_is_rejected_before_decoding() {
+        let small = message(1);
+        let large = MockData::new(2, 64);
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            max_message_size: Some(small.encoded_size()),
+            ..Default::default()
+        }));
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![
+                    (PROTOCOL, large.encode().into()),
+                    (PROTOCOL, small.encode().into()),
+                ],
+            ))
+            .expect("Should handle");
+
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (small, peer_id.clone())
+        );
+        assert_eq!(
+            test_data.network.reported_peers.lock().first(),
+            Some(&(peer_id, MALFORMED_MESSAGE))
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_connection_events_are_published() {
+        let mut test_data = TestData::prepare();
+        let connection_events = test_data.service.subscribe_connection_events();
+
+        let peer_id = random_peer_id();
+        for event in [
+            MockEvent::StreamOpened(peer_id.clone(), PROTOCOL),
+            // Neither reopening nor closing twice changes anything.
+            MockEvent::StreamOpened(peer_id.clone(), PROTOCOL),
+            MockEvent::StreamClosed(peer_id.clone(), PROTOCOL),
+            MockEvent::StreamClosed(peer_id.clone(), PROTOCOL),
+        ] {
+            test_data
+                .service
+                .handle_network_event(event)
+                .expect("Should handle");
+        }
+        drop(test_data.service);
+
+        assert_eq!(
+            connection_events.collect::<Vec<_>>().await,
+            vec![
+                ConnectionEvent::PeerConnected(peer_id.clone(), PROTOCOL),
+                ConnectionEvent::PeerDisconnected(peer_id, PROTOCOL),
+            ]
+        );
+
+        test_data.network.close_channels().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_peers_with_growing_queues_are_evicted() {
+        let mut test_data = TestData::prepare_with_config(Config {
+            authentication: ProtocolConfig {
+                stuck_peer_detection: Some(StuckPeerConfig {
+                    growth_intervals: 3,
+                    evict: true,
+                }),
+                ..Default::default()
+            },
+            // Well before peer senders that never run count as dead.
+            status_report_interval: Duration::from_secs(1),
+            ..Default::default()
+        });
+        // Peer senders never run, so nothing leaves the queues.
+        test_data.service.set_spawner(ManualSpawner::default());
+        let connection_events = test_data.service.subscribe_connection_events();
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        let mut service = test_data.run();
+
+        // The first report, as soon as the service starts, notes the empty queue, each of the
+        // following ones sees it growing.
+        for i in 0..3 {
+            assert_eq!(
+                service.handle.connected_peers(PROTOCOL).await,
+                Ok(vec![peer_id.clone()])
+            );
+            service.broadcast(message(i)).await;
+            service.tick_status().await;
+        }
+        assert_eq!(service.handle.connected_peers(PROTOCOL).await, Ok(vec![]));
+        service.stop().await;
+
+        assert_eq!(
+            connection_events.collect::<Vec<_>>().await,
+            vec![
+                ConnectionEvent::PeerConnected(peer_id.clone(), PROTOCOL),
+                ConnectionEvent::PeerDisconnected(peer_id, PROTOCOL),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_peers_without_running_peer_sender_are_reaped() {
+        let mut test_data = TestData::prepare();
+        let spawner = ManualSpawner::default();
+        test_data.service.set_spawner(spawner.clone());
+        let connection_events = test_data.service.subscribe_connection_events();
+
+        let running_peer = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(running_peer.clone(), PROTOCOL))
+            .expect("Should handle");
+        // Only the first peer sender ever runs.
+        spawner.step();
+        let dead_peer = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(dead_peer.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        test_data.service.status_report();
+        assert_eq!(test_data.service.connected_peers(PROTOCOL).len(), 2);
+
+        time::advance(PEER_SENDER_START_TIMEOUT).await;
+        assert!(test_data
+            .service
+            .status()
+            .contains("dead peer senders - 1; "));
+        test_data.service.status_report();
+        assert_eq!(
+            test_data.service.connected_peers(PROTOCOL),
+            vec![running_peer.clone()]
+        );
+        assert!(!test_data
+            .service
+            .authentication
+            .peer_senders
+            .contains_key(&dead_peer));
+        drop(test_data.service);
+
+        let events = connection_events.collect::<Vec<_>>().await;
+        assert!(events.contains(&ConnectionEvent::PeerDisconnected(dead_peer, PROTOCOL)));
+        assert!(!events.contains(&ConnectionEvent::PeerDisconnected(running_peer, PROTOCOL)));
+
+        test_data.network.close_channels().await;
+    }
+
+    #[tokio::test]
+    async fn test_recent_broadcasts_are_replayed_to_new_peers() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            replay_buffer_size: 2,
+            ..Default::default()
+        }));
+
+        let old_peer = random_peer_id();
+        let new_peer = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(old_peer.clone(), PROTOCOL))
+            .expect("Should handle");
+        for i in 0..3 {
+            test_data
+                .service
+                .authentication
+                .broadcast(message(i), Priority::Normal, None);
+        }
+        // Data sent to a single peer is never replayed.
+        test_data
+            .service
+            .authentication
+            .send_to_peer(message(3), old_peer.clone())
+            .expect("peer is connected");
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(new_peer.clone(), PROTOCOL))
+            .expect("Should handle");
+
+        let expected: HashSet<_> = (0..4)
+            .map(|i| (message(i).encode(), old_peer.clone(), PROTOCOL))
+            .chain((1..3).map(|i| (message(i).encode(), new_peer.clone(), PROTOCOL)))
+            .collect();
+        let sent: HashSet<_> = test_data
+            .network
+            .send_message
+            .take(6)
+            .await
+            .into_iter()
+            .collect();
+        assert_eq!(sent, expected);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_peers_over_limit_are_rejected() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            peer_limit: Some(PeerLimitConfig {
+                max_peers: 2,
+                policy: PeerLimitPolicy::RejectNew,
+            }),
+            ..Default::default()
+        }));
+
+        let peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        // Reopening a connected peer does not count against the limit.
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_ids[0].clone(), PROTOCOL))
+            .expect("Should handle");
+
+        assert_eq!(
+            HashSet::<_>::from_iter(test_data.service.connected_peers(PROTOCOL)),
+            HashSet::from_iter(peer_ids[..2].iter().cloned())
+        );
+        assert!(!test_data
+            .service
+            .authentication
+            .peer_senders
+            .contains_key(&peer_ids[2]));
+        assert_eq!(
+            *test_data.network.reported_peers.lock(),
+            vec![(peer_ids[2].clone(), PEER_LIMIT_REACHED)]
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_important_peer_is_served_first_and_evicted_last() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            peer_limit: Some(PeerLimitConfig {
+                max_peers: 3,
+                policy: PeerLimitPolicy::EvictLeastRecentlySeen,
+            }),
+            ..Default::default()
+        }));
+        // Peer senders never run, the outcome of queueing does not matter here.
+        test_data.service.set_spawner(ManualSpawner::default());
+        let peer_ids: Vec<_> = (0..4).map(|_| random_peer_id()).collect();
+        let important = peer_ids[0].clone();
+        test_data
+            .service
+            .set_peer_priority(move |a, b| (b == &important).cmp(&(a == &important)));
+
+        for peer_id in &peer_ids[..3] {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        // The important peer is heard from least recently.
+        for peer_id in &peer_ids[1..3] {
+            test_data
+                .service
+                .handle_network_event(MockEvent::Messages(
+                    peer_id.clone(),
+                    vec![(PROTOCOL, message(1).encode().into())],
+                ))
+                .expect("Should handle");
+        }
+
+        let mut seconds = HashSet::new();
+        for i in 0..3 {
+            let peers = test_data.service.authentication.broadcast_peers();
+            assert_eq!(peers[0], peer_ids[0]);
+            seconds.insert(peers[1].clone());
+            test_data
+                .service
+                .authentication
+                .broadcast(message(i), Priority::Normal, None);
+        }
+        // The other peers still take turns.
+        assert_eq!(seconds, HashSet::from_iter(peer_ids[1..3].iter().cloned()));
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_ids[3].clone(), PROTOCOL))
+            .expect("Should handle");
+        let connected = HashSet::<_>::from_iter(test_data.service.connected_peers(PROTOCOL));
+        assert!(connected.contains(&peer_ids[0]));
+        assert!(connected.contains(&peer_ids[3]));
+        assert_eq!(connected.len(), 3);
+        drop(test_data.service);
+
+        test_data.network.close_channels().await;
+    }
+
+    #[tokio::test]
+    async fn test_least_recently_seen_peer_is_evicted() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            peer_limit: Some(PeerLimitConfig {
+                max_peers: 2,
+                policy: PeerLimitPolicy::EvictLeastRecentlySeen,
+            }),
+            ..Default::default()
+        }));
+        let connection_events = test_data.service.subscribe_connection_events();
+
+        let peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids[..2] {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        // The second peer is heard from after the first one.
+        for peer_id in peer_ids[..2].iter().rev() {
+            test_data
+                .service
+                .handle_network_event(MockEvent::Messages(
+                    peer_id.clone(),
+                    vec![(PROTOCOL, message(1).encode().into())],
+                ))
+                .expect("Should handle");
+            test_data.next().await.expect("Should receive message");
+        }
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_ids[2].clone(), PROTOCOL))
+            .expect("Should handle");
+
+        assert_eq!(
+            HashSet::<_>::from_iter(test_data.service.connected_peers(PROTOCOL)),
+            HashSet::from_iter([peer_ids[0].clone(), peer_ids[2].clone()])
+        );
+        drop(test_data.service);
+        assert_eq!(
+            connection_events.collect::<Vec<_>>().await,
+            vec![
+                ConnectionEvent::PeerConnected(peer_ids[0].clone(), PROTOCOL),
+                ConnectionEvent::PeerConnected(peer_ids[1].clone(), PROTOCOL),
+                ConnectionEvent::PeerDisconnected(peer_ids[1].clone(), PROTOCOL),
+                ConnectionEvent::PeerConnected(peer_ids[2].clone(), PROTOCOL),
+            ]
+        );
+
+        test_data.network.close_channels().await;
+    }
+
+    #[tokio::test]
+    async fn test_messages_from_peers_not_on_allowlist_are_dropped() {
+        let mut test_data =
+            TestData::prepare_with_config_and_registry(Config::default(), Some(Registry::new()));
+
+        let allowed = random_peer_id();
+        let other = random_peer_id();
+        test_data
+            .service
+            .set_allowlist(PROTOCOL, Some(HashSet::from([allowed.clone()])));
+        for peer_id in [&other, &allowed] {
+            test_data
+                .service
+                .handle_network_event(MockEvent::Messages(
+                    peer_id.clone(),
+                    vec![(PROTOCOL, message(1).encode().into())],
+                ))
+                .expect("Should handle");
+        }
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message(1), allowed)
+        );
+
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "not_allowed"])
+                .get(),
+            1
+        );
+
+        test_data.service.set_allowlist(PROTOCOL, None);
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                other.clone(),
+                vec![(PROTOCOL, message(2).encode().into())],
+            ))
+            .expect("Should handle");
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message(2), other)
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_blocked_peers_never_get_a_sender() {
+        let mut test_data =
+            TestData::prepare_with_config_and_registry(Config::default(), Some(Registry::new()));
+
+        let blocked = random_peer_id();
+        test_data
+            .service
+            .handle()
+            .set_blocklist(HashSet::from([blocked.clone()]))
+            .expect("service is running");
+        while let Ok(Some(query)) = test_data.service.queries_from_handles.try_next() {
+            test_data.service.handle_query(query);
+        }
+        for _ in 0..3 {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(blocked.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                blocked.clone(),
+                vec![(PROTOCOL, message(1).encode().into())],
+            ))
+            .expect("Should handle");
+
+        assert!(test_data.network.created_senders.try_next().await.is_none());
+        assert!(test_data.service.authentication.peer_senders.is_empty());
+        assert!(!test_data.service.is_connected(&blocked, PROTOCOL));
+        assert_eq!(
+            *test_data.network.disconnected_peers.lock(),
+            vec![blocked.clone(); 3]
+        );
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "blocked"])
+                .get(),
+            1
+        );
+
+        test_data.service.set_blocklist(HashSet::new());
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(blocked.clone(), PROTOCOL))
+            .expect("Should handle");
+        assert!(test_data.service.is_connected(&blocked, PROTOCOL));
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_messages_from_unconnected_peers_are_dropped() {
+        let tolerance = Duration::from_secs(1);
+        let mut test_data = TestData::prepare_with_config_and_registry(
+            authentication_config(ProtocolConfig {
+                unconnected_tolerance: Some(tolerance),
+                ..Default::default()
+            }),
+            Some(Registry::new()),
+        );
+
+        let unknown = random_peer_id();
+        let leaving = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(leaving.clone(), PROTOCOL))
+            .expect("Should handle");
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamClosed(leaving.clone(), PROTOCOL))
+            .expect("Should handle");
+        // Messages sent just before disconnecting are still handled.
+        for peer_id in [&unknown, &leaving] {
+            test_data
+                .service
+                .handle_network_event(MockEvent::Messages(
+                    peer_id.clone(),
+                    vec![(PROTOCOL, message(1).encode().into())],
+                ))
+                .expect("Should handle");
+        }
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message(1), leaving.clone())
+        );
+
+        time::advance(tolerance).await;
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                leaving.clone(),
+                vec![(PROTOCOL, message(2).encode().into())],
+            ))
+            .expect("Should handle");
+
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "not_connected"])
+                .get(),
+            2
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_streams_of_peers_not_on_allowlist_are_skipped() {
+        let mut service = TestData::prepare().run();
+
+        let allowed = random_peer_id();
+        let other = random_peer_id();
+        service
+            .handle
+            .set_allowlist(PROTOCOL, Some(HashSet::from([allowed.clone()])))
+            .expect("service is running");
+        service.settle().await;
+        service
+            .emit([
+                MockEvent::StreamOpened(other, PROTOCOL),
+                MockEvent::StreamOpened(allowed.clone(), PROTOCOL),
+            ])
+            .await;
+        assert_eq!(
+            service
+                .handle
+                .connected_peers(PROTOCOL)
+                .await
+                .expect("service is running"),
+            vec![allowed]
+        );
+
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_removes_peer_and_closes_connection() {
+        let mut test_data = TestData::prepare();
+        let connection_events = test_data.service.subscribe_connection_events();
+
+        let peer_id = random_peer_id();
+        let protocols = [Protocol::Authentication, Protocol::BlockSync];
+        for protocol in protocols {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), protocol))
+                .expect("Should handle");
+        }
+        let handle = test_data.service.handle();
+        handle
+            .disconnect_peer(peer_id.clone())
+            .expect("service is running");
+        while let Ok(Some(query)) = test_data.service.queries_from_handles.try_next() {
+            test_data.service.handle_query(query);
+        }
+
+        for protocol in protocols {
+            assert!(test_data.service.connected_peers(protocol).is_empty());
+        }
+        assert!(test_data.service.authentication.peer_senders.is_empty());
+        assert!(test_data.service.block_sync.peer_senders.is_empty());
+        assert_eq!(
+            *test_data.network.disconnected_peers.lock(),
+            vec![peer_id.clone()]
+        );
+        drop(test_data.service);
+        assert_eq!(
+            connection_events.collect::<Vec<_>>().await,
+            protocols
+                .iter()
+                .map(|protocol| ConnectionEvent::PeerConnected(peer_id.clone(), *protocol))
+                .chain(
+                    protocols
+                        .iter()
+                        .map(|protocol| ConnectionEvent::PeerDisconnected(
+                            peer_id.clone(),
+                            *protocol
+                        ))
+                )
+                .collect::<Vec<_>>()
+        );
+
+        test_data.network.close_channels().await;
+    }
+
+    #[tokio::test]
+    async fn test_dial_reaches_the_network_with_the_address() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+        let address = String::from("/ip4/10.0.0.1/tcp/30333");
+        let handle = test_data.service.handle();
+        handle
+            .dial(peer_id.clone(), address.clone())
+            .expect("service is running");
+        while let Ok(Some(query)) = test_data.service.queries_from_handles.try_next() {
+            test_data.service.handle_query(query);
+        }
+
+        assert_eq!(
+            *test_data.network.dialed_peers.lock(),
+            vec![(peer_id, address)]
+        );
+        // Dialing only asks the network, the peer connects once its streams open.
+        assert!(test_data.service.connected_peers(PROTOCOL).is_empty());
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_broadcasts_rotate_among_peers() {
+        let mut test_data = TestData::prepare();
+
+        let peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+
+        for first in [0, 1, 2, 0] {
+            let expected: Vec<_> = peer_ids[first..]
+                .iter()
+                .chain(&peer_ids[..first])
+                .cloned()
+                .collect();
+            assert_eq!(test_data.service.authentication.rotated_peers(), expected);
+            test_data
+                .service
+                .authentication
+                .broadcast(message(1), Priority::Normal, None);
+        }
+        test_data.network.send_message.take(12).await;
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamClosed(peer_ids[0].clone(), PROTOCOL))
+            .expect("Should handle");
+        assert_eq!(
+            test_data.service.authentication.rotated_peers(),
+            vec![peer_ids[1].clone(), peer_ids[2].clone()]
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_waits_until_queued_messages_are_sent() {
+        let mut test_data = TestData::prepare();
+        *test_data.network.send_delay.lock() = Duration::from_millis(100);
+
+        let peer_ids: Vec<_> = (0..2).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        for i in 0..3 {
+            test_data
+                .service
+                .authentication
+                .broadcast(message(i), Priority::Normal, None);
+        }
+        let mut service = test_data.run();
+
+        // Peer senders are still busy sending the first messages.
+        assert_eq!(
+            service.handle.flush(Duration::ZERO).await,
+            Err(Error::FlushTimedOut(6))
+        );
+        // Messages the service was not told about yet are flushed too.
+        service
+            .gossip_network
+            .broadcast(message(3))
+            .expect("service is running");
+        assert_eq!(service.handle.flush(Duration::from_secs(1)).await, Ok(()));
+        for _ in 0..8 {
+            assert!(service.network.send_message.try_next().await.is_some());
+        }
+
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_flush_handles_at_most_a_command_buffer() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            command_buffer: 1,
+            ..Default::default()
+        }));
+
+        // The channel fits the buffer and one message per sender.
+        for i in 0..2 {
+            test_data.broadcast(message(i)).expect("channel has space");
+        }
+        let (tx, _rx) = oneshot::channel();
+        test_data.service.flush(Duration::from_secs(1), tx);
+        assert!(matches!(
+            test_data
+                .service
+                .authentication
+                .messages_from_user
+                .try_next(),
+            Ok(Some(_))
+        ));
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_times_out_while_events_keep_arriving() {
+        let mut test_data = TestData::prepare();
+        *test_data.network.send_delay.lock() = Duration::from_secs(10);
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        test_data
+            .service
+            .authentication
+            .broadcast(message(0), Priority::Normal, None);
+        let service = test_data.run();
+
+        // Events arrive more often than the flush gets polled.
+        let mut network = service.network.clone();
+        let flood = tokio::spawn(async move {
+            loop {
+                network.emit_event(MockEvent::StreamClosed(random_peer_id(), PROTOCOL));
+                time::sleep(FLUSH_POLL_INTERVAL / 10).await;
+            }
+        });
+        let timeout = Duration::from_millis(100);
+        assert_eq!(
+            time::timeout(2 * timeout, service.handle.flush(timeout)).await,
+            Ok(Err(Error::FlushTimedOut(1)))
+        );
+        flood.abort();
+        let _ = flood.await;
+
+        time::sleep(Duration::from_secs(10)).await;
+        assert!(service.network.send_message.try_next().await.is_some());
+        service.stop().await;
+    }
+
+    const TAG: u8 = 0xab;
+
+    /// Prefixes every message with a tag.
+    struct TaggedCodec;
+
+    impl Codec<MockData> for TaggedCodec {
+        fn encode_to(&self, data: &MockData, dest: &mut Vec<u8>) {
+            dest.push(TAG);
+            data.encode_to(dest);
+        }
+
+        fn decode(&self, input: &mut &[u8]) -> Result<MockData, CodecError> {
+            match input.split_first() {
+                Some((&TAG, rest)) => {
+                    *input = rest;
+                    MockData::decode(input)
+                }
+                _ => Err("missing tag".into()),
+            }
+        }
+    }
+
+    fn tagged(data: &MockData) -> Vec<u8> {
+        let mut encoded = vec![TAG];
+        data.encode_to(&mut encoded);
+        encoded
+    }
+
+    #[tokio::test]
+    async fn test_custom_codec_round_trips() {
+        let mut test_data = TestData::prepare_with_codec(Config::default(), None, TaggedCodec);
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        test_data
+            .service
+            .authentication
+            .send_to_peer(message(1), peer_id.clone())
+            .expect("peer is connected");
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((tagged(&message(1)), peer_id.clone(), PROTOCOL))
+        );
+
+        for encoded in [message(2).encode(), tagged(&message(3))] {
+            test_data
+                .service
+                .handle_network_event(MockEvent::Messages(
+                    peer_id.clone(),
+                    vec![(PROTOCOL, encoded.into())],
+                ))
+                .expect("Should handle");
+        }
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message(3), peer_id.clone())
+        );
+        assert_eq!(
+            test_data.network.reported_peers.lock().first(),
+            Some(&(peer_id, MALFORMED_MESSAGE))
+        );
+
+        test_data.cleanup().await
+    }
+
+    /// The SCALE encoding, which used to prefix every message with a tag in the previous version.
+    struct UpgradedCodec;
+
+    impl Codec<MockData> for UpgradedCodec {
+        fn encode_to(&self, data: &MockData, dest: &mut Vec<u8>) {
+            data.encode_to(dest);
+        }
+
+        fn decode(&self, input: &mut &[u8]) -> Result<MockData, CodecError> {
+            MockData::decode(input)
+        }
+
+        fn decode_previous(&self, input: &mut &[u8]) -> Result<MockData, CodecError> {
+            TaggedCodec.decode(input)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_decoded_according_to_their_encoding_version() {
+        let version = 3;
+        let mut test_data = TestData::prepare_with_codec(
+            authentication_config(ProtocolConfig {
+                encoding_version: Some(version),
+                ..Default::default()
+            }),
+            Some(Registry::new()),
+            UpgradedCodec,
+        );
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        test_data
+            .service
+            .authentication
+            .send_to_peer(message(1), peer_id.clone())
+            .expect("peer is connected");
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((
+                codec::with_version(version, &message(1).encode()),
+                peer_id.clone(),
+                PROTOCOL
+            ))
+        );
+
+        for encoded in [
+            codec::with_version(version, &message(2).encode()),
+            codec::with_version(version - 1, &tagged(&message(3))),
+        ] {
+            test_data
+                .service
+                .handle_network_event(MockEvent::Messages(
+                    peer_id.clone(),
+                    vec![(PROTOCOL, encoded.into())],
+                ))
+                .expect("Should handle");
+        }
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message(2), peer_id.clone())
+        );
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message(3), peer_id.clone())
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_messages_of_unknown_encoding_versions_are_dropped() {
+        let version = 3;
+        let mut test_data = TestData::prepare_with_config_and_registry(
+            authentication_config(ProtocolConfig {
+                encoding_version: Some(version),
+                ..Default::default()
+            }),
+            Some(Registry::new()),
+        );
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        for encoded in [
+            codec::with_version(version + 1, &message(1).encode()),
+            codec::with_version(version - 2, &message(2).encode()),
+            codec::with_version(version, &message(3).encode()),
+        ] {
+            test_data
+                .service
+                .handle_network_event(MockEvent::Messages(
+                    peer_id.clone(),
+                    vec![(PROTOCOL, encoded.into())],
+                ))
+                .expect("Should handle");
+        }
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message(3), peer_id.clone())
+        );
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "unknown_encoding_version"])
+                .get(),
+            2
+        );
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "decode_error"])
+                .get(),
+            0
+        );
+        assert!(!test_data
+            .network
+            .reported_peers
+            .lock()
+            .contains(&(peer_id, MALFORMED_MESSAGE)));
+
+        test_data.cleanup().await
+    }
+
+    /// The SCALE encoding, counting how many times data was encoded.
+    #[derive(Clone, Default)]
+    struct CountingCodec(Arc<AtomicUsize>);
+
+    impl Codec<MockData> for CountingCodec {
+        fn encode_to(&self, data: &MockData, dest: &mut Vec<u8>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            data.encode_to(dest);
+        }
+
+        fn decode(&self, input: &mut &[u8]) -> Result<MockData, CodecError> {
+            MockData::decode(input)
+        }
+    }
+
+    /// The SCALE encoding, telling messages with odd and even numbers apart.
+    struct ParityCodec;
+
+    fn parity_kind(first_byte: Option<&u8>) -> MessageKind {
+        match first_byte {
+            Some(byte) if byte % 2 == 0 => MessageKind::Named("even"),
+            Some(_) => MessageKind::Named("odd"),
+            None => MessageKind::Unknown,
+        }
+    }
+
+    impl Codec<MockData> for ParityCodec {
+        fn encode_to(&self, data: &MockData, dest: &mut Vec<u8>) {
+            data.encode_to(dest);
+        }
+
+        fn decode(&self, input: &mut &[u8]) -> Result<MockData, CodecError> {
+            MockData::decode(input)
+        }
+
+        fn kind(&self, data: &MockData) -> MessageKind {
+            parity_kind(data.encode().first())
+        }
+
+        fn encoded_kind(&self, encoded: &[u8]) -> MessageKind {
+            parity_kind(encoded.first())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decoded_messages_are_counted_by_kind() {
+        let mut test_data =
+            TestData::prepare_with_codec(Config::default(), Some(Registry::new()), ParityCodec);
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![
+                    (PROTOCOL, message(1).encode().into()),
+                    (PROTOCOL, message(2).encode().into()),
+                    (PROTOCOL, message(4).encode().into()),
+                    (PROTOCOL, vec![3u8].into()),
+                ],
+            ))
+            .expect("Should handle");
+
+        let messages_decoded = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_decoded, ..
+            } => messages_decoded.clone(),
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        let count = |kind, result| {
+            messages_decoded
+                .with_label_values(&["authentication", kind, result])
+                .get()
+        };
+        assert_eq!(count("odd", "ok"), 1);
+        assert_eq!(count("even", "ok"), 2);
+        assert_eq!(count("odd", "error"), 1);
+        assert_eq!(count("unknown", "ok"), 0);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_routed_to_the_sinks_of_their_kinds() {
+        let mut test_data = TestData::prepare_with_codec(Config::default(), None, ParityCodec);
+
+        let mut even = test_data
+            .service
+            .route_authentication_messages(MessageKind::Named("even"));
+        let mut odd = test_data
+            .service
+            .route_authentication_messages(MessageKind::Named("odd"));
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                (1..=4)
+                    .map(|i| (PROTOCOL, message(i).encode().into()))
+                    .collect(),
+            ))
+            .expect("Should handle");
+        for i in [2, 4] {
+            assert_eq!(even.next().await, Some((message(i), peer_id.clone())));
+        }
+        for i in [1, 3] {
+            assert_eq!(odd.next().await, Some((message(i), peer_id.clone())));
+        }
+        assert!(even.try_next().is_err());
+        assert!(odd.try_next().is_err());
+
+        // Without their sink, messages of the kind go to the user again.
+        drop(odd);
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                (5..=6)
+                    .map(|i| (PROTOCOL, message(i).encode().into()))
+                    .collect(),
+            ))
+            .expect("Should handle");
+        assert_eq!(even.next().await, Some((message(6), peer_id.clone())));
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message(5), peer_id)
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_is_encoded_once() {
+        let codec = CountingCodec::default();
+        let mut test_data = TestData::prepare_with_codec(Config::default(), None, codec.clone());
+
+        let peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        test_data
+            .service
+            .authentication
+            .broadcast(message(1), Priority::Normal, None);
+
+        let sent = test_data.network.send_message.take(peer_ids.len()).await;
+        assert!(sent.iter().all(|(data, _, _)| data == &message(1).encode()));
+        assert_eq!(codec.0.load(Ordering::SeqCst), 1);
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_custom_codec_encodes_batches() {
+        let mut test_data = TestData::prepare_with_codec(
+            authentication_config(ProtocolConfig {
+                batching: Some(BatchConfig { max_messages: 8 }),
+                ..Default::default()
+            }),
+            None,
+            TaggedCodec,
+        );
+
+        let peer_id = random_peer_id();
+        let batch: Vec<_> = (0..3).map(message).collect();
+        let mut encoded = Compact(batch.len() as u32).encode();
+        for data in &batch {
+            encoded.extend(tagged(data));
+        }
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![(PROTOCOL, encoded.clone().into())],
+            ))
+            .expect("Should handle");
+        for expected in &batch {
+            assert_eq!(
+                test_data.next().await.expect("Should receive message"),
+                (expected.clone(), peer_id.clone())
+            );
+        }
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        for data in batch {
+            test_data
+                .service
+                .authentication
+                .send_to_peer(data, peer_id.clone())
+                .expect("peer is connected");
+        }
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((encoded, peer_id, PROTOCOL))
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_results_are_reported() {
+        let (feedback_tx, mut feedback_rx) = mpsc::unbounded();
+        let mut test_data = TestData::prepare_with(
+            Config::default(),
+            None,
+            Some(feedback_tx),
+            None,
+            None,
+            ScaleCodec,
+            None,
+        );
+
+        let peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        // The sender of the last peer is missing, e.g. because its stream is being reopened.
+        test_data
+            .service
+            .authentication
+            .peer_senders
+            .remove(&peer_ids[2]);
+
+        test_data.broadcast(message(1)).expect("channel has space");
+        let command = test_data
+            .service
+            .authentication
+            .messages_from_user
+            .next()
+            .await
+            .expect("command was sent");
+        test_data.service.authentication.handle_command(command);
+
+        assert_eq!(
+            feedback_rx.next().await,
+            Some(BroadcastResult {
+                protocol: PROTOCOL,
+                attempted: 3,
+                succeeded: 2,
+                failed_peers: vec![peer_ids[2].clone()],
+                skipped_circuit_open: 0,
+            })
+        );
+        test_data.network.send_message.take(2).await;
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_send_latency_is_averaged() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            latency_tracking: Some(LatencyConfig {
+                smoothing: 0.5,
+                slow_threshold: Duration::from_millis(120),
+            }),
+            ..Default::default()
+        }));
+
+        let peer_ids: Vec<_> = (0..2).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        let average = |test_data: &TestData| {
+            test_data.service.authentication.send_latencies[&peer_ids[0]]
+                .average()
+                .expect("something was sent")
+        };
+
+        for (i, delay) in [100, 200].into_iter().enumerate() {
+            *test_data.network.send_delay.lock() = Duration::from_millis(delay);
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(i as u8), peer_ids[0].clone())
+                .expect("peer is connected");
+            test_data
+                .flush(Duration::from_secs(1))
+                .await
+                .expect("should flush");
+        }
+        test_data.network.send_message.take(2).await;
+
+        // Halfway between the two sends, up to the timer resolution.
+        let average = average(&test_data);
+        assert!(average >= Duration::from_millis(150));
+        assert!(average < Duration::from_millis(152));
+        assert_eq!(
+            test_data
+                .service
+                .authentication
+                .deprioritize_slow_peers(peer_ids.clone()),
+            vec![peer_ids[1].clone(), peer_ids[0].clone()]
+        );
+        assert!(test_data.service.status().contains("slow peers - 1;"));
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_best_peer_is_the_one_with_the_lowest_latency() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            latency_tracking: Some(LatencyConfig {
+                smoothing: 0.5,
+                slow_threshold: Duration::from_secs(1),
+            }),
+            ..Default::default()
+        }));
+
+        let peer_ids: Vec<_> = (0..2).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        assert_eq!(test_data.service.best_peer(PROTOCOL), None);
+
+        for (peer_id, delay) in peer_ids.iter().zip([300, 100]) {
+            *test_data.network.send_delay.lock() = Duration::from_millis(delay);
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(1), peer_id.clone())
+                .expect("peer is connected");
+            test_data
+                .flush(Duration::from_secs(1))
+                .await
+                .expect("should flush");
+        }
+        test_data.network.send_message.take(2).await;
+        assert_eq!(
+            test_data.service.best_peer(PROTOCOL),
+            Some(peer_ids[1].clone())
+        );
+
+        test_data
+            .send_to_best(message(2))
+            .expect("channel has space");
+        let command = test_data
+            .service
+            .authentication
+            .messages_from_user
+            .next()
+            .await
+            .expect("command was sent");
+        test_data.service.authentication.handle_command(command);
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((message(2).encode(), peer_ids[1].clone(), PROTOCOL))
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_best_peer_ties_are_broken_by_priority_and_connection_order() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            latency_tracking: Some(LatencyConfig {
+                smoothing: 0.5,
+                slow_threshold: Duration::from_secs(1),
+            }),
+            ..Default::default()
+        }));
+
+        let peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        *test_data.network.send_delay.lock() = Duration::from_millis(100);
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .authentication
+                .send_to_peer(message(1), peer_id.clone())
+                .expect("peer is connected");
+        }
+        test_data
+            .flush(Duration::from_secs(1))
+            .await
+            .expect("should flush");
+        test_data.network.send_message.take(3).await;
+        assert_eq!(
+            test_data.service.best_peer(PROTOCOL),
+            Some(peer_ids[0].clone())
+        );
+
+        let important = peer_ids[2].clone();
+        test_data
+            .service
+            .set_peer_priority(move |a, b| (b == &important).cmp(&(a == &important)));
+        assert_eq!(
+            test_data.service.best_peer(PROTOCOL),
+            Some(peer_ids[2].clone())
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_messages_are_not_sent() {
+        let mut test_data =
+            TestData::prepare_with_config_and_registry(Config::default(), Some(Registry::new()));
+        *test_data.network.send_delay.lock() = Duration::from_millis(100);
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        test_data
+            .service
+            .authentication
+            .send_to_peer(message(1), peer_id.clone())
+            .expect("peer is connected");
+        // Expires while the first message is still being sent.
+        test_data
+            .send_to_with_ttl(message(2), peer_id.clone(), Duration::from_millis(10))
+            .expect("channel has space");
+        let command = test_data
+            .service
+            .authentication
+            .messages_from_user
+            .next()
+            .await
+            .expect("command was sent");
+        test_data.service.authentication.handle_command(command);
+        test_data
+            .service
+            .authentication
+            .send_to_peer(message(3), peer_id.clone())
+            .expect("peer is connected");
+
+        test_data
+            .flush(Duration::from_secs(1))
+            .await
+            .expect("should flush");
+        assert_eq!(
+            test_data.network.send_message.take(2).await,
+            vec![
+                (message(1).encode(), peer_id.clone(), PROTOCOL),
+                (message(3).encode(), peer_id, PROTOCOL),
+            ]
+        );
+
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "expired"])
+                .get(),
+            1
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_message_rates_are_averaged() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            rate_smoothing: 0.5,
+            ..Default::default()
+        }));
+
+        let peer_id = random_peer_id();
+        for (count, interval) in [(10, 1), (40, 2)] {
+            for i in 0..count {
+                test_data
+                    .service
+                    .handle_network_event(MockEvent::Messages(
+                        peer_id.clone(),
+                        vec![(PROTOCOL, message(i).encode().into())],
+                    ))
+                    .expect("Should handle");
+                test_data
+                    .gossip_network
+                    .broadcast(message(i))
+                    .expect("channel has space");
+            }
+            for _ in 0..count {
+                test_data.next().await.expect("Should receive message");
+            }
+            while let Ok(Some(command)) = test_data
+                .service
+                .authentication
+                .messages_from_user
+                .try_next()
+            {
+                test_data.service.authentication.handle_command(command);
+            }
+            time::advance(Duration::from_secs(interval)).await;
+            test_data.service.status_report();
+        }
+
+        // The second interval had a rate of 20 per second, halfway to it from 10 per second.
+        let rates = &test_data.service.authentication;
+        assert!((rates.received_rate.average() - 15.0).abs() < 0.1);
+        assert!((rates.broadcast_rate.average() - 15.0).abs() < 0.1);
+        assert!(test_data
+            .service
+            .status()
+            .contains("received per second - 15.00; broadcasts per second - 15.00;"));
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_messages_over_rate_limit_are_dropped() {
+        let mut test_data = TestData::prepare_with_config_and_registry(
+            authentication_config(ProtocolConfig {
+                inbound_rate_limit: Some(InboundRateLimitConfig {
+                    rate: 1.0,
+                    burst: 3,
+                }),
+                ..Default::default()
+            }),
+            Some(Registry::new()),
+        );
+
+        let spammer = random_peer_id();
+        let other = random_peer_id();
+        for peer_id in [&spammer, &other] {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        let messages = (0..5)
+            .map(|i| (PROTOCOL, message(i).encode().into()))
+            .collect();
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(spammer.clone(), messages))
+            .expect("Should handle");
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                other.clone(),
+                vec![(PROTOCOL, message(5).encode().into())],
+            ))
+            .expect("Should handle");
 
-        peer_ids.iter().for_each(|peer_id| {
+        for i in 0..3 {
+            assert_eq!(
+                test_data.next().await.expect("Should receive message"),
+                (message(i), spammer.clone())
+            );
+        }
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message(5), other.clone())
+        );
+        let penalties = test_data
+            .network
+            .reported_peers
+            .lock()
+            .iter()
+            .filter(|report| **report == (spammer.clone(), RATE_LIMIT_EXCEEDED))
+            .count();
+        assert_eq!(penalties, 2);
+
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "rate_limited"])
+                .get(),
+            2
+        );
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamClosed(spammer.clone(), PROTOCOL))
+            .expect("Should handle");
+        assert!(!test_data
+            .service
+            .authentication
+            .inbound_limits
+            .contains_key(&spammer));
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_signed_messages_round_trip() {
+        let mut test_data = TestData::prepare_with(
+            Config::default(),
+            None,
+            None,
+            None,
+            None,
+            ScaleCodec,
+            Some(MessageSigning::new(KeyedSum(7), KeyedSum(7))),
+        );
+
+        let peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        test_data
+            .service
+            .authentication
+            .send_to_peer(message(1), peer_id.clone())
+            .expect("peer is connected");
+        let (signed, _, _) = test_data
+            .network
+            .send_message
+            .next()
+            .await
+            .expect("Should send message");
+        assert_ne!(signed, message(1).encode());
+
+        let mut tampered = signed.clone();
+        *tampered.last_mut().expect("message is not empty") ^= 1;
+        for data in [tampered, signed] {
+            test_data
+                .service
+                .handle_network_event(MockEvent::Messages(
+                    peer_id.clone(),
+                    vec![(PROTOCOL, data.into())],
+                ))
+                .expect("Should handle");
+        }
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message(1), peer_id.clone())
+        );
+        assert!(test_data
+            .network
+            .reported_peers
+            .lock()
+            .contains(&(peer_id, INVALID_SIGNATURE)));
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_paused_protocol_does_not_broadcast() {
+        let mut test_data = TestData::prepare();
+
+        let peer_id = random_peer_id();
+        test_data.service.pause_protocol(PROTOCOL);
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        assert_eq!(
+            test_data.service.connected_peers(PROTOCOL),
+            vec![peer_id.clone()]
+        );
+        let result = test_data
+            .service
+            .authentication
+            .broadcast(message(1), Priority::Normal, None);
+        assert_eq!(result.attempted, 0);
+        assert!(test_data.network.send_message.try_next().await.is_none());
+
+        test_data.service.resume_protocol(PROTOCOL);
+        test_data
+            .service
+            .authentication
+            .broadcast(message(2), Priority::Normal, None);
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((message(2).encode(), peer_id, PROTOCOL))
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_peers_not_on_allowlist_are_not_sent_to() {
+        let mut test_data = TestData::prepare();
+
+        let allowed = random_peer_id();
+        let other = random_peer_id();
+        test_data
+            .service
+            .set_allowlist(PROTOCOL, Some(HashSet::from([allowed.clone()])));
+        for peer_id in [&other, &allowed] {
             test_data
                 .service
                 .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
                 .expect("Should handle");
-        });
+        }
+        test_data
+            .service
+            .authentication
+            .broadcast(message(1), Priority::Normal, None);
+        assert!(test_data
+            .service
+            .authentication
+            .send_to_peer(message(2), other.clone())
+            .is_err());
 
-        peer_ids
-            .iter()
-            .skip(opened_authorities_n)
-            .for_each(|peer_id| {
-                test_data
-                    .service
-                    .handle_network_event(MockEvent::StreamClosed(peer_id.clone(), PROTOCOL))
-                    .expect("Should handle");
-            });
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((message(1).encode(), allowed, PROTOCOL))
+        );
+        assert_eq!(
+            test_data.network.assert_idle(Duration::from_secs(1)).await,
+            Ok(())
+        );
 
-        let message = message(1);
-        test_data.service.broadcast_authentication(message.clone());
+        test_data.cleanup().await
+    }
 
-        let broadcasted_messages = HashSet::<_>::from_iter(
+    #[tokio::test]
+    async fn test_subscribers_get_copies_of_forwarded_messages() {
+        let mut test_data =
+            TestData::prepare_with_config_and_registry(Config::default(), Some(Registry::new()));
+
+        let mut subscriber = test_data.service.subscribe_authentication_messages(0);
+        let mut slow_subscriber = test_data.service.subscribe_authentication_messages(0);
+        let peer_id = random_peer_id();
+        for i in 1..=2 {
             test_data
-                .network
-                .send_message
-                .take(opened_authorities_n)
-                .await
-                .into_iter(),
+                .service
+                .handle_network_event(MockEvent::Messages(
+                    peer_id.clone(),
+                    vec![(PROTOCOL, message(i).encode().into())],
+                ))
+                .expect("Should handle");
+            if i == 1 {
+                assert_eq!(subscriber.next().await, Some((message(1), peer_id.clone())));
+            }
+        }
+
+        for i in 1..=2 {
+            assert_eq!(
+                test_data.next().await.expect("Should receive message"),
+                (message(i), peer_id.clone())
+            );
+        }
+        assert_eq!(subscriber.next().await, Some((message(2), peer_id.clone())));
+        // The slow subscriber only had room for the first message.
+        assert_eq!(slow_subscriber.next().await, Some((message(1), peer_id)));
+        assert!(slow_subscriber.try_next().is_err());
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "slow_subscriber"])
+                .get(),
+            1
         );
 
-        let expected_messages = HashSet::from_iter(
-            peer_ids
-                .into_iter()
-                .take(opened_authorities_n)
-                .map(|peer_id| (message.clone().encode(), peer_id, PROTOCOL)),
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_payloads_from_different_peers_are_forwarded_once() {
+        let mut test_data = TestData::prepare_with_config_and_registry(
+            authentication_config(ProtocolConfig {
+                inbound_dedup: Some(InboundDedupConfig {
+                    cache_size: 16,
+                    ttl: Duration::from_secs(60),
+                    excluded_kinds: Vec::new(),
+                }),
+                ..Default::default()
+            }),
+            Some(Registry::new()),
+        );
+
+        let peer_ids: Vec<_> = (0..2).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::Messages(
+                    peer_id.clone(),
+                    vec![(PROTOCOL, message(1).encode().into())],
+                ))
+                .expect("Should handle");
+        }
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_ids[1].clone(),
+                vec![(PROTOCOL, message(2).encode().into())],
+            ))
+            .expect("Should handle");
+
+        // The copy from the second peer never reaches the user.
+        for expected in [
+            (message(1), peer_ids[0].clone()),
+            (message(2), peer_ids[1].clone()),
+        ] {
+            assert_eq!(
+                test_data.next().await.expect("Should receive message"),
+                expected
+            );
+        }
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "duplicate"])
+                .get(),
+            1
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_excluded_kinds_are_never_dropped_as_duplicates() {
+        let mut test_data = TestData::prepare_with_codec(
+            authentication_config(ProtocolConfig {
+                inbound_dedup: Some(InboundDedupConfig {
+                    cache_size: 16,
+                    ttl: Duration::from_secs(60),
+                    excluded_kinds: vec![MessageKind::Named("even")],
+                }),
+                ..Default::default()
+            }),
+            None,
+            ParityCodec,
+        );
+
+        let peer_ids: Vec<_> = (0..2).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::Messages(
+                    peer_id.clone(),
+                    vec![
+                        (PROTOCOL, message(1).encode().into()),
+                        (PROTOCOL, message(2).encode().into()),
+                    ],
+                ))
+                .expect("Should handle");
+        }
+
+        for expected in [
+            (message(1), peer_ids[0].clone()),
+            (message(2), peer_ids[0].clone()),
+            (message(2), peer_ids[1].clone()),
+        ] {
+            assert_eq!(
+                test_data.next().await.expect("Should receive message"),
+                expected
+            );
+        }
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_messages_received_while_paused_are_dropped() {
+        let mut test_data =
+            TestData::prepare_with_config_and_registry(Config::default(), Some(Registry::new()));
+
+        let peer_id = random_peer_id();
+        test_data.service.pause_protocol(PROTOCOL);
+        for i in 1..=2 {
+            test_data
+                .service
+                .handle_network_event(MockEvent::Messages(
+                    peer_id.clone(),
+                    vec![(PROTOCOL, message(i).encode().into())],
+                ))
+                .expect("Should handle");
+        }
+        let messages_dropped = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                messages_dropped, ..
+            } => messages_dropped,
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(
+            messages_dropped
+                .with_label_values(&["authentication", "paused"])
+                .get(),
+            2
+        );
+
+        test_data.service.resume_protocol(PROTOCOL);
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![(PROTOCOL, message(3).encode().into())],
+            ))
+            .expect("Should handle");
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message(3), peer_id)
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_bytes_transferred_are_counted() {
+        let mut test_data = TestData::prepare();
+
+        let peer_ids: Vec<_> = (0..3).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        test_data
+            .service
+            .authentication
+            .broadcast(message(1), Priority::Normal, None);
+        test_data.network.send_message.take(peer_ids.len()).await;
+        let expected_sent = (message(1).encode().len() * peer_ids.len()) as u64;
+        // The peer senders count the bytes right after handing them over.
+        time::timeout(Duration::from_secs(1), async {
+            while test_data.service.bytes_transferred(PROTOCOL).0 < expected_sent {
+                time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("peer senders should count the sent bytes");
+        assert_eq!(
+            test_data.service.bytes_transferred(PROTOCOL),
+            (expected_sent, 0)
+        );
+
+        let received = message(2).encode();
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_ids[0].clone(),
+                vec![(PROTOCOL, received.clone().into())],
+            ))
+            .expect("Should handle");
+        test_data.next().await.expect("Should receive message");
+        assert_eq!(
+            test_data.service.bytes_transferred(PROTOCOL),
+            (expected_sent, received.len() as u64)
+        );
+
+        test_data.cleanup().await
+    }
+
+    /// Opens a stream of the peer, checks our handshake is the first thing sent over it and
+    /// delivers the handshake of the peer.
+    async fn exchange_handshakes(test_data: &mut TestData, peer_id: &MockPublicKey, version: u16) {
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((3u16.encode(), peer_id.clone(), PROTOCOL))
+        );
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![(PROTOCOL, version.encode().into())],
+            ))
+            .expect("Should handle");
+    }
+
+    #[tokio::test]
+    async fn test_matching_versions_are_negotiated() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            versions: Some(VersionRange { min: 2, max: 3 }),
+            ..Default::default()
+        }));
+
+        let peer_id = random_peer_id();
+        assert_eq!(
+            test_data.service.negotiated_version(PROTOCOL, &peer_id),
+            None
+        );
+        exchange_handshakes(&mut test_data, &peer_id, 2).await;
+        assert_eq!(
+            test_data.service.negotiated_version(PROTOCOL, &peer_id),
+            Some(2)
+        );
+
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![(PROTOCOL, message(1).encode().into())],
+            ))
+            .expect("Should handle");
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message(1), peer_id.clone())
+        );
+        test_data
+            .service
+            .authentication
+            .send_to_peer(message(2), peer_id.clone())
+            .expect("peer is connected");
+        assert_eq!(
+            test_data.network.send_message.next().await,
+            Some((message(2).encode(), peer_id, PROTOCOL))
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_reopened_streams_negotiate_the_version_again() {
+        let (failures_tx, mut failures_rx) = mpsc::unbounded();
+        let mut test_data = TestData::prepare_with(
+            authentication_config(ProtocolConfig {
+                versions: Some(VersionRange { min: 2, max: 3 }),
+                ..Default::default()
+            }),
+            None,
+            None,
+            None,
+            Some(failures_tx),
+            ScaleCodec,
+            None,
+        );
+
+        let peer_id = random_peer_id();
+        exchange_handshakes(&mut test_data, &peer_id, 2).await;
+        exchange_handshakes(&mut test_data, &peer_id, 3).await;
+        assert_eq!(
+            test_data.service.negotiated_version(PROTOCOL, &peer_id),
+            Some(3)
+        );
+        test_data
+            .service
+            .handle_network_event(MockEvent::Messages(
+                peer_id.clone(),
+                vec![(PROTOCOL, message(1).encode().into())],
+            ))
+            .expect("Should handle");
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message(1), peer_id.clone())
+        );
+        assert!(failures_rx.try_next().is_err());
+        assert_eq!(
+            *test_data.network.reported_peers.lock(),
+            vec![(peer_id, VALID_MESSAGE)]
         );
-
-        assert_eq!(broadcasted_messages, expected_messages);
 
         test_data.cleanup().await
     }
 
     #[tokio::test]
-    async fn test_create_sender_error() {
-        let mut test_data = TestData::prepare();
+    async fn test_peers_below_minimum_version_are_rejected() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            versions: Some(VersionRange { min: 2, max: 3 }),
+            ..Default::default()
+        }));
 
-        test_data
+        let old_peer = random_peer_id();
+        let other = random_peer_id();
+        exchange_handshakes(&mut test_data, &old_peer, 1).await;
+        exchange_handshakes(&mut test_data, &other, 3).await;
+        assert_eq!(
+            test_data.service.negotiated_version(PROTOCOL, &old_peer),
+            None
+        );
+        assert!(test_data
             .network
-            .create_sender_errors
+            .reported_peers
             .lock()
-            .push_back(MockSenderError);
+            .contains(&(old_peer.clone(), INCOMPATIBLE_VERSION)));
 
-        let peer_id = random_peer_id();
+        for peer_id in [&old_peer, &other] {
+            test_data
+                .service
+                .handle_network_event(MockEvent::Messages(
+                    peer_id.clone(),
+                    vec![(PROTOCOL, message(1).encode().into())],
+                ))
+                .expect("Should handle");
+        }
+        assert_eq!(
+            test_data.next().await.expect("Should receive message"),
+            (message(1), other)
+        );
 
-        let message_1 = message(1);
-        let message_2 = message(4);
+        test_data.cleanup().await
+    }
 
-        test_data
-            .service
-            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
-            .expect("Should handle");
+    #[tokio::test]
+    async fn test_peers_above_maximum_version_use_ours() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            versions: Some(VersionRange { min: 2, max: 3 }),
+            ..Default::default()
+        }));
+
+        let peer_id = random_peer_id();
+        exchange_handshakes(&mut test_data, &peer_id, 7).await;
+        assert_eq!(
+            test_data.service.negotiated_version(PROTOCOL, &peer_id),
+            Some(3)
+        );
+        assert!(test_data.network.reported_peers.lock().is_empty());
 
-        test_data.service.broadcast_authentication(message_1);
+        test_data.cleanup().await
+    }
 
-        test_data
-            .service
-            .broadcast_authentication(message_2.clone());
+    #[tokio::test(start_paused = true)]
+    async fn test_quickly_reopened_stream_keeps_sender() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            close_grace_period: Duration::from_secs(1),
+            ..Default::default()
+        }));
+        let spawner = ManualSpawner::default();
+        test_data.service.set_spawner(spawner.clone());
+        let connection_events = test_data.service.subscribe_connection_events();
 
-        let expected = (message_2.encode(), peer_id, PROTOCOL);
+        let peer_id = random_peer_id();
+        for event in [
+            MockEvent::StreamOpened(peer_id.clone(), PROTOCOL),
+            MockEvent::StreamClosed(peer_id.clone(), PROTOCOL),
+            MockEvent::StreamOpened(peer_id.clone(), PROTOCOL),
+        ] {
+            test_data
+                .service
+                .handle_network_event(event)
+                .expect("Should handle");
+        }
+        time::advance(Duration::from_secs(2)).await;
+        test_data.service.close_expired_streams();
 
+        assert_eq!(spawner.pending_tasks().len(), 1);
         assert_eq!(
-            test_data
-                .network
-                .send_message
-                .next()
-                .await
-                .expect("Should receive message"),
-            expected,
+            test_data.service.connected_peers(PROTOCOL),
+            vec![peer_id.clone()]
+        );
+        drop(test_data.service);
+        assert_eq!(
+            connection_events.collect::<Vec<_>>().await,
+            vec![ConnectionEvent::PeerConnected(peer_id, PROTOCOL)]
         );
 
-        test_data.cleanup().await
+        test_data.network.close_channels().await;
     }
 
-    #[tokio::test]
-    async fn test_send_error() {
-        let mut test_data = TestData::prepare();
+    #[tokio::test(start_paused = true)]
+    async fn test_closed_stream_is_dropped_after_grace_period() {
+        let mut test_data = TestData::prepare_with_config(authentication_config(ProtocolConfig {
+            close_grace_period: Duration::from_secs(1),
+            ..Default::default()
+        }));
+        let connection_events = test_data.service.subscribe_connection_events();
+
+        let peer_id = random_peer_id();
+        for event in [
+            MockEvent::StreamOpened(peer_id.clone(), PROTOCOL),
+            MockEvent::StreamClosed(peer_id.clone(), PROTOCOL),
+        ] {
+            test_data
+                .service
+                .handle_network_event(event)
+                .expect("Should handle");
+        }
+        assert_eq!(
+            test_data.service.next_pending_close(),
+            Some(time::Instant::now() + Duration::from_secs(1))
+        );
+        test_data.service.close_expired_streams();
+        assert_eq!(
+            test_data.service.connected_peers(PROTOCOL),
+            vec![peer_id.clone()]
+        );
+
+        time::advance(Duration::from_secs(1)).await;
+        test_data.service.close_expired_streams();
+        assert!(test_data.service.connected_peers(PROTOCOL).is_empty());
+        assert_eq!(test_data.service.next_pending_close(), None);
+        drop(test_data.service);
+        assert_eq!(
+            connection_events.collect::<Vec<_>>().await,
+            vec![
+                ConnectionEvent::PeerConnected(peer_id.clone(), PROTOCOL),
+                ConnectionEvent::PeerDisconnected(peer_id, PROTOCOL),
+            ]
+        );
+
+        test_data.network.close_channels().await;
+    }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_time_to_first_byte_includes_slow_first_send() {
+        let mut test_data =
+            TestData::prepare_with_config_and_registry(Config::default(), Some(Registry::new()));
         test_data
             .network
-            .send_errors
+            .scripted_send_delays
             .lock()
-            .push_back(MockSenderError);
+            .push_back(Duration::from_secs(2));
 
         let peer_id = random_peer_id();
-
-        let message_1 = message(1);
-        let message_2 = message(4);
-
         test_data
             .service
             .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
             .expect("Should handle");
-
-        test_data.service.broadcast_authentication(message_1);
-
-        test_data
-            .service
-            .broadcast_authentication(message_2.clone());
-
-        let expected = (message_2.encode(), peer_id, PROTOCOL);
-
-        assert_eq!(
+        for i in 0..2 {
             test_data
-                .network
-                .send_message
-                .next()
-                .await
-                .expect("Should receive message"),
-            expected,
+                .service
+                .authentication
+                .send_to_peer(message(i), peer_id.clone())
+                .expect("peer is connected");
+        }
+        test_data.network.send_message.take(2).await;
+
+        let histogram = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                times_to_first_byte,
+                ..
+            } => times_to_first_byte[&PROTOCOL].clone(),
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        // Only the first send counts.
+        assert_eq!(histogram.get_sample_count(), 1);
+        let time_to_first_byte = histogram.get_sample_sum();
+        assert!(
+            (1.9..2.1).contains(&time_to_first_byte),
+            "first byte after {time_to_first_byte}s"
         );
 
         test_data.cleanup().await
     }
 
-    #[tokio::test]
-    async fn test_notification_received() {
-        let mut test_data = TestData::prepare();
+    #[tokio::test(start_paused = true)]
+    async fn test_connection_durations_are_recorded_on_close() {
+        let mut test_data =
+            TestData::prepare_with_config_and_registry(Config::default(), Some(Registry::new()));
 
-        let message = message(1);
+        let old_peer = random_peer_id();
+        let new_peer = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(old_peer.clone(), PROTOCOL))
+            .expect("Should handle");
+        time::advance(Duration::from_secs(60)).await;
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(new_peer.clone(), PROTOCOL))
+            .expect("Should handle");
+        time::advance(Duration::from_secs(30)).await;
+        assert!(test_data
+            .service
+            .authentication
+            .status(Duration::from_secs(600))
+            .contains("longest connection - 90s"));
 
-        let peer_id = random_peer_id();
         test_data
             .service
-            .handle_network_event(MockEvent::Messages(
-                peer_id.clone(),
-                vec![(PROTOCOL, message.clone().encode().into())],
-            ))
+            .handle_network_event(MockEvent::StreamClosed(old_peer.clone(), PROTOCOL))
             .expect("Should handle");
+        assert!(test_data
+            .service
+            .authentication
+            .status(Duration::from_secs(600))
+            .contains("longest connection - 30s"));
+        assert!(!test_data
+            .service
+            .authentication
+            .connected_at
+            .contains_key(&old_peer));
 
-        let (received_message, received_peer_id) =
-            test_data.next().await.expect("Should receive message");
-        assert_eq!(received_message, message);
-        assert_eq!(received_peer_id, peer_id);
+        let histogram = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                connection_durations,
+                ..
+            } => connection_durations[&PROTOCOL].clone(),
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        assert_eq!(histogram.get_sample_count(), 1);
+        assert_eq!(histogram.get_sample_sum(), 90.0);
 
         test_data.cleanup().await
     }
 
+    /// The number of observations in every bucket of the histogram, by the upper bound of the
+    /// bucket, leaving out empty ones.
+    fn bucket_counts(histogram: &Histogram) -> Vec<(f64, u64)> {
+        let mut previous = 0;
+        histogram
+            .metric()
+            .get_histogram()
+            .get_bucket()
+            .iter()
+            .filter_map(|bucket| {
+                let count = bucket.get_cumulative_count() - previous;
+                previous = bucket.get_cumulative_count();
+                (count > 0).then_some((bucket.get_upper_bound(), count))
+            })
+            .collect()
+    }
+
     #[tokio::test]
-    async fn test_send_to_connected() {
-        let mut test_data = TestData::prepare();
+    async fn test_message_sizes_land_in_their_buckets() {
+        let mut test_data =
+            TestData::prepare_with_config_and_registry(Config::default(), Some(Registry::new()));
 
         let peer_id = random_peer_id();
-
-        let message = message(1);
-
         test_data
             .service
             .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
             .expect("Should handle");
+        let sent: Vec<_> = [10, 100, 1000]
+            .into_iter()
+            .enumerate()
+            .map(|(i, filler_size)| MockData::new(i as u32, filler_size))
+            .collect();
+        for data in &sent {
+            test_data
+                .service
+                .authentication
+                .send_to_peer(data.clone(), peer_id.clone())
+                .expect("peer is connected");
+        }
+        test_data.network.send_message.take(sent.len()).await;
+        // Whatever the bytes decode to does not matter for their size.
+        for size in [50, 500, 5000] {
+            test_data
+                .service
+                .handle_network_event(MockEvent::Messages(
+                    peer_id.clone(),
+                    vec![(PROTOCOL, vec![0; size].into())],
+                ))
+                .expect("Should handle");
+        }
 
-        test_data
-            .service
-            .send_to_authentication_peer(message.clone(), peer_id.clone())
-            .expect("interface works");
-
-        let expected = (message.encode(), peer_id, PROTOCOL);
-
+        let (sent_sizes, received_sizes) = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                sent_message_sizes,
+                received_message_sizes,
+                ..
+            } => (
+                sent_message_sizes[&PROTOCOL].clone(),
+                received_message_sizes[&PROTOCOL].clone(),
+            ),
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
         assert_eq!(
-            test_data
-                .network
-                .send_message
-                .next()
-                .await
-                .expect("Should receive message"),
-            expected,
+            sent.iter()
+                .map(|data| data.encode().len())
+                .collect::<Vec<_>>(),
+            vec![16, 107, 1007]
+        );
+        assert_eq!(
+            bucket_counts(&sent_sizes),
+            vec![(64.0, 1), (128.0, 1), (1024.0, 1)]
+        );
+        assert_eq!(
+            bucket_counts(&received_sizes),
+            vec![(64.0, 1), (512.0, 1), (8192.0, 1)]
         );
 
         test_data.cleanup().await
     }
 
-    #[tokio::test]
-    async fn test_no_send_to_disconnected() {
-        let mut test_data = TestData::prepare();
+    #[tokio::test(start_paused = true)]
+    async fn test_queue_wait_grows_while_peer_is_blocked() {
+        let mut test_data =
+            TestData::prepare_with_config_and_registry(Config::default(), Some(Registry::new()));
+        *test_data.network.send_delay.lock() = Duration::from_secs(1);
 
         let peer_id = random_peer_id();
-
-        let message = message(1);
-
-        assert!(matches!(
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        for i in 0..3 {
             test_data
                 .service
-                .send_to_authentication_peer(message, peer_id),
-            Err(SendError::MissingSender)
-        ));
+                .authentication
+                .send_to_peer(message(i), peer_id.clone())
+                .expect("peer is connected");
+        }
+        test_data.network.send_message.take(3).await;
+
+        let histogram = match &test_data.service.authentication.metrics {
+            Metrics::Prometheus {
+                queue_wait_times, ..
+            } => queue_wait_times[&PROTOCOL].clone(),
+            Metrics::Noop => panic!("metrics should be registered"),
+        };
+        // Every message waits for all the ones before it to be sent.
+        assert_eq!(histogram.get_sample_count(), 3);
+        let total_wait = histogram.get_sample_sum();
+        assert!((2.9..3.1).contains(&total_wait), "waited {total_wait}s");
 
         test_data.cleanup().await
     }
 
     #[tokio::test]
-    async fn test_send_to_random_connected() {
+    async fn test_closing_stream_aborts_pending_send() {
         let mut test_data = TestData::prepare();
+        *test_data.network.send_delay.lock() = Duration::from_secs(60);
 
         let peer_id = random_peer_id();
-
-        let message = message(1);
-
         test_data
             .service
             .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
             .expect("Should handle");
-
         test_data
             .service
-            .send_to_random_authentication(message.clone(), iter::once(peer_id.clone()).collect());
-
-        let expected = (message.encode(), peer_id, PROTOCOL);
+            .authentication
+            .send_to_peer(message(1), peer_id.clone())
+            .expect("peer is connected");
+        while test_data.network.live_senders() == 0 {
+            time::sleep(Duration::from_millis(1)).await;
+        }
 
-        assert_eq!(
-            test_data
-                .network
-                .send_message
-                .next()
-                .await
-                .expect("Should receive message"),
-            expected,
-        );
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamClosed(peer_id, PROTOCOL))
+            .expect("Should handle");
+        time::timeout(Duration::from_secs(1), async {
+            while test_data.network.live_senders() > 0 {
+                time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("peer sender should exit right away");
 
         test_data.cleanup().await
     }
 
     #[tokio::test]
-    async fn test_send_to_random_disconnected() {
+    async fn test_broadcasts_skip_peers_according_to_weight() {
         let mut test_data = TestData::prepare();
+        // Peer senders never run, the outcome of queueing does not matter here.
+        test_data.service.set_spawner(ManualSpawner::default());
+        test_data.service.authentication.broadcast_rng = StdRng::seed_from_u64(42);
 
         let peer_id = random_peer_id();
-        let other_peer_id = random_peer_id();
+        test_data
+            .service
+            .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+            .expect("Should handle");
+        test_data.service.set_peer_weight(PROTOCOL, peer_id, 0.5);
 
-        let message = message(1);
+        let broadcasts = 1000;
+        let skipped = (0..broadcasts)
+            .filter(|i| {
+                test_data
+                    .service
+                    .authentication
+                    .broadcast(message(*i as u8), Priority::Normal, None)
+                    .attempted
+                    == 0
+            })
+            .count();
+        assert!(
+            (400..=600).contains(&skipped),
+            "skipped {skipped} of {broadcasts} broadcasts"
+        );
+        drop(test_data.service);
 
+        test_data.network.close_channels().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_losing_all_peers_is_published_once() {
+        let mut test_data = TestData::prepare();
+        test_data.service.set_spawner(ManualSpawner::default());
+        let mut connection_events = test_data.service.subscribe_connection_events();
+        let debounce = ProtocolConfig::default().connectivity_debounce;
+
+        let peer_ids: Vec<_> = (0..2).map(|_| random_peer_id()).collect();
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamOpened(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        for peer_id in &peer_ids {
+            test_data
+                .service
+                .handle_network_event(MockEvent::StreamClosed(peer_id.clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        // A peer flapping within the debounce period does not make any difference.
+        for event in [MockEvent::StreamOpened, MockEvent::StreamClosed] {
+            test_data
+                .service
+                .handle_network_event(event(peer_ids[0].clone(), PROTOCOL))
+                .expect("Should handle");
+        }
+        test_data.service.check_connectivity();
+        time::advance(debounce).await;
+        test_data.service.check_connectivity();
+        time::advance(debounce).await;
+        test_data.service.check_connectivity();
+        assert_eq!(test_data.service.next_connectivity_deadline(), None);
         test_data
             .service
-            .handle_network_event(MockEvent::StreamOpened(other_peer_id.clone(), PROTOCOL))
+            .handle_network_event(MockEvent::StreamOpened(peer_ids[1].clone(), PROTOCOL))
             .expect("Should handle");
+        drop(test_data.service);
 
-        test_data
+        let mut connectivity_events = Vec::new();
+        while let Some(event) = connection_events.next().await {
+            if let ConnectionEvent::ProtocolConnectivityLost(_)
+            | ConnectionEvent::ProtocolConnectivityRegained(_) = event
+            {
+                connectivity_events.push(event);
+            }
+        }
+        assert_eq!(
+            connectivity_events,
+            vec![
+                ConnectionEvent::ProtocolConnectivityLost(PROTOCOL),
+                ConnectionEvent::ProtocolConnectivityRegained(PROTOCOL),
+            ]
+        );
+
+        test_data.network.close_channels().await;
+    }
+
+    fn rebroadcast_schedule() -> RebroadcastConfig {
+        RebroadcastConfig {
+            initial_interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(4),
+            duration: Duration::from_secs(12),
+        }
+    }
+
+    /// Starts rebroadcasting and runs the scheduled rebroadcasts until there are none left,
+    /// returning when the broadcasts happened, relative to the start.
+    async fn run_rebroadcasts(
+        test_data: &mut TestData,
+        cancel_after: Option<usize>,
+    ) -> Vec<Duration> {
+        let (feedback_tx, mut feedback_rx) = mpsc::unbounded();
+        test_data.service.authentication.broadcast_feedback = Some(feedback_tx);
+        let started = time::Instant::now();
+        let handle: RebroadcastHandle = test_data
+            .rebroadcast(message(1), rebroadcast_schedule())
+            .expect("interface works");
+        let command = test_data
             .service
-            .send_to_random_authentication(message.clone(), iter::once(peer_id.clone()).collect());
+            .authentication
+            .messages_from_user
+            .next()
+            .await
+            .expect("command was sent");
+        test_data.service.authentication.handle_command(command);
 
-        let expected = (message.encode(), other_peer_id, PROTOCOL);
+        let mut broadcasts = Vec::new();
+        loop {
+            while let Ok(Some(_)) = feedback_rx.try_next() {
+                broadcasts.push(started.elapsed());
+            }
+            if Some(broadcasts.len()) == cancel_after {
+                handle.cancel();
+            }
+            match test_data.service.next_rebroadcast() {
+                Some(at) => time::sleep_until(at).await,
+                None => return broadcasts,
+            }
+            test_data.service.rebroadcast_due();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rebroadcast_intervals_grow() {
+        let mut test_data = TestData::prepare();
 
         assert_eq!(
-            test_data
-                .network
-                .send_message
-                .next()
-                .await
-                .expect("Should receive message"),
-            expected,
+            run_rebroadcasts(&mut test_data, None).await,
+            [0, 1, 3, 7, 11].map(Duration::from_secs)
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rebroadcast_stops_when_cancelled() {
+        let mut test_data = TestData::prepare();
+
+        assert_eq!(
+            run_rebroadcasts(&mut test_data, Some(2)).await,
+            [0, 1].map(Duration::from_secs)
         );
 
         test_data.cleanup().await
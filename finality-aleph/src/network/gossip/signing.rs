@@ -0,0 +1,69 @@
+//! Optional application level authentication of messages. The encoded data gets wrapped together
+//! with a signature over it, and received messages are only decoded if the signature is valid.
+use std::sync::Arc;
+
+use parity_scale_codec::{Decode, Encode};
+
+/// Signs the encoded data of outgoing messages.
+pub trait Signer: Send + Sync + 'static {
+    fn sign(&self, payload: &[u8]) -> Vec<u8>;
+}
+
+/// Checks the signatures of received messages.
+pub trait Verifier: Send + Sync + 'static {
+    fn verify(&self, payload: &[u8], signature: &[u8]) -> bool;
+}
+
+/// Signing of outgoing and verification of received messages of a protocol.
+#[derive(Clone)]
+pub struct MessageSigning {
+    signer: Arc<dyn Signer>,
+    verifier: Arc<dyn Verifier>,
+}
+
+impl MessageSigning {
+    pub fn new(signer: impl Signer, verifier: impl Verifier) -> Self {
+        MessageSigning {
+            signer: Arc::new(signer),
+            verifier: Arc::new(verifier),
+        }
+    }
+
+    /// Wraps the payload together with its signature.
+    pub fn sign(&self, payload: &[u8]) -> Vec<u8> {
+        let signature = self.signer.sign(payload);
+        (payload, signature).encode()
+    }
+
+    /// Returns the payload of the wrapped message, if its signature is valid.
+    pub fn verify(&self, mut message: &[u8]) -> Option<Vec<u8>> {
+        let (payload, signature) = <(Vec<u8>, Vec<u8>)>::decode(&mut message).ok()?;
+        if !message.is_empty() || !self.verifier.verify(&payload, &signature) {
+            return None;
+        }
+        Some(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MessageSigning;
+    use crate::network::gossip::mock::KeyedSum;
+
+    #[test]
+    fn verifies_only_intact_messages() {
+        let signing = MessageSigning::new(KeyedSum(7), KeyedSum(7));
+        let signed = signing.sign(&[1, 2, 3]);
+        assert_eq!(signing.verify(&signed), Some(vec![1, 2, 3]));
+
+        let mut tampered = signed.clone();
+        tampered[1] ^= 1;
+        assert_eq!(signing.verify(&tampered), None);
+        let mut extended = signed.clone();
+        extended.push(0);
+        assert_eq!(signing.verify(&extended), None);
+
+        let other_key = MessageSigning::new(KeyedSum(8), KeyedSum(8));
+        assert_eq!(other_key.verify(&signed), None);
+    }
+}
@@ -12,7 +12,30 @@ pub mod tcp;
 #[cfg(test)]
 pub use gossip::mock::{MockEvent, MockRawNetwork};
 pub use gossip::{
-    Error as GossipError, Network as GossipNetwork, Protocol, Service as GossipService,
+    AdaptiveStatusConfig as GossipAdaptiveStatusConfig, BackoffConfig as GossipBackoffConfig,
+    BatchConfig as GossipBatchConfig, BroadcastAllHandle as GossipBroadcastAllHandle,
+    BroadcastDedupConfig as GossipBroadcastDedupConfig, BroadcastResult as GossipBroadcastResult,
+    CircuitBreakerConfig as GossipCircuitBreakerConfig, Codec as GossipCodec,
+    CompressionConfig as GossipCompressionConfig, Config as GossipConfig,
+    ConnectionEvent as GossipConnectionEvent, DecodeFailure as GossipDecodeFailure,
+    Delivery as GossipDelivery, Direction as GossipDirection, DropPolicy as GossipDropPolicy,
+    Envelope as GossipEnvelope, Error as GossipError, FairDrainConfig as GossipFairDrainConfig,
+    FragmentationConfig as GossipFragmentationConfig,
+    InboundDedupConfig as GossipInboundDedupConfig,
+    InboundRateLimitConfig as GossipInboundRateLimitConfig, Incoming as GossipIncoming,
+    LatencyConfig as GossipLatencyConfig, MessageKind as GossipMessageKind,
+    MessageSigning as GossipMessageSigning, Network as GossipNetwork,
+    PeerLimitConfig as GossipPeerLimitConfig, PeerLimitPolicy as GossipPeerLimitPolicy,
+    PeerSnapshot as GossipPeerSnapshot, Priority as GossipPriority, Protocol,
+    ProtocolConfig as GossipProtocolConfig, ProtocolSnapshot as GossipProtocolSnapshot,
+    QuietStatusConfig as GossipQuietStatusConfig, RebroadcastConfig as GossipRebroadcastConfig,
+    RebroadcastHandle as GossipRebroadcastHandle, RequestError as GossipRequestError,
+    RequestResponse as GossipRequestResponse, Response as GossipResponse,
+    ScaleCodec as GossipScaleCodec, SendDedupConfig as GossipSendDedupConfig,
+    Service as GossipService, ServiceHandle as GossipServiceHandle, Signer as GossipSigner,
+    Spawner as GossipSpawner, StateSnapshot as GossipStateSnapshot, StopHandle as GossipStopHandle,
+    StuckPeerConfig as GossipStuckPeerConfig, TerminationReason as GossipTerminationReason,
+    Verifier as GossipVerifier, VersionRange as GossipVersionRange,
 };
 use network_clique::{AddressingInformation, NetworkIdentity, PeerId};
 pub use substrate::{
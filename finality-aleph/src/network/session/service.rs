@@ -25,7 +25,7 @@ use crate::{
             },
             Network, SessionHandlerError, SessionManager, SessionSender, VersionedAuthentication,
         },
-        AddressingInformation, Data, GossipNetwork, NetworkIdentity,
+        AddressingInformation, Data, GossipError, GossipNetwork, NetworkIdentity,
     },
     MillisecsPerBlock, NodeIndex, SessionId, SessionPeriod, STATUS_REPORT_INTERVAL,
 };
@@ -176,7 +176,7 @@ pub struct Service<
     D: Data,
     NI: NetworkIdentity,
     CN: CliqueNetwork<NI::PeerId, NI::AddressingInformation, DataInSession<D>>,
-    GN: GossipNetwork<VersionedAuthentication<NI::AddressingInformation>>,
+    GN: GossipNetwork<VersionedAuthentication<NI::AddressingInformation>, Error = GossipError>,
     VCU: ValidatorAddressCacheUpdater,
 > where
     NI::PeerId: PublicKey,
@@ -215,7 +215,7 @@ impl<
         D: Data,
         NI: NetworkIdentity,
         CN: CliqueNetwork<NI::PeerId, NI::AddressingInformation, DataInSession<D>>,
-        GN: GossipNetwork<VersionedAuthentication<NI::AddressingInformation>>,
+        GN: GossipNetwork<VersionedAuthentication<NI::AddressingInformation>, Error = GossipError>,
         VCU: ValidatorAddressCacheUpdater,
     > Service<D, NI, CN, GN, VCU>
 where
@@ -269,9 +269,23 @@ where
         to_send: Vec<VersionedAuthentication<NI::AddressingInformation>>,
     ) -> Result<(), Error<GN::Error>> {
         for auth in to_send {
-            self.gossip_network
-                .broadcast(auth)
-                .map_err(Error::GossipNetwork)?;
+            match self.gossip_network.broadcast(auth) {
+                Ok(()) => {}
+                Err(GossipError::ServiceStopped) => {
+                    return Err(Error::GossipNetwork(GossipError::ServiceStopped))
+                }
+                // Discovery runs regularly, so the skipped authentications get sent again later.
+                Err(GossipError::Congested) => {
+                    warn!(
+                        target: "aleph-network",
+                        "Gossip network congested, skipping authentications until the next discovery."
+                    );
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!(target: "aleph-network", "Failed to broadcast authentication: {}.", e)
+                }
+            }
         }
         Ok(())
     }
@@ -5,14 +5,17 @@ use futures::stream::{Fuse, Stream, StreamExt};
 use log::{error, trace, warn};
 use sc_network::{
     multiaddr::Protocol as MultiaddressProtocol, Event as SubstrateEvent, Multiaddr,
-    NetworkEventStream as _, NetworkNotification, NetworkPeers, NetworkService,
-    NotificationSenderT, PeerId, ProtocolName, SyncEventStream,
+    NetworkEventStream as _, NetworkNotification, NetworkPeers, NetworkService, NetworkStateInfo,
+    NotificationSenderT, PeerId, ProtocolName, ReputationChange as SubstrateReputationChange,
+    SyncEventStream,
 };
 use sc_network_common::{sync::SyncEvent, ExHashT};
 use sc_network_sync::SyncingService;
 use sp_runtime::traits::Block;
 
-use crate::network::gossip::{Event, EventStream, NetworkSender, Protocol, RawNetwork};
+use crate::network::gossip::{
+    Event, EventStream, NetworkSender, Protocol, RawNetwork, ReputationChange,
+};
 
 /// Name of the network protocol used by Aleph Zero to disseminate validator
 /// authentications.
@@ -234,6 +237,11 @@ impl<B: Block, H: ExHashT> SubstrateNetwork<B, H> {
         }
     }
 
+    /// The id of this node in the network.
+    pub fn local_peer_id(&self) -> PeerId {
+        self.network.local_peer_id()
+    }
+
     pub fn event_stream(&self) -> NetworkEventStream<B, H> {
         NetworkEventStream {
             stream: self.network.event_stream("aleph-network").fuse(),
@@ -251,6 +259,7 @@ impl<B: Block, H: ExHashT> RawNetwork for SubstrateNetwork<B, H> {
     type SenderError = SenderError;
     type NetworkSender = SubstrateNetworkSender;
     type PeerId = PeerId;
+    type Address = Multiaddr;
 
     fn sender(
         &self,
@@ -267,4 +276,39 @@ impl<B: Block, H: ExHashT> RawNetwork for SubstrateNetwork<B, H> {
             peer_id,
         })
     }
+
+    fn report_peer(&self, peer_id: Self::PeerId, change: ReputationChange) {
+        self.network.report_peer(
+            peer_id,
+            SubstrateReputationChange::new(change.value, change.reason),
+        );
+    }
+
+    fn disconnect_peer(&self, peer_id: Self::PeerId) {
+        for protocol in [Protocol::Authentication, Protocol::BlockSync] {
+            let protocol_name = self.naming.protocol_name(&protocol);
+            // Otherwise a dialed peer would be reconnected to right away.
+            if let Err(e) = self
+                .network
+                .remove_peers_from_reserved_set(protocol_name.clone(), vec![peer_id])
+            {
+                warn!(target: "aleph-network", "Error while removing peer from {:?} reserved set: {}", protocol, e);
+            }
+            self.network.disconnect_peer(peer_id, protocol_name);
+        }
+    }
+
+    /// Adds the peer to the reserved sets of all the protocols, it stays there until it is
+    /// disconnected with `disconnect_peer`.
+    fn dial(&self, peer_id: Self::PeerId, address: Self::Address) {
+        let address = address.with(MultiaddressProtocol::P2p(peer_id.into()));
+        for protocol in [Protocol::Authentication, Protocol::BlockSync] {
+            if let Err(e) = self.network.add_peers_to_reserved_set(
+                self.naming.protocol_name(&protocol),
+                iter::once(address.clone()).collect(),
+            ) {
+                error!(target: "aleph-network", "add_reserved failed for {:?}: {}", protocol, e);
+            }
+        }
+    }
 }
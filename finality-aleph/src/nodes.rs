@@ -81,6 +81,7 @@ where
         external_addresses,
         validator_port,
         rate_limiter_config,
+        gossip_config,
         sync_oracle,
         validator_address_cache,
         transaction_pool,
@@ -132,14 +133,20 @@ where
         }
     });
 
+    let local_peer_id = network.local_peer_id();
     let (gossip_network_service, authentication_network, block_sync_network) = GossipService::new(
         network,
         network_event_stream,
         spawn_handle.clone(),
         registry.clone(),
+        gossip_config,
+        None,
+        Some(local_peer_id),
+        None,
     );
+    let (_gossip_network_exit, gossip_network_exit) = oneshot::channel();
     let gossip_network_task = async move {
-        match gossip_network_service.run().await {
+        match gossip_network_service.run(gossip_network_exit).await {
             Ok(_) => error!(target: LOG_TARGET, "GossipNetwork finished."),
             Err(err) => error!(
                 target: LOG_TARGET,
@@ -26,7 +26,8 @@ use crate::{
             authentication, ConnectionManager, ConnectionManagerConfig, DataInSession,
             ManagerError, SessionHandler, SessionManager, VersionedAuthentication,
         },
-        GossipError, GossipNetwork, GossipService, MockEvent, MockRawNetwork, Protocol,
+        GossipConfig, GossipError, GossipNetwork, GossipService, MockEvent, MockRawNetwork,
+        Protocol,
     },
     MillisecsPerBlock, NodeIndex, Recipient, SessionId, SessionPeriod,
 };
@@ -106,6 +107,10 @@ async fn prepare_one_session_test_data() -> TestData {
         network.event_stream(),
         task_manager.spawn_handle().into(),
         None,
+        GossipConfig::default(),
+        None,
+        None,
+        None,
     );
 
     let (connection_manager_service, session_manager) = ConnectionManager::new(
@@ -129,13 +134,7 @@ async fn prepare_one_session_test_data() -> TestData {
     };
 
     let gossip_service_task = async move {
-        if gossip_service_exit_rx.is_terminated() {
-            return;
-        }
-        tokio::select! {
-            _ = gossip_service.run() => { },
-            _ = gossip_service_exit_rx => { },
-        };
+        let _ = gossip_service.run(gossip_service_exit_rx).await;
     };
     let network_manager_handle = tokio::spawn(network_manager_task);
     let gossip_service_handle = tokio::spawn(gossip_service_task);